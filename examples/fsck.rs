@@ -0,0 +1,43 @@
+//! Check the consistency of a Theory book.
+//!
+//! The process exits with a non-zero status if any issue is found.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Path of the book.
+    book: PathBuf,
+}
+
+fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let input = BufReader::new(File::open(&args.book)?);
+    let mut book = theory::Book::load(input)?;
+
+    let report = book.verify();
+
+    println!(
+        "Checked {} pages and {} data blocks.",
+        report.num_pages(),
+        report.num_blocks()
+    );
+
+    if report.is_ok() {
+        println!("No issues found.");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    println!("Found {} issues:", report.issues().len());
+    for issue in report.issues() {
+        println!("\t{}", issue);
+    }
+
+    Ok(ExitCode::FAILURE)
+}
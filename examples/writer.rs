@@ -28,6 +28,12 @@ struct Args {
     #[arg(short, long)]
     #[cfg(feature = "lz4")]
     lz4: bool,
+
+    /// Use Zstandard to compress data blocks, with a dictionary trained
+    /// from the pages.
+    #[arg(long)]
+    #[cfg(feature = "zstd")]
+    zstd: bool,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -45,6 +51,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         book.set_compression(theory::BlockCompression::Lz4);
     }
 
+    #[cfg(feature = "zstd")]
+    if args.zstd {
+        book.set_compression(theory::BlockCompression::Zstd {
+            level: 19,
+            dictionary: true,
+        });
+    }
+
     if let Some(title) = args.title.take() {
         book.add_metadata(theory::MetadataEntry::Title(title));
     }
@@ -56,7 +70,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Write the book in the file.
     let output = BufWriter::new(File::create(&args.book)?);
-    book.dump(output)?;
+    let stats = book.dump(output)?;
+
+    if stats.duplicated_pages() > 0 {
+        println!(
+            "{} duplicated pages ({} bytes saved).",
+            stats.duplicated_pages(),
+            stats.bytes_saved()
+        );
+    }
 
     Ok(())
 }
@@ -0,0 +1,702 @@
+//! Module with the `BookAppender` implementation.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::analysis::Analyzer;
+use crate::builder::book_language;
+use crate::page::persistence::build_page;
+use crate::page::PageError;
+use crate::persistence::{self, PersistenceError, Version};
+use crate::search::analysis::Registry;
+use crate::search::persistence::IndexWriter;
+use crate::{BlockCompression, Book, CompressionPolicy, MetadataEntry, Page, PageId};
+
+/// Add, replace, or remove pages in an existing book, without rewriting it.
+///
+/// Changes are kept in memory until [`finish`](Self::finish) is called. Then,
+/// the content of the new pages is written at the end of the book, followed by
+/// a new page index, a new search index, and a new metadata table. Finally,
+/// the header is updated to point to the new sections. If the process is
+/// interrupted before the header is updated, the book keeps its previous
+/// content.
+///
+/// When the book is opened with [`Book::open_for_append`], the new sections
+/// are written to the disk (with [`File::sync_data`]) before the header is
+/// updated, so the header never refers to data lost in a crash.
+///
+/// The data of the replaced or removed pages is still in the book, but it is
+/// not used anymore. It can be dropped with [`compact`](Self::compact).
+///
+/// ```
+/// use theory::{Book, BookAppender};
+/// use std::io::Cursor;
+///
+/// let mut builder = Book::builder();
+/// let first = builder.new_page("First").set_content("- 1 -").id();
+///
+/// let mut buffer = Vec::new();
+/// builder.dump(Cursor::new(&mut buffer)).unwrap();
+///
+/// let mut appender = BookAppender::new(Cursor::new(buffer)).unwrap();
+///
+/// let mut page = appender.new_page("Second");
+/// page.set_content("- 2 -");
+/// appender.add_page(page);
+///
+/// let buffer = appender.finish().unwrap().into_inner();
+///
+/// let mut book = Book::load(Cursor::new(buffer)).unwrap();
+/// assert_eq!(book.num_pages(), 2);
+/// assert_eq!(book.get_page_by_id(first).unwrap().content(), b"- 1 -");
+/// ```
+pub struct BookAppender<F> {
+    book: Book<F>,
+
+    /// File of the book, if it was opened with
+    /// [`Book::open_for_append`].
+    path: Option<PathBuf>,
+
+    /// Function to write the data in the stream to the storage device.
+    sync: Option<fn(&mut F) -> io::Result<()>>,
+
+    metadata: Vec<MetadataEntry>,
+
+    /// Pages added or replaced, or `None` for the removed pages.
+    changes: BTreeMap<PageId, Option<Page>>,
+
+    next_page_id: NonZeroU32,
+
+    compression: CompressionPolicy,
+
+    analyzers: Registry,
+}
+
+impl Book<()> {
+    /// Open a book file to add, replace, or remove pages.
+    ///
+    /// See [`BookAppender`] for more details.
+    pub fn open_for_append(path: impl AsRef<Path>) -> Result<BookAppender<File>, PersistenceError> {
+        let path = path.as_ref();
+        let file = File::options().read(true).write(true).open(path)?;
+
+        let mut appender = BookAppender::new(file)?;
+        appender.path = Some(path.to_owned());
+        appender.sync = Some(|file| file.sync_data());
+        Ok(appender)
+    }
+}
+
+impl<F: Read + Write + Seek> BookAppender<F> {
+    /// Load the book in `stream` to add, replace, or remove pages.
+    ///
+    /// The book must start at the beginning of the stream, and use version 2
+    /// of the file format.
+    pub fn new(stream: F) -> Result<Self, PersistenceError> {
+        let mut book = Book::load(stream)?;
+
+        if book.data_blocks.version() != Version::V2 {
+            return Err(PersistenceError::UnsupportedVersion);
+        }
+
+        let metadata = book
+            .metadata()?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| PageError::InvalidMetadata(e.to_string()))?;
+
+        let next_page_id = (&book.page_index)
+            .into_iter()
+            .next_back()
+            .map_or(NonZeroU32::new(1), |(id, _)| id.0.checked_add(1))
+            .ok_or(PersistenceError::TooManyPages)?;
+
+        Ok(BookAppender {
+            book,
+            path: None,
+            sync: None,
+            metadata,
+            changes: BTreeMap::new(),
+            next_page_id,
+            compression: CompressionPolicy::default(),
+            analyzers: Registry::default(),
+        })
+    }
+
+    /// Set the compression method to store the new pages.
+    ///
+    /// It is the default method of the [`CompressionPolicy`].
+    pub fn set_compression(&mut self, compression: BlockCompression) -> &mut Self {
+        self.compression.set_default(compression);
+        self
+    }
+
+    /// Set the policy to select the compression method of the new pages.
+    pub fn set_compression_policy(&mut self, policy: CompressionPolicy) -> &mut Self {
+        self.compression = policy;
+        self
+    }
+
+    /// Set the analyzer to build the search index for pages written in
+    /// `language`.
+    ///
+    /// The search index is rebuilt with the terms of all pages, so this
+    /// function must be used if the book was created with custom analyzers.
+    /// See [`BookBuilder::add_analyzer`](crate::BookBuilder::add_analyzer)
+    /// for more details.
+    pub fn add_analyzer(&mut self, language: &str, analyzer: impl Analyzer + 'static) -> &mut Self {
+        self.analyzers.add(Some(language), Arc::new(analyzer));
+        self
+    }
+
+    /// Return the metadata entries of the book.
+    pub fn metadata(&self) -> &[MetadataEntry] {
+        &self.metadata
+    }
+
+    /// Replace the metadata entries of the book.
+    pub fn set_metadata(&mut self, metadata: Vec<MetadataEntry>) -> &mut Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Create a new page with a title, and a new identifier.
+    ///
+    /// The page is not included in the book until it is added with
+    /// [`add_page`](Self::add_page).
+    pub fn new_page(&mut self, title: impl Into<String>) -> Page {
+        let page = Page::new(title.into(), self.next_page_id);
+        self.next_page_id = self.next_page_id.saturating_add(1);
+        page
+    }
+
+    /// Add a page to the book. If the book already contains a page with the
+    /// same identifier, it is replaced.
+    pub fn add_page(&mut self, page: Page) -> &mut Self {
+        if page.id >= self.next_page_id {
+            self.next_page_id = page.id.saturating_add(1);
+        }
+
+        self.changes.insert(page.id(), Some(page));
+        self
+    }
+
+    /// Remove a page from the book.
+    ///
+    /// Its children must be removed too, or moved to another parent.
+    /// Otherwise, [`finish`](Self::finish) and [`compact_to`](Self::compact_to)
+    /// fail with [`PageError::HasChildren`].
+    pub fn remove_page(&mut self, page_id: PageId) -> &mut Self {
+        self.changes.insert(page_id, None);
+        self
+    }
+
+    /// Write the changes at the end of the book, and update its header.
+    ///
+    /// The search index is rebuilt, so the content of every page in the book
+    /// is read again. If the book has no search index, it is not created, and
+    /// only the content of the new pages is written.
+    ///
+    /// On success, returns the stream with the book.
+    pub fn finish(self) -> Result<F, PersistenceError> {
+        let BookAppender {
+            mut book,
+            sync,
+            metadata,
+            changes,
+            compression,
+            analyzers,
+            ..
+        } = self;
+
+        check_removed_parents(&book, &changes)?;
+
+        let book_language = book_language(&metadata);
+
+        let mut keys: HashMap<PageId, Vec<String>> = HashMap::new();
+        for (key, page_id) in book.page_index.keys() {
+            keys.entry(page_id).or_default().push(key.to_owned());
+        }
+
+        // Terms of the pages not modified. Books without a search index are
+        // updated without one, so the content of the pages is not read.
+        let mut search_index = book.fts_pos.map(|_| IndexWriter::default());
+        let mut existing = Vec::new();
+        for (page_id, entry) in &book.page_index {
+            if !changes.contains_key(page_id) {
+                if let Some(search_index) = &mut search_index {
+                    let page = build_page(entry, &mut book.data_blocks)?;
+                    search_index.add_page(&page, book_language, &analyzers)?;
+                }
+
+                existing.push((entry.clone(), keys.remove(page_id).unwrap_or_default()));
+            }
+        }
+
+        let output = BufWriter::new(book.data_blocks.into_inner());
+        let mut writer =
+            persistence::Writer::append(output, compression.default_compression(), search_index)?;
+
+        for (entry, keys) in existing {
+            writer.add_existing(entry, &keys)?;
+        }
+
+        for page in changes.values().flatten() {
+            let method = compression.select(page);
+            writer.add_page(page, method, book_language, &analyzers)?;
+        }
+
+        let output = writer.finish_with(&metadata, |output| match sync {
+            Some(sync) => sync(output.get_mut()),
+            None => Ok(()),
+        })?;
+
+        Ok(output.into_inner().map_err(|e| e.into_error())?)
+    }
+
+    /// Write a new book to `output`, with the current pages of the book and
+    /// the changes in this appender.
+    ///
+    /// Unlike [`finish`](Self::finish), the new book does not contain the
+    /// data of the replaced or removed pages.
+    pub fn compact_to<O: Write + Seek>(self, output: O) -> Result<O, PersistenceError> {
+        let BookAppender {
+            mut book,
+            metadata,
+            mut changes,
+            compression,
+            analyzers,
+            ..
+        } = self;
+
+        check_removed_parents(&book, &changes)?;
+
+        let book_language = book_language(&metadata);
+
+        let mut writer = persistence::Writer::new(output, compression.default_compression())?;
+        if book.fts_pos.is_none() {
+            writer.disable_search_index();
+        }
+
+        for (page_id, entry) in &book.page_index {
+            let page = match changes.remove(page_id) {
+                Some(Some(page)) => page,
+                Some(None) => continue,
+                None => build_page(entry, &mut book.data_blocks)?,
+            };
+
+            let method = compression.select(&page);
+            writer.add_page(&page, method, book_language, &analyzers)?;
+        }
+
+        // New pages.
+        for page in changes.values().flatten() {
+            let method = compression.select(page);
+            writer.add_page(page, method, book_language, &analyzers)?;
+        }
+
+        writer.finish(&metadata)
+    }
+}
+
+/// Fail if a removed page is the parent of a page that is kept in the book.
+fn check_removed_parents<F>(
+    book: &Book<F>,
+    changes: &BTreeMap<PageId, Option<Page>>,
+) -> Result<(), PageError> {
+    let existing = (&book.page_index)
+        .into_iter()
+        .filter(|(page_id, _)| !changes.contains_key(page_id))
+        .map(|(_, entry)| entry.parent_id());
+
+    let parents = existing.chain(changes.values().flatten().map(Page::parent));
+
+    for parent in parents.flatten() {
+        if let Some(None) = changes.get(&parent) {
+            return Err(PageError::HasChildren(parent.0.get()));
+        }
+    }
+
+    Ok(())
+}
+
+impl BookAppender<File> {
+    /// Rewrite the book file, without the data of the replaced or removed
+    /// pages.
+    ///
+    /// The new book is written to a temporary file in the same directory,
+    /// which replaces the original file when it is complete.
+    ///
+    /// The appender must be created with [`Book::open_for_append`].
+    pub fn compact(mut self) -> Result<(), PersistenceError> {
+        let path = match self.path.take() {
+            Some(path) => path,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The path of the book is unknown.",
+                )
+                .into())
+            }
+        };
+
+        let mut temp_path = OsString::from(path.as_os_str());
+        temp_path.push(".compact");
+        let temp_path = PathBuf::from(temp_path);
+
+        let output = BufWriter::new(File::create(&temp_path)?);
+        let result = self.compact_to(output).and_then(|output| {
+            let file = output.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+
+        fs::rename(&temp_path, &path)?;
+
+        // The new name of the file is stored when its directory is synced.
+        #[cfg(unix)]
+        {
+            let directory = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+
+            File::open(directory)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BookAppender;
+    use crate::errors::{PageError, PersistenceError};
+    use crate::{Book, MetadataEntry};
+    use std::io::Cursor;
+
+    /// Build a book with 3 pages.
+    fn base_book() -> (Vec<u8>, Vec<crate::PageId>) {
+        let mut builder = Book::builder();
+        builder.add_metadata(MetadataEntry::Title("Base".into()));
+
+        let ids = (1..=3)
+            .map(|n| {
+                builder
+                    .new_page(format!("Page {}", n))
+                    .set_content(format!("Content of the page number {}", n))
+                    .id()
+            })
+            .collect();
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+        (buffer, ids)
+    }
+
+    #[test]
+    fn append_pages() {
+        let (buffer, ids) = base_book();
+        let original_len = buffer.len();
+
+        let mut appender = BookAppender::new(Cursor::new(buffer)).unwrap();
+        assert_eq!(appender.metadata(), [MetadataEntry::Title("Base".into())]);
+
+        appender.set_metadata(vec![MetadataEntry::Title("Updated".into())]);
+
+        let mut new_page = appender.new_page("Page 4");
+        new_page.set_content("Content of the page number 4, with unicorns");
+        let new_id = new_page.id();
+        appender.add_page(new_page);
+
+        let mut replaced = Book::load(Cursor::new(base_book().0))
+            .unwrap()
+            .get_page_by_id(ids[1])
+            .unwrap();
+        replaced.set_content("Replaced with dragons");
+        appender.add_page(replaced);
+
+        appender.remove_page(ids[2]);
+
+        let buffer = appender.finish().unwrap().into_inner();
+
+        // The previous content is kept.
+        let header_end = crate::persistence::HEADER_END;
+        assert_eq!(
+            &buffer[header_end..original_len],
+            &base_book().0[header_end..]
+        );
+
+        let mut book = Book::load(Cursor::new(&buffer)).unwrap();
+        assert!(book.verify().is_ok());
+        assert_eq!(book.num_pages(), 3);
+
+        let metadata: Vec<_> = book.metadata().unwrap().map(Result::unwrap).collect();
+        assert_eq!(metadata, [MetadataEntry::Title("Updated".into())]);
+
+        assert_eq!(
+            book.get_page_by_id(ids[0]).unwrap().content(),
+            b"Content of the page number 1"
+        );
+        assert_eq!(
+            book.get_page_by_id(ids[1]).unwrap().content(),
+            b"Replaced with dragons"
+        );
+        assert!(book.get_page_by_id(ids[2]).is_err());
+
+        // The search index includes all pages.
+        let hits = |book: &mut Book<_>, query| {
+            let mut ids: Vec<_> = book
+                .search(query)
+                .unwrap()
+                .iter()
+                .map(|h| h.page_id())
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(hits(&mut book, "number"), [ids[0], new_id]);
+        assert_eq!(hits(&mut book, "dragons"), [ids[1]]);
+        assert_eq!(hits(&mut book, "unicorns"), [new_id]);
+
+        // Compact the book.
+        let mut appender = BookAppender::new(Cursor::new(buffer.clone())).unwrap();
+        appender.remove_page(new_id);
+        let compacted = appender
+            .compact_to(Cursor::new(Vec::new()))
+            .unwrap()
+            .into_inner();
+
+        assert!(compacted.len() < buffer.len());
+
+        let mut book = Book::load(Cursor::new(&compacted)).unwrap();
+        assert!(book.verify().is_ok());
+        assert_eq!(book.num_pages(), 2);
+        assert_eq!(
+            book.get_page_by_id(ids[1]).unwrap().content(),
+            b"Replaced with dragons"
+        );
+        assert_eq!(hits(&mut book, "number"), [ids[0]]);
+    }
+
+    #[test]
+    fn keep_keys() {
+        let mut builder = Book::builder();
+        builder.new_page("First").set_key("first");
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        let mut appender = BookAppender::new(Cursor::new(buffer.clone())).unwrap();
+        let mut page = appender.new_page("Second");
+        page.set_key("second");
+        appender.add_page(page);
+
+        let appended = appender.finish().unwrap().into_inner();
+
+        let mut book = Book::load(Cursor::new(appended)).unwrap();
+        assert_eq!(book.get_page_by_key("first").unwrap().key(), Some("first"));
+        assert_eq!(
+            book.get_page_by_key("second").unwrap().key(),
+            Some("second")
+        );
+
+        // Keys of the existing pages can't be reused.
+        let mut appender = BookAppender::new(Cursor::new(buffer)).unwrap();
+        let mut page = appender.new_page("Second");
+        page.set_key("first");
+        appender.add_page(page);
+
+        assert!(matches!(
+            appender.finish(),
+            Err(PersistenceError::PageError(PageError::DuplicatedKey(_)))
+        ));
+    }
+
+    #[test]
+    fn remove_parent() {
+        let mut builder = Book::builder();
+        let parent = builder.new_page("Parent").id();
+        let child = builder.new_page("Child").set_parent(parent).id();
+        builder.new_page("Other");
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        let toc_titles = |buffer: Vec<u8>| {
+            let mut book = Book::load(Cursor::new(buffer)).unwrap();
+            assert!(book.verify().is_ok());
+            book.toc()
+                .unwrap()
+                .map(|entry| entry.title().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        // A page with children can't be removed.
+        let mut appender = BookAppender::new(Cursor::new(buffer.clone())).unwrap();
+        appender.remove_page(parent);
+        assert!(matches!(
+            appender.finish(),
+            Err(PersistenceError::PageError(PageError::HasChildren(id))) if id == parent.0.get()
+        ));
+
+        let mut appender = BookAppender::new(Cursor::new(buffer.clone())).unwrap();
+        appender.remove_page(parent);
+        assert!(matches!(
+            appender.compact_to(Cursor::new(Vec::new())),
+            Err(PersistenceError::PageError(PageError::HasChildren(_)))
+        ));
+
+        // Remove the whole subtree.
+        let mut appender = BookAppender::new(Cursor::new(buffer.clone())).unwrap();
+        appender.remove_page(parent).remove_page(child);
+        let appended = appender.finish().unwrap().into_inner();
+        assert_eq!(toc_titles(appended), ["Other"]);
+
+        // Move the child to another parent.
+        let mut book = Book::load(Cursor::new(buffer.clone())).unwrap();
+        let mut child_page = book.get_page_by_id(child).unwrap();
+        child_page.parent_id = None;
+
+        let mut appender = BookAppender::new(Cursor::new(buffer)).unwrap();
+        appender.remove_page(parent).add_page(child_page);
+        let appended = appender.finish().unwrap().into_inner();
+
+        let mut titles = toc_titles(appended);
+        titles.sort();
+        assert_eq!(titles, ["Child", "Other"]);
+    }
+
+    #[test]
+    fn existing_content_not_read() {
+        let mut builder = Book::builder();
+        builder.disable_search_index();
+        builder
+            .new_page("First")
+            .set_key("first")
+            .set_content("Content of the first page");
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        // Corrupt the data block of the page. It is not read, because the
+        // book has no search index.
+        let needle = b"the first page";
+        let position = buffer.windows(needle.len()).position(|w| w == needle);
+        buffer[position.unwrap()] = b'T';
+
+        let mut appender = BookAppender::new(Cursor::new(buffer)).unwrap();
+        let page = appender.new_page("Second");
+        appender.add_page(page);
+
+        let appended = appender.finish().unwrap().into_inner();
+
+        let book = Book::load(Cursor::new(appended)).unwrap();
+        assert_eq!(book.num_pages(), 2);
+        assert_eq!(book.page_index.id_by_key("first").unwrap().0.get(), 1);
+    }
+
+    #[test]
+    fn unfinished_append() {
+        let (buffer, _) = base_book();
+
+        // Write a new page, but don't update the header.
+        let mut stream = Cursor::new(buffer.clone());
+        let mut writer =
+            crate::persistence::Writer::append(&mut stream, Default::default(), Default::default())
+                .unwrap();
+
+        // The first page is written when the second one is added.
+        for n in 4..6 {
+            let mut page = crate::Page::new("New".into(), std::num::NonZeroU32::new(n).unwrap());
+            page.set_content(vec![b'0' + n as u8; 100_000]);
+            writer
+                .add_page(&page, Default::default(), None, &Default::default())
+                .unwrap();
+        }
+
+        drop(writer);
+
+        let stream = stream.into_inner();
+        assert!(stream.len() > buffer.len());
+        assert_eq!(stream[..buffer.len()], buffer);
+
+        let book = Book::load(Cursor::new(stream)).unwrap();
+        assert_eq!(book.num_pages(), 3);
+    }
+
+    #[test]
+    fn sync_before_header() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let (buffer, _) = base_book();
+        let mut appender = BookAppender::new(Cursor::new(buffer)).unwrap();
+
+        // The header is updated between the two calls.
+        appender.sync = Some(|output| {
+            let book = Book::load(Cursor::new(output.get_ref().clone())).unwrap();
+            let expected = match CALLS.fetch_add(1, Ordering::SeqCst) {
+                0 => 3,
+                _ => 4,
+            };
+
+            assert_eq!(book.num_pages(), expected);
+            Ok(())
+        });
+
+        let page = appender.new_page("Page 4");
+        appender.add_page(page);
+        appender.finish().unwrap();
+
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn open_file() {
+        let (buffer, ids) = base_book();
+
+        let path = std::env::temp_dir().join(format!("theory-append-{}.book", std::process::id()));
+        std::fs::write(&path, &buffer).unwrap();
+
+        let mut appender = Book::open_for_append(&path).unwrap();
+        let mut page = appender.new_page("Page 4");
+        page.set_content("New content");
+        appender.add_page(page);
+        appender.remove_page(ids[0]);
+        appender.finish().unwrap();
+
+        let appended_len = std::fs::metadata(&path).unwrap().len();
+        assert!(appended_len > buffer.len() as u64);
+
+        Book::open_for_append(&path).unwrap().compact().unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < appended_len);
+
+        let mut book = Book::load(std::fs::File::open(&path).unwrap()).unwrap();
+        assert!(book.verify().is_ok());
+        assert_eq!(book.num_pages(), 3);
+        assert!(book.get_page_by_id(ids[0]).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reject_version_1() {
+        let buffer = include_bytes!("persistence/testdata/v1.book");
+        assert!(matches!(
+            BookAppender::new(Cursor::new(buffer.to_vec())),
+            Err(PersistenceError::UnsupportedVersion)
+        ));
+    }
+}
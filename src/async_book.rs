@@ -0,0 +1,249 @@
+//! Module with the `AsyncBook` implementation.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use futures_util::io::{AsyncRead, AsyncSeek};
+use futures_util::Stream;
+
+use crate::cache::CacheStats;
+use crate::errors::MetadataError;
+use crate::page::persistence::build_page_async;
+use crate::persistence::datablock::AsyncDataBlocksReader;
+use crate::{metadata, page, persistence, toc};
+use crate::{BookOptions, MetadataEntry};
+
+/// A book loaded from an asynchronous input stream.
+///
+/// It provides the same methods of [`Book`](crate::Book) to read the
+/// metadata and the pages of a book, but the input is read with
+/// [`AsyncRead`] and [`AsyncSeek`], so they don't block the executor.
+///
+/// The page index and the metadata table are read when the book is loaded.
+///
+/// ```
+/// # futures_executor::block_on(async {
+/// use futures_util::io::Cursor;
+/// use futures_util::StreamExt;
+/// use theory::{AsyncBook, Book};
+///
+/// let mut builder = Book::builder();
+/// builder.new_page("First").set_content("Content");
+///
+/// let mut buffer = Vec::new();
+/// builder.dump(std::io::Cursor::new(&mut buffer)).unwrap();
+///
+/// let mut book = AsyncBook::load(Cursor::new(buffer)).await.unwrap();
+///
+/// let pages: Vec<_> = book.pages().collect().await;
+/// assert_eq!(pages.len(), 1);
+/// # });
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub struct AsyncBook<I> {
+    /// Data blocks in the input stream.
+    pub(crate) data_blocks: AsyncDataBlocksReader<I>,
+
+    /// Number of pages in the book.
+    pub(crate) num_pages: usize,
+
+    /// Bytes of the metadata table.
+    pub(crate) metadata: Vec<u8>,
+
+    /// Page index loaded from the input.
+    pub(crate) page_index: page::Index,
+}
+
+impl<I: AsyncRead + AsyncSeek + Unpin> AsyncBook<I> {
+    /// Load book from a stream, serialized with
+    /// [`BookBuilder::dump()`](crate::BookBuilder::dump).
+    pub async fn load(input: I) -> Result<Self, persistence::PersistenceError> {
+        AsyncBook::load_with(input, &BookOptions::default()).await
+    }
+
+    /// Load book from a stream, with the options to control how it is read.
+    ///
+    /// See [`BookOptions`] for more details.
+    pub async fn load_with(
+        input: I,
+        options: &BookOptions,
+    ) -> Result<Self, persistence::PersistenceError> {
+        persistence::load_async(input, options).await
+    }
+
+    /// Return the number of pages included in the book.
+    pub fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Return an iterator to get all metadata entries in the book.
+    ///
+    /// The metadata table is already in memory, so the input is not used.
+    pub fn metadata(&self) -> impl Iterator<Item = Result<MetadataEntry, MetadataError>> + '_ {
+        metadata::load(Cursor::new(&self.metadata), self.metadata.len() as u64)
+    }
+
+    /// Return a stream to get all pages in the book.
+    pub fn pages(&mut self) -> impl Stream<Item = Result<page::Page, page::PageError>> + '_ {
+        let entries = self.page_index.into_iter();
+
+        futures_util::stream::unfold(
+            (entries, &mut self.data_blocks),
+            |(mut entries, data_blocks)| async move {
+                let (_, entry) = entries.next()?;
+                let page = build_page_async(entry, data_blocks).await;
+                Some((page, (entries, data_blocks)))
+            },
+        )
+    }
+
+    /// Return a single page by its identifier.
+    pub async fn get_page_by_id(
+        &mut self,
+        page_id: page::PageId,
+    ) -> Result<page::Page, page::PageError> {
+        let entry = self.page_index.entry(page_id)?;
+        build_page_async(entry, &mut self.data_blocks).await
+    }
+
+    /// Return a single page by its key.
+    ///
+    /// See [`Book::get_page_by_key`](crate::Book::get_page_by_key) for more
+    /// details.
+    pub async fn get_page_by_key(&mut self, key: &str) -> Result<page::Page, page::PageError> {
+        let page_id = self.page_index.id_by_key(key)?;
+        self.get_page_by_id(page_id).await
+    }
+
+    /// Table of contents of this book.
+    pub async fn toc(&mut self) -> Result<impl Iterator<Item = crate::TocEntry>, toc::TocError> {
+        // Titles are read before building the tree, since the function to
+        // get them can't be async.
+        let mut titles = HashMap::with_capacity(self.num_pages);
+        for (_, entry) in &self.page_index {
+            let title = entry
+                .get_page_title_async(&mut self.data_blocks)
+                .await
+                .map_err(toc::TocError::TitleError)?;
+
+            titles.insert(entry.page_id(), title);
+        }
+
+        let toc = toc::BookToc::with_titles(&self.page_index, |entry| {
+            Ok(titles.remove(&entry.page_id()).unwrap_or_default())
+        })?;
+
+        Ok(toc.into_iter())
+    }
+
+    /// Return the statistics of the cache for the data blocks of this book.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.data_blocks.cache_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncBook;
+    use crate::{Book, MetadataEntry, PageId};
+    use futures_executor::block_on;
+    use futures_util::io::Cursor;
+    use futures_util::StreamExt;
+
+    #[test]
+    fn read_book() {
+        let mut builder = Book::builder();
+        builder.add_metadata(MetadataEntry::Title("Async".into()));
+
+        let p1 = builder.new_page("A").set_content("- A -").id();
+        builder.new_page("B").set_parent(p1).set_content("- B -");
+        builder.new_page("C").set_content("- C -");
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(std::io::Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(std::io::Cursor::new(buffer.clone())).unwrap();
+
+        block_on(async {
+            let mut async_book = AsyncBook::load(Cursor::new(buffer)).await.unwrap();
+            assert_eq!(async_book.num_pages(), 3);
+
+            let metadata: Vec<_> = async_book.metadata().map(Result::unwrap).collect();
+            assert_eq!(metadata, [MetadataEntry::Title("Async".into())]);
+
+            for page in book.pages() {
+                let page = page.unwrap();
+                assert_eq!(async_book.get_page_by_id(page.id()).await.unwrap(), page);
+            }
+
+            let pages: Vec<_> = async_book.pages().map(Result::unwrap).collect().await;
+            let expected: Vec<_> = book.pages().map(Result::unwrap).collect();
+            assert_eq!(pages, expected);
+
+            let titles = |toc: &mut dyn Iterator<Item = crate::TocEntry>| {
+                toc.map(|e| (e.title().to_owned(), e.children().count()))
+                    .collect::<Vec<_>>()
+            };
+
+            assert_eq!(
+                titles(&mut async_book.toc().await.unwrap()),
+                titles(&mut book.toc().unwrap()),
+            );
+
+            assert!(async_book
+                .get_page_by_id(PageId::force_value(10))
+                .await
+                .is_err());
+            assert!(async_book.cache_stats().hits() > 0);
+        });
+    }
+
+    #[test]
+    fn load_v1_file() {
+        let input = Cursor::new(&include_bytes!("persistence/testdata/v1.book")[..]);
+
+        block_on(async {
+            let mut book = AsyncBook::load(input).await.unwrap();
+
+            let metadata: Vec<_> = book.metadata().map(Result::unwrap).collect();
+            assert_eq!(
+                metadata,
+                [
+                    MetadataEntry::Title("Theory Example".into()),
+                    MetadataEntry::Date(1234)
+                ]
+            );
+
+            let page = book.get_page_by_id(PageId::force_value(2)).await.unwrap();
+            assert_eq!(page.content(), b"A hash map.");
+        });
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_dictionary() {
+        let mut builder = Book::builder();
+        builder.set_compression(crate::BlockCompression::Zstd {
+            level: 3,
+            dictionary: true,
+        });
+
+        for n in 0..200 {
+            builder
+                .new_page(format!("Page {}", n))
+                .set_content(format!("<p>Content of the page {}.</p>", n));
+        }
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder.dump(std::io::Cursor::new(&mut buffer)).unwrap();
+
+        block_on(async {
+            let mut book = AsyncBook::load(Cursor::new(buffer)).await.unwrap();
+            let pages: Vec<_> = book.pages().map(Result::unwrap).collect().await;
+            assert_eq!(pages.len(), 200);
+            assert_eq!(pages[150].content(), b"<p>Content of the page 150.</p>");
+        });
+    }
+}
@@ -1,11 +1,18 @@
 //! Module with the `Book` implementation.
 
-use std::io::{self, Read, Seek, SeekFrom};
+use std::borrow::Cow;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::Arc;
 
+#[cfg(feature = "mmap")]
+use std::{fs::File, path::Path};
+
+use crate::analysis::Analyzer;
 use crate::builder::BookBuilder;
 use crate::errors::MetadataError;
 use crate::persistence::datablock::DataBlocksReader;
-use crate::{metadata, page, persistence, toc, MetadataEntry};
+use crate::{metadata, page, persistence, search, toc};
+use crate::{BookOptions, MetadataEntry, Query, SearchHit, SearchOptions};
 
 /// A book loaded from an input stream, like a file.
 pub struct Book<I> {
@@ -20,6 +27,15 @@ pub struct Book<I> {
 
     /// Page index loaded from the input.
     pub(crate) page_index: page::Index,
+
+    /// Position, in bytes, of the search index in the input, if any.
+    pub(crate) fts_pos: Option<u64>,
+
+    /// Search index, loaded when it is used for the first time.
+    pub(crate) search_index: Option<search::Index>,
+
+    /// Analyzers to process search queries.
+    pub(crate) analyzers: search::analysis::Registry,
 }
 
 impl Book<()> {
@@ -33,7 +49,17 @@ impl Book<()> {
 impl<I: Read + Seek> Book<I> {
     /// Load book from a stream, serialized with [`BookBuilder::dump()`].
     pub fn load(input: I) -> Result<Self, persistence::PersistenceError> {
-        persistence::load(input)
+        Book::load_with(input, &BookOptions::default())
+    }
+
+    /// Load book from a stream, with the options to control how it is read.
+    ///
+    /// See [`BookOptions`] for more details.
+    pub fn load_with(
+        input: I,
+        options: &BookOptions,
+    ) -> Result<Self, persistence::PersistenceError> {
+        persistence::load(input, options)
     }
 
     /// Return the number of pages included in the book.
@@ -61,9 +87,223 @@ impl<I: Read + Seek> Book<I> {
         self.page_index.get_by_id(&mut self.data_blocks, page_id)
     }
 
+    /// Return handles to all pages in the book.
+    ///
+    /// Data blocks are not read until the metadata or the content of a page
+    /// is requested. See [`PageRef`](page::PageRef) for more details.
+    pub fn page_refs(&mut self) -> impl Iterator<Item = page::PageRef<'_, I>> + '_ {
+        self.page_index.page_refs(&mut self.data_blocks)
+    }
+
+    /// Return a handle to a single page by its identifier.
+    pub fn page_ref(
+        &mut self,
+        page_id: page::PageId,
+    ) -> Result<page::PageRef<'_, I>, page::PageError> {
+        self.page_index.page_ref(&mut self.data_blocks, page_id)
+    }
+
+    /// Return a single page by its key.
+    ///
+    /// Keys are set with [`Page::set_key`](page::Page::set_key), and they
+    /// are found in the key table of the book, without reading other pages.
+    ///
+    /// ```
+    /// # use theory::Book;
+    /// # use std::io::Cursor;
+    /// let mut builder = Book::builder();
+    /// builder
+    ///     .new_page("HashMap")
+    ///     .set_key("std/collections/hash_map");
+    ///
+    /// let mut buffer = Vec::new();
+    /// builder.dump(Cursor::new(&mut buffer)).unwrap();
+    ///
+    /// let mut book = Book::load(Cursor::new(buffer)).unwrap();
+    /// let page = book.get_page_by_key("std/collections/hash_map").unwrap();
+    ///
+    /// assert_eq!(page.key(), Some("std/collections/hash_map"));
+    /// ```
+    pub fn get_page_by_key(&mut self, key: &str) -> Result<page::Page, page::PageError> {
+        let page_id = self.page_index.id_by_key(key)?;
+        self.page_index.get_by_id(&mut self.data_blocks, page_id)
+    }
+
     /// Table of contents of this book.
     pub fn toc(&mut self) -> Result<impl Iterator<Item = crate::TocEntry> + '_, toc::TocError> {
         let toc = toc::BookToc::new(&mut self.data_blocks, &self.page_index)?;
         Ok(toc.into_iter())
     }
+
+    /// Search pages matching `query`.
+    ///
+    /// The syntax of the query is described in [`Query`].
+    ///
+    /// Pages are sorted by their relevance, so the most relevant page is the
+    /// first item in the list. Matches in the title and in the keywords are
+    /// more relevant than matches in the content.
+    pub fn search(&mut self, query: &str) -> Result<Vec<SearchHit>, search::SearchError> {
+        self.search_query(&Query::parse(query)?)
+    }
+
+    /// Search pages matching a [`Query`].
+    ///
+    /// The terms in the query are processed with the same
+    /// [analyzers](crate::analysis) used to build the search index.
+    pub fn search_query(&mut self, query: &Query) -> Result<Vec<SearchHit>, search::SearchError> {
+        self.search_with(query, &SearchOptions::default())
+    }
+
+    /// Search pages matching a [`Query`], with the options to control the
+    /// results.
+    ///
+    /// [`SearchOptions`] can be used to get [snippets](crate::Snippet) of the
+    /// content of every page.
+    pub fn search_with(
+        &mut self,
+        query: &Query,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchHit>, search::SearchError> {
+        self.search_with_statistics(query, options, None)
+    }
+
+    /// Search pages matching a [`Query`], computing the scores with the
+    /// statistics of multiple books.
+    pub(crate) fn search_with_statistics(
+        &mut self,
+        query: &Query,
+        options: &SearchOptions,
+        statistics: Option<&search::Statistics>,
+    ) -> Result<Vec<SearchHit>, search::SearchError> {
+        let index = load_search_index(&mut self.search_index, &mut self.data_blocks, self.fts_pos)?;
+
+        index.search(
+            &mut self.data_blocks,
+            &self.page_index,
+            &self.analyzers,
+            query,
+            options,
+            statistics,
+        )
+    }
+
+    /// Collect the statistics of the search index to compute the score of
+    /// the pages matching `query`.
+    pub(crate) fn search_statistics(
+        &mut self,
+        query: &Query,
+    ) -> Result<search::Statistics, search::SearchError> {
+        let index = load_search_index(&mut self.search_index, &mut self.data_blocks, self.fts_pos)?;
+        index.statistics(&mut self.data_blocks, &self.analyzers, query)
+    }
+
+    /// Return up to `limit` terms from the search index to complete `prefix`.
+    ///
+    /// This is intended for search-as-you-type interfaces. Small typos are
+    /// tolerated, so `itre` can be completed with `iterator`. Exact
+    /// completions are returned first, and then terms are sorted by the
+    /// number of pages containing them.
+    ///
+    /// Only the last word of `prefix` is completed. Terms are returned as
+    /// they are stored in the index, after being processed by the
+    /// [analyzers](crate::analysis) (for example, in lowercase).
+    pub fn suggest(
+        &mut self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, search::SearchError> {
+        let index = load_search_index(&mut self.search_index, &mut self.data_blocks, self.fts_pos)?;
+        index.suggest(&self.analyzers, prefix, limit)
+    }
+
+    /// Check the consistency of the whole book.
+    ///
+    /// Every data block, page, and metadata entry is read, and the tree of
+    /// pages is validated. Problems are collected in the returned
+    /// [`VerifyReport`](crate::VerifyReport), instead of stopping at the first
+    /// one.
+    pub fn verify(&mut self) -> crate::VerifyReport {
+        crate::verify::verify(self)
+    }
+
+    /// Return the statistics of the cache for the data blocks of this book.
+    pub fn cache_stats(&self) -> crate::cache::CacheStats {
+        self.data_blocks.cache_stats()
+    }
+
+    /// Register a custom analyzer to process search queries.
+    ///
+    /// It must be the same analyzer used to build the book with
+    /// [`BookBuilder::add_analyzer`]. Built-in analyzers are always
+    /// available.
+    pub fn add_analyzer(&mut self, analyzer: impl Analyzer + 'static) -> &mut Self {
+        self.analyzers.add(None, Arc::new(analyzer));
+        self
+    }
+}
+
+impl<T: AsRef<[u8]>> Book<Cursor<T>> {
+    /// Return the content of a page, borrowed from the input bytes.
+    ///
+    /// If the data block of the page is not compressed, the content is not
+    /// copied, and the block cache is not used. Compressed blocks are read
+    /// like in [`get_page_by_id`](Book::get_page_by_id).
+    ///
+    /// ```
+    /// # use theory::Book;
+    /// # use std::io::Cursor;
+    /// let mut builder = Book::builder();
+    /// let page_id = builder.new_page("First").set_content("Content").id();
+    ///
+    /// let mut buffer = Vec::new();
+    /// builder.dump(Cursor::new(&mut buffer)).unwrap();
+    ///
+    /// let mut book = Book::load(Cursor::new(&buffer[..])).unwrap();
+    /// let content = book.page_content(page_id).unwrap();
+    ///
+    /// assert_eq!(&content[..], b"Content");
+    /// ```
+    pub fn page_content(
+        &mut self,
+        page_id: page::PageId,
+    ) -> Result<Cow<'_, [u8]>, page::PageError> {
+        self.page_index
+            .get_content_borrowed(&mut self.data_blocks, page_id)
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Book<Cursor<memmap2::Mmap>> {
+    /// Open a book from a file mapped in memory.
+    ///
+    /// Use [`page_content`](Book::page_content) to access the content of the
+    /// pages without copying it.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified while the book is open. See
+    /// [`memmap2::Mmap`] for more details.
+    #[cfg_attr(docsrs, doc(cfg(feature = "mmap")))]
+    pub unsafe fn open_mmap(path: impl AsRef<Path>) -> Result<Self, persistence::PersistenceError> {
+        let file = File::open(path)?;
+        let mmap = memmap2::Mmap::map(&file)?;
+        Book::load(Cursor::new(mmap))
+    }
+}
+
+/// Load the search index, if it is not already loaded.
+fn load_search_index<'a, I: Read + Seek>(
+    search_index: &'a mut Option<search::Index>,
+    data_blocks: &mut DataBlocksReader<I>,
+    fts_pos: Option<u64>,
+) -> Result<&'a search::Index, search::SearchError> {
+    match search_index {
+        Some(index) => Ok(index),
+
+        None => {
+            let fts_pos = fts_pos.ok_or(search::SearchError::MissingIndex)?;
+            let index = search::Index::load(data_blocks, fts_pos)?;
+            Ok(search_index.insert(index))
+        }
+    }
 }
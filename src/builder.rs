@@ -1,17 +1,93 @@
 //! This module provide the implementation to create a new book.
 
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufWriter, Seek, Write};
+use std::io::{BufWriter, Read, Seek, Write};
 use std::num::NonZeroU32;
 use std::path::Path;
+use std::sync::Arc;
 
+use crate::analysis::Analyzer;
+use crate::page::persistence::build_page;
+use crate::page::PageError;
+use crate::persistence::datablock;
 use crate::persistence::PersistenceError;
-use crate::{persistence, BlockCompression, MetadataEntry, Page};
+use crate::search::analysis::Registry;
+use crate::{persistence, BlockCompression, Book, CompressionPolicy, MetadataEntry, Page, PageId};
+
+/// Statistics of a book written by [`BookBuilder::dump`] or [`BookWriter`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    pub(crate) num_pages: u64,
+    pub(crate) duplicated_pages: u64,
+    pub(crate) bytes_saved: u64,
+}
+
+impl WriteStats {
+    /// Number of pages written.
+    pub fn num_pages(&self) -> u64 {
+        self.num_pages
+    }
+
+    /// Number of pages with the same content as a previous page.
+    ///
+    /// The content of these pages is stored only once.
+    pub fn duplicated_pages(&self) -> u64 {
+        self.duplicated_pages
+    }
+
+    /// Number of bytes not written because of duplicated content, before
+    /// compression.
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_saved
+    }
+}
+
+/// Order to store the content of the pages in the data blocks.
+///
+/// The content of a page is read by decompressing its whole data block, so
+/// pages that are usually read together should be in the same block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PagePacking {
+    /// Pages are stored in the same order they were created.
+    #[default]
+    InsertionOrder,
+
+    /// Pages are stored in the order of the table of contents, and every
+    /// subtree of pages (a page and its descendants, by [`Page::parent`]) is
+    /// kept in a single data block if it fits in one.
+    TocSubtree,
+}
+
+/// Options to copy a book with [`BookWriter::import_book`].
+///
+/// ```
+/// use theory::ImportOptions;
+///
+/// let options = ImportOptions::default().title("Standard Library");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    title: Option<String>,
+}
+
+impl ImportOptions {
+    /// Set the title of the page created for the book.
+    ///
+    /// By default, it is the `Title` entry of the book, or `Untitled` if the
+    /// book does not have one.
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+}
 
 /// A builder for new books.
 ///
 /// The pages are kept in memory, and then they can be stored with
-/// [`BookBuilder::dump()`].
+/// [`BookBuilder::dump()`]. To write books too large to keep in memory, use
+/// [`BookWriter`].
 ///
 /// See the [crate documentation](crate) for an example of [`BookBuilder`].
 pub struct BookBuilder {
@@ -21,7 +97,18 @@ pub struct BookBuilder {
 
     pages: Vec<Page>,
 
-    compression: BlockCompression,
+    compression: CompressionPolicy,
+
+    block_size: usize,
+
+    packing: PagePacking,
+
+    search_index: bool,
+
+    #[cfg(feature = "parallel")]
+    threads: usize,
+
+    analyzers: Registry,
 }
 
 impl BookBuilder {
@@ -32,12 +119,79 @@ impl BookBuilder {
             metadata: Vec::new(),
             pages: Vec::new(),
             compression: Default::default(),
+            block_size: datablock::DEFAULT_BLOCK_SIZE as usize,
+            packing: PagePacking::default(),
+            search_index: true,
+
+            #[cfg(feature = "parallel")]
+            threads: 1,
+
+            analyzers: Registry::default(),
         }
     }
 
     /// Set the compression method to store data in each block.
+    ///
+    /// It is the default method of the [`CompressionPolicy`].
     pub fn set_compression(&mut self, compression: BlockCompression) -> &mut BookBuilder {
-        self.compression = compression;
+        self.compression.set_default(compression);
+        self
+    }
+
+    /// Set the policy to select the compression method of every page.
+    ///
+    /// The default method of the policy is also used for the metadata of the
+    /// pages and the search index.
+    pub fn set_compression_policy(&mut self, policy: CompressionPolicy) -> &mut BookBuilder {
+        self.compression = policy;
+        self
+    }
+
+    /// Set the target size, in bytes, of the data blocks before compression.
+    /// Default is 64 KiB.
+    ///
+    /// Reading a page decompresses its whole block, so smaller blocks make
+    /// random access faster, and larger blocks have a better compression
+    /// ratio. Pages larger than this size are stored in their own block.
+    pub fn set_block_size(&mut self, block_size: usize) -> &mut BookBuilder {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Set the order to store the pages in the data blocks. Default is
+    /// [`PagePacking::InsertionOrder`].
+    pub fn set_page_packing(&mut self, packing: PagePacking) -> &mut BookBuilder {
+        self.packing = packing;
+        self
+    }
+
+    /// Set the number of threads to compress the data blocks. With `0`, it
+    /// uses one thread for every available CPU. Default is `1`.
+    ///
+    /// The output is the same for any number of threads.
+    #[cfg(feature = "parallel")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "parallel")))]
+    pub fn set_compression_threads(&mut self, threads: usize) -> &mut BookBuilder {
+        self.threads = threads;
+        self
+    }
+
+    /// Set the analyzer to build the search index for pages written in
+    /// `language`.
+    ///
+    /// `language` is compared with the `Language` metadata entry of the pages,
+    /// or the one of the book. Only the primary subtag is used, so `en` is
+    /// also applied to `en-US`.
+    ///
+    /// Readers have to register the same analyzer with
+    /// [`Book::add_analyzer`](crate::Book::add_analyzer) to search in the
+    /// book.
+    pub fn add_analyzer(
+        &mut self,
+        language: &str,
+        analyzer: impl Analyzer + 'static,
+    ) -> &mut BookBuilder {
+        self.analyzers.add(Some(language), Arc::new(analyzer));
         self
     }
 
@@ -65,17 +219,1189 @@ impl BookBuilder {
 
     /// Dump this book to the specified stream. The written data can be
     /// loaded with [`load`](crate::Book::load).
-    pub fn dump<O>(&self, output: O) -> Result<(), PersistenceError>
+    ///
+    /// Pages with identical content share the same data, and the bytes saved
+    /// are reported in the returned [`WriteStats`].
+    pub fn dump<O>(&self, output: O) -> Result<WriteStats, PersistenceError>
     where
         O: Write + Seek,
     {
-        persistence::dump(output, &self.pages, &self.metadata, self.compression)
+        let book_language = book_language(&self.metadata);
+        let analyzers = &self.analyzers;
+
+        let mut writer = persistence::Writer::new(output, self.compression.default_compression())?;
+        writer.set_block_size(self.block_size as u64);
+
+        if !self.search_index {
+            writer.disable_search_index();
+        }
+
+        #[cfg(feature = "parallel")]
+        writer.set_threads(compression_threads(self.threads));
+
+        // If the dictionary can't be trained (for example, when there are
+        // not enough pages), blocks are compressed without it.
+        #[cfg(feature = "zstd")]
+        if self.compression.methods().any(uses_dictionary) {
+            let pages: Vec<_> = self
+                .pages
+                .iter()
+                .filter(|page| uses_dictionary(self.compression.select(page)))
+                .collect();
+
+            let samples = dictionary_samples(&pages);
+            if let Ok(dictionary) = datablock::train_dictionary(&samples) {
+                writer.write_zstd_dictionary(dictionary)?;
+            }
+        }
+
+        match self.packing {
+            PagePacking::InsertionOrder => {
+                for page in &self.pages {
+                    let compression = self.compression.select(page);
+                    writer.add_page(page, compression, book_language, analyzers)?;
+                }
+            }
+
+            PagePacking::TocSubtree => {
+                for (index, subtree_size) in toc_order(&self.pages) {
+                    let page = &self.pages[index];
+                    let compression = self.compression.select(page);
+                    writer.begin_group(subtree_size)?;
+                    writer.add_page(page, compression, book_language, analyzers)?;
+                }
+            }
+        }
+
+        let stats = writer.stats();
+        writer.finish(&self.metadata)?;
+        Ok(stats)
+    }
+
+    /// Don't build the search index for the book.
+    ///
+    /// [`Book::search`] and [`Book::suggest`] can't be used with the book,
+    /// and they return [`SearchError::MissingIndex`].
+    ///
+    /// [`SearchError::MissingIndex`]: crate::errors::SearchError::MissingIndex
+    pub fn disable_search_index(&mut self) -> &mut BookBuilder {
+        self.search_index = false;
+        self
     }
 
     /// Dump this page to the specified file.
     ///
     /// See [`dump`](Self::dump) for more details.
-    pub fn dump_to_file(&self, path: impl AsRef<Path>) -> Result<(), PersistenceError> {
+    pub fn dump_to_file(&self, path: impl AsRef<Path>) -> Result<WriteStats, PersistenceError> {
         self.dump(BufWriter::new(File::create(path)?))
     }
 }
+
+/// A writer to create books with many pages.
+///
+/// Unlike [`BookBuilder`], pages are written to the output stream as soon as
+/// they are added with [`add_page`](Self::add_page), so their content is not
+/// kept in memory. However, the page index, the metadata of the pages, and
+/// the search index are kept in memory until [`finish`](Self::finish) is
+/// called. The search index contains the position of every word in the
+/// pages, so its size grows with the content of the book. It can be disabled
+/// with [`disable_search_index`](Self::disable_search_index).
+///
+/// The book can't be loaded if `finish` is not called.
+///
+/// ```
+/// use theory::{Book, BookWriter, MetadataEntry};
+/// use std::io::Cursor;
+///
+/// let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+/// writer.add_metadata(MetadataEntry::Title("Streaming".into()));
+///
+/// for n in 0..10 {
+///     let mut page = writer.new_page(format!("Page {}", n));
+///     page.set_content(format!("Content of page {}", n));
+///     writer.add_page(&page).unwrap();
+/// }
+///
+/// let buffer = writer.finish().unwrap().into_inner();
+///
+/// let book = Book::load(Cursor::new(buffer)).unwrap();
+/// assert_eq!(book.num_pages(), 10);
+/// ```
+pub struct BookWriter<O: Write> {
+    writer: persistence::Writer<O>,
+
+    next_page_id: NonZeroU32,
+
+    /// Identifiers of the pages already written.
+    page_ids: HashSet<NonZeroU32>,
+
+    metadata: Vec<MetadataEntry>,
+
+    compression: CompressionPolicy,
+
+    analyzers: Registry,
+}
+
+impl BookWriter<BufWriter<File>> {
+    /// Create a new book in the specified file.
+    pub fn create_file(path: impl AsRef<Path>) -> Result<Self, PersistenceError> {
+        BookWriter::create(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<O: Write + Seek> BookWriter<O> {
+    /// Create a new book in the output stream.
+    ///
+    /// The header of the book is written immediately, and it is updated when
+    /// the writer is finished.
+    pub fn create(output: O) -> Result<Self, PersistenceError> {
+        Ok(BookWriter {
+            writer: persistence::Writer::new(output, BlockCompression::default())?,
+            next_page_id: NonZeroU32::new(1).unwrap(),
+            page_ids: HashSet::new(),
+            metadata: Vec::new(),
+            compression: CompressionPolicy::default(),
+            analyzers: Registry::default(),
+        })
+    }
+
+    /// Set the compression method to store data in each block.
+    ///
+    /// It is the default method of the [`CompressionPolicy`], and it is
+    /// applied to the pages added after this call.
+    pub fn set_compression(&mut self, compression: BlockCompression) -> &mut Self {
+        self.compression.set_default(compression);
+        self.writer.set_compression(compression);
+        self
+    }
+
+    /// Set the policy to select the compression method of every page.
+    ///
+    /// It is applied to the pages added after this call. See
+    /// [`BookBuilder::set_compression_policy`] for more details.
+    pub fn set_compression_policy(&mut self, policy: CompressionPolicy) -> &mut Self {
+        self.writer.set_compression(policy.default_compression());
+        self.compression = policy;
+        self
+    }
+
+    /// Train a Zstandard dictionary from the `samples`, and store it in the
+    /// book.
+    ///
+    /// The dictionary is used by the pages added after this call, if the
+    /// compression method is [`BlockCompression::Zstd`] with `dictionary`
+    /// enabled. Samples are usually the content of some pages.
+    #[cfg(feature = "zstd")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+    pub fn train_dictionary<S: AsRef<[u8]>>(
+        &mut self,
+        samples: &[S],
+    ) -> Result<&mut Self, PersistenceError> {
+        let dictionary = datablock::train_dictionary(samples)?;
+        self.writer.write_zstd_dictionary(dictionary)?;
+        Ok(self)
+    }
+
+    /// Don't build the search index for the book.
+    ///
+    /// The terms of the pages already added are discarded. See
+    /// [`BookBuilder::disable_search_index`] for more details.
+    pub fn disable_search_index(&mut self) -> &mut Self {
+        self.writer.disable_search_index();
+        self
+    }
+
+    /// Set the target size, in bytes, of the data blocks before compression.
+    ///
+    /// It is applied to the data blocks created after this call. See
+    /// [`BookBuilder::set_block_size`] for more details.
+    pub fn set_block_size(&mut self, block_size: usize) -> &mut Self {
+        self.writer.set_block_size(block_size as u64);
+        self
+    }
+
+    /// Set the number of threads to compress the data blocks.
+    ///
+    /// See [`BookBuilder::set_compression_threads`] for more details.
+    #[cfg(feature = "parallel")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "parallel")))]
+    pub fn set_compression_threads(&mut self, threads: usize) -> &mut Self {
+        self.writer.set_threads(compression_threads(threads));
+        self
+    }
+
+    /// Set the analyzer to build the search index for pages written in
+    /// `language`.
+    ///
+    /// It is applied to the pages added after this call. See
+    /// [`BookBuilder::add_analyzer`] for more details.
+    pub fn add_analyzer(&mut self, language: &str, analyzer: impl Analyzer + 'static) -> &mut Self {
+        self.analyzers.add(Some(language), Arc::new(analyzer));
+        self
+    }
+
+    /// Add a new metadata entry.
+    ///
+    /// The metadata table is written when the writer is finished, so entries
+    /// can be added at any time. However, the `Language` entry, used to
+    /// select the analyzer for the search index, only affects the pages added
+    /// after it.
+    pub fn add_metadata(&mut self, entry: MetadataEntry) -> &mut Self {
+        self.metadata.push(entry);
+        self
+    }
+
+    /// Create a new page with a title, and a new identifier.
+    ///
+    /// The page is not included in the book until it is written with
+    /// [`add_page`](Self::add_page).
+    pub fn new_page(&mut self, title: impl Into<String>) -> Page {
+        let page = Page::new(title.into(), self.next_page_id);
+        self.next_page_id = self.next_page_id.saturating_add(1);
+        page
+    }
+
+    /// Write a page to the output stream.
+    ///
+    /// Every page can be added only once.
+    pub fn add_page(&mut self, page: &Page) -> Result<(), PersistenceError> {
+        self.write_page(page, None)
+    }
+
+    /// Copy the pages of another book.
+    ///
+    /// A new page is added with the metadata of `book`, and the pages of
+    /// `book` are added as its descendants, with new identifiers. The
+    /// relationships between the pages are kept, and the pages without a
+    /// parent become children of the new page. If `book` has a `Language`
+    /// entry, it is added to the pages without one.
+    ///
+    /// The title of the new page is set in `options`, or taken from the book.
+    /// The `Volume` and `VolumeFile` entries of the book are not copied.
+    ///
+    /// The keys of the pages are kept, so they must be unique among all the
+    /// books written to the same file. Otherwise, the import fails with
+    /// [`PageError::DuplicatedKey`](crate::errors::PageError::DuplicatedKey).
+    ///
+    /// The data blocks of `book` compressed with the same method that the
+    /// [`CompressionPolicy`] selects for their pages are copied as-is, without
+    /// compressing them again. The content of every page is still read to
+    /// build the search index.
+    ///
+    /// Returns the identifier of the new page.
+    ///
+    /// ```
+    /// use theory::{Book, BookWriter, ImportOptions};
+    /// use std::io::Cursor;
+    ///
+    /// let mut books = Vec::new();
+    /// for name in ["alpha", "beta"] {
+    ///     let mut builder = Book::builder();
+    ///     builder.add_metadata(theory::MetadataEntry::Title(name.into()));
+    ///     builder.new_page("Intro").set_content("...");
+    ///
+    ///     let mut buffer = Vec::new();
+    ///     builder.dump(Cursor::new(&mut buffer)).unwrap();
+    ///     books.push(Book::load(Cursor::new(buffer)).unwrap());
+    /// }
+    ///
+    /// let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+    /// for book in &mut books {
+    ///     writer.import_book(book, &ImportOptions::default()).unwrap();
+    /// }
+    ///
+    /// let options = ImportOptions::default().title("gamma");
+    /// writer.import_book(&mut books[0], &options).unwrap();
+    ///
+    /// let buffer = writer.finish().unwrap().into_inner();
+    /// let mut book = Book::load(Cursor::new(buffer)).unwrap();
+    ///
+    /// let titles: Vec<_> = book.toc().unwrap().map(|e| e.title().to_owned()).collect();
+    /// assert_eq!(titles, ["alpha", "beta", "gamma"]);
+    /// ```
+    pub fn import_book<I: Read + Seek>(
+        &mut self,
+        book: &mut Book<I>,
+        options: &ImportOptions,
+    ) -> Result<PageId, PersistenceError> {
+        let mut metadata = book
+            .metadata()?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| PageError::InvalidMetadata(e.to_string()))?;
+
+        let language = book_language(&metadata).map(str::to_owned);
+
+        let title = match &options.title {
+            Some(title) => title.clone(),
+            None => metadata
+                .iter()
+                .find_map(|entry| match entry {
+                    MetadataEntry::Title(t) if !t.is_empty() => Some(t.clone()),
+                    _ => None,
+                })
+                .unwrap_or_else(|| "Untitled".to_owned()),
+        };
+
+        metadata.retain(|entry| {
+            !matches!(
+                entry,
+                MetadataEntry::Title(_) | MetadataEntry::Volume(_) | MetadataEntry::VolumeFile(..)
+            )
+        });
+
+        let mut root = self.new_page(title);
+        root.metadata.extend(metadata);
+        self.write_page(&root, None)?;
+
+        let mut ids = HashMap::new();
+        for (page_id, _) in &book.page_index {
+            ids.insert(page_id.0, self.next_page_id);
+            self.next_page_id = self
+                .next_page_id
+                .checked_add(1)
+                .ok_or(PersistenceError::TooManyPages)?;
+        }
+
+        // Blocks from `book`, with their index in the writer if they were
+        // copied. The decision is taken with the first page in every block.
+        let mut blocks = HashMap::new();
+
+        for (_, entry) in &book.page_index {
+            let mut page = build_page(entry, &mut book.data_blocks)?;
+
+            page.id = ids[&page.id];
+            page.parent_id = Some(
+                page.parent_id
+                    .and_then(|id| ids.get(&id).copied())
+                    .unwrap_or(root.id),
+            );
+
+            if let Some(language) = &language {
+                if !page
+                    .metadata
+                    .iter()
+                    .any(|e| matches!(e, MetadataEntry::Language(_)))
+                {
+                    page.metadata
+                        .push(MetadataEntry::Language(language.clone()));
+                }
+            }
+
+            let (block_id, offset) = entry.content_location();
+            let block = match blocks.get(&block_id) {
+                Some(&block) => block,
+                None => {
+                    let compression = self.compression.select(&page);
+                    let block = match book.data_blocks.raw_block(block_id)? {
+                        Some(raw) if raw.uses(compression) => {
+                            Some(self.writer.write_raw_block(raw)?)
+                        }
+                        _ => None,
+                    };
+
+                    blocks.insert(block_id, block);
+                    block
+                }
+            };
+
+            self.write_page(&page, block.map(|block| (block, offset)))?;
+        }
+
+        Ok(root.id())
+    }
+
+    /// Write a page to the output stream. If `content` is not `None`, it is
+    /// the location of the content in a block copied from another book.
+    fn write_page(
+        &mut self,
+        page: &Page,
+        content: Option<(u64, u64)>,
+    ) -> Result<(), PersistenceError> {
+        if self.page_ids.contains(&page.id) {
+            return Err(PageError::DuplicatedId(page.id.get()).into());
+        }
+
+        let book_language = book_language(&self.metadata);
+        match content {
+            Some((block, offset)) => {
+                self.writer
+                    .add_page_at(page, block, offset, book_language, &self.analyzers)?
+            }
+
+            None => {
+                let compression = self.compression.select(page);
+                self.writer
+                    .add_page(page, compression, book_language, &self.analyzers)?;
+            }
+        }
+
+        self.page_ids.insert(page.id);
+
+        // Identifiers from `new_page` must not collide with pages created
+        // elsewhere.
+        if page.id >= self.next_page_id {
+            self.next_page_id = page.id.saturating_add(1);
+        }
+
+        Ok(())
+    }
+
+    /// Return the statistics of the pages written so far.
+    ///
+    /// See [`BookBuilder::dump`] for more details.
+    pub fn stats(&self) -> WriteStats {
+        self.writer.stats()
+    }
+
+    /// Write the page index, the search index, and the metadata table, and
+    /// complete the header of the book.
+    ///
+    /// On success, returns the output stream.
+    pub fn finish(self) -> Result<O, PersistenceError> {
+        self.writer.finish(&self.metadata)
+    }
+}
+
+/// Sort the pages in the order of the table of contents (pre-order, with
+/// children in insertion order).
+///
+/// Returns the index of every page in `pages`, and the size of the content of
+/// its subtree.
+fn toc_order(pages: &[Page]) -> Vec<(usize, u64)> {
+    let tree: Vec<_> = pages.iter().map(|p| (p.id, p.parent_id)).collect();
+    let order = toc_tree(&tree);
+
+    // Children are after their parent, so the sizes are accumulated in
+    // reverse order.
+    let mut sizes: Vec<u64> = pages.iter().map(|p| p.content.len() as u64).collect();
+    for &(index, parent) in order.iter().rev() {
+        if let Some(parent) = parent {
+            sizes[parent] += sizes[index];
+        }
+    }
+
+    order
+        .into_iter()
+        .map(|(index, _)| (index, sizes[index]))
+        .collect()
+}
+
+/// Sort a tree of pages in the order of the table of contents (pre-order,
+/// with children in insertion order).
+///
+/// `pages` contains the identifier and the parent of every page. Returns the
+/// index of every page in `pages`, and the index of its parent in the tree,
+/// or `None` for the first page of every subtree.
+pub(crate) fn toc_tree(pages: &[(NonZeroU32, Option<NonZeroU32>)]) -> Vec<(usize, Option<usize>)> {
+    let positions: HashMap<_, _> = pages
+        .iter()
+        .enumerate()
+        .map(|(index, (id, _))| (*id, index))
+        .collect();
+
+    let mut children = vec![Vec::new(); pages.len()];
+    let mut roots = Vec::new();
+
+    for (index, (_, parent_id)) in pages.iter().enumerate() {
+        match parent_id.and_then(|id| positions.get(&id)) {
+            Some(&parent) if parent != index => children[parent].push(index),
+            _ => roots.push(index),
+        }
+    }
+
+    // Pages in a cycle of parents are not reachable from any root, so any
+    // page not visited yet starts a new subtree.
+    let mut visited = vec![false; pages.len()];
+    let mut tree_parent = vec![None; pages.len()];
+    let mut order = Vec::with_capacity(pages.len());
+
+    for start in roots.into_iter().chain(0..pages.len()) {
+        let mut stack = vec![start];
+        while let Some(index) = stack.pop() {
+            if visited[index] {
+                continue;
+            }
+
+            visited[index] = true;
+            order.push((index, tree_parent[index]));
+
+            for &child in children[index].iter().rev() {
+                if !visited[child] {
+                    tree_parent[child] = Some(index);
+                    stack.push(child);
+                }
+            }
+        }
+    }
+
+    order
+}
+
+/// Return the number of threads to compress data blocks. `0` is replaced by
+/// the number of available CPUs.
+#[cfg(feature = "parallel")]
+fn compression_threads(threads: usize) -> usize {
+    match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+/// Maximum size of the content used to train a Zstandard dictionary.
+#[cfg(feature = "zstd")]
+const MAX_SAMPLES_SIZE: usize = 10 << 20;
+
+/// Select the content of some pages to train a Zstandard dictionary.
+///
+/// If the content of all pages is larger than `MAX_SAMPLES_SIZE`, pages are
+/// taken at regular intervals.
+#[cfg(feature = "zstd")]
+fn dictionary_samples<'a>(pages: &[&'a Page]) -> Vec<&'a [u8]> {
+    let total: usize = pages.iter().map(|p| p.content.len()).sum();
+    let step = total / MAX_SAMPLES_SIZE + 1;
+
+    pages
+        .iter()
+        .step_by(step)
+        .map(|p| &p.content[..])
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+/// Return `true` if the method uses a Zstandard dictionary.
+#[cfg(feature = "zstd")]
+fn uses_dictionary(compression: BlockCompression) -> bool {
+    matches!(
+        compression,
+        BlockCompression::Zstd {
+            dictionary: true,
+            ..
+        }
+    )
+}
+
+/// Return the value of the `Language` metadata entry of a book.
+pub(crate) fn book_language(metadata: &[MetadataEntry]) -> Option<&str> {
+    metadata.iter().find_map(|entry| match entry {
+        MetadataEntry::Language(l) => Some(l.as_str()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BookWriter, ImportOptions};
+    use crate::errors::{PageError, PersistenceError, SearchError};
+    use crate::{Book, MetadataEntry};
+    use std::io::Cursor;
+
+    #[test]
+    fn same_output_as_builder() {
+        let mut builder = Book::builder();
+        let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+
+        let entry = MetadataEntry::Language("en".into());
+        builder.add_metadata(entry.clone());
+        writer.add_metadata(entry);
+
+        for n in 0..100 {
+            let content = format!("Content of the page number {}", n).repeat(n + 1);
+            let page = builder
+                .new_page(format!("Page {}", n))
+                .set_content(content.clone());
+            if n > 0 {
+                page.set_parent(crate::PageId::force_value(1));
+            }
+
+            let page = page.clone();
+            let mut new_page = writer.new_page(format!("Page {}", n));
+            new_page.set_content(content);
+            if let Some(parent) = page.parent() {
+                new_page.set_parent(parent);
+            }
+
+            assert_eq!(new_page, page);
+            writer.add_page(&new_page).unwrap();
+        }
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        let written = writer.finish().unwrap().into_inner();
+        assert_eq!(written, buffer);
+
+        let mut book = Book::load(Cursor::new(written)).unwrap();
+        assert_eq!(book.num_pages(), 100);
+        assert_eq!(book.search("number").unwrap().len(), 100);
+        assert!(book.verify().is_ok());
+    }
+
+    #[test]
+    fn pages_out_of_order() {
+        let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+
+        let pages: Vec<_> = (0..20)
+            .map(|n| {
+                let mut page = writer.new_page(format!("Page {}", n));
+                page.set_content(format!("Content of the page number {}", n % 3));
+                page
+            })
+            .collect();
+
+        for page in pages.iter().rev() {
+            writer.add_page(page).unwrap();
+        }
+
+        let buffer = writer.finish().unwrap().into_inner();
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+
+        let mut ids: Vec<_> = book
+            .search("\"page number 2\"")
+            .unwrap()
+            .iter()
+            .map(|hit| hit.page_id())
+            .collect();
+        ids.sort();
+
+        let expected: Vec<_> = pages.iter().skip(2).step_by(3).map(|p| p.id()).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn without_search_index() {
+        let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+        let mut builder = Book::builder();
+
+        for n in 0..5 {
+            let mut page = writer.new_page(format!("Page {}", n));
+            page.set_content("Content");
+            writer.add_page(&page).unwrap();
+            builder
+                .new_page(format!("Page {}", n))
+                .set_content("Content");
+        }
+
+        writer.disable_search_index();
+        builder.disable_search_index();
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        let written = writer.finish().unwrap().into_inner();
+        assert_eq!(written, buffer);
+
+        let mut book = Book::load(Cursor::new(written)).unwrap();
+        assert_eq!(book.num_pages(), 5);
+        assert!(book.verify().is_ok());
+        assert!(matches!(
+            book.search("content"),
+            Err(SearchError::MissingIndex)
+        ));
+    }
+
+    #[test]
+    fn unfinished_book() {
+        let mut buffer = Vec::new();
+
+        let mut writer = BookWriter::create(Cursor::new(&mut buffer)).unwrap();
+        let page = writer.new_page("First");
+        writer.add_page(&page).unwrap();
+
+        assert!(matches!(
+            writer.add_page(&page),
+            Err(PersistenceError::PageError(PageError::DuplicatedId(1)))
+        ));
+
+        // The header is not valid until `finish` is called.
+        drop(writer);
+        assert!(Book::load(Cursor::new(&buffer)).is_err());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn compression_policy() {
+        use crate::{BlockCompression, CompressionPolicy};
+
+        let policy = CompressionPolicy::new(BlockCompression::Lz4)
+            .content_type("image/", BlockCompression::None);
+
+        let contains =
+            |buffer: &[u8], content: &[u8]| buffer.windows(content.len()).any(|w| w == content);
+
+        let image = [&b"\x89PNG\r\n\x1a\n"[..], &[b'I'; 500]].concat();
+        let text = [b'T'; 500];
+        let raw = [b'R'; 500];
+
+        let mut builder = Book::builder();
+        builder.set_compression_policy(policy.clone());
+        builder.new_page("Image").set_content(image.clone());
+        builder.new_page("Text").set_content(text);
+        builder
+            .new_page("Raw")
+            .set_content(raw)
+            .set_compression(BlockCompression::None);
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        assert!(contains(&buffer, &image));
+        assert!(!contains(&buffer, &text));
+        assert!(contains(&buffer, &raw));
+
+        let mut book = Book::load(Cursor::new(&buffer)).unwrap();
+        let contents: Vec<_> = book.pages().map(|p| p.unwrap().content).collect();
+        assert_eq!(contents, [&image[..], &text[..], &raw[..]]);
+
+        // Same output with `BookWriter`.
+        let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+        writer.set_compression_policy(policy);
+        for page in builder.pages.iter() {
+            writer.add_page(page).unwrap();
+        }
+
+        assert_eq!(writer.finish().unwrap().into_inner(), buffer);
+    }
+
+    #[test]
+    fn page_keys() {
+        let mut builder = Book::builder();
+        let intro = builder.new_page("Intro").set_key("intro").id();
+        builder.new_page("Unkeyed");
+        let map = builder
+            .new_page("HashMap")
+            .set_key("old/hash_map")
+            .set_key("std/collections/hash_map")
+            .add_metadata(MetadataEntry::Key("std/hash_map".into()))
+            .id();
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        assert_eq!(book.get_page_by_key("intro").unwrap().id(), intro);
+
+        let page = book.get_page_by_key("std/collections/hash_map").unwrap();
+        assert_eq!(page.id(), map);
+        assert_eq!(page.key(), Some("std/collections/hash_map"));
+
+        // Additional keys can be used as aliases.
+        assert_eq!(book.get_page_by_key("std/hash_map").unwrap().id(), map);
+
+        assert!(matches!(
+            book.get_page_by_key("old/hash_map"),
+            Err(PageError::InvalidKey(key)) if key == "old/hash_map"
+        ));
+
+        // Keys must be unique.
+        let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+
+        let mut page = writer.new_page("A");
+        page.set_key("same");
+        writer.add_page(&page).unwrap();
+
+        let mut page = writer.new_page("B");
+        page.set_key("same");
+        assert!(matches!(
+            writer.add_page(&page),
+            Err(PersistenceError::PageError(PageError::DuplicatedKey(key))) if key == "same"
+        ));
+
+        let mut builder = Book::builder();
+        builder.new_page("A").set_key("same");
+        builder.new_page("B").set_key("same");
+        assert!(matches!(
+            builder.dump(Cursor::new(Vec::new())),
+            Err(PersistenceError::PageError(PageError::DuplicatedKey(_)))
+        ));
+    }
+
+    #[test]
+    fn duplicated_content() {
+        let content = "Re-exported item. ".repeat(100);
+
+        let mut builder = Book::builder();
+        let first = builder.new_page("First").set_content(content.clone()).id();
+        builder.new_page("Other").set_content("Other content");
+        let second = builder.new_page("Second").set_content(content.clone()).id();
+        builder.new_page("Empty 1");
+        builder.new_page("Empty 2");
+
+        let mut buffer = Vec::new();
+        let stats = builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        assert_eq!(stats.num_pages(), 5);
+        assert_eq!(stats.duplicated_pages(), 1);
+        assert_eq!(stats.bytes_saved(), content.len() as u64);
+
+        // The content is stored once.
+        let occurrences = buffer
+            .windows(content.len())
+            .filter(|w| *w == content.as_bytes())
+            .count();
+        assert_eq!(occurrences, 1);
+
+        let mut book = Book::load(Cursor::new(&buffer)).unwrap();
+        assert!(book.verify().is_ok());
+
+        let entry = |id| book.page_index.entry(id).unwrap().block_ids()[1];
+        assert_eq!(entry(first), entry(second));
+
+        assert_eq!(
+            book.get_page_by_id(first).unwrap().content(),
+            content.as_bytes()
+        );
+        assert_eq!(
+            book.get_page_by_id(second).unwrap().content(),
+            content.as_bytes()
+        );
+
+        // Same statistics with `BookWriter`.
+        let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+        for page in &builder.pages {
+            writer.add_page(page).unwrap();
+        }
+
+        assert_eq!(writer.stats(), stats);
+        assert_eq!(writer.finish().unwrap().into_inner(), buffer);
+    }
+
+    #[test]
+    fn same_checksum_different_content() {
+        // Same length and same CRC-32C.
+        let contents = ["page 1371838", "page 2000402"];
+        assert_eq!(
+            crc32c::crc32c(contents[0].as_bytes()),
+            crc32c::crc32c(contents[1].as_bytes())
+        );
+
+        let mut builder = Book::builder();
+        let ids = contents.map(|c| builder.new_page(c).set_content(c).id());
+
+        let mut buffer = Vec::new();
+        let stats = builder.dump(Cursor::new(&mut buffer)).unwrap();
+        assert_eq!(stats.duplicated_pages(), 0);
+
+        let mut book = Book::load(Cursor::new(&buffer)).unwrap();
+        for (id, content) in ids.into_iter().zip(contents) {
+            assert_eq!(
+                book.get_page_by_id(id).unwrap().content(),
+                content.as_bytes()
+            );
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn import_books() {
+        use crate::BlockCompression;
+
+        let source = |name: &str| {
+            let mut builder = Book::builder();
+            builder
+                .set_compression(BlockCompression::Lz4)
+                .add_metadata(MetadataEntry::Title(name.into()))
+                .add_metadata(MetadataEntry::Language("es".into()));
+
+            let intro = builder
+                .new_page("Intro")
+                .set_content(format!("Introducción de {}. ", name).repeat(20))
+                .id();
+
+            builder
+                .new_page("Details")
+                .set_content(format!("Detalles de {}. ", name).repeat(20))
+                .set_parent(intro);
+
+            let mut buffer = Vec::new();
+            builder.dump(Cursor::new(&mut buffer)).unwrap();
+            buffer
+        };
+
+        let merge = |compression| {
+            let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+            writer.set_compression(compression);
+
+            let mut roots = Vec::new();
+            for name in ["alfa", "beta"] {
+                let mut book = Book::load(Cursor::new(source(name))).unwrap();
+                let options = ImportOptions::default();
+                roots.push(writer.import_book(&mut book, &options).unwrap());
+            }
+
+            (writer.finish().unwrap().into_inner(), roots)
+        };
+
+        let (buffer, roots) = merge(BlockCompression::Lz4);
+
+        let mut book = Book::load(Cursor::new(&buffer)).unwrap();
+        assert!(book.verify().is_ok());
+        assert_eq!(book.num_pages(), 6);
+
+        // Every book is a top-level entry in the TOC.
+        let toc: Vec<_> = book.toc().unwrap().collect();
+        assert_eq!(toc.len(), 2);
+
+        for (entry, name) in toc.iter().zip(["alfa", "beta"]) {
+            assert_eq!(entry.title(), name);
+
+            let intro: Vec<_> = entry.children().collect();
+            assert_eq!(intro.len(), 1);
+            assert_eq!(intro[0].title(), "Intro");
+
+            let details: Vec<_> = intro[0].children().collect();
+            assert_eq!(details.len(), 1);
+            assert_eq!(details[0].title(), "Details");
+        }
+
+        assert_eq!(toc[0].id(), roots[0]);
+        let root = book.get_page_by_id(roots[0]).unwrap();
+        assert!(root
+            .metadata()
+            .contains(&MetadataEntry::Title("alfa".into())));
+
+        // The language of the book is used for the pages.
+        let hits = book.search("introducción").unwrap();
+        assert_eq!(hits.len(), 2);
+
+        let page = book.get_page_by_id(hits[0].page_id()).unwrap();
+        assert!(page
+            .metadata()
+            .contains(&MetadataEntry::Language("es".into())));
+
+        // The data blocks with content are copied from the sources.
+        let source_buffer = source("beta");
+        let source_book = Book::load(Cursor::new(&source_buffer)).unwrap();
+        let (block_id, _) = (&source_book.page_index)
+            .into_iter()
+            .next()
+            .unwrap()
+            .1
+            .content_location();
+
+        let block_end = {
+            let mut book = Book::load(Cursor::new(&source_buffer)).unwrap();
+            book.data_blocks.block_end(block_id).unwrap()
+        };
+
+        let block = &source_buffer[block_id as usize..block_end as usize];
+        assert!(buffer.windows(block.len()).any(|w| w == block));
+
+        // With a different compression method, the content is copied after
+        // decompressing it.
+        let (buffer, _) = merge(BlockCompression::None);
+        assert!(!buffer.windows(block.len()).any(|w| w == block));
+
+        let mut book = Book::load(Cursor::new(&buffer)).unwrap();
+        assert!(book.verify().is_ok());
+        assert_eq!(book.search("detalles").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn import_root_title() {
+        let source = |title: Option<&str>| {
+            let mut builder = Book::builder();
+            if let Some(title) = title {
+                builder.add_metadata(MetadataEntry::Title(title.into()));
+            }
+
+            builder
+                .add_metadata(MetadataEntry::Volume(2))
+                .add_metadata(MetadataEntry::VolumeFile(1, "docs.1.book".into()))
+                .add_metadata(MetadataEntry::Author("Someone".into()));
+
+            builder.new_page("Page");
+
+            let mut buffer = Vec::new();
+            builder.dump(Cursor::new(&mut buffer)).unwrap();
+            Book::load(Cursor::new(buffer)).unwrap()
+        };
+
+        let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+
+        let options = ImportOptions::default();
+        let root = writer.import_book(&mut source(None), &options).unwrap();
+        writer
+            .import_book(&mut source(Some("Titled")), &options)
+            .unwrap();
+
+        let options = ImportOptions::default().title("Custom");
+        writer
+            .import_book(&mut source(Some("Titled")), &options)
+            .unwrap();
+
+        let buffer = writer.finish().unwrap().into_inner();
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        assert!(book.verify().is_ok());
+
+        let titles: Vec<_> = book.toc().unwrap().map(|e| e.title().to_owned()).collect();
+        assert_eq!(titles, ["Untitled", "Titled", "Custom"]);
+
+        // Volume entries are not copied.
+        assert_eq!(
+            book.get_page_by_id(root).unwrap().metadata(),
+            [
+                MetadataEntry::Title("Untitled".into()),
+                MetadataEntry::Author("Someone".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn toc_subtree_packing() {
+        use crate::{PageId, PagePacking};
+
+        let dump = |packing| {
+            let mut builder = Book::builder();
+            builder.set_block_size(1000).set_page_packing(packing);
+
+            let a = builder.new_page("A").set_content("A").id();
+            let b = builder.new_page("B").set_content("B").id();
+
+            let mut ids = Vec::new();
+            for n in 0..2 {
+                for (parent, name) in [(a, "a"), (b, "b")] {
+                    let content = format!("{}{}", name, n).repeat(200);
+                    let id = builder
+                        .new_page(format!("{}{}", name, n))
+                        .set_content(content)
+                        .set_parent(parent)
+                        .id();
+                    ids.push(id);
+                }
+            }
+
+            let mut buffer = Vec::new();
+            builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+            let mut book = Book::load(Cursor::new(buffer)).unwrap();
+            assert!(book.verify().is_ok());
+
+            let mut original = builder.pages.clone();
+            let mut loaded: Vec<_> = book.pages().map(Result::unwrap).collect();
+            original.sort_by_key(|p| p.id);
+            loaded.sort_by_key(|p| p.id);
+            assert_eq!(original, loaded);
+
+            // Content block of `a0`, `a1`, `b0`, and `b1`.
+            let block = |id: PageId| book.page_index.entry(id).unwrap().block_ids()[1];
+            [ids[0], ids[2], ids[1], ids[3]].map(block)
+        };
+
+        let [a0, a1, b0, b1] = dump(PagePacking::InsertionOrder);
+        assert_eq!(a0, b0);
+        assert_ne!(a0, a1);
+        assert_eq!(a1, b1);
+
+        let [a0, a1, b0, b1] = dump(PagePacking::TocSubtree);
+        assert_eq!(a0, a1);
+        assert_eq!(b0, b1);
+        assert_ne!(a0, b0);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_compression() {
+        let dump = |threads| {
+            let mut builder = Book::builder();
+
+            #[cfg(feature = "deflate")]
+            builder.set_compression(crate::BlockCompression::Deflate(9));
+
+            builder.set_compression_threads(threads);
+
+            for n in 0..200 {
+                let content = format!("Page {} with some words to compress. ", n);
+                builder
+                    .new_page(format!("Page {}", n))
+                    .set_content(content.repeat(n % 50 + 1));
+            }
+
+            let mut buffer = Vec::new();
+            builder.dump(Cursor::new(&mut buffer)).unwrap();
+            buffer
+        };
+
+        let expected = dump(1);
+        assert!(dump(4) == expected);
+        assert!(dump(0) == expected);
+
+        let mut book = Book::load(Cursor::new(expected)).unwrap();
+        assert_eq!(book.search("compress").unwrap().len(), 200);
+        assert!(book.verify().is_ok());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_dictionary() {
+        use crate::BlockCompression;
+
+        // Magic number at the beginning of Zstandard dictionaries.
+        const DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xA4, 0x30, 0xEC];
+
+        let compression = BlockCompression::Zstd {
+            level: 3,
+            dictionary: true,
+        };
+
+        let content = |n: usize| {
+            format!(
+                "<h1>Page {}</h1><p>Pages in <em>Theory</em> books are small.</p>",
+                n
+            )
+        };
+
+        let mut builder = Book::builder();
+        builder.set_compression(compression);
+        for n in 0..500 {
+            builder
+                .new_page(format!("Page {}", n))
+                .set_content(content(n));
+        }
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+        assert!(buffer.windows(4).any(|w| w == DICTIONARY_MAGIC));
+
+        let mut book = Book::load(Cursor::new(&buffer)).unwrap();
+        for (n, page) in book.pages().enumerate() {
+            assert_eq!(page.unwrap().content(), content(n).as_bytes());
+        }
+
+        assert!(book.verify().is_ok());
+        assert_eq!(book.search("theory").unwrap().len(), 500);
+
+        // Train the dictionary with the writer.
+        let samples: Vec<_> = (0..500).map(content).collect();
+        let mut writer = BookWriter::create(Cursor::new(Vec::new())).unwrap();
+        writer
+            .set_compression(compression)
+            .train_dictionary(&samples)
+            .unwrap();
+
+        for n in 0..500 {
+            let mut page = writer.new_page(format!("Page {}", n));
+            page.set_content(content(n));
+            writer.add_page(&page).unwrap();
+        }
+
+        let written = writer.finish().unwrap().into_inner();
+        assert!(written.windows(4).any(|w| w == DICTIONARY_MAGIC));
+
+        let mut book = Book::load(Cursor::new(written)).unwrap();
+        let page = book.pages().nth(123).unwrap().unwrap();
+        assert_eq!(page.content(), content(123).as_bytes());
+
+        // Without enough pages, the dictionary is not used.
+        let mut builder = Book::builder();
+        builder.set_compression(compression);
+        builder.new_page("Single").set_content(content(0));
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+        assert!(!buffer.windows(4).any(|w| w == DICTIONARY_MAGIC));
+
+        let mut book = Book::load(Cursor::new(&buffer)).unwrap();
+        let page = book.pages().next().unwrap().unwrap();
+        assert_eq!(page.content(), content(0).as_bytes());
+    }
+}
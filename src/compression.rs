@@ -0,0 +1,180 @@
+//! Module with the `CompressionPolicy` implementation.
+
+use crate::{BlockCompression, Page};
+
+/// Rules to select the compression method of every page.
+///
+/// Rules are evaluated in the same order they were added, and the first
+/// matching rule selects the method. If no rule matches the page, the default
+/// method is used. A method set with [`Page::set_compression`] overrides the
+/// policy.
+///
+/// Pages with different methods are stored in different data blocks.
+///
+/// ```
+/// # #[cfg(feature = "deflate")] {
+/// use theory::{Book, BlockCompression, CompressionPolicy};
+///
+/// // Don't compress images, and use DEFLATE for anything else.
+/// let policy = CompressionPolicy::new(BlockCompression::Deflate(9))
+///     .content_type("image/", BlockCompression::None);
+///
+/// let mut builder = Book::builder();
+/// builder.set_compression_policy(policy);
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CompressionPolicy {
+    default: BlockCompression,
+
+    rules: Vec<(Rule, BlockCompression)>,
+}
+
+/// Condition to select a compression method.
+#[derive(Clone, Debug)]
+enum Rule {
+    /// Pages with a content type starting with this prefix.
+    ContentType(String),
+
+    /// Pages with a content larger than this size, in bytes.
+    LargerThan(usize),
+}
+
+impl CompressionPolicy {
+    /// Create a policy that uses `default` for every page.
+    pub fn new(default: BlockCompression) -> Self {
+        CompressionPolicy {
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Use `compression` for pages with a content type starting with
+    /// `prefix`, like `image/` or `font/woff2`.
+    ///
+    /// The content type is detected from the first bytes of the content.
+    /// Only formats that are usually compressed are detected: `image/png`,
+    /// `image/jpeg`, `image/gif`, `image/webp`, `font/woff`, `font/woff2`,
+    /// `application/gzip`, `application/zip`, and `application/zstd`.
+    pub fn content_type(
+        mut self,
+        prefix: impl Into<String>,
+        compression: BlockCompression,
+    ) -> Self {
+        self.rules
+            .push((Rule::ContentType(prefix.into()), compression));
+        self
+    }
+
+    /// Use `compression` for pages with a content larger than `size` bytes.
+    pub fn larger_than(mut self, size: usize, compression: BlockCompression) -> Self {
+        self.rules.push((Rule::LargerThan(size), compression));
+        self
+    }
+
+    /// Return the method used for pages not matched by any rule.
+    pub fn default_compression(&self) -> BlockCompression {
+        self.default
+    }
+
+    /// Change the method used for pages not matched by any rule.
+    pub(crate) fn set_default(&mut self, compression: BlockCompression) {
+        self.default = compression;
+    }
+
+    /// Return the compression method for a page.
+    pub fn select(&self, page: &Page) -> BlockCompression {
+        if let Some(compression) = page.compression {
+            return compression;
+        }
+
+        let content_type = detect_content_type(&page.content);
+
+        self.rules
+            .iter()
+            .find(|(rule, _)| match rule {
+                Rule::ContentType(prefix) => {
+                    content_type.map_or(false, |ct| ct.starts_with(prefix.as_str()))
+                }
+
+                Rule::LargerThan(size) => page.content.len() > *size,
+            })
+            .map_or(self.default, |(_, compression)| *compression)
+    }
+
+    /// Return all methods that can be selected by this policy.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn methods(&self) -> impl Iterator<Item = BlockCompression> + '_ {
+        std::iter::once(self.default).chain(self.rules.iter().map(|(_, c)| *c))
+    }
+}
+
+/// Detect the content type of data in a compressed format, from its magic
+/// number.
+fn detect_content_type(content: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    ];
+
+    // WebP files are RIFF containers.
+    if content.len() >= 12 && &content[..4] == b"RIFF" && &content[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    SIGNATURES
+        .iter()
+        .find(|(magic, _)| content.starts_with(magic))
+        .map(|(_, content_type)| *content_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompressionPolicy;
+    use crate::{BlockCompression, Book};
+
+    #[test]
+    fn select_methods() {
+        #[cfg(feature = "deflate")]
+        let (zip, large) = (BlockCompression::Deflate(1), BlockCompression::Deflate(9));
+
+        #[cfg(not(feature = "deflate"))]
+        let (zip, large) = (BlockCompression::None, BlockCompression::None);
+
+        let policy = CompressionPolicy::new(BlockCompression::None)
+            .content_type("image/", BlockCompression::None)
+            .content_type("application/zip", zip)
+            .larger_than(1000, large);
+
+        let mut builder = Book::builder();
+
+        let png = builder
+            .new_page("PNG")
+            .set_content(&b"\x89PNG\r\n\x1a\n-"[..]);
+        assert_eq!(policy.select(png), BlockCompression::None);
+
+        let webp = builder
+            .new_page("WebP")
+            .set_content(&b"RIFF\0\0\0\0WEBPVP8 "[..]);
+        assert_eq!(policy.select(webp), BlockCompression::None);
+
+        let zip_page = builder.new_page("ZIP").set_content(&b"PK\x03\x04"[..]);
+        assert_eq!(policy.select(zip_page), zip);
+
+        let html = builder.new_page("HTML").set_content(vec![b'<'; 2000]);
+        assert_eq!(policy.select(html), large);
+
+        html.set_compression(BlockCompression::None);
+        assert_eq!(policy.select(html), BlockCompression::None);
+
+        let text = builder.new_page("Text").set_content("text");
+        assert_eq!(policy.select(text), policy.default_compression());
+    }
+}
@@ -0,0 +1,739 @@
+//! Module with the `diff` implementation, to compare two books.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Seek};
+
+use crate::page::persistence::build_page;
+use crate::page::{PageError, PageId};
+use crate::{Book, MetadataEntry};
+
+/// Maximum number of lines added or removed in a content diff. If the
+/// difference is larger, the content is reported as completely replaced.
+///
+/// Memory used to compute the diff is quadratic in this number.
+const MAX_EDIT_DISTANCE: usize = 1024;
+
+/// Maximum depth of the title paths. Deeper pages are usually in a cycle of
+/// parents.
+const MAX_PATH_DEPTH: usize = 32;
+
+/// Compare two books, and return the differences between them.
+///
+/// Pages of `old` are matched with the pages of `new` with these rules, in
+/// order:
+///
+/// 1. Same [key](crate::Page::set_key).
+/// 2. Same identifier and same title path (the titles of the page and its
+///    ancestors).
+/// 3. Same title path, if it is unique in both books. This is useful when
+///    identifiers change between builds.
+/// 4. Same identifier.
+///
+/// Pages not matched are reported as added or removed.
+///
+/// ```
+/// use theory::{diff, Book, MetadataEntry};
+/// use std::io::Cursor;
+///
+/// let mut builder = Book::builder();
+/// builder.new_page("Intro").set_content("Hello\nWorld\n");
+///
+/// let mut buffer = Vec::new();
+/// builder.dump(Cursor::new(&mut buffer)).unwrap();
+/// let mut old = Book::load(Cursor::new(buffer)).unwrap();
+///
+/// let mut builder = Book::builder();
+/// builder.add_metadata(MetadataEntry::Title("Book".into()));
+/// builder.new_page("Intro").set_content("Hello\nThere\n");
+/// builder.new_page("Usage");
+///
+/// let mut buffer = Vec::new();
+/// builder.dump(Cursor::new(&mut buffer)).unwrap();
+/// let mut new = Book::load(Cursor::new(buffer)).unwrap();
+///
+/// let changes = diff(&mut old, &mut new).unwrap();
+///
+/// assert_eq!(changes.metadata().added(), [MetadataEntry::Title("Book".into())]);
+/// assert_eq!(changes.added_pages().len(), 1);
+/// assert_eq!(changes.changed_pages().len(), 1);
+/// ```
+pub fn diff<A, B>(old: &mut Book<A>, new: &mut Book<B>) -> Result<BookDiff, PageError>
+where
+    A: Read + Seek,
+    B: Read + Seek,
+{
+    let metadata = MetadataChanges::new(&book_metadata(old)?, &book_metadata(new)?);
+
+    let old_tree = PageTree::new(old)?;
+    let new_tree = PageTree::new(new)?;
+
+    let matches = match_pages(&old_tree, &new_tree);
+    let matched_new: HashSet<_> = matches.values().copied().collect();
+
+    let removed = old_tree
+        .pages
+        .keys()
+        .filter(|id| !matches.contains_key(id))
+        .copied()
+        .collect();
+
+    let added = new_tree
+        .pages
+        .keys()
+        .filter(|id| !matched_new.contains(id))
+        .copied()
+        .collect();
+
+    let mut changed = Vec::new();
+    for (&old_id, &new_id) in &matches {
+        let old_page = old.page_index.entry(old_id)?;
+        let old_page = build_page(old_page, &mut old.data_blocks)?;
+
+        let new_page = new.page_index.entry(new_id)?;
+        let new_page = build_page(new_page, &mut new.data_blocks)?;
+
+        let old_parent = old_page.parent().filter(|p| old_tree.pages.contains_key(p));
+        let new_parent = new_page.parent().filter(|p| new_tree.pages.contains_key(p));
+
+        let moved = match old_parent.and_then(|p| matches.get(&p)) {
+            Some(parent) if Some(*parent) == new_parent => None,
+            None if old_parent.is_none() && new_parent.is_none() => None,
+            _ => Some((old_parent, new_parent)),
+        };
+
+        let old_title = &old_tree.pages[&old_id].1;
+        let new_title = &new_tree.pages[&new_id].1;
+        let retitled = if old_title != new_title {
+            Some((old_title.clone(), new_title.clone()))
+        } else {
+            None
+        };
+
+        let not_title = |e: &&MetadataEntry| !matches!(e, MetadataEntry::Title(_));
+        let metadata = MetadataChanges::new(
+            old_page.metadata().iter().filter(not_title),
+            new_page.metadata().iter().filter(not_title),
+        );
+
+        let content = if old_page.content() != new_page.content() {
+            Some(ContentDiff::new(old_page.content(), new_page.content()))
+        } else {
+            None
+        };
+
+        let change = PageChange {
+            old_id,
+            new_id,
+            moved,
+            retitled,
+            metadata,
+            content,
+        };
+
+        if !change.is_empty() {
+            changed.push(change);
+        }
+    }
+
+    Ok(BookDiff {
+        metadata,
+        added,
+        removed,
+        changed,
+    })
+}
+
+/// Differences between two books, computed by [`diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookDiff {
+    metadata: MetadataChanges,
+    added: Vec<PageId>,
+    removed: Vec<PageId>,
+    changed: Vec<PageChange>,
+}
+
+impl BookDiff {
+    /// Return `true` if both books have the same metadata and pages.
+    pub fn is_empty(&self) -> bool {
+        self.metadata.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+
+    /// Changes in the metadata of the books.
+    pub fn metadata(&self) -> &MetadataChanges {
+        &self.metadata
+    }
+
+    /// Identifiers, in the new book, of the pages not found in the old one.
+    pub fn added_pages(&self) -> &[PageId] {
+        &self.added
+    }
+
+    /// Identifiers, in the old book, of the pages not found in the new one.
+    pub fn removed_pages(&self) -> &[PageId] {
+        &self.removed
+    }
+
+    /// Pages found in both books, with some difference between them.
+    ///
+    /// Pages are sorted by their identifier in the old book.
+    pub fn changed_pages(&self) -> &[PageChange] {
+        &self.changed
+    }
+}
+
+/// Metadata entries added or removed.
+///
+/// The order of the entries is not compared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataChanges {
+    added: Vec<MetadataEntry>,
+    removed: Vec<MetadataEntry>,
+}
+
+impl MetadataChanges {
+    fn new<'a, O, N>(old: O, new: N) -> MetadataChanges
+    where
+        O: IntoIterator<Item = &'a MetadataEntry>,
+        N: IntoIterator<Item = &'a MetadataEntry>,
+    {
+        let mut removed: Vec<_> = old.into_iter().cloned().collect();
+        let mut added = Vec::new();
+
+        for entry in new {
+            match removed.iter().position(|e| e == entry) {
+                Some(position) => {
+                    removed.remove(position);
+                }
+
+                None => added.push(entry.clone()),
+            }
+        }
+
+        MetadataChanges { added, removed }
+    }
+
+    /// Return `true` if there are no changes.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// Entries found only in the new version.
+    pub fn added(&self) -> &[MetadataEntry] {
+        &self.added
+    }
+
+    /// Entries found only in the old version.
+    pub fn removed(&self) -> &[MetadataEntry] {
+        &self.removed
+    }
+}
+
+/// Differences between two versions of a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageChange {
+    old_id: PageId,
+    new_id: PageId,
+    moved: Option<(Option<PageId>, Option<PageId>)>,
+    retitled: Option<(String, String)>,
+    metadata: MetadataChanges,
+    content: Option<ContentDiff>,
+}
+
+impl PageChange {
+    fn is_empty(&self) -> bool {
+        self.moved.is_none()
+            && self.retitled.is_none()
+            && self.metadata.is_empty()
+            && self.content.is_none()
+    }
+
+    /// Identifier of the page in the old book.
+    pub fn old_id(&self) -> PageId {
+        self.old_id
+    }
+
+    /// Identifier of the page in the new book.
+    pub fn new_id(&self) -> PageId {
+        self.new_id
+    }
+
+    /// If the page has a different parent, return the old parent (identifier
+    /// in the old book) and the new one (identifier in the new book).
+    pub fn moved(&self) -> Option<(Option<PageId>, Option<PageId>)> {
+        self.moved
+    }
+
+    /// If the page has a different title, return the old and the new titles.
+    pub fn retitled(&self) -> Option<(&str, &str)> {
+        self.retitled
+            .as_ref()
+            .map(|(old, new)| (old.as_str(), new.as_str()))
+    }
+
+    /// Changes in the metadata of the page, excluding the title.
+    pub fn metadata(&self) -> &MetadataChanges {
+        &self.metadata
+    }
+
+    /// Changes in the content of the page, if it is different.
+    pub fn content(&self) -> Option<&ContentDiff> {
+        self.content.as_ref()
+    }
+}
+
+/// Differences in the content of a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ContentDiff {
+    /// Both versions are valid UTF-8. The content is compared line by line.
+    ///
+    /// Lines include their terminator (`\n`), so changes in the line endings
+    /// are reported too.
+    Text(Vec<DiffLine>),
+
+    /// Some version is not valid UTF-8, so the content is not compared.
+    Binary,
+}
+
+impl ContentDiff {
+    fn new(old: &[u8], new: &[u8]) -> ContentDiff {
+        match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+            (Ok(old), Ok(new)) => {
+                let old: Vec<_> = old.split_inclusive('\n').collect();
+                let new: Vec<_> = new.split_inclusive('\n').collect();
+                ContentDiff::Text(diff_lines(&old, &new))
+            }
+
+            _ => ContentDiff::Binary,
+        }
+    }
+}
+
+/// A line in the diff of a text content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    /// Line found in both versions.
+    Unchanged(String),
+
+    /// Line found only in the new version.
+    Added(String),
+
+    /// Line found only in the old version.
+    Removed(String),
+}
+
+/// Parent and title of every page in a book.
+struct PageTree {
+    pages: BTreeMap<PageId, (Option<PageId>, String)>,
+
+    keys: HashMap<String, PageId>,
+}
+
+impl PageTree {
+    fn new<I: Read + Seek>(book: &mut Book<I>) -> Result<PageTree, PageError> {
+        let mut pages = BTreeMap::new();
+        for (id, entry) in &book.page_index {
+            let title = entry.get_page_title(&mut book.data_blocks)?;
+            pages.insert(*id, (entry.parent_id(), title));
+        }
+
+        let keys = book
+            .page_index
+            .keys()
+            .map(|(key, id)| (key.to_owned(), id))
+            .collect();
+
+        Ok(PageTree { pages, keys })
+    }
+
+    /// Titles of the page and its ancestors, starting from the root.
+    fn title_path(&self, page_id: PageId) -> Vec<&str> {
+        let mut path = Vec::new();
+        let mut next = Some(page_id);
+
+        while let Some((parent, title)) = next.and_then(|id| self.pages.get(&id)) {
+            if path.len() == MAX_PATH_DEPTH {
+                break;
+            }
+
+            path.push(title.as_str());
+            next = *parent;
+        }
+
+        path.reverse();
+        path
+    }
+
+    /// Pages by their title paths. Paths used by multiple pages are not
+    /// included.
+    fn unique_paths(&self) -> HashMap<Vec<&str>, PageId> {
+        let mut paths = HashMap::new();
+        let mut duplicated = HashSet::new();
+
+        for id in self.pages.keys() {
+            let path = self.title_path(*id);
+            if paths.insert(path.clone(), *id).is_some() {
+                duplicated.insert(path);
+            }
+        }
+
+        paths.retain(|path, _| !duplicated.contains(path));
+        paths
+    }
+}
+
+/// Match the pages of both books, with the rules described in [`diff`].
+///
+/// Returns the identifier in `new` of every matched page in `old`.
+fn match_pages(old: &PageTree, new: &PageTree) -> BTreeMap<PageId, PageId> {
+    let mut matches = BTreeMap::new();
+    let mut matched_new = HashSet::new();
+
+    // Same key.
+    for (key, old_id) in &old.keys {
+        if let Some(new_id) = new.keys.get(key) {
+            if !matches.contains_key(old_id) && !matched_new.contains(new_id) {
+                matches.insert(*old_id, *new_id);
+                matched_new.insert(*new_id);
+            }
+        }
+    }
+
+    // Same identifier and same title path.
+    for id in old.pages.keys() {
+        if !matches.contains_key(id)
+            && !matched_new.contains(id)
+            && new.pages.contains_key(id)
+            && old.title_path(*id) == new.title_path(*id)
+        {
+            matches.insert(*id, *id);
+            matched_new.insert(*id);
+        }
+    }
+
+    // Same title path, if it is unique.
+    let new_paths = new.unique_paths();
+    for (path, old_id) in old.unique_paths() {
+        if let Some(new_id) = new_paths.get(&path) {
+            if !matches.contains_key(&old_id) && !matched_new.contains(new_id) {
+                matches.insert(old_id, *new_id);
+                matched_new.insert(*new_id);
+            }
+        }
+    }
+
+    // Same identifier.
+    for id in old.pages.keys() {
+        if new.pages.contains_key(id) && !matches.contains_key(id) && !matched_new.contains(id) {
+            matches.insert(*id, *id);
+            matched_new.insert(*id);
+        }
+    }
+
+    matches
+}
+
+/// Read the metadata entries of a book.
+fn book_metadata<I: Read + Seek>(book: &mut Book<I>) -> Result<Vec<MetadataEntry>, PageError> {
+    book.metadata()?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| PageError::InvalidMetadata(e.to_string()))
+}
+
+/// Compute the differences between two lists of lines, with the Myers
+/// algorithm.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    // Lines at the beginning and at the end are usually the same, so they are
+    // excluded from the algorithm.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let unchanged = |lines: &[&str]| {
+        lines
+            .iter()
+            .map(|l| DiffLine::Unchanged((*l).to_owned()))
+            .collect::<Vec<_>>()
+    };
+
+    let mut lines = unchanged(&old[..prefix]);
+
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    match shortest_edit(old_middle, new_middle) {
+        Some(edit) => lines.extend(edit),
+        None => {
+            let removed = old_middle
+                .iter()
+                .map(|l| DiffLine::Removed((*l).to_owned()));
+            let added = new_middle.iter().map(|l| DiffLine::Added((*l).to_owned()));
+            lines.extend(removed.chain(added));
+        }
+    }
+
+    lines.extend(unchanged(&old[old.len() - suffix..]));
+    lines
+}
+
+/// Find the shortest edit script from `old` to `new`.
+///
+/// Returns `None` if it needs more than `MAX_EDIT_DISTANCE` changes.
+fn shortest_edit(old: &[&str], new: &[&str]) -> Option<Vec<DiffLine>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = (old.len() + new.len()).min(MAX_EDIT_DISTANCE) as isize;
+
+    // `v[k]` is the furthest `x` reached in the diagonal `k = x - y`. The
+    // state before every step is kept to find the path when the end is
+    // reached. In the step `d`, only diagonals in `-d..=d` are used.
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    let mut trace = Vec::new();
+
+    let at = |k: isize| (k + offset) as usize;
+
+    let mut distance = None;
+    'outer: for d in 0..=max {
+        trace.push(v[at(-d)..=at(d)].to_vec());
+
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                v[at(k + 1)]
+            } else {
+                v[at(k - 1)] + 1
+            };
+
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+
+            v[at(k)] = x;
+
+            if x >= n && y >= m {
+                distance = Some(d);
+                break 'outer;
+            }
+        }
+    }
+
+    // Walk the path backwards, from the end.
+    let mut lines = Vec::new();
+    let (mut x, mut y) = (n, m);
+
+    for d in (0..=distance?).rev() {
+        let v = &trace[d as usize];
+        let get = |k: isize| v[(k + d) as usize];
+
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+
+        let prev_x = if d == 0 { 0 } else { get(prev_k) };
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            lines.push(DiffLine::Unchanged(old[x as usize - 1].to_owned()));
+            x -= 1;
+            y -= 1;
+        }
+
+        if d > 0 {
+            if x == prev_x {
+                lines.push(DiffLine::Added(new[y as usize - 1].to_owned()));
+            } else {
+                lines.push(DiffLine::Removed(old[x as usize - 1].to_owned()));
+            }
+        }
+
+        x = prev_x;
+        y = prev_y;
+    }
+
+    lines.reverse();
+    Some(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, diff_lines, ContentDiff, DiffLine};
+    use crate::{Book, BookBuilder, MetadataEntry};
+    use std::io::Cursor;
+
+    fn load(builder: &BookBuilder) -> Book<Cursor<Vec<u8>>> {
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+        Book::load(Cursor::new(buffer)).unwrap()
+    }
+
+    #[test]
+    fn line_diff() {
+        use DiffLine::*;
+
+        let old = ["a\n", "b\n", "c\n", "d\n"];
+        let new = ["a\n", "c\n", "x\n", "d\n", "e\n"];
+
+        assert_eq!(
+            diff_lines(&old, &new),
+            [
+                Unchanged("a\n".into()),
+                Removed("b\n".into()),
+                Unchanged("c\n".into()),
+                Added("x\n".into()),
+                Unchanged("d\n".into()),
+                Added("e\n".into()),
+            ]
+        );
+
+        assert_eq!(diff_lines(&[], &["a"]), [Added("a".into())]);
+        assert_eq!(diff_lines(&["a"], &[]), [Removed("a".into())]);
+        assert_eq!(diff_lines(&["a"], &["a"]), [Unchanged("a".into())]);
+
+        // Very different contents are replaced.
+        let old: Vec<_> = (0..2000)
+            .map(|n| if n % 2 == 0 { "a" } else { "b" })
+            .collect();
+        let new: Vec<_> = (0..2000)
+            .map(|n| if n % 2 == 0 { "b" } else { "c" })
+            .collect();
+        let lines = diff_lines(&old, &new);
+        assert_eq!(lines.len(), 4000);
+        assert!(lines[..2000].iter().all(|l| matches!(l, Removed(_))));
+    }
+
+    #[test]
+    fn match_by_title_path() {
+        let mut old = Book::builder();
+        let guide = old.new_page("Guide").id();
+        let install = old
+            .new_page("Install")
+            .set_content("Step 1\nStep 2\n")
+            .set_parent(guide)
+            .id();
+        let old_page = old.new_page("Old").id();
+
+        // Identifiers are shifted by the new page.
+        let mut new = Book::builder();
+        let news = new.new_page("News").id();
+        let new_guide = new.new_page("Guide").id();
+        let new_install = new
+            .new_page("Install")
+            .set_content("Step 1\nStep 1.5\nStep 2\n")
+            .set_parent(new_guide)
+            .id();
+
+        let changes = diff(&mut load(&old), &mut load(&new)).unwrap();
+
+        assert!(changes.metadata().is_empty());
+        assert_eq!(changes.added_pages(), [news]);
+        assert_eq!(changes.removed_pages(), [old_page]);
+
+        let changed = changes.changed_pages();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].old_id(), install);
+        assert_eq!(changed[0].new_id(), new_install);
+        assert_eq!(changed[0].moved(), None);
+        assert_eq!(changed[0].retitled(), None);
+        assert_eq!(
+            changed[0].content(),
+            Some(&ContentDiff::Text(vec![
+                DiffLine::Unchanged("Step 1\n".into()),
+                DiffLine::Added("Step 1.5\n".into()),
+                DiffLine::Unchanged("Step 2\n".into()),
+            ]))
+        );
+
+        // No differences with the same book.
+        assert!(diff(&mut load(&new), &mut load(&new)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn match_by_key() {
+        let mut old = Book::builder();
+        let guide = old.new_page("Guide").id();
+        let install = old
+            .new_page("Install")
+            .set_key("install")
+            .set_parent(guide)
+            .id();
+
+        // The page is moved and retitled, and the identifiers are shifted.
+        let mut new = Book::builder();
+        new.new_page("News");
+        new.new_page("Guide");
+        let setup = new.new_page("Setup").set_key("install").id();
+
+        let changes = diff(&mut load(&old), &mut load(&new)).unwrap();
+
+        let changed = changes.changed_pages();
+        assert_eq!(changed.len(), 1);
+        assert_eq!((changed[0].old_id(), changed[0].new_id()), (install, setup));
+        assert_eq!(changed[0].retitled(), Some(("Install", "Setup")));
+        assert_eq!(changed[0].moved(), Some((Some(guide), None)));
+    }
+
+    #[test]
+    fn moved_and_retitled() {
+        let mut old = Book::builder();
+        old.add_metadata(MetadataEntry::Title("Book".into()))
+            .add_metadata(MetadataEntry::Author("A".into()));
+
+        let guide = old.new_page("Guide").id();
+        let install = old.new_page("Install").set_parent(guide).id();
+        let faq = old.new_page("FAQ").set_content(&[0xFF, 0][..]).id();
+
+        let mut new = Book::builder();
+        new.add_metadata(MetadataEntry::Title("Book".into()))
+            .add_metadata(MetadataEntry::Author("B".into()));
+
+        new.new_page("Guide");
+        new.new_page("Setup").set_parent(guide);
+        new.new_page("FAQ")
+            .set_content(&[0xFF, 1][..])
+            .add_metadata(MetadataEntry::Keyword("questions".into()))
+            .set_parent(guide);
+
+        let changes = diff(&mut load(&old), &mut load(&new)).unwrap();
+
+        assert_eq!(
+            changes.metadata().added(),
+            [MetadataEntry::Author("B".into())]
+        );
+        assert_eq!(
+            changes.metadata().removed(),
+            [MetadataEntry::Author("A".into())]
+        );
+
+        assert!(changes.added_pages().is_empty());
+        assert!(changes.removed_pages().is_empty());
+
+        let changed = changes.changed_pages();
+        assert_eq!(changed.len(), 2);
+
+        assert_eq!(changed[0].old_id(), install);
+        assert_eq!(changed[0].retitled(), Some(("Install", "Setup")));
+        assert_eq!(changed[0].moved(), None);
+        assert_eq!(changed[0].content(), None);
+
+        assert_eq!(changed[1].old_id(), faq);
+        assert_eq!(changed[1].retitled(), None);
+        assert_eq!(changed[1].moved(), Some((None, Some(guide))));
+        assert_eq!(changed[1].content(), Some(&ContentDiff::Binary));
+        assert_eq!(
+            changed[1].metadata().added(),
+            [MetadataEntry::Keyword("questions".into())]
+        );
+        assert_eq!(changed[1].new_id(), faq);
+    }
+}
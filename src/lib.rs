@@ -22,6 +22,20 @@
 //! Optionally, data can be compressed with
 //! [`set_compression`](BookBuilder::set_compression).
 //!
+//! [`BookWriter`] creates books without keeping all pages in memory: the
+//! content of every page is written as soon as it is added.
+//!
+//! Pages in an existing book can be added, replaced, or removed with
+//! [`Book::open_for_append`], which writes the changes at the end of the file.
+//!
+//! To read only some parts of the pages, like their titles to build a list
+//! of pages, use [`Book::page_refs`]. The content of a page is read only when
+//! it is requested with [`PageRef::content`].
+//!
+//! Multiple books can be merged into a single one with
+//! [`BookWriter::import_book`]. Large books can be split into multiple files
+//! with [`Book::split_volumes`], and read as a single book with [`VolumeSet`].
+//!
 //! ### Example
 //!
 //! ```
@@ -47,6 +61,19 @@
 //!
 //! A book written by [`BookBuilder::dump`] can be loaded with [`Book::load`].
 //!
+//! ## Searching
+//!
+//! [`BookBuilder::dump`] includes a full-text index of the pages in the book.
+//! [`Book::search`] uses this index to find pages without reading all of them.
+//! Queries can be written with the syntax described in [`Query`], or built
+//! programmatically with [`Book::search_query`].
+//!
+//! Words are extracted from the pages with an [analyzer](analysis::Analyzer),
+//! selected from the `Language` metadata entry of every page.
+//!
+//! [`Book::suggest`] completes partial words from the terms in the index, for
+//! search-as-you-type interfaces.
+//!
 //! # Crate Features
 //!
 //! Features can be used for controlling some functionalities in the library:
@@ -54,34 +81,100 @@
 //! * `deflate`
 //!
 //!     Add supports for compressing books with
-//!     [DEFLATE](https://en.wikipedia.org/wiki/Deflate).
+//!   [DEFLATE](https://en.wikipedia.org/wiki/Deflate).
 //!
 //! * `lz4`
 //!
 //!     Add supports for compressing books with
-//!     [LZ4](https://en.wikipedia.org/wiki/LZ4_(compression_algorithm)).
+//!   [LZ4](https://en.wikipedia.org/wiki/LZ4_(compression_algorithm)).
+//!
+//! * `zstd`
+//!
+//!     Add supports for compressing books with
+//!   [Zstandard](https://en.wikipedia.org/wiki/Zstd), optionally with a
+//!   dictionary trained from the pages.
+//!
+//! * `mmap`
 //!
-//! All features are enabled by default.
+//!     Add `Book::open_mmap`, to read books from memory-mapped files.
+//!
+//! * `async`
+//!
+//!     Add `AsyncBook`, to read books from asynchronous streams.
+//!
+//! * `parallel`
+//!
+//!     Add `BookBuilder::set_compression_threads`, to compress data blocks
+//!   in multiple threads.
+//!
+//! Only `deflate` and `lz4` are enabled by default.
 
+mod append;
 mod book;
+mod compression;
+mod diff;
 mod metadata;
+mod options;
 mod page;
+mod search;
+mod shared;
 mod toc;
+mod verify;
+mod volume;
+
+#[cfg(feature = "async")]
+mod async_book;
 
 pub(crate) mod builder;
 pub(crate) mod persistence;
 
+pub use append::BookAppender;
+#[cfg(feature = "async")]
+pub use async_book::AsyncBook;
 pub use book::Book;
-pub use builder::BookBuilder;
+pub use builder::{BookBuilder, BookWriter, ImportOptions, PagePacking, WriteStats};
+pub use compression::CompressionPolicy;
+pub use diff::{diff, BookDiff, ContentDiff, DiffLine, MetadataChanges, PageChange};
 pub use metadata::MetadataEntry;
-pub use page::{Page, PageId};
+pub use options::BookOptions;
+pub use page::{Page, PageId, PageRef};
 pub use persistence::datablock::BlockCompression;
+pub use persistence::ReadAt;
+pub use search::query::{Field, Query};
+pub use search::snippets::{SearchOptions, Snippet};
+pub use search::SearchHit;
+pub use shared::SharedBook;
 pub use toc::TocEntry;
+pub use verify::{VerifyIssue, VerifyReport};
+pub use volume::{SplitOptions, VolumeSet};
+
+/// Text analysis for the search index.
+///
+/// See [`Analyzer`](analysis::Analyzer) for more details.
+pub mod analysis {
+    pub use crate::search::analysis::{
+        AccentFoldingFilter, Analyzer, LowercaseFilter, StemmerFilter, StopWordsFilter,
+        TextAnalyzer, Token, TokenFilter, Tokenizer, UnicodeTokenizer,
+    };
+
+    pub use rust_stemmers::Algorithm;
+}
+
+/// Caches for the data blocks of the books.
+///
+/// See [`BookOptions`] to configure the cache of a book.
+pub mod cache {
+    pub use crate::persistence::datablock::cache::{
+        BlockCache, CacheKey, CacheStats, LruBlockCache,
+    };
+}
 
 /// Types to describe errors.
 pub mod errors {
     pub use crate::metadata::MetadataError;
     pub use crate::page::PageError;
-    pub use crate::persistence::PersistenceError;
+    pub use crate::persistence::{PersistenceError, Section};
+    pub use crate::search::query::QueryError;
+    pub use crate::search::SearchError;
     pub use crate::toc::TocError;
 }
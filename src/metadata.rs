@@ -58,6 +58,10 @@ pub(crate) enum ByteTag {
     Date = 4,
     License = 5,
     Keyword = 6,
+    SearchBoost = 7,
+    Volume = 8,
+    VolumeFile = 9,
+    Key = 10,
     User = 100,
 }
 
@@ -110,6 +114,28 @@ pub enum MetadataEntry {
     Date(u64),
     License(String),
     Keyword(String),
+
+    /// Factor applied to the relevance of the page in search results, as a
+    /// percentage.
+    ///
+    /// `100` keeps the computed relevance, `200` doubles it, and `50` halves
+    /// it. It can be used to rank overview pages above deep reference pages.
+    SearchBoost(u32),
+
+    /// Number of the volume, starting at `1`, in a book split with
+    /// [`Book::split_volumes`](crate::Book::split_volumes).
+    Volume(u32),
+
+    /// Number and file name of a volume in the same set. Every volume
+    /// contains an entry for each volume in the set, including itself.
+    VolumeFile(u32, String),
+
+    /// Stable key of a page, like a slug or a URL path, to find it with
+    /// [`Book::get_page_by_key`](crate::Book::get_page_by_key).
+    ///
+    /// Keys must be unique in a book. See [`Page::set_key`](crate::Page::set_key).
+    Key(String),
+
     User(String, String),
 }
 
@@ -143,6 +169,10 @@ where
             MetadataEntry::Date(d) => w!(Date, &d.to_be_bytes()),
             MetadataEntry::License(s) => w!(License, s.as_bytes()),
             MetadataEntry::Keyword(s) => w!(Keyword, s.as_bytes()),
+            MetadataEntry::SearchBoost(b) => w!(SearchBoost, &b.to_be_bytes()),
+            MetadataEntry::Volume(n) => w!(Volume, &n.to_be_bytes()),
+            MetadataEntry::VolumeFile(n, f) => w!(VolumeFile, &n.to_be_bytes(), f.as_bytes()),
+            MetadataEntry::Key(s) => w!(Key, s.as_bytes()),
             MetadataEntry::User(k, v) => w!(User, k.as_bytes(), v.as_bytes()),
         }
     }
@@ -231,12 +261,33 @@ impl<I: Read> Iterator for BinaryDataParser<I> {
             ByteTag::Language => Ok(MetadataEntry::Language(next_str!())),
             ByteTag::License => Ok(MetadataEntry::License(next_str!())),
             ByteTag::Keyword => Ok(MetadataEntry::Keyword(next_str!())),
+            ByteTag::Key => Ok(MetadataEntry::Key(next_str!())),
             ByteTag::User => Ok(MetadataEntry::User(next_str!(), next_str!())),
 
             ByteTag::Date => next_value!()
                 .try_into()
                 .map(|b| MetadataEntry::Date(u64::from_be_bytes(b)))
                 .map_err(|e| MetadataError::InvalidLength(e.len() as u64)),
+
+            ByteTag::SearchBoost => next_value!()
+                .try_into()
+                .map(|b| MetadataEntry::SearchBoost(u32::from_be_bytes(b)))
+                .map_err(|e| MetadataError::InvalidLength(e.len() as u64)),
+
+            ByteTag::Volume => next_value!()
+                .try_into()
+                .map(|b| MetadataEntry::Volume(u32::from_be_bytes(b)))
+                .map_err(|e| MetadataError::InvalidLength(e.len() as u64)),
+
+            ByteTag::VolumeFile => {
+                let number = next_value!();
+                let file = next_str!();
+
+                number
+                    .try_into()
+                    .map(|b| MetadataEntry::VolumeFile(u32::from_be_bytes(b), file))
+                    .map_err(|e| MetadataError::InvalidLength(e.len() as u64))
+            }
         };
 
         Some(item)
@@ -248,6 +299,10 @@ fn write_read_metadata() {
     let entries = [
         MetadataEntry::Title("title".into()),
         MetadataEntry::Date(1234567890),
+        MetadataEntry::SearchBoost(150),
+        MetadataEntry::Volume(2),
+        MetadataEntry::VolumeFile(1, "book.1.theory".into()),
+        MetadataEntry::Key("std/collections".into()),
         MetadataEntry::User("key".into(), "value".into()),
     ];
 
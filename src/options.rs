@@ -0,0 +1,87 @@
+//! Module with the `BookOptions` implementation.
+
+use std::sync::Arc;
+
+use crate::persistence::datablock::cache::{
+    BlockCache, CacheHandle, Capacity, LruBlockCache, ShardedCache, DEFAULT_CACHE_BLOCKS,
+};
+
+/// Options to load a book with [`Book::load_with`](crate::Book::load_with).
+///
+/// ```
+/// use theory::BookOptions;
+///
+/// // Keep up to 8 MiB of decompressed data.
+/// let options = BookOptions::default().cache_bytes(8 << 20);
+/// ```
+#[derive(Clone)]
+pub struct BookOptions {
+    cache: CacheOption,
+}
+
+/// Cache for the data blocks.
+#[derive(Clone)]
+enum CacheOption {
+    Disabled,
+    Capacity(Capacity),
+    Shared(Arc<dyn BlockCache>),
+}
+
+impl Default for BookOptions {
+    fn default() -> Self {
+        BookOptions {
+            cache: CacheOption::Capacity(Capacity::Blocks(DEFAULT_CACHE_BLOCKS)),
+        }
+    }
+}
+
+impl BookOptions {
+    /// Keep up to `blocks` data blocks in the cache. Default is `16`.
+    pub fn cache_blocks(mut self, blocks: usize) -> Self {
+        self.cache = CacheOption::Capacity(Capacity::Blocks(blocks));
+        self
+    }
+
+    /// Keep up to `bytes` bytes of decompressed data in the cache.
+    ///
+    /// Blocks larger than this limit are never cached.
+    pub fn cache_bytes(mut self, bytes: usize) -> Self {
+        self.cache = CacheOption::Capacity(Capacity::Bytes(bytes));
+        self
+    }
+
+    /// Don't cache the data blocks.
+    ///
+    /// Blocks are read, and decompressed, every time they are used.
+    pub fn disable_cache(mut self) -> Self {
+        self.cache = CacheOption::Disabled;
+        self
+    }
+
+    /// Use a cache that can be shared with other books, so all of them use
+    /// the same memory budget.
+    ///
+    /// See [`LruBlockCache`](crate::cache::LruBlockCache) for an example.
+    pub fn shared_cache(mut self, cache: Arc<dyn BlockCache>) -> Self {
+        self.cache = CacheOption::Shared(cache);
+        self
+    }
+
+    /// Cache for a book used from a single thread.
+    pub(crate) fn cache_handle(&self) -> CacheHandle {
+        CacheHandle::new(match &self.cache {
+            CacheOption::Disabled => None,
+            CacheOption::Capacity(c) => Some(Arc::new(LruBlockCache::new(*c))),
+            CacheOption::Shared(cache) => Some(Arc::clone(cache)),
+        })
+    }
+
+    /// Cache for a book used from multiple threads.
+    pub(crate) fn sharded_cache_handle(&self) -> CacheHandle {
+        CacheHandle::new(match &self.cache {
+            CacheOption::Disabled => None,
+            CacheOption::Capacity(c) => Some(Arc::new(ShardedCache::new(*c))),
+            CacheOption::Shared(cache) => Some(Arc::clone(cache)),
+        })
+    }
+}
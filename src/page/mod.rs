@@ -1,15 +1,21 @@
 //! This module provides the [`Page`] type, which contains a single page in
 //! memory.
 
+mod page_ref;
 pub(crate) mod persistence;
 
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::num::NonZeroU32;
+use std::rc::Rc;
 
-use self::persistence::IndexEntry;
+use self::persistence::{IndexEntry, IndexEntryV1};
 use crate::persistence::datablock::DataBlocksReader;
-use crate::MetadataEntry;
+use crate::persistence::{PersistenceError, Version};
+use crate::{BlockCompression, MetadataEntry};
+pub use page_ref::PageRef;
 
 use endiannezz::Io;
 
@@ -37,11 +43,32 @@ pub enum PageError {
 
     #[error("Duplicated page identifier ({0})")]
     DuplicatedId(u32),
+
+    #[error("Invalid page key: {0}")]
+    InvalidKey(String),
+
+    #[error("Duplicated page key ({0})")]
+    DuplicatedKey(String),
+
+    #[error("Invalid data block: {0}")]
+    DataBlock(Box<PersistenceError>),
+
+    #[error("The page {0} can not be removed, because it has children")]
+    HasChildren(u32),
+}
+
+impl From<PersistenceError> for PageError {
+    fn from(e: PersistenceError) -> Self {
+        match e {
+            PersistenceError::Io(e) => PageError::Io(e),
+            e => PageError::DataBlock(Box::new(e)),
+        }
+    }
 }
 
 /// Page identifier.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
-pub struct PageId(NonZeroU32);
+pub struct PageId(pub(crate) NonZeroU32);
 
 impl From<PageId> for u32 {
     fn from(id: PageId) -> u32 {
@@ -66,6 +93,10 @@ pub struct Page {
     pub(crate) metadata: Vec<MetadataEntry>,
 
     pub(crate) content: Vec<u8>,
+
+    /// Compression method for the content, overriding the
+    /// [`CompressionPolicy`](crate::CompressionPolicy) of the writer.
+    pub(crate) compression: Option<BlockCompression>,
 }
 
 impl Page {
@@ -75,6 +106,7 @@ impl Page {
             parent_id: None,
             metadata: vec![MetadataEntry::Title(title)],
             content: Vec::new(),
+            compression: None,
         }
     }
 
@@ -101,11 +133,52 @@ impl Page {
         self
     }
 
+    /// Set a stable key for this page, like a slug (`std/collections/hash_map`)
+    /// or a URL path, to find it with
+    /// [`Book::get_page_by_key`](crate::Book::get_page_by_key).
+    ///
+    /// Unlike the identifiers, keys don't change when pages are added or
+    /// reordered. They are stored as [`MetadataEntry::Key`] entries, and
+    /// writing a book with a key used by multiple pages fails with
+    /// [`PageError::DuplicatedKey`].
+    ///
+    /// Any previous key of the page is replaced.
+    pub fn set_key(&mut self, key: impl Into<String>) -> &mut Page {
+        self.metadata
+            .retain(|entry| !matches!(entry, MetadataEntry::Key(_)));
+        self.metadata.push(MetadataEntry::Key(key.into()));
+        self
+    }
+
+    /// Set the compression method for the content of this page, instead of
+    /// the one selected by the [`CompressionPolicy`](crate::CompressionPolicy)
+    /// of the writer.
+    ///
+    /// The method is not stored in the book, so pages loaded from a book
+    /// don't have it.
+    pub fn set_compression(&mut self, compression: BlockCompression) -> &mut Page {
+        self.compression = Some(compression);
+        self
+    }
+
     /// Return the parent of this page.
     pub fn parent(&self) -> Option<PageId> {
         self.parent_id.map(PageId)
     }
 
+    /// Return the key of this page, if any.
+    pub fn key(&self) -> Option<&str> {
+        self.keys().next()
+    }
+
+    /// Return all keys of this page.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &str> {
+        self.metadata.iter().filter_map(|entry| match entry {
+            MetadataEntry::Key(key) => Some(key.as_str()),
+            _ => None,
+        })
+    }
+
     /// Return the content of this page.
     pub fn content(&self) -> &[u8] {
         &self.content
@@ -120,11 +193,19 @@ impl Page {
 /// Page index stored in the `page_pos` position.
 pub(crate) struct Index {
     entries: BTreeMap<PageId, IndexEntry>,
+
+    /// Keys of the pages, sorted to find them with a binary search.
+    keys: Vec<(String, PageId)>,
 }
 
 impl Index {
     /// Load the page entries located at `position`.
-    pub(crate) fn new<R>(mut input: R, num_pages: usize, position: u64) -> Result<Self, PageError>
+    pub(crate) fn new<R>(
+        mut input: R,
+        num_pages: usize,
+        position: u64,
+        version: Version,
+    ) -> Result<Self, PageError>
     where
         R: Read + Seek,
     {
@@ -132,7 +213,10 @@ impl Index {
         input.seek(SeekFrom::Start(position))?;
 
         for _ in 0..num_pages {
-            let ie = IndexEntry::read(&mut input)?;
+            let ie = match version {
+                Version::V1 => IndexEntryV1::read(&mut input)?.into(),
+                Version::V2 => IndexEntry::read(&mut input)?,
+            };
 
             let page_id = match NonZeroU32::new(ie.id) {
                 Some(id) => id,
@@ -144,7 +228,44 @@ impl Index {
             }
         }
 
-        Ok(Index { entries })
+        Ok(Index {
+            entries,
+            keys: Vec::new(),
+        })
+    }
+
+    /// Set the keys of the pages, loaded from the key table.
+    pub(crate) fn set_keys(&mut self, mut keys: Vec<(String, PageId)>) -> Result<(), PageError> {
+        // The table is written in order, but it is sorted again in case the
+        // book was created by another program.
+        keys.sort_unstable();
+
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(PageError::DuplicatedKey(pair[0].0.clone()));
+        }
+
+        self.keys = keys;
+        Ok(())
+    }
+
+    /// Get the keys of the pages, sorted.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = (&str, PageId)> {
+        self.keys.iter().map(|(key, id)| (key.as_str(), *id))
+    }
+
+    /// Get the identifier of the page with a key.
+    pub(crate) fn id_by_key(&self, key: &str) -> Result<PageId, PageError> {
+        self.keys
+            .binary_search_by(|(k, _)| k.as_str().cmp(key))
+            .map(|position| self.keys[position].1)
+            .map_err(|_| PageError::InvalidKey(key.to_owned()))
+    }
+
+    /// Get the index entry of a page.
+    pub(crate) fn entry(&self, page_id: PageId) -> Result<&IndexEntry, PageError> {
+        self.entries
+            .get(&page_id)
+            .ok_or(PageError::InvalidId(page_id.0.get()))
     }
 
     /// Get an iterator to get all pages in the book.
@@ -160,22 +281,80 @@ impl Index {
             .map(move |entry| persistence::build_page(entry, db_reader))
     }
 
+    /// Get handles to all pages in the book, without reading their data.
+    pub(crate) fn page_refs<'a, R>(
+        &'a self,
+        db_reader: &'a mut DataBlocksReader<R>,
+    ) -> impl Iterator<Item = PageRef<'a, R>> + 'a
+    where
+        R: Read + Seek + 'a,
+    {
+        let db_reader = Rc::new(RefCell::new(db_reader));
+        self.entries
+            .iter()
+            .map(move |(page_id, entry)| PageRef::new(*page_id, entry, Rc::clone(&db_reader)))
+    }
+
+    /// Get a handle to a single page, without reading its data.
+    pub(crate) fn page_ref<'a, R>(
+        &'a self,
+        db_reader: &'a mut DataBlocksReader<R>,
+        page_id: PageId,
+    ) -> Result<PageRef<'a, R>, PageError>
+    where
+        R: Read + Seek,
+    {
+        let entry = self.entry(page_id)?;
+
+        Ok(PageRef::new(
+            page_id,
+            entry,
+            Rc::new(RefCell::new(db_reader)),
+        ))
+    }
+
     /// Get a single page.
     pub(crate) fn get_by_id<R>(
-        &mut self,
+        &self,
         db_reader: &mut DataBlocksReader<R>,
         page_id: PageId,
     ) -> Result<Page, PageError>
     where
         R: Read + Seek,
     {
-        let entry = match self.entries.get(&page_id) {
-            Some(e) => e,
-            None => return Err(PageError::InvalidId(page_id.0.get())),
-        };
+        let entry = self.entry(page_id)?;
 
         persistence::build_page(entry, db_reader)
     }
+
+    /// Get the content of a single page, without its metadata.
+    pub(crate) fn get_content<R>(
+        &self,
+        db_reader: &mut DataBlocksReader<R>,
+        page_id: PageId,
+    ) -> Result<Vec<u8>, PageError>
+    where
+        R: Read + Seek,
+    {
+        let entry = self.entry(page_id)?;
+
+        persistence::read_content(entry, db_reader)
+    }
+
+    /// Get the content of a single page, borrowed from the input if its data
+    /// block is not compressed.
+    pub(crate) fn get_content_borrowed<'a, T>(
+        &self,
+        db_reader: &'a mut DataBlocksReader<Cursor<T>>,
+        page_id: PageId,
+    ) -> Result<Cow<'a, [u8]>, PageError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let entry = self.entry(page_id)?;
+
+        persistence::read_content_borrowed(entry, db_reader)
+    }
 }
 
 impl<'a> IntoIterator for &'a Index {
@@ -0,0 +1,138 @@
+//! Module with the [`PageRef`] type, to read the data of a page only when it
+//! is needed.
+
+use std::cell::RefCell;
+use std::io::{Read, Seek};
+use std::rc::Rc;
+
+use super::persistence::{self, IndexEntry};
+use super::{PageError, PageId};
+use crate::persistence::datablock::DataBlocksReader;
+use crate::MetadataEntry;
+
+/// Reader shared by all the handles created from the same book.
+pub(crate) type SharedReader<'a, I> = Rc<RefCell<&'a mut DataBlocksReader<I>>>;
+
+/// A handle to a page in a [`Book`](crate::Book), created from its index.
+///
+/// The identifier and the parent of the page are available without reading
+/// any data block. The metadata of every page is stored in a block shared by
+/// all pages, so [`metadata`](Self::metadata) and [`title`](Self::title)
+/// don't read the content of the page. The content is read only when
+/// [`content`](Self::content) is called.
+///
+/// ```
+/// # use theory::Book;
+/// # use std::io::Cursor;
+/// let mut builder = Book::builder();
+/// let parent = builder.new_page("Parent").set_content("A").id();
+/// builder.new_page("Child").set_content("B").set_parent(parent);
+///
+/// let mut buffer = Vec::new();
+/// builder.dump(Cursor::new(&mut buffer)).unwrap();
+///
+/// let mut book = Book::load(Cursor::new(buffer)).unwrap();
+/// for page in book.page_refs() {
+///     let title = page.title().unwrap();
+///     if page.parent() == Some(parent) {
+///         assert_eq!(title, "Child");
+///         assert_eq!(page.content().unwrap(), b"B");
+///     }
+/// }
+/// ```
+pub struct PageRef<'a, I> {
+    page_id: PageId,
+    entry: &'a IndexEntry,
+    data_blocks: SharedReader<'a, I>,
+}
+
+impl<'a, I: Read + Seek> PageRef<'a, I> {
+    pub(crate) fn new(
+        page_id: PageId,
+        entry: &'a IndexEntry,
+        data_blocks: SharedReader<'a, I>,
+    ) -> Self {
+        PageRef {
+            page_id,
+            entry,
+            data_blocks,
+        }
+    }
+
+    /// Return the page identifier.
+    pub fn id(&self) -> PageId {
+        self.page_id
+    }
+
+    /// Return the identifier of the parent page, if any.
+    pub fn parent(&self) -> Option<PageId> {
+        self.entry.parent_id()
+    }
+
+    /// Read the metadata entries of the page.
+    pub fn metadata(&self) -> Result<Vec<MetadataEntry>, PageError> {
+        persistence::read_metadata(self.entry, &mut self.data_blocks.borrow_mut())
+    }
+
+    /// Read the title of the page, or an empty string if it has no title.
+    pub fn title(&self) -> Result<String, PageError> {
+        self.entry
+            .get_page_title(&mut self.data_blocks.borrow_mut())
+    }
+
+    /// Read the content of the page.
+    pub fn content(&self) -> Result<Vec<u8>, PageError> {
+        persistence::read_content(self.entry, &mut self.data_blocks.borrow_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Book, BookOptions, MetadataEntry};
+    use std::io::Cursor;
+
+    #[test]
+    fn read_metadata_without_content() {
+        let mut builder = Book::builder();
+        builder.set_block_size(16);
+
+        let mut ids = Vec::new();
+        for n in 0..5 {
+            let content = format!("content of the page number {}", n);
+            let page = builder.new_page(format!("P{}", n)).set_content(content);
+            page.add_metadata(MetadataEntry::Language("en".into()));
+            ids.push(page.id());
+        }
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        let options = BookOptions::default().cache_blocks(16);
+        let mut book = Book::load_with(Cursor::new(buffer), &options).unwrap();
+
+        let titles: Vec<_> = book.page_refs().map(|p| p.title().unwrap()).collect();
+        assert_eq!(titles, ["P0", "P1", "P2", "P3", "P4"]);
+
+        // Only the metadata block is read.
+        assert_eq!(book.cache_stats().misses(), 1);
+
+        let page = book.page_ref(ids[3]).unwrap();
+        assert_eq!(page.id(), ids[3]);
+        assert_eq!(page.parent(), None);
+        assert_eq!(
+            page.metadata().unwrap(),
+            [
+                MetadataEntry::Title("P3".into()),
+                MetadataEntry::Language("en".into())
+            ]
+        );
+
+        assert_eq!(book.cache_stats().misses(), 1);
+
+        let page = book.page_ref(ids[3]).unwrap();
+        assert_eq!(page.content().unwrap(), b"content of the page number 3");
+        assert_eq!(book.cache_stats().misses(), 2);
+
+        assert!(book.page_ref(crate::PageId::force_value(100)).is_err());
+    }
+}
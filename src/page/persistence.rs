@@ -14,15 +14,35 @@
 //! 5. Data black with the page content.
 //! 6. Offset in the data block for the page content.
 //!
-//! Each number is encoded as a 4 bytes, big-endian, unsigned integer. The total
-//! size of each entry is `24` bytes.
+//! Numbers are encoded as big-endian, unsigned integers. In version 2 of the
+//! file format, identifiers of pages use 4 bytes, and block identifiers and
+//! offsets use 8 bytes. The total size of each entry is `40` bytes.
+//!
+//! In version 1, all numbers use 4 bytes, and each entry is `24` bytes.
+//!
+//! # Key Table
+//!
+//! In version 2, the keys of the pages (from the `Key` metadata entries) are
+//! stored in a table, sorted by their bytes, so a page can be found with a
+//! binary search. Each entry is the length of the key (LEB128), the key
+//! (UTF-8), and the page identifier (`u32`, big-endian).
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{self, Cursor, Read, Seek, Write};
 use std::num::NonZeroU32;
 
+use sha2::{Digest, Sha256};
+
 use crate::page::PageError;
-use crate::persistence::datablock::{DataBlocksReader, DataBlocksWriter};
-use crate::{metadata, page, BlockCompression, MetadataEntry, Page};
+use crate::persistence::datablock::{DataBlocksReader, DataBlocksWriter, RawBlock};
+use crate::{metadata, page, BlockCompression, MetadataEntry, Page, WriteStats};
+
+#[cfg(feature = "async")]
+use crate::persistence::datablock::AsyncDataBlocksReader;
+
+#[cfg(feature = "async")]
+use futures_util::io::{AsyncRead, AsyncSeek};
 
 use endiannezz::Io;
 
@@ -40,8 +60,15 @@ macro_rules! to_u32 {
     };
 }
 
+/// Size, in bytes, of every entry in the page index.
+pub(crate) const INDEX_ENTRY_SIZE: usize = 40;
+
+/// Size, in bytes, of every entry in the page index of version 1 files.
+#[cfg(feature = "async")]
+pub(crate) const INDEX_ENTRY_V1_SIZE: usize = 24;
+
 /// A single entry in the page index.
-#[derive(Io, Debug)]
+#[derive(Io, Debug, Clone)]
 #[endian(big)]
 pub(crate) struct IndexEntry {
     /// Page identifier.
@@ -51,19 +78,59 @@ pub(crate) struct IndexEntry {
     pub(super) parent_id: u32,
 
     /// Data block with the metadata.
-    pub(super) metadata_block_id: u32,
+    pub(super) metadata_block_id: u64,
 
     /// Offset in the data block for the metadata.
-    pub(super) metadata_block_offset: u32,
+    pub(super) metadata_block_offset: u64,
 
     /// Data black with the page content.
-    pub(super) content_block_id: u32,
+    pub(super) content_block_id: u64,
 
     /// Offset in the data block for the page content.
-    pub(super) content_block_offset: u32,
+    pub(super) content_block_offset: u64,
+}
+
+/// Entry in the page index of version 1 files.
+#[derive(Io)]
+#[endian(big)]
+pub(super) struct IndexEntryV1 {
+    id: u32,
+    parent_id: u32,
+    metadata_block_id: u32,
+    metadata_block_offset: u32,
+    content_block_id: u32,
+    content_block_offset: u32,
+}
+
+impl From<IndexEntryV1> for IndexEntry {
+    fn from(entry: IndexEntryV1) -> Self {
+        IndexEntry {
+            id: entry.id,
+            parent_id: entry.parent_id,
+            metadata_block_id: entry.metadata_block_id.into(),
+            metadata_block_offset: entry.metadata_block_offset.into(),
+            content_block_id: entry.content_block_id.into(),
+            content_block_offset: entry.content_block_offset.into(),
+        }
+    }
 }
 
 impl IndexEntry {
+    /// Data blocks with the metadata and the content of the page.
+    pub(crate) fn block_ids(&self) -> [u64; 2] {
+        [self.metadata_block_id, self.content_block_id]
+    }
+
+    /// Data block and offset of the page content.
+    pub(crate) fn content_location(&self) -> (u64, u64) {
+        (self.content_block_id, self.content_block_offset)
+    }
+
+    /// Identifier of the page.
+    pub(crate) fn page_id(&self) -> u32 {
+        self.id
+    }
+
     pub(crate) fn parent_id(&self) -> Option<page::PageId> {
         NonZeroU32::new(self.parent_id).map(page::PageId)
     }
@@ -76,102 +143,338 @@ impl IndexEntry {
         I: Read + Seek,
     {
         let title = db_reader.with_block(
-            self.metadata_block_id.into(),
+            self.metadata_block_id,
             self.metadata_block_offset,
-            |bytes: &[u8]| -> Result<Option<String>, PageError> {
-                let input_len = bytes.len() as u64;
-                let cursor = Cursor::new(bytes);
-
-                let entries = metadata::load(cursor, input_len).flatten();
-                for entry in entries {
-                    if let MetadataEntry::Title(title) = entry {
-                        return Ok(Some(title));
-                    }
-                }
+            find_title,
+        )?;
 
-                Ok(None)
-            },
-        );
+        Ok(title.unwrap_or_default())
+    }
 
-        Ok(title??.unwrap_or_default())
+    #[cfg(feature = "async")]
+    pub(crate) async fn get_page_title_async<I>(
+        &self,
+        db_reader: &mut AsyncDataBlocksReader<I>,
+    ) -> Result<String, PageError>
+    where
+        I: AsyncRead + AsyncSeek + Unpin,
+    {
+        let title = db_reader
+            .with_block(
+                self.metadata_block_id,
+                self.metadata_block_offset,
+                find_title,
+            )
+            .await?;
+
+        Ok(title.unwrap_or_default())
     }
 }
 
-/// Write the page table and the data block in the output stream.
+/// Find the title in the metadata of a page.
+fn find_title(bytes: &[u8]) -> Option<String> {
+    let entries = metadata::load(Cursor::new(bytes), bytes.len() as u64).flatten();
+    entries.into_iter().find_map(|entry| match entry {
+        MetadataEntry::Title(title) => Some(title),
+        _ => None,
+    })
+}
+
+/// Writer for the page table and the data blocks.
+///
+/// The content of every page is written to the output stream when the page
+/// is added. To reduce the seek operations, the page index is kept in memory
+/// until [`finish`](Self::finish) is called.
 ///
-/// On success, returns the offset to the page index.
-pub(crate) fn dump_pages<'a, O, P, I>(
-    output: O,
-    pages: I,
+/// All metadata is written in the same data block, after the content of the
+/// pages.
+///
+/// If the content of a page is identical to the content of a previous page,
+/// it is not written again, and both entries in the page index point to the
+/// same location. Contents are compared by their length and their SHA-256
+/// digest, so they are not kept in memory.
+pub(crate) struct PagesWriter<O: Write> {
+    db_writer: DataBlocksWriter<O>,
+
+    /// Compression method for the metadata block.
     compression: BlockCompression,
-) -> io::Result<u64>
-where
-    O: Write + Seek,
-    P: Into<&'a Page>,
-    I: IntoIterator<Item = P>,
-{
-    // To reduce the seek operations, the page index is written in memory, while
-    // the data blocks are written to the stream.
-    //
-    // All metadata is written in the same data block.
 
-    let pages = pages.into_iter();
+    metadata_buf: Vec<u8>,
+
+    page_index: Vec<IndexEntry>,
 
-    let mut metadata_buf = Vec::with_capacity(4 * 1024);
-    let mut page_index = Vec::with_capacity(pages.size_hint().0);
+    /// Entries of pages already stored in the output.
+    existing: Vec<IndexEntry>,
 
-    let mut db_writer = DataBlocksWriter::new(output, compression);
+    /// Location (block index and offset) of every content written.
+    contents: HashMap<ContentKey, (u64, u64)>,
 
-    for page in pages.map(|e| e.into()) {
-        // Content is written directly to the output stream.
+    stats: WriteStats,
+}
+
+/// Key to find identical contents.
+///
+/// A cryptographic digest is used, so different contents can't be crafted
+/// to share the same location.
+#[derive(PartialEq, Eq, Hash)]
+struct ContentKey {
+    len: usize,
+    sha256: [u8; 32],
+}
+
+impl ContentKey {
+    fn new(content: &[u8]) -> Self {
+        ContentKey {
+            len: content.len(),
+            sha256: Sha256::digest(content).into(),
+        }
+    }
+}
+
+impl<O: Write + Seek> PagesWriter<O> {
+    pub(crate) fn new(output: O, compression: BlockCompression) -> Self {
+        PagesWriter {
+            db_writer: DataBlocksWriter::new(output, compression),
+            compression,
+            metadata_buf: Vec::with_capacity(4 * 1024),
+            page_index: Vec::new(),
+            existing: Vec::new(),
+            contents: HashMap::new(),
+            stats: WriteStats::default(),
+        }
+    }
+
+    /// Number of pages added to the writer.
+    pub(crate) fn num_pages(&self) -> usize {
+        self.page_index.len() + self.existing.len()
+    }
+
+    /// Statistics of the pages written.
+    pub(crate) fn stats(&self) -> WriteStats {
+        self.stats
+    }
+
+    /// Set the compression method for the metadata of the pages.
+    pub(crate) fn set_compression(&mut self, compression: BlockCompression) {
+        self.compression = compression;
+    }
+
+    /// Write a Zstandard dictionary, used by the blocks created after this
+    /// call.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn write_zstd_dictionary(&mut self, dictionary: Vec<u8>) -> io::Result<()> {
+        self.db_writer.write_zstd_dictionary(dictionary)
+    }
+
+    /// Set the number of threads to compress the data blocks.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn set_threads(&mut self, threads: usize) {
+        self.db_writer.set_threads(threads);
+    }
+
+    /// Set the target size of the data blocks with the content of the pages.
+    pub(crate) fn set_block_size(&mut self, block_size: u64) {
+        self.db_writer.set_block_size(block_size);
+    }
+
+    /// Start a group of pages that should be stored in the same data block.
+    /// `size` is the total size of their content.
+    pub(crate) fn begin_group(&mut self, size: u64) -> io::Result<()> {
+        self.db_writer.begin_group(size)
+    }
+
+    /// Write the content of a page, and keep its metadata and its entry for
+    /// the page index.
+    ///
+    /// Pages with different compression methods are stored in different
+    /// data blocks.
+    pub(crate) fn add(&mut self, page: &Page, compression: BlockCompression) -> io::Result<()> {
+        // Content is written directly to the output stream, unless it was
+        // already written for a previous page. Empty pages are not
+        // deduplicated.
         let content = &page.content;
-        let mut fragment = db_writer.fragment(content.len() as u64)?;
+        let key = ContentKey::new(content);
+
+        let (content_block, content_block_offset) = match self.contents.get(&key) {
+            Some(&location) if !content.is_empty() => {
+                self.stats.duplicated_pages += 1;
+                self.stats.bytes_saved += content.len() as u64;
+                location
+            }
+
+            _ => {
+                self.db_writer.set_compression(compression);
+                let mut fragment = self.db_writer.fragment(content.len() as u64)?;
 
-        leb128::write::unsigned(&mut fragment, content.len() as u64)?;
-        fragment.write_all(content)?;
-        let loc = fragment.location();
+                leb128::write::unsigned(&mut fragment, content.len() as u64)?;
+                fragment.write_all(content)?;
+                let loc = fragment.location();
+
+                self.contents.insert(key, (loc.block, loc.offset));
+                (loc.block, loc.offset)
+            }
+        };
+
+        self.add_at(page, content_block, content_block_offset)
+    }
 
-        let content_block_id = to_u32!(loc.block_id);
-        let content_block_offset = to_u32!(loc.offset);
+    /// Add a page with its content already stored in the output, at
+    /// `offset` in the block with the index `block`.
+    pub(crate) fn add_at(&mut self, page: &Page, block: u64, offset: u64) -> io::Result<()> {
+        self.stats.num_pages += 1;
 
         // Metadata
-        let metadata_block_offset = to_u32!(metadata_buf.len());
-        metadata::dump(&mut metadata_buf, &page.metadata)?;
+        let metadata_block_offset = self.metadata_buf.len() as u64;
+        metadata::dump(&mut self.metadata_buf, &page.metadata)?;
 
         // Page index.
         //
         // `metadata_block_id` is updated after `metadata_buf` is written.
-        page_index.push(IndexEntry {
+        // `content_block_id` contains the index of the block in the writer,
+        // and it is replaced by its identifier when all blocks are written.
+        self.page_index.push(IndexEntry {
             id: to_u32!(page.id),
             parent_id: page.parent_id.map(|id| id.get()).unwrap_or(0),
             metadata_block_id: !0,
             metadata_block_offset,
-            content_block_id,
-            content_block_offset,
+            content_block_id: block,
+            content_block_offset: offset,
         });
+
+        Ok(())
     }
 
-    // Send the metadata to the output.
-    let mut fragment_metadata = db_writer.fragment(u64::MAX)?;
-    fragment_metadata.write_all(&metadata_buf)?;
-    let loc = fragment_metadata.location();
+    /// Copy a data block from another book, and return its index.
+    pub(crate) fn write_raw(&mut self, block: RawBlock) -> io::Result<u64> {
+        self.db_writer.write_raw(block)
+    }
 
-    let metadata_block_id = to_u32!(loc.block_id);
+    /// Add the entry of a page already stored in the output, so it is
+    /// included in the page index.
+    pub(crate) fn add_existing(&mut self, entry: IndexEntry) {
+        self.existing.push(entry);
+    }
 
-    let mut output = db_writer.finish()?;
+    /// Write the metadata of the pages and the page index.
+    ///
+    /// On success, returns the output stream, the offset to the page index,
+    /// and the CRC-32C checksum of the index.
+    pub(crate) fn finish(self) -> io::Result<(O, u64, u32)> {
+        let PagesWriter {
+            mut db_writer,
+            compression,
+            metadata_buf,
+            page_index,
+            existing,
+            ..
+        } = self;
+
+        // Send the metadata to the output.
+        db_writer.set_compression(compression);
+        let mut fragment_metadata = db_writer.fragment(u64::MAX)?;
+        fragment_metadata.write_all(&metadata_buf)?;
+        let loc = fragment_metadata.location();
+
+        // The metadata is in the last block, so all blocks with content are
+        // written before its identifier is known.
+        let metadata_block_id = db_writer.block_id(loc.block)?;
+
+        let num_entries = page_index.len() + existing.len();
+        let mut index_bytes = Vec::with_capacity(num_entries * INDEX_ENTRY_SIZE);
+        for mut page in page_index {
+            page.metadata_block_id = metadata_block_id;
+            page.content_block_id = db_writer.block_id(page.content_block_id)?;
+            page.write(&mut index_bytes)?;
+        }
 
-    // Write the index.
-    let page_index_position = output.stream_position()?;
-    for mut page in page_index {
-        page.metadata_block_id = metadata_block_id;
-        page.write(&mut output)?;
+        for page in existing {
+            page.write(&mut index_bytes)?;
+        }
+
+        let mut output = db_writer.finish()?;
+
+        // Write the index.
+        let page_index_position = output.stream_position()?;
+        output.write_all(&index_bytes)?;
+
+        Ok((output, page_index_position, crc32c::crc32c(&index_bytes)))
     }
+}
+
+/// Extract the content of a page from the data block, starting at the offset
+/// of the page.
+fn content_bytes(bytes: &[u8]) -> Result<&[u8], PageError> {
+    let mut cursor = Cursor::new(bytes);
+
+    let len = leb128::read::unsigned(&mut cursor)?;
+    let position = cursor.position() as usize;
+
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| bytes.get(position..position.checked_add(len)?))
+        .ok_or(PageError::InvalidLength(len))
+}
+
+/// Read the content of a page.
+pub(super) fn read_content<R>(
+    entry: &IndexEntry,
+    db_reader: &mut DataBlocksReader<R>,
+) -> Result<Vec<u8>, PageError>
+where
+    R: Read + Seek,
+{
+    db_reader.with_block(
+        entry.content_block_id,
+        entry.content_block_offset,
+        |bytes: &[u8]| content_bytes(bytes).map(<[u8]>::to_vec),
+    )?
+}
+
+/// Read the length of the content of a page, without copying it.
+pub(crate) fn read_content_len<R>(
+    entry: &IndexEntry,
+    db_reader: &mut DataBlocksReader<R>,
+) -> Result<u64, PageError>
+where
+    R: Read + Seek,
+{
+    db_reader.with_block(
+        entry.content_block_id,
+        entry.content_block_offset,
+        |bytes: &[u8]| content_bytes(bytes).map(|content| content.len() as u64),
+    )?
+}
+
+/// Read the content of a page, borrowing it from the input if its data block
+/// is not compressed.
+pub(super) fn read_content_borrowed<'a, T>(
+    entry: &IndexEntry,
+    db_reader: &'a mut DataBlocksReader<Cursor<T>>,
+) -> Result<Cow<'a, [u8]>, PageError>
+where
+    T: AsRef<[u8]>,
+{
+    let range = match db_reader.borrowable_range(entry.content_block_id)? {
+        Some(range) => range,
+        None => return read_content(entry, db_reader).map(Cow::Owned),
+    };
 
-    Ok(page_index_position)
+    let block = &db_reader.input_bytes()[range];
+    let bytes = usize::try_from(entry.content_block_offset)
+        .ok()
+        .and_then(|offset| block.get(offset..))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "offset is beyond end of the block",
+            )
+        })?;
+
+    content_bytes(bytes).map(Cow::Borrowed)
 }
 
 /// Build a `Page` value using the data from a stream.
-pub(super) fn build_page<R>(
+pub(crate) fn build_page<R>(
     entry: &IndexEntry,
     db_reader: &mut DataBlocksReader<R>,
 ) -> Result<Page, PageError>
@@ -179,41 +482,112 @@ where
     R: Read + Seek,
 {
     // Page content.
-    let content = db_reader.with_block(
-        entry.content_block_id.into(),
-        entry.content_block_offset,
-        |bytes: &[u8]| -> Result<_, PageError> {
-            let mut cursor = Cursor::new(bytes);
+    let content = read_content(entry, db_reader)?;
 
-            let len = leb128::read::unsigned(&mut cursor)? as usize;
-            let position = cursor.position() as usize;
-            let bytes = match bytes.get(position..len + position) {
-                Some(bytes) => bytes,
-                None => return Err(PageError::InvalidLength(len as u64)),
-            };
+    // Page metadata.
+    let metadata = read_metadata(entry, db_reader)?;
 
-            Ok(bytes.to_owned())
-        },
-    )??;
+    new_page(entry, metadata, content)
+}
 
-    // Page metadata.
-    let metadata = db_reader.with_block(
-        entry.metadata_block_id.into(),
+/// Read the metadata of a page, without its content.
+pub(super) fn read_metadata<R>(
+    entry: &IndexEntry,
+    db_reader: &mut DataBlocksReader<R>,
+) -> Result<Vec<MetadataEntry>, PageError>
+where
+    R: Read + Seek,
+{
+    db_reader.with_block(
+        entry.metadata_block_id,
         entry.metadata_block_offset,
-        |bytes: &[u8]| -> Result<_, PageError> {
-            crate::metadata::load(io::Cursor::new(bytes), bytes.len() as u64)
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|e| PageError::InvalidMetadata(e.to_string()))
-        },
-    )??;
-
-    // Final page.
-    let page = Page {
+        parse_metadata,
+    )?
+}
+
+/// Build a `Page` value using the data from an asynchronous stream.
+#[cfg(feature = "async")]
+pub(crate) async fn build_page_async<R>(
+    entry: &IndexEntry,
+    db_reader: &mut AsyncDataBlocksReader<R>,
+) -> Result<Page, PageError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let content = db_reader
+        .with_block(
+            entry.content_block_id,
+            entry.content_block_offset,
+            |bytes| content_bytes(bytes).map(<[u8]>::to_vec),
+        )
+        .await??;
+
+    let metadata = db_reader
+        .with_block(
+            entry.metadata_block_id,
+            entry.metadata_block_offset,
+            parse_metadata,
+        )
+        .await??;
+
+    new_page(entry, metadata, content)
+}
+
+/// Parse the metadata of a page.
+fn parse_metadata(bytes: &[u8]) -> Result<Vec<MetadataEntry>, PageError> {
+    metadata::load(Cursor::new(bytes), bytes.len() as u64)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| PageError::InvalidMetadata(e.to_string()))
+}
+
+/// Build a `Page` value from its index entry.
+fn new_page(
+    entry: &IndexEntry,
+    metadata: Vec<MetadataEntry>,
+    content: Vec<u8>,
+) -> Result<Page, PageError> {
+    Ok(Page {
         id: NonZeroU32::new(entry.id).ok_or(PageError::InvalidId(0))?,
         parent_id: NonZeroU32::new(entry.parent_id),
         metadata,
         content,
-    };
+        compression: None,
+    })
+}
+
+/// Write the key table, with the keys sorted by their bytes.
+pub(crate) fn write_keys(keys: &BTreeMap<String, u32>) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for (key, page_id) in keys {
+        leb128::write::unsigned(&mut bytes, key.len() as u64)?;
+        bytes.write_all(key.as_bytes())?;
+        bytes.write_all(&page_id.to_be_bytes())?;
+    }
+
+    Ok(bytes)
+}
+
+/// Parse the key table.
+pub(crate) fn read_keys(bytes: &[u8]) -> Result<Vec<(String, page::PageId)>, PageError> {
+    let mut cursor = Cursor::new(bytes);
+    let mut keys = Vec::new();
+
+    while (cursor.position() as usize) < bytes.len() {
+        let len = leb128::read::unsigned(&mut cursor)?;
+        if len > bytes.len() as u64 {
+            return Err(PageError::InvalidLength(len));
+        }
+
+        let mut key = vec![0; len as usize];
+        cursor.read_exact(&mut key)?;
+
+        let mut page_id = [0; 4];
+        cursor.read_exact(&mut page_id)?;
+        let page_id = u32::from_be_bytes(page_id);
+
+        let page_id = NonZeroU32::new(page_id).ok_or(PageError::InvalidId(page_id))?;
+        keys.push((String::from_utf8(key)?, page::PageId(page_id)));
+    }
 
-    Ok(page)
+    Ok(keys)
 }
@@ -0,0 +1,130 @@
+//! Reader for data blocks in an asynchronous stream.
+//!
+//! It is the same of [`DataBlocksReader`](super::DataBlocksReader), but the
+//! stream is read with [`AsyncRead`] and [`AsyncSeek`].
+
+use std::io::SeekFrom;
+use std::sync::Arc;
+
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+use super::cache::{CacheHandle, CacheStats};
+use super::reader::{
+    apply_at, block_offset, check_dictionary, decode_block, dictionary_id, verify_checksum,
+    BlockHeader,
+};
+use crate::persistence::{PersistenceError, Version};
+
+pub(crate) struct AsyncDataBlocksReader<S> {
+    stream: S,
+
+    stream_len: u64,
+
+    version: Version,
+
+    cache: CacheHandle,
+
+    /// Last dictionary loaded to decompress blocks.
+    dictionary: Option<(u64, Arc<[u8]>)>,
+}
+
+impl<S: AsyncRead + AsyncSeek + Unpin> AsyncDataBlocksReader<S> {
+    pub(crate) async fn new(
+        mut stream: S,
+        version: Version,
+        cache: CacheHandle,
+    ) -> Result<Self, PersistenceError> {
+        let stream_len = stream.seek(SeekFrom::End(0)).await?;
+
+        Ok(AsyncDataBlocksReader {
+            stream,
+            stream_len,
+            version,
+            cache,
+            dictionary: None,
+        })
+    }
+
+    /// Return the statistics of the block cache.
+    pub(crate) fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Get a block from its identifier.
+    ///
+    /// See [`DataBlocksReader::with_block`](super::DataBlocksReader::with_block)
+    /// for more details.
+    pub(crate) async fn with_block<F, T, O>(
+        &mut self,
+        block_id: u64,
+        offset: O,
+        f: F,
+    ) -> Result<T, PersistenceError>
+    where
+        F: FnOnce(&[u8]) -> T,
+        O: TryInto<usize>,
+    {
+        let offset = block_offset(offset)?;
+
+        if let Some(data) = self.cache.get(block_id) {
+            return apply_at(&data, offset, f);
+        }
+
+        let data = self.read_block(block_id).await?;
+
+        if !self.cache.is_enabled() {
+            return apply_at(&data, offset, f);
+        }
+
+        let data: Arc<[u8]> = data.into();
+        self.cache.insert(block_id, Arc::clone(&data));
+        apply_at(&data, offset, f)
+    }
+
+    /// Read and decompress the data of a block.
+    async fn read_block(&mut self, block_id: u64) -> Result<Vec<u8>, PersistenceError> {
+        let (header, raw) = self.read_raw(block_id).await?;
+
+        let dictionary = match dictionary_id(&header, &raw)? {
+            Some(id) => Some(self.dictionary(id).await?),
+            None => None,
+        };
+
+        decode_block(&header, raw, &self.cache, dictionary.as_deref())
+    }
+
+    /// Load the dictionary stored in a block.
+    async fn dictionary(&mut self, block_id: u64) -> Result<Arc<[u8]>, PersistenceError> {
+        if let Some((id, bytes)) = &self.dictionary {
+            if *id == block_id {
+                return Ok(Arc::clone(bytes));
+            }
+        }
+
+        let (header, raw) = self.read_raw(block_id).await?;
+        check_dictionary(&header)?;
+
+        let bytes: Arc<[u8]> = raw.into();
+        self.dictionary = Some((block_id, Arc::clone(&bytes)));
+        Ok(bytes)
+    }
+
+    /// Read the data of a block, and verify its checksum.
+    async fn read_raw(
+        &mut self,
+        block_id: u64,
+    ) -> Result<(BlockHeader, Vec<u8>), PersistenceError> {
+        self.stream.seek(SeekFrom::Start(block_id)).await?;
+
+        let mut bytes = vec![0; BlockHeader::size(self.version)];
+        self.stream.read_exact(&mut bytes).await?;
+
+        let header = BlockHeader::parse(&bytes, self.stream_len, self.version, block_id)?;
+
+        let mut raw = vec![0; header.data_len()?];
+        self.stream.read_exact(&mut raw).await?;
+
+        verify_checksum(&header, &raw, block_id)?;
+        Ok((header, raw))
+    }
+}
@@ -0,0 +1,540 @@
+//! Caches for the data of the blocks.
+//!
+//! Readers keep the decompressed data of the last used blocks in a
+//! [`BlockCache`]. The same cache can be used by many books, so all of them
+//! share a single memory budget. Blocks are identified by a [`CacheKey`],
+//! which includes an identifier for every loaded book.
+//!
+//! Only the blocks that can be read are stored in the cache, so a failed read
+//! is tried again the next time the block is used.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Default capacity of the cache, in blocks.
+pub(crate) const DEFAULT_CACHE_BLOCKS: usize = 16;
+
+/// Number of bits of the key hash used to select a shard.
+const SHARD_BITS: u32 = 3;
+
+/// Number of independent shards in a `ShardedCache`.
+const NUM_SHARDS: usize = 1 << SHARD_BITS;
+
+/// Identifier for the next book using a cache.
+static NEXT_BOOK_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Key of a block in a [`BlockCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    book_id: u64,
+    block_id: u64,
+}
+
+impl CacheKey {
+    /// Identifier of the book, unique in the process.
+    pub fn book_id(&self) -> u64 {
+        self.book_id
+    }
+
+    /// Identifier of the block in the book.
+    pub fn block_id(&self) -> u64 {
+        self.block_id
+    }
+}
+
+/// A cache for the decompressed data of the blocks.
+///
+/// The cache can be shared by many books, even if they are used from
+/// different threads, with [`BookOptions::shared_cache`].
+///
+/// [`LruBlockCache`] is the implementation used by default.
+///
+/// [`BookOptions::shared_cache`]: crate::BookOptions::shared_cache
+pub trait BlockCache: Send + Sync {
+    /// Return the data of a block, if it is in the cache.
+    fn get(&self, key: CacheKey) -> Option<Arc<[u8]>>;
+
+    /// Add the data of a block to the cache.
+    ///
+    /// Return the number of blocks evicted to make room for it.
+    fn insert(&self, key: CacheKey, data: Arc<[u8]>) -> usize;
+}
+
+/// Limit for the data stored in a cache.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Capacity {
+    Blocks(usize),
+    Bytes(usize),
+}
+
+impl Capacity {
+    /// Maximum number of blocks or bytes.
+    fn limit(self) -> usize {
+        match self {
+            Capacity::Blocks(n) | Capacity::Bytes(n) => n,
+        }
+    }
+
+    /// Part of the capacity used by a block.
+    fn usage(self, data: &[u8]) -> usize {
+        match self {
+            Capacity::Blocks(_) => 1,
+            Capacity::Bytes(_) => data.len(),
+        }
+    }
+}
+
+/// [`BlockCache`] that evicts the least recently used blocks when it is
+/// full.
+///
+/// ```
+/// # use theory::{Book, BookOptions};
+/// # use theory::cache::LruBlockCache;
+/// # use std::io::Cursor;
+/// # use std::sync::Arc;
+/// # let mut buffer = Vec::new();
+/// # Book::builder().dump(Cursor::new(&mut buffer)).unwrap();
+/// // 64 MiB for all books.
+/// let cache = Arc::new(LruBlockCache::with_bytes(64 << 20));
+/// let options = BookOptions::default().shared_cache(cache);
+///
+/// let book1 = Book::load_with(Cursor::new(&buffer), &options).unwrap();
+/// let book2 = Book::load_with(Cursor::new(&buffer), &options).unwrap();
+/// ```
+pub struct LruBlockCache {
+    capacity: Capacity,
+    state: Mutex<LruState>,
+}
+
+struct LruState {
+    entries: lru::LruCache<CacheKey, Arc<[u8]>>,
+
+    /// Sum of the size of every block in `entries`.
+    bytes: usize,
+}
+
+impl LruBlockCache {
+    /// Create a cache that keeps up to `blocks` blocks.
+    pub fn with_blocks(blocks: usize) -> Self {
+        LruBlockCache::new(Capacity::Blocks(blocks))
+    }
+
+    /// Create a cache that keeps up to `bytes` bytes of decompressed data.
+    ///
+    /// Blocks larger than the capacity are never stored.
+    pub fn with_bytes(bytes: usize) -> Self {
+        LruBlockCache::new(Capacity::Bytes(bytes))
+    }
+
+    pub(crate) fn new(capacity: Capacity) -> Self {
+        let state = LruState {
+            entries: lru::LruCache::unbounded(),
+            bytes: 0,
+        };
+
+        LruBlockCache {
+            capacity,
+            state: Mutex::new(state),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, LruState> {
+        // The state is always valid, even if a thread panicked while holding
+        // the lock.
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl BlockCache for LruBlockCache {
+    fn get(&self, key: CacheKey) -> Option<Arc<[u8]>> {
+        self.state().entries.get(&key).cloned()
+    }
+
+    fn insert(&self, key: CacheKey, data: Arc<[u8]>) -> usize {
+        let fits = match self.capacity {
+            Capacity::Blocks(n) => n > 0,
+            Capacity::Bytes(n) => data.len() <= n,
+        };
+
+        if !fits {
+            return 0;
+        }
+
+        let mut state = self.state();
+
+        state.bytes += data.len();
+        if let Some(old) = state.entries.put(key, data) {
+            state.bytes -= old.len();
+        }
+
+        // The new block is the most recently used, so it is not evicted.
+        let mut evicted = 0;
+        loop {
+            let full = match self.capacity {
+                Capacity::Blocks(n) => state.entries.len() > n,
+                Capacity::Bytes(n) => state.bytes > n,
+            };
+
+            if !full {
+                break;
+            }
+
+            match state.entries.pop_lru() {
+                Some((_, data)) => {
+                    state.bytes -= data.len();
+                    evicted += 1;
+                }
+
+                None => break,
+            }
+        }
+
+        evicted
+    }
+}
+
+/// LRU cache split in shards, so threads reading different blocks don't
+/// compete for the same lock.
+///
+/// All shards share the same capacity. When the cache is full, blocks are
+/// evicted from the shard of the new block, and then from the other shards,
+/// so the order is only LRU within every shard.
+pub(crate) struct ShardedCache {
+    capacity: Capacity,
+
+    /// Blocks or bytes, depending on `capacity`, stored in all shards.
+    usage: AtomicUsize,
+
+    shards: Vec<Mutex<lru::LruCache<CacheKey, Arc<[u8]>>>>,
+}
+
+impl ShardedCache {
+    /// Create a cache with `capacity` shared by all shards.
+    pub(crate) fn new(capacity: Capacity) -> Self {
+        let shards = (0..NUM_SHARDS)
+            .map(|_| Mutex::new(lru::LruCache::unbounded()))
+            .collect();
+
+        ShardedCache {
+            capacity,
+            usage: AtomicUsize::new(0),
+            shards,
+        }
+    }
+
+    fn shard_index(&self, key: CacheKey) -> usize {
+        // Block identifiers are positions in the file, so they are mixed
+        // (with Fibonacci hashing) to spread them between the shards.
+        let hash = (key.block_id ^ key.book_id.rotate_left(32)).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            >> (64 - SHARD_BITS);
+
+        hash as usize
+    }
+
+    fn shard(&self, index: usize) -> MutexGuard<'_, lru::LruCache<CacheKey, Arc<[u8]>>> {
+        // The shards are always valid, even if a thread panicked while
+        // holding the lock.
+        self.shards[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn is_full(&self) -> bool {
+        self.usage.load(Ordering::Relaxed) > self.capacity.limit()
+    }
+
+    /// Remove the least recently used block of a shard, if any.
+    fn pop_lru(&self, shard: &mut lru::LruCache<CacheKey, Arc<[u8]>>) -> bool {
+        match shard.pop_lru() {
+            Some((_, data)) => {
+                let usage = self.capacity.usage(&data);
+                self.usage.fetch_sub(usage, Ordering::Relaxed);
+                true
+            }
+
+            None => false,
+        }
+    }
+}
+
+impl BlockCache for ShardedCache {
+    fn get(&self, key: CacheKey) -> Option<Arc<[u8]>> {
+        self.shard(self.shard_index(key)).get(&key).cloned()
+    }
+
+    fn insert(&self, key: CacheKey, data: Arc<[u8]>) -> usize {
+        if self.capacity.usage(&data) > self.capacity.limit() {
+            return 0;
+        }
+
+        let index = self.shard_index(key);
+        let mut evicted = 0;
+
+        {
+            let mut shard = self.shard(index);
+
+            self.usage
+                .fetch_add(self.capacity.usage(&data), Ordering::Relaxed);
+
+            if let Some(old) = shard.put(key, data) {
+                self.usage
+                    .fetch_sub(self.capacity.usage(&old), Ordering::Relaxed);
+            }
+
+            // The new block is the most recently used, so it is not evicted.
+            while self.is_full() && shard.len() > 1 && self.pop_lru(&mut shard) {
+                evicted += 1;
+            }
+        }
+
+        // Only one lock is held at a time, to avoid deadlocks with other
+        // threads.
+        for offset in 1..NUM_SHARDS {
+            if !self.is_full() {
+                break;
+            }
+
+            let mut shard = self.shard((index + offset) % NUM_SHARDS);
+            while self.is_full() && self.pop_lru(&mut shard) {
+                evicted += 1;
+            }
+        }
+
+        evicted
+    }
+}
+
+/// Statistics of the block cache of a book.
+///
+/// See [`Book::cache_stats`](crate::Book::cache_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    bytes_decompressed: u64,
+}
+
+impl CacheStats {
+    /// Number of blocks found in the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of blocks read from the input, because they were not in the
+    /// cache.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Number of blocks removed from the cache to make room for the blocks
+    /// of this book.
+    ///
+    /// If the cache is shared, the evicted blocks can be from other books.
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Number of bytes produced by decompressing blocks.
+    ///
+    /// Uncompressed blocks are not included.
+    pub fn bytes_decompressed(&self) -> u64 {
+        self.bytes_decompressed
+    }
+}
+
+/// Counters for [`CacheStats`], shared by all the readers of a book.
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    bytes_decompressed: AtomicU64,
+}
+
+/// Cache used by the readers of a book, and the statistics of its usage.
+#[derive(Clone)]
+pub(crate) struct CacheHandle {
+    cache: Option<Arc<dyn BlockCache>>,
+    book_id: u64,
+    counters: Arc<Counters>,
+}
+
+impl CacheHandle {
+    /// Create a handle for a new book. If `cache` is `None`, blocks are
+    /// never cached.
+    pub(crate) fn new(cache: Option<Arc<dyn BlockCache>>) -> Self {
+        CacheHandle {
+            cache,
+            book_id: NEXT_BOOK_ID.fetch_add(1, Ordering::Relaxed),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Return `true` if the blocks can be stored in a cache.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    /// Return the data of a block, if it is in the cache.
+    pub(crate) fn get(&self, block_id: u64) -> Option<Arc<[u8]>> {
+        let data = self.cache.as_ref().and_then(|c| c.get(self.key(block_id)));
+
+        let counter = match data {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+        data
+    }
+
+    /// Add the data of a block to the cache.
+    pub(crate) fn insert(&self, block_id: u64, data: Arc<[u8]>) {
+        if let Some(cache) = &self.cache {
+            let evicted = cache.insert(self.key(block_id), data);
+            self.counters
+                .evictions
+                .fetch_add(evicted as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Track the bytes produced by decompressing a block.
+    pub(crate) fn add_decompressed(&self, bytes: usize) {
+        self.counters
+            .bytes_decompressed
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        CacheStats {
+            hits: load(&self.counters.hits),
+            misses: load(&self.counters.misses),
+            evictions: load(&self.counters.evictions),
+            bytes_decompressed: load(&self.counters.bytes_decompressed),
+        }
+    }
+
+    fn key(&self, block_id: u64) -> CacheKey {
+        CacheKey {
+            book_id: self.book_id,
+            block_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockCache, CacheKey, Capacity, LruBlockCache, ShardedCache};
+    use crate::{Book, BookOptions};
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    fn key(block_id: u64) -> CacheKey {
+        CacheKey {
+            book_id: 0,
+            block_id,
+        }
+    }
+
+    #[test]
+    fn lru_capacity() {
+        let cache = LruBlockCache::with_blocks(2);
+        assert_eq!(cache.insert(key(1), Arc::from(&b"1"[..])), 0);
+        assert_eq!(cache.insert(key(2), Arc::from(&b"2"[..])), 0);
+        assert!(cache.get(key(1)).is_some());
+        assert_eq!(cache.insert(key(3), Arc::from(&b"3"[..])), 1);
+        assert!(cache.get(key(2)).is_none());
+        assert!(cache.get(key(1)).is_some());
+
+        let cache = LruBlockCache::with_bytes(10);
+        assert_eq!(cache.insert(key(1), Arc::from(&[0; 4][..])), 0);
+        assert_eq!(cache.insert(key(2), Arc::from(&[0; 4][..])), 0);
+        assert_eq!(cache.insert(key(3), Arc::from(&[0; 8][..])), 2);
+        assert!(cache.get(key(3)).is_some());
+
+        // Too large for the cache.
+        assert_eq!(cache.insert(key(4), Arc::from(&[0; 11][..])), 0);
+        assert!(cache.get(key(4)).is_none());
+        assert!(cache.get(key(3)).is_some());
+
+        // Replace a block.
+        assert_eq!(cache.insert(key(3), Arc::from(&[0; 10][..])), 0);
+        assert_eq!(cache.get(key(3)).unwrap().len(), 10);
+
+        let cache = LruBlockCache::with_blocks(0);
+        assert_eq!(cache.insert(key(1), Arc::from(&b"1"[..])), 0);
+        assert!(cache.get(key(1)).is_none());
+    }
+
+    #[test]
+    fn sharded_capacity() {
+        // The capacity is shared by all shards.
+        let cache = ShardedCache::new(Capacity::Blocks(16));
+        for n in 0..16 {
+            assert_eq!(cache.insert(key(n), Arc::from(&b"1"[..])), 0);
+        }
+
+        assert!((0..16).all(|n| cache.get(key(n)).is_some()));
+
+        assert_eq!(cache.insert(key(16), Arc::from(&b"1"[..])), 1);
+        assert_eq!((0..17).filter(|n| cache.get(key(*n)).is_some()).count(), 16);
+        assert!(cache.get(key(16)).is_some());
+
+        // Blocks larger than the size of a shard.
+        let cache = ShardedCache::new(Capacity::Bytes(256 << 10));
+        for n in 0..4 {
+            assert_eq!(cache.insert(key(n), Arc::from(&[0; 64 << 10][..])), 0);
+        }
+
+        assert!((0..4).all(|n| cache.get(key(n)).is_some()));
+
+        assert_eq!(cache.insert(key(4), Arc::from(&[0; 128 << 10][..])), 2);
+        assert!(cache.get(key(4)).is_some());
+
+        // Too large for the cache.
+        assert_eq!(cache.insert(key(5), Arc::from(&[0; 257 << 10][..])), 0);
+        assert!(cache.get(key(5)).is_none());
+
+        let cache = ShardedCache::new(Capacity::Blocks(0));
+        assert_eq!(cache.insert(key(1), Arc::from(&b"1"[..])), 0);
+        assert!(cache.get(key(1)).is_none());
+    }
+
+    #[test]
+    fn share_cache_between_books() {
+        let dump = |content: &str| {
+            let mut builder = Book::builder();
+            let page_id = builder.new_page("Page").set_content(content).id();
+
+            let mut buffer: Vec<u8> = Vec::new();
+            builder
+                .dump(Cursor::new(&mut buffer))
+                .expect("BookBuilder::dump");
+
+            (buffer, page_id)
+        };
+
+        // Both books use the same block identifiers.
+        let (buffer1, page_id) = dump("- 1 -");
+        let (buffer2, _) = dump("- 2 -");
+
+        let cache = Arc::new(LruBlockCache::with_blocks(10));
+        let options = BookOptions::default().shared_cache(cache);
+
+        let mut book1 = Book::load_with(Cursor::new(buffer1), &options).unwrap();
+        let mut book2 = Book::load_with(Cursor::new(buffer2), &options).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(book1.get_page_by_id(page_id).unwrap().content(), b"- 1 -");
+            assert_eq!(book2.get_page_by_id(page_id).unwrap().content(), b"- 2 -");
+        }
+
+        for book in [&book1, &book2] {
+            let stats = book.cache_stats();
+            assert_eq!((stats.hits(), stats.misses()), (2, 2));
+        }
+    }
+}
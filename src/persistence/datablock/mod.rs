@@ -6,14 +6,41 @@
 //! The first byte in the block indicates the compression, or `0` is the data is
 //! not compressed.
 //!
-//! The next 4 bytes are the length of the block (`u32`, big-endian).
+//! The next bytes are the length of the block, as a big-endian number: `u32`
+//! in version 1 of the file format, and `u64` in version 2.
+//!
+//! In version 2, the length is followed by the CRC-32C checksum of the data
+//! stored in the block (`u32`, big-endian), before decompressing it.
+//!
+//! If compressing a block does not reduce its size, the writer stores it
+//! uncompressed, regardless of the selected compression method.
+//!
+//! # Zstandard Dictionaries
+//!
+//! The data of a block compressed with Zstandard starts with the identifier
+//! of the block containing the dictionary (as LEB128), or `0` if the block
+//! does not use a dictionary. It is followed by the compressed frame.
+//!
+//! The dictionary is stored in a non-compressed block, so it is written only
+//! once, and shared by all blocks.
 
 mod reader;
 mod writer;
 
+#[cfg(feature = "async")]
+mod async_reader;
+
+#[cfg(feature = "parallel")]
+mod pool;
+
+pub(crate) mod cache;
+
 #[cfg(test)]
 mod tests;
 
+/// Size of the header of every block in version 2: tag, length, and checksum.
+const BLOCK_HEADER_SIZE: u64 = 1 + 8 + 4;
+
 /// Tag to indicate the block type.
 #[derive(num_enum::TryFromPrimitive, num_enum::IntoPrimitive, Debug, Copy, Clone)]
 #[repr(u8)]
@@ -25,13 +52,19 @@ enum BlockType {
 
     #[cfg(feature = "lz4")]
     Lz4 = 3,
+
+    #[cfg(feature = "zstd")]
+    Zstd = 4,
 }
 
 pub(crate) use reader::DataBlocksReader;
-pub(crate) use writer::DataBlocksWriter;
+
+#[cfg(feature = "async")]
+pub(crate) use async_reader::AsyncDataBlocksReader;
+pub(crate) use writer::{DataBlocksWriter, DEFAULT_BLOCK_SIZE};
 
 /// Method to compress data in blocks.
-#[derive(Default, Clone, Copy, Debug)]
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BlockCompression {
     /// Don't compress data.
     #[default]
@@ -46,6 +79,16 @@ pub enum BlockCompression {
     #[cfg(feature = "lz4")]
     #[cfg_attr(docsrs, doc(cfg(feature = "lz4")))]
     Lz4,
+
+    /// Use Zstandard, with the specified compression level (`1..=22`).
+    ///
+    /// If `dictionary` is `true`, [`BookBuilder`](crate::BookBuilder) trains
+    /// a dictionary from a sample of the pages, and stores it in the book.
+    /// With [`BookWriter`](crate::BookWriter), the dictionary has to be
+    /// trained with [`train_dictionary`](crate::BookWriter::train_dictionary).
+    #[cfg(feature = "zstd")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+    Zstd { level: i32, dictionary: bool },
 }
 
 impl BlockCompression {
@@ -58,10 +101,40 @@ impl BlockCompression {
 
             #[cfg(feature = "lz4")]
             BlockCompression::Lz4 => BlockType::Lz4,
+
+            #[cfg(feature = "zstd")]
+            BlockCompression::Zstd { .. } => BlockType::Zstd,
         }
     }
 }
 
+/// Data of a block, as it is stored in a book, so it can be copied to
+/// another book without decompressing it.
+pub(crate) struct RawBlock {
+    block_type: BlockType,
+    data: Vec<u8>,
+    checksum: u32,
+}
+
+impl RawBlock {
+    /// Return `true` if the block is compressed with the same method of
+    /// `compression`. The compression level is not checked.
+    pub(crate) fn uses(&self, compression: BlockCompression) -> bool {
+        u8::from(self.block_type) == u8::from(compression.tag())
+    }
+}
+
+/// Maximum size of the Zstandard dictionaries. It is the same default of the
+/// `zstd` command.
+#[cfg(feature = "zstd")]
+const MAX_DICTIONARY_SIZE: usize = 110 * 1024;
+
+/// Train a Zstandard dictionary from the `samples`.
+#[cfg(feature = "zstd")]
+pub(crate) fn train_dictionary<S: AsRef<[u8]>>(samples: &[S]) -> std::io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, MAX_DICTIONARY_SIZE)
+}
+
 /// Convert an error from `lz4_flex` to `std::io::Error`.
 #[cfg(feature = "lz4")]
 fn map_lz4_err(e: lz4_flex::frame::Error) -> std::io::Error {
@@ -0,0 +1,142 @@
+//! Worker threads to compress data blocks.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use super::writer::{BlockEncoder, EncodedBlock};
+
+/// Block to compress, with its index in the writer.
+type Job = (u64, BlockEncoder, Vec<u8>);
+
+/// Compressed block, with its index in the writer.
+type Output = (u64, io::Result<EncodedBlock>);
+
+/// Threads to compress blocks.
+///
+/// Blocks can be compressed in any order. The writer uses the index of every
+/// block to write them in the same order they were created.
+pub(super) struct Pool {
+    jobs: Option<Sender<Job>>,
+
+    results: Receiver<Output>,
+
+    workers: Vec<JoinHandle<()>>,
+
+    /// Blocks received before the ones with a lower index.
+    ready: BTreeMap<u64, io::Result<EncodedBlock>>,
+
+    /// Number of blocks sent to the threads, and not taken yet.
+    pending: usize,
+}
+
+impl Pool {
+    pub(super) fn new(threads: usize) -> Pool {
+        let (jobs, jobs_receiver) = mpsc::channel::<Job>();
+        let (results_sender, results) = mpsc::channel();
+
+        let jobs_receiver = Arc::new(Mutex::new(jobs_receiver));
+
+        let workers = (0..threads)
+            .map(|_| {
+                let jobs = Arc::clone(&jobs_receiver);
+                let results = results_sender.clone();
+
+                thread::spawn(move || loop {
+                    let job = jobs.lock().unwrap_or_else(PoisonError::into_inner).recv();
+
+                    // The channel is closed when the pool is dropped.
+                    let (index, encoder, data) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+
+                    if results.send((index, encoder.encode(data))).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        Pool {
+            jobs: Some(jobs),
+            results,
+            workers,
+            ready: BTreeMap::new(),
+            pending: 0,
+        }
+    }
+
+    /// Number of threads in the pool.
+    pub(super) fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Number of blocks sent to the threads, and not taken yet.
+    pub(super) fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Send a block to be compressed.
+    pub(super) fn submit(&mut self, index: u64, encoder: BlockEncoder, data: Vec<u8>) {
+        if let Some(jobs) = &self.jobs {
+            // If the threads are stopped, the error is reported by `take`.
+            let _ = jobs.send((index, encoder, data));
+            self.pending += 1;
+        }
+    }
+
+    /// Add a block that doesn't need to be compressed.
+    pub(super) fn insert(&mut self, index: u64, block: EncodedBlock) {
+        self.ready.insert(index, Ok(block));
+        self.pending += 1;
+    }
+
+    /// Return the block with the specified index, if it is already
+    /// compressed.
+    pub(super) fn try_take(&mut self, index: u64) -> Option<io::Result<EncodedBlock>> {
+        while let Ok((i, block)) = self.results.try_recv() {
+            self.ready.insert(i, block);
+        }
+
+        let block = self.ready.remove(&index)?;
+        self.pending -= 1;
+        Some(block)
+    }
+
+    /// Wait until the block with the specified index is compressed.
+    pub(super) fn take(&mut self, index: u64) -> io::Result<EncodedBlock> {
+        loop {
+            if let Some(block) = self.ready.remove(&index) {
+                self.pending -= 1;
+                return block;
+            }
+
+            match self.results.recv() {
+                Ok((i, block)) => {
+                    self.ready.insert(i, block);
+                }
+
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "compression threads stopped",
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // Close the channel, so the threads can finish.
+        drop(self.jobs.take());
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -1,35 +1,71 @@
 //! Reader for data blocks.
 
-use core::num::NonZeroUsize;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::Arc;
 
-use super::BlockType;
-
-/// Size of the LRU cache.
-const LRU_CACHE_SIZE: NonZeroUsize = match NonZeroUsize::new(16) {
-    // TODO use Option::unwrap when const_option feature is stable.
-    Some(n) => n,
-    None => panic!(),
-};
+use super::cache::{CacheHandle, CacheStats};
+use super::{BlockType, RawBlock};
+use crate::persistence::{PersistenceError, Version};
 
 pub(crate) struct DataBlocksReader<S> {
     stream: S,
 
     stream_len: u64,
 
-    cache: lru::LruCache<u64, Result<Vec<u8>, io::Error>>,
+    version: Version,
+
+    cache: CacheHandle,
+
+    /// Location of the non-compressed blocks that can be borrowed from the
+    /// input. Only used when the input is a byte slice.
+    borrowable: HashMap<u64, Range<usize>>,
+
+    /// Last dictionary loaded to decompress blocks.
+    dictionary: Option<(u64, Arc<[u8]>)>,
 }
 
 impl<S: Read + Seek> DataBlocksReader<S> {
-    pub(crate) fn new(mut stream: S) -> io::Result<Self> {
+    pub(crate) fn new(mut stream: S, version: Version, cache: CacheHandle) -> io::Result<Self> {
         let stream_len = stream.seek(SeekFrom::End(0))?;
-        let cache = lru::LruCache::new(LRU_CACHE_SIZE);
+        Ok(DataBlocksReader::with_len(
+            stream, stream_len, version, cache,
+        ))
+    }
 
-        Ok(DataBlocksReader {
+    /// Create a reader for a stream with a known length.
+    ///
+    /// The cache can be shared with other readers of the same input.
+    pub(crate) fn with_len(
+        stream: S,
+        stream_len: u64,
+        version: Version,
+        cache: CacheHandle,
+    ) -> Self {
+        DataBlocksReader {
             stream,
             stream_len,
+            version,
             cache,
-        })
+            borrowable: HashMap::new(),
+            dictionary: None,
+        }
+    }
+
+    /// Return the underlying stream.
+    pub(crate) fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Return the statistics of the block cache.
+    pub(crate) fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Return the version of the file format.
+    pub(crate) fn version(&self) -> Version {
+        self.version
     }
 
     /// Return a mutable reference to the input stream.
@@ -42,91 +78,386 @@ impl<S: Read + Seek> DataBlocksReader<S> {
         self.stream_len
     }
 
+    /// Return the position of the end of the block, without reading its
+    /// data.
+    pub(crate) fn block_end(&mut self, block_id: u64) -> Result<u64, PersistenceError> {
+        let header = read_header(&mut self.stream, self.stream_len, self.version, block_id)?;
+        Ok(header.data_pos + header.len)
+    }
+
     /// Get a block from its identifier.
     ///
     /// The function is applied only if the block can be fully read, and the
     /// offset is within the block.
-    pub(crate) fn with_block<F, T, O>(&mut self, block_id: u64, offset: O, f: F) -> io::Result<T>
+    ///
+    /// In version 2 files, the checksum of the block is verified before
+    /// decompressing its data.
+    pub(crate) fn with_block<F, T, O>(
+        &mut self,
+        block_id: u64,
+        offset: O,
+        f: F,
+    ) -> Result<T, PersistenceError>
     where
         F: FnOnce(&[u8]) -> T,
         O: TryInto<usize>,
     {
-        let offset = O::try_into(offset).map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "offset cannot be converted to usize",
-            )
-        })?;
+        let offset = block_offset(offset)?;
+
+        if let Some(data) = self.cache.get(block_id) {
+            return apply_at(&data, offset, f);
+        }
+
+        // Errors are not cached, so the block is read again in the next
+        // call.
+        let data = self.read_block(block_id)?;
+
+        if !self.cache.is_enabled() {
+            return apply_at(&data, offset, f);
+        }
+
+        let data: Arc<[u8]> = data.into();
+        self.cache.insert(block_id, Arc::clone(&data));
+        apply_at(&data, offset, f)
+    }
+
+    /// Read and decompress the data of a block.
+    fn read_block(&mut self, block_id: u64) -> Result<Vec<u8>, PersistenceError> {
+        let (header, raw) = read_raw(&mut self.stream, self.stream_len, self.version, block_id)?;
+
+        let dictionary = match dictionary_id(&header, &raw)? {
+            Some(id) => Some(self.dictionary(id)?),
+            None => None,
+        };
 
-        let stream = &mut self.stream;
+        decode_block(&header, raw, &self.cache, dictionary.as_deref())
+    }
 
-        let result = self.cache.get_or_insert(block_id, || {
-            stream.seek(SeekFrom::Start(block_id))?;
+    /// Read the data of a block without decompressing it, so it can be copied
+    /// to another book.
+    ///
+    /// Returns `None` if the block can't be copied: blocks from version 1
+    /// files, and blocks that need a Zstandard dictionary.
+    pub(crate) fn raw_block(
+        &mut self,
+        block_id: u64,
+    ) -> Result<Option<RawBlock>, PersistenceError> {
+        if self.version != Version::V2 {
+            return Ok(None);
+        }
 
-            // Block type.
-            let mut byte_tag = [0];
-            stream.read_exact(&mut byte_tag)?;
+        let (header, raw) = read_raw(&mut self.stream, self.stream_len, self.version, block_id)?;
 
-            let block_type = BlockType::try_from(byte_tag[0])
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Invalid block type"))?;
+        if dictionary_id(&header, &raw)?.is_some() {
+            return Ok(None);
+        }
 
-            // Block length.
-            //
-            // Return an error if the length is beyond the end of the input.
-            let mut len = [0; 4];
-            stream.read_exact(&mut len)?;
-            let len = u32::from_be_bytes(len);
+        Ok(header.checksum.map(|checksum| RawBlock {
+            block_type: header.block_type,
+            data: raw,
+            checksum,
+        }))
+    }
 
-            if offset > len as usize {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "offset is beyond end of the block",
-                ));
+    /// Load the dictionary stored in a block.
+    fn dictionary(&mut self, block_id: u64) -> Result<Arc<[u8]>, PersistenceError> {
+        if let Some((id, bytes)) = &self.dictionary {
+            if *id == block_id {
+                return Ok(Arc::clone(bytes));
             }
+        }
+
+        let (header, raw) = read_raw(&mut self.stream, self.stream_len, self.version, block_id)?;
+        check_dictionary(&header)?;
+
+        let bytes: Arc<[u8]> = raw.into();
+        self.dictionary = Some((block_id, Arc::clone(&bytes)));
+        Ok(bytes)
+    }
+}
+
+impl<T: AsRef<[u8]>> DataBlocksReader<Cursor<T>> {
+    /// Return the location of the data of a block in the input, if it is not
+    /// compressed, so it can be used without copying it.
+    ///
+    /// The checksum of the block is verified only the first time.
+    pub(crate) fn borrowable_range(
+        &mut self,
+        block_id: u64,
+    ) -> Result<Option<Range<usize>>, PersistenceError> {
+        if let Some(range) = self.borrowable.get(&block_id) {
+            return Ok(Some(range.clone()));
+        }
+
+        let header = read_header(&mut self.stream, self.stream_len, self.version, block_id)?;
+
+        if !matches!(header.block_type, BlockType::Uncompressed) {
+            return Ok(None);
+        }
+
+        // `read_header` verifies that the block is within the input, so
+        // these numbers fit in a `usize`.
+        let start = header.data_pos as usize;
+        let range = start..start + header.len as usize;
 
-            if block_id.saturating_add(len as u64) > self.stream_len {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Block beyond the end of the input",
-                ));
+        if let Some(checksum) = header.checksum {
+            if crc32c::crc32c(&self.stream.get_ref().as_ref()[range.clone()]) != checksum {
+                return Err(PersistenceError::ChecksumMismatch { block_id });
             }
+        }
 
-            // Block data.
-            let mut data;
+        self.borrowable.insert(block_id, range.clone());
+        Ok(Some(range))
+    }
 
-            match block_type {
-                BlockType::Uncompressed => {
-                    data = vec![0; len as usize];
-                    stream.read_exact(&mut data)?;
-                }
+    /// Return the bytes of the input.
+    pub(crate) fn input_bytes(&self) -> &[u8] {
+        self.stream.get_ref().as_ref()
+    }
+}
+
+/// Convert the offset of some data in a block to a `usize`.
+pub(super) fn block_offset<O: TryInto<usize>>(offset: O) -> Result<usize, PersistenceError> {
+    O::try_into(offset).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "offset cannot be converted to usize",
+        )
+        .into()
+    })
+}
+
+/// Apply `f` to the data of a block, starting at `offset`.
+pub(super) fn apply_at<F, T>(data: &[u8], offset: usize, f: F) -> Result<T, PersistenceError>
+where
+    F: FnOnce(&[u8]) -> T,
+{
+    match data.get(offset..) {
+        Some(bytes) => Ok(f(bytes)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "offset is beyond end of the block",
+        )
+        .into()),
+    }
+}
+
+/// Header of a data block.
+pub(super) struct BlockHeader {
+    block_type: BlockType,
+
+    /// Length of the data stored in the block.
+    len: u64,
+
+    /// Checksum of the data. Only available in version 2.
+    checksum: Option<u32>,
 
-                #[cfg(feature = "deflate")]
-                BlockType::Deflate => {
-                    data = Vec::with_capacity(len.next_power_of_two() as usize);
-                    let mut deflater = flate2::write::DeflateDecoder::new(&mut data);
-                    io::copy(&mut stream.take(len as u64), &mut deflater)?;
-                    deflater.finish()?;
-                    data.shrink_to_fit()
+    /// Position of the data in the stream.
+    data_pos: u64,
+}
+
+impl BlockHeader {
+    /// Size, in bytes, of the header of every block.
+    pub(super) fn size(version: Version) -> usize {
+        match version {
+            Version::V1 => 1 + 4,
+            Version::V2 => super::BLOCK_HEADER_SIZE as usize,
+        }
+    }
+
+    /// Parse the header of the block at `block_id`.
+    ///
+    /// `bytes` must contain [`BlockHeader::size`] bytes.
+    pub(super) fn parse(
+        bytes: &[u8],
+        stream_len: u64,
+        version: Version,
+        block_id: u64,
+    ) -> Result<BlockHeader, PersistenceError> {
+        let block_type = BlockType::try_from(bytes[0])
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Invalid block type"))?;
+
+        // Block length, and its checksum in version 2.
+        let (len, checksum) = match version {
+            Version::V1 => {
+                let len = bytes[1..5].try_into().unwrap();
+                (u64::from(u32::from_be_bytes(len)), None)
+            }
+
+            Version::V2 => {
+                let len = bytes[1..9].try_into().unwrap();
+                let checksum = bytes[9..13].try_into().unwrap();
+                (u64::from_be_bytes(len), Some(u32::from_be_bytes(checksum)))
+            }
+        };
+
+        let data_pos = block_id.saturating_add(bytes.len() as u64);
+
+        // Return an error if the length is beyond the end of the input.
+        if data_pos.saturating_add(len) > stream_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Block beyond the end of the input",
+            )
+            .into());
+        }
+
+        Ok(BlockHeader {
+            block_type,
+            len,
+            checksum,
+            data_pos,
+        })
+    }
+
+    /// Length of the data stored in the block, as a `usize`.
+    pub(super) fn data_len(&self) -> Result<usize, PersistenceError> {
+        usize::try_from(self.len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Block is too large").into())
+    }
+}
+
+/// Read the header of the block at `block_id`.
+fn read_header<S: Read + Seek>(
+    stream: &mut S,
+    stream_len: u64,
+    version: Version,
+    block_id: u64,
+) -> Result<BlockHeader, PersistenceError> {
+    stream.seek(SeekFrom::Start(block_id))?;
+
+    let mut bytes = [0; super::BLOCK_HEADER_SIZE as usize];
+    let bytes = &mut bytes[..BlockHeader::size(version)];
+    stream.read_exact(bytes)?;
+
+    BlockHeader::parse(bytes, stream_len, version, block_id)
+}
+
+/// Read the data of a block, and verify its checksum.
+fn read_raw<S: Read + Seek>(
+    stream: &mut S,
+    stream_len: u64,
+    version: Version,
+    block_id: u64,
+) -> Result<(BlockHeader, Vec<u8>), PersistenceError> {
+    let header = read_header(stream, stream_len, version, block_id)?;
+
+    let mut raw = vec![0; header.data_len()?];
+    stream.read_exact(&mut raw)?;
+
+    verify_checksum(&header, &raw, block_id)?;
+    Ok((header, raw))
+}
+
+/// Verify the checksum of the data read from a block.
+pub(super) fn verify_checksum(
+    header: &BlockHeader,
+    raw: &[u8],
+    block_id: u64,
+) -> Result<(), PersistenceError> {
+    match header.checksum {
+        Some(checksum) if crc32c::crc32c(raw) != checksum => {
+            Err(PersistenceError::ChecksumMismatch { block_id })
+        }
+
+        _ => Ok(()),
+    }
+}
+
+/// Return the identifier of the block with the dictionary needed to
+/// decompress a block, if any.
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub(super) fn dictionary_id(
+    header: &BlockHeader,
+    raw: &[u8],
+) -> Result<Option<u64>, PersistenceError> {
+    #[cfg(feature = "zstd")]
+    if let BlockType::Zstd = header.block_type {
+        let id = leb128::read::unsigned(&mut &raw[..]).map_err(invalid_block)?;
+        return Ok(if id == 0 { None } else { Some(id) });
+    }
+
+    Ok(None)
+}
+
+/// Verify that a block can be used as a dictionary.
+///
+/// Dictionaries are never compressed, so loading them never needs another
+/// dictionary.
+pub(super) fn check_dictionary(header: &BlockHeader) -> Result<(), PersistenceError> {
+    if matches!(header.block_type, BlockType::Uncompressed) {
+        Ok(())
+    } else {
+        Err(invalid_block("Invalid dictionary block"))
+    }
+}
+
+/// Error for blocks with unexpected data.
+fn invalid_block<E>(error: E) -> PersistenceError
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error).into()
+}
+
+/// Decompress the data read from a block.
+///
+/// `dictionary` is the content of the block returned by [`dictionary_id`].
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub(super) fn decode_block(
+    header: &BlockHeader,
+    raw: Vec<u8>,
+    cache: &CacheHandle,
+    dictionary: Option<&[u8]>,
+) -> Result<Vec<u8>, PersistenceError> {
+    let block_type = header.block_type;
+
+    // Block data.
+    let data = match block_type {
+        BlockType::Uncompressed => raw,
+
+        #[cfg(feature = "deflate")]
+        BlockType::Deflate => {
+            let mut data = Vec::with_capacity(raw.len().next_power_of_two());
+            flate2::read::DeflateDecoder::new(&raw[..]).read_to_end(&mut data)?;
+            data.shrink_to_fit();
+            data
+        }
+
+        #[cfg(feature = "lz4")]
+        BlockType::Lz4 => {
+            let mut data = Vec::with_capacity(raw.len().next_power_of_two());
+            lz4_flex::frame::FrameDecoder::new(&raw[..]).read_to_end(&mut data)?;
+            data.shrink_to_fit();
+            data
+        }
+
+        #[cfg(feature = "zstd")]
+        BlockType::Zstd => {
+            // Skip the dictionary identifier.
+            let mut frame = &raw[..];
+            leb128::read::unsigned(&mut frame).map_err(invalid_block)?;
+
+            let mut data = Vec::with_capacity(raw.len().next_power_of_two());
+            match dictionary {
+                Some(dictionary) => {
+                    zstd::stream::read::Decoder::with_dictionary(frame, dictionary)?
+                        .read_to_end(&mut data)?;
                 }
 
-                #[cfg(feature = "lz4")]
-                BlockType::Lz4 => {
-                    data = Vec::with_capacity(len.next_power_of_two() as usize);
-                    let mut decoder = lz4_flex::frame::FrameDecoder::new(stream.take(len as u64));
-                    decoder.read_to_end(&mut data)?;
-                    data.shrink_to_fit()
+                None => {
+                    zstd::stream::read::Decoder::new(frame)?.read_to_end(&mut data)?;
                 }
             }
 
-            Ok(data)
-        });
+            data.shrink_to_fit();
+            data
+        }
+    };
 
-        result
-            .as_ref()
-            .map(|data| f(&data[offset..]))
-            .map_err(|e| match e.get_ref() {
-                Some(r) => io::Error::new(e.kind(), r.to_string()),
-                None => io::Error::new(e.kind(), ""),
-            })
+    if !matches!(block_type, BlockType::Uncompressed) {
+        cache.add_decompressed(data.len());
     }
+
+    Ok(data)
 }
@@ -1,5 +1,7 @@
-use crate::BlockCompression;
-use std::io::{Cursor, Read, Write};
+use crate::persistence::{PersistenceError, Version};
+use crate::{BlockCompression, BookOptions};
+use std::cell::Cell;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
 #[test]
 fn write_read() {
@@ -13,6 +15,12 @@ fn write_read() {
 
     #[cfg(feature = "lz4")]
     write_read_with_compression(BlockCompression::Lz4);
+
+    #[cfg(feature = "zstd")]
+    write_read_with_compression(BlockCompression::Zstd {
+        level: 3,
+        dictionary: false,
+    });
 }
 
 fn write_read_with_compression(compression: BlockCompression) {
@@ -36,7 +44,7 @@ fn write_read_with_compression(compression: BlockCompression) {
     fragment.write_all(&[b'C'; 10]).unwrap();
     let fragment = fragment.location();
 
-    assert_eq!(block1.block_id, fragment.block_id);
+    assert_eq!(block1.block, fragment.block);
     assert_eq!(fragment.offset, 100);
 
     // Third fragment: 10×'D', but use a very big number as the hint.
@@ -46,6 +54,10 @@ fn write_read_with_compression(compression: BlockCompression) {
     let block2 = fragment.location();
     assert_eq!(block2.offset, 0);
 
+    let block1_id = writer.block_id(block1.block).unwrap();
+    let block2_id = writer.block_id(block2.block).unwrap();
+    assert_eq!(block1_id, 8);
+
     writer.finish().unwrap();
 
     // Check the written data.
@@ -56,7 +68,9 @@ fn write_read_with_compression(compression: BlockCompression) {
     reader.read_exact(&mut prefix).unwrap();
     assert_eq!(&prefix, b"<prefix>");
 
-    let mut reader = super::DataBlocksReader::new(reader).unwrap();
+    let mut reader =
+        super::DataBlocksReader::new(reader, Version::V2, BookOptions::default().cache_handle())
+            .unwrap();
 
     // The first block contains the ABC sequences.
     let expected = {
@@ -67,15 +81,378 @@ fn write_read_with_compression(compression: BlockCompression) {
         bytes
     };
 
-    let block_bytes = reader
-        .with_block(block1.block_id, 0, |b| Vec::from(b))
-        .unwrap();
+    let block_bytes = reader.with_block(block1_id, 0, |b| Vec::from(b)).unwrap();
     assert_eq!(block_bytes, expected);
 
     // The second block contains 10×'D'.
     let expected = vec![b'D'; 10];
-    let block_bytes = reader
-        .with_block(block2.block_id, 0, |b| Vec::from(b))
-        .unwrap();
+    let block_bytes = reader.with_block(block2_id, 0, |b| Vec::from(b)).unwrap();
     assert_eq!(block_bytes, expected);
 }
+
+#[test]
+fn detect_corrupted_blocks() {
+    let mut buffer = Vec::new();
+
+    let mut writer = super::DataBlocksWriter::new(Cursor::new(&mut buffer), BlockCompression::None);
+    let mut fragment = writer.fragment(100).unwrap();
+    fragment.write_all(b"0123456789").unwrap();
+    let location = fragment.location();
+    let block_id = writer.block_id(location.block).unwrap();
+    writer.finish().unwrap();
+
+    let last = buffer.len() - 1;
+    buffer[last] = b'X';
+
+    let mut reader = super::DataBlocksReader::new(
+        Cursor::new(&buffer),
+        Version::V2,
+        BookOptions::default().cache_handle(),
+    )
+    .unwrap();
+    let result = reader.with_block(block_id, 0, |b| Vec::from(b));
+
+    match result {
+        Err(PersistenceError::ChecksumMismatch { block_id: id }) => assert_eq!(id, block_id),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+/// Stream that fails to read while `broken` is `true`.
+struct FlakyStream<'a> {
+    inner: Cursor<&'a [u8]>,
+    broken: &'a Cell<bool>,
+}
+
+impl Read for FlakyStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.broken.get() {
+            return Err(io::Error::new(io::ErrorKind::Other, "broken stream"));
+        }
+
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for FlakyStream<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+fn cache_stats() {
+    let write_block = |compression| {
+        let mut buffer = Vec::new();
+        let mut writer = super::DataBlocksWriter::new(Cursor::new(&mut buffer), compression);
+        let mut fragment = writer.fragment(100).unwrap();
+        fragment.write_all(&[b'A'; 100]).unwrap();
+        let location = fragment.location();
+        let block_id = writer.block_id(location.block).unwrap();
+        writer.finish().unwrap();
+        (buffer, block_id)
+    };
+
+    let (buffer, block_id) = write_block(BlockCompression::None);
+    let broken = Cell::new(false);
+
+    let reader = |options: BookOptions| {
+        let stream = FlakyStream {
+            inner: Cursor::new(&buffer[..]),
+            broken: &broken,
+        };
+
+        super::DataBlocksReader::new(stream, Version::V2, options.cache_handle()).unwrap()
+    };
+
+    // Failed reads are not cached.
+    let mut cached = reader(BookOptions::default());
+    broken.set(true);
+    assert!(matches!(
+        cached.with_block(block_id, 0, <[u8]>::len),
+        Err(PersistenceError::Io(_))
+    ));
+
+    broken.set(false);
+    for _ in 0..3 {
+        assert_eq!(cached.with_block(block_id, 0, <[u8]>::len).unwrap(), 100);
+    }
+
+    let stats = cached.cache_stats();
+    assert_eq!((stats.hits(), stats.misses()), (2, 2));
+    assert_eq!(stats.evictions(), 0);
+    assert_eq!(stats.bytes_decompressed(), 0);
+
+    // Without cache, every read is a miss.
+    let mut uncached = reader(BookOptions::default().disable_cache());
+    for _ in 0..3 {
+        assert_eq!(uncached.with_block(block_id, 0, <[u8]>::len).unwrap(), 100);
+    }
+
+    let stats = uncached.cache_stats();
+    assert_eq!((stats.hits(), stats.misses()), (0, 3));
+
+    // Decompressed bytes.
+    #[cfg(feature = "lz4")]
+    {
+        let (buffer, block_id) = write_block(BlockCompression::Lz4);
+        let options = BookOptions::default().cache_bytes(1000);
+        let mut reader =
+            super::DataBlocksReader::new(Cursor::new(&buffer), Version::V2, options.cache_handle())
+                .unwrap();
+
+        for _ in 0..3 {
+            reader.with_block(block_id, 0, |_| ()).unwrap();
+        }
+
+        let stats = reader.cache_stats();
+        assert_eq!((stats.hits(), stats.misses()), (2, 1));
+        assert_eq!(stats.bytes_decompressed(), 100);
+    }
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_compression() {
+    let write_blocks = |compression, threads| {
+        let mut buffer = Vec::new();
+        let mut writer = super::DataBlocksWriter::new(Cursor::new(&mut buffer), compression);
+        writer.set_threads(threads);
+
+        let mut locations = Vec::new();
+        for n in 0..500_u32 {
+            let mut fragment = writer.fragment(u64::from(n) * 10).unwrap();
+            for m in 0..n {
+                write!(fragment, "{}-{};", n, m * m).unwrap();
+            }
+
+            let location = fragment.location();
+
+            // Request some identifiers before the block is written.
+            if n % 100 == 0 {
+                writer.block_id(location.block).unwrap();
+            }
+
+            locations.push(location);
+        }
+
+        let ids: Vec<_> = locations
+            .iter()
+            .map(|l| (writer.block_id(l.block).unwrap(), l.offset))
+            .collect();
+
+        writer.finish().unwrap();
+        (buffer, ids)
+    };
+
+    let compressions = [
+        BlockCompression::None,
+        #[cfg(feature = "deflate")]
+        BlockCompression::Deflate(9),
+        #[cfg(feature = "lz4")]
+        BlockCompression::Lz4,
+        #[cfg(feature = "zstd")]
+        BlockCompression::Zstd {
+            level: 3,
+            dictionary: false,
+        },
+    ];
+
+    for compression in compressions {
+        let (expected, expected_ids) = write_blocks(compression, 1);
+        assert!(expected_ids.last().unwrap().0 > 0);
+
+        for threads in [2, 4] {
+            let (buffer, ids) = write_blocks(compression, threads);
+            assert_eq!(ids, expected_ids);
+            assert!(
+                buffer == expected,
+                "Different output with {} threads",
+                threads
+            );
+        }
+
+        let mut reader = super::DataBlocksReader::new(
+            Cursor::new(&expected),
+            Version::V2,
+            BookOptions::default().cache_handle(),
+        )
+        .unwrap();
+
+        let (block_id, offset) = expected_ids[123];
+        let fragment = reader
+            .with_block(block_id, offset, |b| b[..6].to_vec())
+            .unwrap();
+        assert_eq!(fragment, b"123-0;");
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_dictionary() {
+    let samples: Vec<_> = (0..500)
+        .map(|n| {
+            format!(
+                "<p>Sample {} for the dictionary of <em>Theory</em> books.</p>",
+                n
+            )
+        })
+        .collect();
+
+    let dictionary = super::train_dictionary(&samples).unwrap();
+
+    let mut buffer = Vec::new();
+    let compression = BlockCompression::Zstd {
+        level: 5,
+        dictionary: true,
+    };
+
+    // Identifier `0` is used for blocks without dictionary, so the first
+    // block can't be at the beginning of the stream.
+    let mut stream = Cursor::new(&mut buffer);
+    stream.write_all(b"<prefix>").unwrap();
+
+    let mut writer = super::DataBlocksWriter::new(stream, compression);
+    writer.write_zstd_dictionary(dictionary.clone()).unwrap();
+
+    let mut locations = Vec::new();
+    for n in 0..5000 {
+        let mut fragment = writer.fragment(100).unwrap();
+        write!(fragment, "<p>Page {} of the <em>Theory</em> books.</p>", n).unwrap();
+        locations.push(fragment.location());
+    }
+
+    // A block without dictionary.
+    writer.set_compression(BlockCompression::Zstd {
+        level: 5,
+        dictionary: false,
+    });
+
+    let mut fragment = writer.fragment(u64::MAX).unwrap();
+    fragment.write_all(b"no dictionary").unwrap();
+    locations.push(fragment.location());
+
+    let ids: Vec<_> = locations
+        .iter()
+        .map(|l| (writer.block_id(l.block).unwrap(), l.offset))
+        .collect();
+
+    writer.finish().unwrap();
+
+    // The dictionary is stored once, in the first block.
+    let dictionary_pos = 8 + super::BLOCK_HEADER_SIZE as usize;
+    assert_eq!(
+        &buffer[dictionary_pos..dictionary_pos + dictionary.len()],
+        &dictionary[..]
+    );
+    assert_eq!(
+        buffer
+            .windows(dictionary.len())
+            .filter(|w| *w == &dictionary[..])
+            .count(),
+        1
+    );
+
+    let mut reader = super::DataBlocksReader::new(
+        Cursor::new(&buffer),
+        Version::V2,
+        BookOptions::default().cache_handle(),
+    )
+    .unwrap();
+
+    let (block_id, offset) = ids[1234];
+    let page = reader
+        .with_block(block_id, offset, |b| b[..10].to_vec())
+        .unwrap();
+    assert_eq!(page, b"<p>Page 12");
+
+    let (block_id, offset) = *ids.last().unwrap();
+    let bytes = reader.with_block(block_id, offset, |b| b.to_vec()).unwrap();
+    assert_eq!(bytes, b"no dictionary");
+
+    // The dictionary must be in an uncompressed block.
+    let (block_id, _) = ids[0];
+    let mut corrupted = buffer.clone();
+    let position = block_id as usize + super::BLOCK_HEADER_SIZE as usize;
+    let data_len = u64::from_be_bytes(corrupted[block_id as usize + 1..][..8].try_into().unwrap());
+    let mut data = Vec::new();
+    leb128::write::unsigned(&mut data, ids[1234].0).unwrap();
+    corrupted[position..position + data.len()].copy_from_slice(&data);
+
+    let checksum = crc32c::crc32c(&corrupted[position..position + data_len as usize]);
+    corrupted[block_id as usize + 9..][..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut reader = super::DataBlocksReader::new(
+        Cursor::new(&corrupted),
+        Version::V2,
+        BookOptions::default().cache_handle(),
+    )
+    .unwrap();
+
+    assert!(matches!(
+        reader.with_block(block_id, 0, |_| ()),
+        Err(PersistenceError::Io(_))
+    ));
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn uncompressed_fallback() {
+    let mut buffer = Vec::new();
+
+    let mut writer = super::DataBlocksWriter::new(Cursor::new(&mut buffer), BlockCompression::Lz4);
+
+    // Pseudo-random bytes can't be compressed.
+    let mut state = 0x2545_f491_u32;
+    let noise: Vec<u8> = (0..1000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+
+    let mut fragment = writer.fragment(u64::MAX).unwrap();
+    fragment.write_all(&noise).unwrap();
+    let noise_location = fragment.location();
+
+    let mut fragment = writer.fragment(u64::MAX).unwrap();
+    fragment.write_all(&[b'A'; 1000]).unwrap();
+    let text_location = fragment.location();
+
+    // A different compression method closes the active block.
+    writer.set_compression(BlockCompression::None);
+    let mut fragment = writer.fragment(10).unwrap();
+    fragment.write_all(&[b'B'; 10]).unwrap();
+    let other_location = fragment.location();
+    assert_ne!(other_location.block, text_location.block);
+
+    let noise_id = writer.block_id(noise_location.block).unwrap();
+    let text_id = writer.block_id(text_location.block).unwrap();
+    let other_id = writer.block_id(other_location.block).unwrap();
+    writer.finish().unwrap();
+
+    assert_eq!(
+        buffer[noise_id as usize],
+        super::BlockType::Uncompressed as u8
+    );
+    assert_eq!(buffer[text_id as usize], super::BlockType::Lz4 as u8);
+    assert_eq!(
+        buffer[other_id as usize],
+        super::BlockType::Uncompressed as u8
+    );
+
+    let mut reader = super::DataBlocksReader::new(
+        Cursor::new(&buffer),
+        Version::V2,
+        BookOptions::default().cache_handle(),
+    )
+    .unwrap();
+
+    let bytes = reader.with_block(noise_id, 0, |b| b.to_vec()).unwrap();
+    assert_eq!(bytes, noise);
+
+    let bytes = reader.with_block(text_id, 0, |b| b.to_vec()).unwrap();
+    assert_eq!(bytes, [b'A'; 1000]);
+}
@@ -1,242 +1,568 @@
 //! Writer for data blocks.
+//!
+//! The data of the active block is kept in memory, and it is compressed when
+//! the block is closed. With the `parallel` feature, blocks can be compressed
+//! in worker threads while the next block is filled. Blocks are always written
+//! in the same order they were created, so the output does not depend on the
+//! number of threads.
 
-use std::io::{self, Seek, SeekFrom, Write};
-use std::mem;
+use std::io::{self, Seek, Write};
 
-use super::BlockCompression;
+use super::{BlockCompression, RawBlock};
 
 #[cfg(feature = "deflate")]
 use flate2::write::DeflateEncoder;
 
-/// Size of the data block.
-const MAX_DATA_BLOCK_SIZE: u64 = 64 * 1024;
+#[cfg(feature = "parallel")]
+use super::pool::Pool;
 
-/// Target of data block data.
-enum Writer<S: Write> {
-    Raw(S),
+#[cfg(feature = "zstd")]
+use std::sync::Arc;
 
-    #[cfg(feature = "deflate")]
-    Deflate(DeflateEncoder<S>),
+/// Default size of the data blocks.
+pub(crate) const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024;
 
-    #[cfg(feature = "lz4")]
-    Lz4(Box<lz4_flex::frame::FrameEncoder<S>>),
+/// A compressed block, ready to be written to the stream.
+pub(super) struct EncodedBlock {
+    tag: u8,
+    data: Vec<u8>,
+    checksum: u32,
 }
 
-impl<W: Write> Writer<W> {
-    fn into_stream(self) -> io::Result<W> {
-        match self {
-            Writer::Raw(r) => Ok(r),
+/// Zstandard dictionary stored in the output.
+#[cfg(feature = "zstd")]
+pub(super) struct ZstdDictionary {
+    /// Block with the dictionary.
+    block_id: u64,
 
-            #[cfg(feature = "deflate")]
-            Writer::Deflate(d) => d.finish(),
+    bytes: Arc<[u8]>,
 
-            #[cfg(feature = "lz4")]
-            Writer::Lz4(l) => l.finish().map_err(super::map_lz4_err),
+    /// Compression level used to prepare the dictionary.
+    level: i32,
+
+    prepared: zstd::dict::EncoderDictionary<'static>,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdDictionary {
+    fn new(block_id: u64, bytes: Arc<[u8]>, level: i32) -> Self {
+        let prepared = zstd::dict::EncoderDictionary::copy(&bytes, level);
+        ZstdDictionary {
+            block_id,
+            bytes,
+            level,
+            prepared,
         }
     }
+}
 
-    fn get_stream(&mut self) -> &mut dyn Write {
-        match self {
-            Writer::Raw(r) => r,
+/// Compression method for a block.
+#[derive(Clone)]
+pub(super) struct BlockEncoder {
+    compression: BlockCompression,
+
+    #[cfg(feature = "zstd")]
+    dictionary: Option<Arc<ZstdDictionary>>,
+}
+
+impl BlockEncoder {
+    /// Compress the data of a block, and compute the checksum of the result.
+    ///
+    /// If the compressed data is not smaller than the original data, the block
+    /// is stored uncompressed.
+    pub(super) fn encode(&self, raw: Vec<u8>) -> io::Result<EncodedBlock> {
+        let compressed: Option<Vec<u8>> = match self.compression {
+            BlockCompression::None => None,
 
             #[cfg(feature = "deflate")]
-            Writer::Deflate(d) => d,
+            BlockCompression::Deflate(level) => {
+                let mut encoder = DeflateEncoder::new(
+                    Vec::with_capacity(raw.len() / 2),
+                    flate2::Compression::new(level),
+                );
+                encoder.write_all(&raw)?;
+                Some(encoder.finish()?)
+            }
 
             #[cfg(feature = "lz4")]
-            Writer::Lz4(l) => l,
+            BlockCompression::Lz4 => {
+                let mut encoder =
+                    lz4_flex::frame::FrameEncoder::new(Vec::with_capacity(raw.len() / 2));
+                encoder.write_all(&raw)?;
+                Some(encoder.finish().map_err(super::map_lz4_err)?)
+            }
+
+            #[cfg(feature = "zstd")]
+            BlockCompression::Zstd { level, .. } => {
+                let (dictionary_id, frame) = match &self.dictionary {
+                    Some(dictionary) => {
+                        let mut compressor =
+                            zstd::bulk::Compressor::with_prepared_dictionary(&dictionary.prepared)?;
+                        (dictionary.block_id, compressor.compress(&raw)?)
+                    }
+
+                    None => (0, zstd::bulk::compress(&raw, level)?),
+                };
+
+                let mut data = Vec::with_capacity(frame.len() + 10);
+                leb128::write::unsigned(&mut data, dictionary_id)?;
+                data.extend_from_slice(&frame);
+                Some(data)
+            }
+        };
+
+        match compressed {
+            Some(data) if data.len() < raw.len() => Ok(EncodedBlock {
+                tag: self.compression.tag() as u8,
+                checksum: crc32c::crc32c(&data),
+                data,
+            }),
+
+            _ => Ok(EncodedBlock::uncompressed(raw)),
+        }
+    }
+}
+
+impl EncodedBlock {
+    fn uncompressed(data: Vec<u8>) -> Self {
+        EncodedBlock {
+            tag: BlockCompression::None.tag() as u8,
+            checksum: crc32c::crc32c(&data),
+            data,
         }
     }
 }
 
-/// Track the active block.
-enum BlockState<S: Write> {
-    Invalid,
+/// Block receiving new fragments.
+struct ActiveBlock {
+    /// Index of the block in the writer.
+    index: u64,
 
-    Wait(S),
+    /// Compression method, selected when the block is created.
+    encoder: BlockEncoder,
 
-    Active {
-        writer: Writer<S>,
-        block_id: u64,
-        offset: u64,
-    },
+    /// Uncompressed data.
+    data: Vec<u8>,
 }
 
 /// Data blocks generator.
+///
+/// Blocks are always written with the format of the latest version.
+///
+/// The identifier of a block is its position in the stream, which is known
+/// only when all previous blocks are written. Fragments are located by the
+/// index of their block, and [`block_id`](Self::block_id) converts it to the
+/// identifier.
 pub(crate) struct DataBlocksWriter<S: Write> {
-    state: BlockState<S>,
+    stream: S,
+
+    /// Position in the stream for the next block. It is read from the stream
+    /// when the first block is written.
+    position: Option<u64>,
 
     compression: BlockCompression,
+
+    /// Target size of the data blocks, before compression.
+    block_size: u64,
+
+    active: Option<ActiveBlock>,
+
+    /// Number of blocks created.
+    created: u64,
+
+    /// Number of blocks closed, and sent to be compressed.
+    closed: u64,
+
+    /// Number of blocks written to the stream.
+    written: u64,
+
+    /// Identifiers of the blocks, by their index.
+    block_ids: Vec<u64>,
+
+    /// Number of threads to compress blocks.
+    #[cfg(feature = "parallel")]
+    threads: usize,
+
+    #[cfg(feature = "parallel")]
+    pool: Option<Pool>,
+
+    /// Dictionary for the blocks compressed with Zstandard.
+    #[cfg(feature = "zstd")]
+    zstd_dictionary: Option<Arc<ZstdDictionary>>,
 }
 
 impl<S: Write + Seek> DataBlocksWriter<S> {
     pub(crate) fn new(stream: S, compression: BlockCompression) -> Self {
         DataBlocksWriter {
-            state: BlockState::Wait(stream),
+            stream,
+            position: None,
             compression,
+            block_size: DEFAULT_BLOCK_SIZE,
+            active: None,
+            created: 0,
+            closed: 0,
+            written: 0,
+            block_ids: Vec::new(),
+
+            #[cfg(feature = "parallel")]
+            threads: 1,
+
+            #[cfg(feature = "parallel")]
+            pool: None,
+
+            #[cfg(feature = "zstd")]
+            zstd_dictionary: None,
         }
     }
 
-    /// Closed the active block and move the writer to `Wait` state.
-    fn close_current(&mut self) -> io::Result<()> {
-        let (mut stream, block_id) = match mem::replace(&mut self.state, BlockState::Invalid) {
-            BlockState::Wait(stream) => (stream, !0),
+    /// Set the compression method for the next fragments.
+    ///
+    /// If the active block uses a different method, it is closed when the next
+    /// fragment is created.
+    pub(crate) fn set_compression(&mut self, compression: BlockCompression) {
+        self.compression = compression;
+    }
+
+    /// Set the target size of the data blocks, before compression.
+    ///
+    /// A block can be larger if it contains a single fragment.
+    pub(crate) fn set_block_size(&mut self, block_size: u64) {
+        self.block_size = block_size;
+    }
+
+    /// Set the number of threads to compress the blocks. With `1`, blocks are
+    /// compressed in the calling thread.
+    ///
+    /// The threads are replaced when the next block is closed.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Write a Zstandard dictionary in a new block. The blocks created after
+    /// this call use it if their compression method is
+    /// [`BlockCompression::Zstd`] with `dictionary` enabled.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn write_zstd_dictionary(&mut self, dictionary: Vec<u8>) -> io::Result<()> {
+        let compression = std::mem::replace(&mut self.compression, BlockCompression::None);
+
+        self.close_current()?;
+        let mut fragment = self.fragment(u64::MAX)?;
+        fragment.write_all(&dictionary)?;
+        let location = fragment.location();
+        self.close_current()?;
+
+        self.compression = compression;
 
-            BlockState::Active {
-                writer, block_id, ..
-            } => (writer.into_stream()?, block_id),
+        // `0` is used in the blocks without dictionary, but a block can't be at
+        // the beginning of a book.
+        let block_id = self.block_id(location.block)?;
+        debug_assert_ne!(block_id, 0);
 
-            BlockState::Invalid => unreachable!(),
+        let level = match compression {
+            BlockCompression::Zstd { level, .. } => level,
+            _ => zstd::DEFAULT_COMPRESSION_LEVEL,
         };
 
-        // Compute how bytes have been written to the stream and update the
-        // block length (4 bytes, big-endian) at the beginning of it.
-        let current_position = stream.stream_position()?;
-        let len = current_position - (block_id + /* tag */ 1 + /* length */ 4);
+        self.zstd_dictionary = Some(Arc::new(ZstdDictionary::new(
+            block_id,
+            dictionary.into(),
+            level,
+        )));
 
-        if len > 0 {
-            let len_bytes = u32::try_from(len)
-                .map_err(|_| {
-                    io::Error::new(io::ErrorKind::Other, "block size can't be written as u32")
-                })?
-                .to_be_bytes();
+        Ok(())
+    }
 
-            stream.seek(SeekFrom::Start(block_id + 1))?;
-            stream.write_all(&len_bytes)?;
-            stream.seek(SeekFrom::Start(current_position))?;
+    /// Return the encoder for a new block.
+    fn encoder(&mut self) -> BlockEncoder {
+        #[cfg(feature = "zstd")]
+        let dictionary = match (self.compression, &self.zstd_dictionary) {
+            (
+                BlockCompression::Zstd {
+                    level,
+                    dictionary: true,
+                },
+                Some(current),
+            ) => {
+                // The dictionary is prepared for a single compression level.
+                if current.level != level {
+                    let dictionary =
+                        ZstdDictionary::new(current.block_id, current.bytes.clone(), level);
+                    self.zstd_dictionary = Some(Arc::new(dictionary));
+                }
+
+                self.zstd_dictionary.clone()
+            }
+
+            _ => None,
+        };
+
+        BlockEncoder {
+            compression: self.compression,
+
+            #[cfg(feature = "zstd")]
+            dictionary,
         }
+    }
 
-        self.state = BlockState::Wait(stream);
+    /// Return the position of the next block in the stream.
+    fn position(&mut self) -> io::Result<u64> {
+        match self.position {
+            Some(position) => Ok(position),
+            None => {
+                let position = self.stream.stream_position()?;
+                self.position = Some(position);
+                Ok(position)
+            }
+        }
+    }
+
+    /// Write the next block to the stream.
+    ///
+    /// Every block starts with the byte-tag, the length (u64), and the
+    /// checksum (u32).
+    fn write_block(&mut self, block: EncodedBlock) -> io::Result<()> {
+        let position = self.position()?;
+
+        // The identifier could be already known if it was requested
+        // with `block_id`.
+        if self.block_ids.len() as u64 == self.written {
+            self.block_ids.push(position);
+        }
+
+        let len = block.data.len() as u64;
+        self.stream.write_all(&[block.tag])?;
+        self.stream.write_all(&len.to_be_bytes())?;
+        self.stream.write_all(&block.checksum.to_be_bytes())?;
+        self.stream.write_all(&block.data)?;
+
+        self.position = Some(position + super::BLOCK_HEADER_SIZE + len);
+        self.written += 1;
 
         Ok(())
     }
 
-    /// Creates a new fragment inside a data block.
-    ///
-    /// The fragment must be closed with its `finish()` function before creating
-    /// another fragment.
-    ///
-    /// `size_hint` is used to determine if a new block should be created to
-    /// store the data.
-    pub(crate) fn fragment(&mut self, size_hint: u64) -> io::Result<Fragment<impl Write + '_>> {
-        let current_offset = match &self.state {
-            BlockState::Active { offset, .. } => *offset,
-            _ => 0,
+    /// Wait until the next closed block is compressed, and write it.
+    #[cfg(feature = "parallel")]
+    fn write_next(&mut self) -> io::Result<()> {
+        let block = match &mut self.pool {
+            Some(pool) => pool.take(self.written)?,
+            None => unreachable!(),
+        };
+
+        self.write_block(block)
+    }
+
+    /// Close the active block, and send it to be compressed.
+    fn close_current(&mut self) -> io::Result<()> {
+        let block = match self.active.take() {
+            Some(block) => block,
+            None => return Ok(()),
         };
 
-        if size_hint == u64::MAX
-            || (current_offset + size_hint > MAX_DATA_BLOCK_SIZE && current_offset > 0)
+        debug_assert_eq!(block.index, self.closed);
+        self.closed += 1;
+
+        #[cfg(feature = "parallel")]
         {
-            self.close_current()?;
-        }
-
-        // Change to `Active` state if it is waiting.
-        //
-        // Every block starts with the byte-tag, and the length (u32).
-        if let BlockState::Wait(_) = self.state {
-            match mem::replace(&mut self.state, BlockState::Invalid) {
-                BlockState::Wait(mut stream) => {
-                    let block_id = stream.stream_position()?;
-
-                    stream.write_all(&[self.compression.tag() as u8, 0, 0, 0, 0])?;
-
-                    let writer = match self.compression {
-                        BlockCompression::None => Writer::Raw(stream),
-
-                        #[cfg(feature = "deflate")]
-                        BlockCompression::Deflate(level) => {
-                            let encoder =
-                                DeflateEncoder::new(stream, flate2::Compression::new(level));
-                            Writer::Deflate(encoder)
-                        }
-
-                        #[cfg(feature = "lz4")]
-                        BlockCompression::Lz4 => {
-                            let encoder = lz4_flex::frame::FrameEncoder::new(stream);
-                            Writer::Lz4(Box::new(encoder))
-                        }
+            self.update_pool()?;
+
+            if let Some(pool) = &mut self.pool {
+                pool.submit(block.index, block.encoder, block.data);
+
+                // Write the blocks that are ready, and limit the memory used
+                // by the pending blocks.
+                let max_pending = 2 * pool.threads();
+                loop {
+                    let pool = self.pool.as_mut().unwrap();
+                    let block = match pool.try_take(self.written) {
+                        Some(block) => block?,
+                        None if pool.pending() > max_pending => pool.take(self.written)?,
+                        None => break,
                     };
 
-                    self.state = BlockState::Active {
-                        writer,
-                        block_id,
-                        offset: 0,
-                    };
+                    self.write_block(block)?;
                 }
 
-                _ => unreachable!(),
+                return Ok(());
             }
         }
 
-        // Extract data from the state.
-        match &mut self.state {
-            BlockState::Active {
-                writer,
-                block_id,
-                offset,
-            } => {
-                let offset_copy = *offset;
-                let fragment = Fragment {
-                    writer: writer.get_stream(),
-                    writer_offset: offset,
-                    block_id: *block_id,
-                    offset: offset_copy,
-                };
+        self.write_block(block.encoder.encode(block.data)?)
+    }
+
+    /// Add a block copied from another book, and return its index.
+    ///
+    /// The active block is closed, so fragments created after this call are
+    /// in a new block.
+    pub(crate) fn write_raw(&mut self, block: RawBlock) -> io::Result<u64> {
+        self.close_current()?;
 
-                Ok(fragment)
+        let index = self.created;
+        self.created += 1;
+        self.closed += 1;
+
+        let block = EncodedBlock {
+            tag: block.block_type.into(),
+            data: block.data,
+            checksum: block.checksum,
+        };
+
+        // The block has to wait until the previous ones are compressed.
+        #[cfg(feature = "parallel")]
+        if let Some(pool) = &mut self.pool {
+            pool.insert(index, block);
+            return Ok(index);
+        }
+
+        self.write_block(block)?;
+        Ok(index)
+    }
+
+    /// Replace the worker threads if the number of threads was changed.
+    #[cfg(feature = "parallel")]
+    fn update_pool(&mut self) -> io::Result<()> {
+        let current = self.pool.as_ref().map(Pool::threads).unwrap_or(1);
+        if current == self.threads {
+            return Ok(());
+        }
+
+        // The pending blocks have to be written before the threads are
+        // removed. The last block, just closed, is not sent yet.
+        while self.written + 1 < self.closed {
+            self.write_next()?;
+        }
+
+        self.pool = if self.threads > 1 {
+            Some(Pool::new(self.threads))
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+
+    /// Return the identifier of a block, from the index in a
+    /// [`FragmentLocation`].
+    ///
+    /// If the block is not written yet, this function waits until all previous
+    /// blocks are written.
+    pub(crate) fn block_id(&mut self, block: u64) -> io::Result<u64> {
+        #[cfg(feature = "parallel")]
+        while self.block_ids.len() as u64 <= block && self.written < self.closed {
+            self.write_next()?;
+        }
+
+        // The block is the active one, and all previous blocks are written.
+        if self.block_ids.len() as u64 == block {
+            let position = self.position()?;
+            self.block_ids.push(position);
+        }
+
+        Ok(self.block_ids[block as usize])
+    }
+
+    /// Start a group of fragments that should be stored in the same block.
+    ///
+    /// If the group fits in a block, but not in the active one, the active
+    /// block is closed.
+    pub(crate) fn begin_group(&mut self, size: u64) -> io::Result<()> {
+        if let Some(active) = &self.active {
+            let current_offset = active.data.len() as u64;
+            if size <= self.block_size && current_offset + size > self.block_size {
+                self.close_current()?;
             }
+        }
 
-            _ => unreachable!(),
+        Ok(())
+    }
+
+    /// Creates a new fragment inside a data block.
+    ///
+    /// The fragment must be closed with its `finish()` function before creating
+    /// another fragment.
+    ///
+    /// `size_hint` is used to determine if a new block should be created to
+    /// store the data.
+    pub(crate) fn fragment(&mut self, size_hint: u64) -> io::Result<Fragment<'_>> {
+        if let Some(active) = &self.active {
+            let current_offset = active.data.len() as u64;
+            if size_hint == u64::MAX
+                || active.encoder.compression != self.compression
+                || (current_offset + size_hint > self.block_size && current_offset > 0)
+            {
+                self.close_current()?;
+            }
+        }
+
+        if self.active.is_none() {
+            self.active = Some(ActiveBlock {
+                index: self.created,
+                encoder: self.encoder(),
+                data: Vec::new(),
+            });
+
+            self.created += 1;
         }
+
+        let active = self.active.as_mut().unwrap();
+        Ok(Fragment {
+            offset: active.data.len() as u64,
+            block: active.index,
+            data: &mut active.data,
+        })
     }
 
     /// Close any active block, and return the underlying stream.
     pub(crate) fn finish(mut self) -> io::Result<S> {
         self.close_current()?;
 
-        match self.state {
-            BlockState::Wait(stream) => Ok(stream),
-            _ => unreachable!(),
+        #[cfg(feature = "parallel")]
+        while self.written < self.closed {
+            self.write_next()?;
         }
+
+        Ok(self.stream)
     }
 }
 
 /// A fragment inside a data block. It is created with the
-/// [`DataBlocksWriter::data`] function, and can be used to add
+/// [`DataBlocksWriter::fragment`] function, and can be used to add
 /// data to the data block.
-pub(crate) struct Fragment<'a, S> {
-    writer: S,
+pub(crate) struct Fragment<'a> {
+    data: &'a mut Vec<u8>,
 
-    writer_offset: &'a mut u64,
-
-    block_id: u64,
+    block: u64,
 
     offset: u64,
 }
 
 /// Location to get a fragment.
 pub(crate) struct FragmentLocation {
-    pub(crate) block_id: u64,
+    /// Index of the block in the writer. The identifier of the block is
+    /// returned by [`DataBlocksWriter::block_id`].
+    pub(crate) block: u64,
+
     pub(crate) offset: u64,
 }
 
-impl<S> Fragment<'_, S> {
+impl Fragment<'_> {
     /// Finish this fragment and returns its location.
     pub(crate) fn location(self) -> FragmentLocation {
         FragmentLocation {
-            block_id: self.block_id,
+            block: self.block,
             offset: self.offset,
         }
     }
 }
 
-impl<S: Write> Write for Fragment<'_, S> {
+impl Write for Fragment<'_> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        let n = self.writer.write(buf)?;
-        *self.writer_offset += n as u64;
-        Ok(n)
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> Result<(), io::Error> {
-        self.writer.flush()
+        Ok(())
     }
 }
@@ -1,13 +1,24 @@
 //! This module provides the implementation to persist book to files.
 
-use std::io::{self, Read, Seek, Write};
+use std::io::{self, Read, Seek};
 
-use crate::{BlockCompression, MetadataEntry, Page};
+use crate::BookOptions;
+#[cfg(feature = "async")]
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
 
+mod read_at;
 mod v1;
+mod v2;
 
 pub(crate) mod datablock;
 
+pub(crate) use read_at::PositionedReader;
+pub use read_at::ReadAt;
+pub(crate) use v2::Writer;
+
+#[cfg(test)]
+pub(crate) use v2::HEADER_END;
+
 /// Errors related to persistence operations.
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
@@ -26,15 +37,69 @@ pub enum PersistenceError {
 
     #[error("Unable to load page index.")]
     PageError(#[from] crate::page::PageError),
+
+    #[error("Checksum mismatch in the data block {block_id}.")]
+    ChecksumMismatch { block_id: u64 },
+
+    #[error("Checksum mismatch in the {0}.")]
+    SectionChecksumMismatch(Section),
+
+    #[error("Books in version 1 of the file format can't be updated.")]
+    UnsupportedVersion,
+
+    #[error("Invalid volume: {0}.")]
+    InvalidVolume(String),
+}
+
+/// Sections of a book file, outside of the data blocks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Section {
+    /// The header at the beginning of the file.
+    Header,
+
+    /// The metadata table of the book.
+    Metadata,
+
+    /// The page index.
+    PageIndex,
+
+    /// The table to find pages by their keys.
+    KeyTable,
+}
+
+impl std::fmt::Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Section::Header => "header",
+            Section::Metadata => "metadata table",
+            Section::PageIndex => "page index",
+            Section::KeyTable => "key table",
+        })
+    }
 }
 
 /// Expected size for magic numbers.
 const MAGIC_SIZE: usize = 8;
 
+/// Versions of the file format.
+///
+/// Version 1 stores positions and block lengths as `u32`, so it is limited to
+/// files of 4 GiB. Version 2 uses 64-bit numbers. New files are always written
+/// with the latest version.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Version {
+    V1,
+    V2,
+}
+
 /// Load a book from an input, like a file or a byte array.
 ///
 /// The input is expected to be generated  by the [`dump`] function.
-pub(crate) fn load<I>(mut input: I) -> Result<crate::Book<I>, PersistenceError>
+pub(crate) fn load<I>(
+    mut input: I,
+    options: &BookOptions,
+) -> Result<crate::Book<I>, PersistenceError>
 where
     I: Read + Seek,
 {
@@ -44,21 +109,49 @@ where
         .map_err(|_| PersistenceError::InvalidMagic)?;
 
     match &magic {
-        v1::MAGIC => v1::load(input),
+        v1::MAGIC => v1::load(input, options),
+
+        v2::MAGIC => v2::load(input, options),
 
         _ => Err(PersistenceError::InvalidMagic),
     }
 }
 
-/// Dump the content of the book in the output stream.
-pub(crate) fn dump<O>(
-    output: O,
-    pages: &[Page],
-    metadata: &[MetadataEntry],
-    compression: BlockCompression,
-) -> Result<(), PersistenceError>
+/// Load a book from an asynchronous input.
+#[cfg(feature = "async")]
+pub(crate) async fn load_async<I>(
+    mut input: I,
+    options: &BookOptions,
+) -> Result<crate::AsyncBook<I>, PersistenceError>
 where
-    O: Write + Seek,
+    I: AsyncRead + AsyncSeek + Unpin,
 {
-    v1::dump(output, pages, metadata, compression)
+    let mut magic = [0; MAGIC_SIZE];
+    input
+        .read_exact(&mut magic)
+        .await
+        .map_err(|_| PersistenceError::InvalidMagic)?;
+
+    match &magic {
+        v1::MAGIC => v1::load_async(input, options).await,
+
+        v2::MAGIC => v2::load_async(input, options).await,
+
+        _ => Err(PersistenceError::InvalidMagic),
+    }
+}
+
+/// Read `len` bytes at `position` from an asynchronous input.
+///
+/// The returned data can be shorter if the input ends before.
+#[cfg(feature = "async")]
+async fn read_range<I>(input: &mut I, position: u64, len: u64) -> io::Result<Vec<u8>>
+where
+    I: AsyncRead + AsyncSeek + Unpin,
+{
+    input.seek(io::SeekFrom::Start(position)).await?;
+
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes).await?;
+    Ok(bytes)
 }
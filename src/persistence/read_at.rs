@@ -0,0 +1,189 @@
+//! Inputs that can be read at any position, without a shared cursor.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+/// An input that can be read at any position, like a file with `pread`.
+///
+/// Unlike [`Read`] and [`Seek`], reading does not change any state in the
+/// input, so it can be used by many threads at the same time. This is the
+/// input of a [`SharedBook`](crate::SharedBook).
+pub trait ReadAt {
+    /// Read bytes into `buf`, starting at `offset`.
+    ///
+    /// Return the number of bytes read, which is `0` if `offset` is beyond
+    /// the end of the input.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Return the size, in bytes, of the input.
+    fn size(&self) -> io::Result<u64>;
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let bytes = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.get(offset..))
+            .unwrap_or_default();
+
+        let len = buf.len().min(bytes.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        Ok(len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self[..].read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self[..].size()
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl ReadAt for std::fs::File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        file_read_at(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+#[cfg(unix)]
+fn file_read_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// `seek_read` moves the cursor of the file, but it is not used by any other
+/// operation.
+#[cfg(windows)]
+fn file_read_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(feature = "mmap")]
+impl ReadAt for memmap2::Mmap {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self[..].read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self[..].size()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Arc<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+/// Adapter to use a [`ReadAt`] input as a [`Read`] + [`Seek`] stream.
+///
+/// The position is stored in the adapter, so every reader can have its own
+/// adapter for the same input.
+pub(crate) struct PositionedReader<R> {
+    input: R,
+    position: u64,
+    len: u64,
+}
+
+impl<R: ReadAt> PositionedReader<R> {
+    pub(crate) fn new(input: R, len: u64) -> Self {
+        PositionedReader {
+            input,
+            position: 0,
+            len,
+        }
+    }
+}
+
+impl<R: ReadAt> Read for PositionedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read_at(buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: ReadAt> Seek for PositionedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => add_offset(self.len, n),
+            SeekFrom::Current(n) => add_offset(self.position, n),
+        };
+
+        match position {
+            Some(n) => {
+                self.position = n;
+                Ok(n)
+            }
+
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Add a signed offset to a position, if the result is valid.
+fn add_offset(position: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        position.checked_add(offset as u64)
+    } else {
+        position.checked_sub(offset.unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PositionedReader, ReadAt};
+    use std::io::{Read, Seek, SeekFrom};
+
+    #[test]
+    fn read_slices() {
+        let input = b"0123456789".to_vec();
+
+        let mut buf = [0; 4];
+        assert_eq!(input.read_at(&mut buf, 2).unwrap(), 4);
+        assert_eq!(&buf, b"2345");
+
+        assert_eq!(input.read_at(&mut buf, 8).unwrap(), 2);
+        assert_eq!(&buf[..2], b"89");
+
+        assert_eq!(input.read_at(&mut buf, 100).unwrap(), 0);
+
+        let mut reader = PositionedReader::new(&input, input.size().unwrap());
+        assert_eq!(reader.seek(SeekFrom::End(-3)).unwrap(), 7);
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "789");
+
+        assert!(reader.seek(SeekFrom::Current(-20)).is_err());
+    }
+}
@@ -1,10 +1,14 @@
 //! Version 1 of the book files.
 
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Read, Seek};
 
-use super::PersistenceError;
+#[cfg(feature = "async")]
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncSeek};
+
+use super::{PersistenceError, Version};
 use crate::persistence::datablock::DataBlocksReader;
-use crate::{metadata, page, BlockCompression, Book, MetadataEntry, Page};
+use crate::search::analysis::Registry;
+use crate::{page, Book};
 
 use endiannezz::Io;
 
@@ -16,6 +20,10 @@ use endiannezz::Io;
 /// Byte `01` can be used to identify the version number.
 pub(super) const MAGIC: &[u8; super::MAGIC_SIZE] = b"\x89\x01THRPKG";
 
+/// Size of the `Header` fields.
+#[cfg(feature = "async")]
+const HEADER_SIZE: usize = 16;
+
 #[derive(Io)]
 #[endian(big)]
 struct Header {
@@ -25,129 +33,137 @@ struct Header {
     fts_pos: u32,
 }
 
-pub(super) fn load<I>(mut input: I) -> Result<crate::Book<I>, PersistenceError>
+pub(super) fn load<I>(
+    mut input: I,
+    options: &crate::BookOptions,
+) -> Result<crate::Book<I>, PersistenceError>
 where
     I: Read + Seek,
 {
     let header = Header::read(&mut input)?;
 
     let num_pages = header.num_pages.try_into()?;
-    let page_index = page::Index::new(&mut input, num_pages, header.pages_pos.into())?;
+    let page_index = page::Index::new(&mut input, num_pages, header.pages_pos.into(), Version::V1)?;
+
+    let fts_pos = match header.fts_pos {
+        u32::MAX => None,
+        pos => Some(pos.into()),
+    };
 
     let book = Book {
-        data_blocks: DataBlocksReader::new(input)?,
+        data_blocks: DataBlocksReader::new(input, Version::V1, options.cache_handle())?,
         num_pages,
         metadata_pos: header.metadata_pos.try_into()?,
         page_index,
+        fts_pos,
+        search_index: None,
+        analyzers: Registry::default(),
     };
 
     Ok(book)
 }
 
-pub(super) fn dump<O>(
-    mut output: O,
-    pages: &[Page],
-    metadata: &[MetadataEntry],
-    compression: BlockCompression,
-) -> Result<(), PersistenceError>
+#[cfg(feature = "async")]
+pub(super) async fn load_async<I>(
+    mut input: I,
+    options: &crate::BookOptions,
+) -> Result<crate::AsyncBook<I>, PersistenceError>
 where
-    O: Write + Seek,
+    I: AsyncRead + AsyncSeek + Unpin,
 {
-    macro_rules! to_u32 {
-        ($v:expr) => {
-            u32::try_from($v).map_err(|_| PersistenceError::TooManyPages)?
-        };
-    }
-
-    let mut header = Header {
-        num_pages: to_u32!(pages.len()),
-        metadata_pos: !0,
-        pages_pos: !0,
-        fts_pos: !0,
-    };
-
-    let beginning = output.stream_position()?;
-
-    // The magic number must be at the beginning of the stream.
-    output.write_all(MAGIC)?;
-
-    // Write the (incomplete) header data to reserve its space in the stream.
-    header.write(&mut output)?;
-
-    // The metadata table.
-    header.metadata_pos = to_u32!(output.stream_position()? - beginning);
-    metadata::dump(&mut output, metadata)?;
-
-    // The pages table.
-    let page_pos = page::persistence::dump_pages(&mut output, pages.iter(), compression)?;
-    header.pages_pos = to_u32!(page_pos - beginning);
-
-    // TODO Write a table for the FTS index.
+    use crate::persistence::datablock::AsyncDataBlocksReader;
+    use page::persistence::INDEX_ENTRY_V1_SIZE;
+    use std::io::Cursor;
 
-    // Write the final header.
-    output.seek(SeekFrom::Start(beginning + MAGIC.len() as u64))?;
-    header.write(&mut output)?;
+    let mut header_bytes = [0; HEADER_SIZE];
+    input.read_exact(&mut header_bytes).await?;
+    let header = Header::read(&header_bytes[..])?;
 
-    Ok(())
+    let num_pages = header.num_pages.try_into()?;
+    let page_index_bytes = super::read_range(
+        &mut input,
+        header.pages_pos.into(),
+        u64::from(header.num_pages) * INDEX_ENTRY_V1_SIZE as u64,
+    )
+    .await?;
+
+    let page_index = page::Index::new(Cursor::new(page_index_bytes), num_pages, 0, Version::V1)?;
+
+    // The length of the metadata table is not in the header, but the table
+    // is written before any other data.
+    let metadata_pos = u64::from(header.metadata_pos);
+    let metadata_end = page_index
+        .into_iter()
+        .flat_map(|(_, entry)| entry.block_ids())
+        .chain([header.pages_pos.into(), header.fts_pos.into()])
+        .filter(|pos| *pos > metadata_pos)
+        .min()
+        .unwrap_or(metadata_pos);
+
+    let metadata = super::read_range(&mut input, metadata_pos, metadata_end - metadata_pos).await?;
+
+    let data_blocks =
+        AsyncDataBlocksReader::new(input, Version::V1, options.cache_handle()).await?;
+
+    Ok(crate::AsyncBook {
+        data_blocks,
+        num_pages,
+        metadata,
+        page_index,
+    })
 }
 
+// `testdata/v1.book` was written by `BookBuilder::dump` in commit 8bb9a8d,
+// before the version 2 of the format. It has no compression and no search
+// index, and it was created with:
+//
+//     let mut builder = Book::builder();
+//     builder.add_metadata(MetadataEntry::Title("Theory Example".into()));
+//     builder.add_metadata(MetadataEntry::Date(1234));
+//
+//     let first = builder
+//         .new_page("First")
+//         .add_metadata(MetadataEntry::Keyword("abcdef".into()))
+//         .set_content("A contiguous growable array.")
+//         .id();
+//
+//     builder
+//         .new_page("Second")
+//         .set_parent(first)
+//         .set_content("A hash map.");
 #[test]
-fn dump_and_load() {
-    use crate::{Book, MetadataEntry};
+fn load_v1_file() {
+    use crate::errors::SearchError;
+    use crate::{MetadataEntry, PageId};
     use std::io::Cursor;
 
-    let metadata = [
-        MetadataEntry::Title("Theory Example".into()),
-        MetadataEntry::Date(1234),
-    ];
-
-    let mut builder = Book::builder();
-
-    for entry in &metadata {
-        builder.add_metadata(entry.clone());
-    }
-
-    let page1 = builder
-        .new_page("First")
-        .add_metadata(MetadataEntry::Keyword("abcdef".into()))
-        .set_content("- 1 -")
-        .clone();
+    let mut book = Book::load(Cursor::new(&include_bytes!("testdata/v1.book")[..])).unwrap();
 
-    let page2 = builder
-        .new_page("Second")
-        .set_parent(page1.id())
-        .add_metadata(MetadataEntry::Keyword("abc, def".into()))
-        .set_content("- 2 -")
-        .clone();
-
-    let mut buffer: Vec<u8> = Vec::new();
-    builder
-        .dump(Cursor::new(&mut buffer))
-        .expect("BookBuilder::dump");
-
-    let mut book = Book::load(Cursor::new(buffer)).unwrap();
-
-    // Check metadata.
-    let pkg_metadata: Vec<_> = book
+    let metadata: Vec<_> = book
         .metadata()
         .expect("Invalid metadata")
         .map(|entry| entry.expect("Invalid entry"))
         .collect();
 
-    assert_eq!(pkg_metadata[..], metadata[..]);
+    assert_eq!(
+        metadata,
+        [
+            MetadataEntry::Title("Theory Example".into()),
+            MetadataEntry::Date(1234)
+        ]
+    );
 
-    // Load a single page.
-    let found_page = book.get_page_by_id(page2.id()).unwrap();
-    assert_eq!(found_page, page2);
+    assert_eq!(book.num_pages(), 2);
 
-    // Check pages iterator.
-    let mut pages: Vec<_> = book
-        .pages()
-        .map(|page| page.expect("Invalid page"))
-        .collect();
+    let page = book.get_page_by_id(PageId::force_value(2)).unwrap();
+    assert_eq!(page.parent(), Some(PageId::force_value(1)));
+    assert_eq!(page.content(), b"A hash map.");
 
-    pages.sort_by_key(|page| page.id());
+    let page = book.get_page_by_id(PageId::force_value(1)).unwrap();
+    assert_eq!(page.content(), b"A contiguous growable array.");
 
-    assert_eq!(book.num_pages(), 2);
-    assert_eq!(pages[..], [page1, page2][..]);
+    assert!(matches!(
+        book.search("growable"),
+        Err(SearchError::MissingIndex)
+    ));
 }
@@ -0,0 +1,774 @@
+//! Version 2 of the book files.
+//!
+//! The layout is the same of the version 1, but positions in the header, in
+//! the page index, and in the data blocks are stored as 64-bit numbers, so
+//! books can be larger than 4 GiB.
+//!
+//! The header, the metadata table, the page index, and every data block are
+//! protected with a CRC-32C checksum. The checksum of the header (which
+//! includes the magic string) is stored after it.
+//!
+//! The keys of the pages are stored in a separate table, also protected with a
+//! checksum. Its position and length are stored in the header.
+//!
+//! If the book has no search index, its position in the header is
+//! `u64::MAX`.
+//!
+//! Sections are found from the positions in the header, so their order is not
+//! fixed. The metadata table of the book is written after the search index,
+//! so it can be updated until the last page is written.
+
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use super::{PersistenceError, Section, Version};
+use crate::page::persistence::IndexEntry;
+use crate::page::PageError;
+use crate::persistence::datablock::{
+    DataBlocksReader, DataBlocksWriter, RawBlock, DEFAULT_BLOCK_SIZE,
+};
+use crate::search::analysis::Registry;
+use crate::{metadata, page, search, BlockCompression, Book, MetadataEntry, Page, WriteStats};
+
+use endiannezz::Io;
+
+#[cfg(feature = "async")]
+use crate::persistence::datablock::AsyncDataBlocksReader;
+
+#[cfg(feature = "async")]
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncSeek};
+
+/// Magic string for this version.
+///
+/// It is the same of the version 1, with `02` as the version number.
+pub(super) const MAGIC: &[u8; super::MAGIC_SIZE] = b"\x89\x02THRPKG";
+
+/// Size of the `Header` fields.
+const HEADER_SIZE: usize = 64;
+
+/// Position of the first byte after the header and its checksum.
+#[cfg(test)]
+pub(crate) const HEADER_END: usize = super::MAGIC_SIZE + HEADER_SIZE + 4;
+
+#[derive(Io)]
+#[endian(big)]
+struct Header {
+    num_pages: u32,
+    metadata_pos: u64,
+    metadata_len: u64,
+    metadata_checksum: u32,
+    pages_pos: u64,
+    pages_checksum: u32,
+    fts_pos: u64,
+    keys_pos: u64,
+    keys_len: u64,
+    keys_checksum: u32,
+}
+
+impl Header {
+    /// Write the header, followed by its checksum.
+    fn write_with_checksum(&self, mut output: impl Write) -> Result<(), PersistenceError> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        self.write(&mut bytes)?;
+
+        let checksum = crc32c::crc32c_append(crc32c::crc32c(MAGIC), &bytes);
+        output.write_all(&bytes)?;
+        output.write_all(&checksum.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+/// Parse the header, and verify its checksum.
+fn parse_header(bytes: &[u8; HEADER_SIZE + 4]) -> Result<Header, PersistenceError> {
+    let (header_bytes, checksum) = bytes.split_at(HEADER_SIZE);
+
+    if crc32c::crc32c_append(crc32c::crc32c(MAGIC), header_bytes)
+        != u32::from_be_bytes(checksum.try_into().unwrap())
+    {
+        return Err(PersistenceError::SectionChecksumMismatch(Section::Header));
+    }
+
+    Ok(Header::read(header_bytes)?)
+}
+
+/// Read a section of the file, and verify its checksum.
+fn read_section(
+    mut input: impl Read + Seek,
+    position: u64,
+    len: u64,
+    checksum: u32,
+    section: Section,
+) -> Result<Vec<u8>, PersistenceError> {
+    input.seek(SeekFrom::Start(position))?;
+
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+
+    check_section(bytes, len, checksum, section)
+}
+
+/// Verify the length and the checksum of a section.
+fn check_section(
+    bytes: Vec<u8>,
+    len: u64,
+    checksum: u32,
+    section: Section,
+) -> Result<Vec<u8>, PersistenceError> {
+    if bytes.len() as u64 != len || crc32c::crc32c(&bytes) != checksum {
+        return Err(PersistenceError::SectionChecksumMismatch(section));
+    }
+
+    Ok(bytes)
+}
+
+pub(super) fn load<I>(
+    mut input: I,
+    options: &crate::BookOptions,
+) -> Result<crate::Book<I>, PersistenceError>
+where
+    I: Read + Seek,
+{
+    let mut header_bytes = [0; HEADER_SIZE + 4];
+    input.read_exact(&mut header_bytes)?;
+    let header = parse_header(&header_bytes)?;
+
+    // The metadata table is read on demand, but its checksum is verified
+    // when the book is loaded.
+    read_section(
+        &mut input,
+        header.metadata_pos,
+        header.metadata_len,
+        header.metadata_checksum,
+        Section::Metadata,
+    )?;
+
+    let num_pages = header.num_pages.try_into()?;
+    let page_index_bytes = read_section(
+        &mut input,
+        header.pages_pos,
+        header.num_pages as u64 * page::persistence::INDEX_ENTRY_SIZE as u64,
+        header.pages_checksum,
+        Section::PageIndex,
+    )?;
+
+    let mut page_index =
+        page::Index::new(Cursor::new(page_index_bytes), num_pages, 0, Version::V2)?;
+
+    if header.keys_len > 0 {
+        let keys_bytes = read_section(
+            &mut input,
+            header.keys_pos,
+            header.keys_len,
+            header.keys_checksum,
+            Section::KeyTable,
+        )?;
+
+        page_index.set_keys(page::persistence::read_keys(&keys_bytes)?)?;
+    }
+
+    let fts_pos = match header.fts_pos {
+        u64::MAX => None,
+        pos => Some(pos),
+    };
+
+    let book = Book {
+        data_blocks: DataBlocksReader::new(input, Version::V2, options.cache_handle())?,
+        num_pages,
+        metadata_pos: header.metadata_pos.try_into()?,
+        page_index,
+        fts_pos,
+        search_index: None,
+        analyzers: Registry::default(),
+    };
+
+    Ok(book)
+}
+
+#[cfg(feature = "async")]
+pub(super) async fn load_async<I>(
+    mut input: I,
+    options: &crate::BookOptions,
+) -> Result<crate::AsyncBook<I>, PersistenceError>
+where
+    I: AsyncRead + AsyncSeek + Unpin,
+{
+    let mut header_bytes = [0; HEADER_SIZE + 4];
+    input.read_exact(&mut header_bytes).await?;
+    let header = parse_header(&header_bytes)?;
+
+    // The metadata table is kept in memory.
+    let metadata = check_section(
+        super::read_range(&mut input, header.metadata_pos, header.metadata_len).await?,
+        header.metadata_len,
+        header.metadata_checksum,
+        Section::Metadata,
+    )?;
+
+    let num_pages = header.num_pages.try_into()?;
+    let page_index_len = header.num_pages as u64 * page::persistence::INDEX_ENTRY_SIZE as u64;
+    let page_index_bytes = check_section(
+        super::read_range(&mut input, header.pages_pos, page_index_len).await?,
+        page_index_len,
+        header.pages_checksum,
+        Section::PageIndex,
+    )?;
+
+    let mut page_index =
+        page::Index::new(Cursor::new(page_index_bytes), num_pages, 0, Version::V2)?;
+
+    if header.keys_len > 0 {
+        let keys_bytes = check_section(
+            super::read_range(&mut input, header.keys_pos, header.keys_len).await?,
+            header.keys_len,
+            header.keys_checksum,
+            Section::KeyTable,
+        )?;
+
+        page_index.set_keys(page::persistence::read_keys(&keys_bytes)?)?;
+    }
+
+    let data_blocks =
+        AsyncDataBlocksReader::new(input, Version::V2, options.cache_handle()).await?;
+
+    Ok(crate::AsyncBook {
+        data_blocks,
+        num_pages,
+        metadata,
+        page_index,
+    })
+}
+
+/// Writer for books in this version.
+///
+/// The header is written when the writer is created, and it is updated
+/// in [`finish`](Self::finish). The content of every page is written as
+/// soon as it is added. The page index, the search index, and the metadata
+/// table are written at the end of the stream.
+pub(crate) struct Writer<O: Write> {
+    pages: page::persistence::PagesWriter<O>,
+
+    /// Builder for the search index, or `None` if the book has no index.
+    search_index: Option<search::persistence::IndexWriter>,
+
+    /// Keys of the pages, with their identifiers.
+    keys: BTreeMap<String, u32>,
+
+    /// Position of the magic string in the output stream.
+    beginning: u64,
+
+    compression: BlockCompression,
+
+    block_size: u64,
+
+    #[cfg(feature = "parallel")]
+    threads: usize,
+}
+
+impl<O: Write + Seek> Writer<O> {
+    pub(crate) fn new(
+        mut output: O,
+        compression: BlockCompression,
+    ) -> Result<Self, PersistenceError> {
+        let beginning = output.stream_position()?;
+
+        // The magic number must be at the beginning of the stream.
+        output.write_all(MAGIC)?;
+
+        // Reserve the space for the header. Its checksum is invalid, so the
+        // book can't be loaded if the writer is not finished.
+        output.write_all(&[0; HEADER_SIZE + 4])?;
+
+        Ok(Writer {
+            pages: page::persistence::PagesWriter::new(output, compression),
+            search_index: Some(Default::default()),
+            keys: BTreeMap::new(),
+            beginning,
+            compression,
+            block_size: DEFAULT_BLOCK_SIZE,
+
+            #[cfg(feature = "parallel")]
+            threads: 1,
+        })
+    }
+
+    /// Create a writer to add new sections at the end of an existing book,
+    /// which starts at the beginning of the stream.
+    ///
+    /// The header is not modified until the writer is finished, so the
+    /// previous content of the book is available if the writer is not
+    /// finished.
+    ///
+    /// The search index is written from `search_index`, so it has to include
+    /// the pages from the existing book. If it is `None`, the book is written
+    /// without a search index.
+    pub(crate) fn append(
+        mut output: O,
+        compression: BlockCompression,
+        search_index: Option<search::persistence::IndexWriter>,
+    ) -> Result<Self, PersistenceError> {
+        output.seek(SeekFrom::End(0))?;
+
+        Ok(Writer {
+            pages: page::persistence::PagesWriter::new(output, compression),
+            search_index,
+            keys: BTreeMap::new(),
+            beginning: 0,
+            compression,
+            block_size: DEFAULT_BLOCK_SIZE,
+
+            #[cfg(feature = "parallel")]
+            threads: 1,
+        })
+    }
+
+    /// Set the compression method for the metadata of the pages and the
+    /// search index.
+    pub(crate) fn set_compression(&mut self, compression: BlockCompression) {
+        self.compression = compression;
+        self.pages.set_compression(compression);
+    }
+
+    /// Write a Zstandard dictionary for the pages added after this call.
+    ///
+    /// The dictionary is trained with the content of the pages, so it is not
+    /// used for the search index.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn write_zstd_dictionary(
+        &mut self,
+        dictionary: Vec<u8>,
+    ) -> Result<(), PersistenceError> {
+        self.pages.write_zstd_dictionary(dictionary)?;
+        Ok(())
+    }
+
+    /// Set the number of threads to compress the data blocks.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+        self.pages.set_threads(threads);
+    }
+
+    /// Don't build the search index for the book.
+    pub(crate) fn disable_search_index(&mut self) {
+        self.search_index = None;
+    }
+
+    /// Set the target size of the data blocks.
+    pub(crate) fn set_block_size(&mut self, block_size: u64) {
+        self.block_size = block_size;
+        self.pages.set_block_size(block_size);
+    }
+
+    /// Start a group of pages that should be stored in the same data block.
+    /// `size` is the total size of their content.
+    pub(crate) fn begin_group(&mut self, size: u64) -> Result<(), PersistenceError> {
+        self.pages.begin_group(size)?;
+        Ok(())
+    }
+
+    /// Write the content of a page, and add its terms to the search index.
+    ///
+    /// The content is compressed with `compression`.
+    pub(crate) fn add_page(
+        &mut self,
+        page: &Page,
+        compression: BlockCompression,
+        book_language: Option<&str>,
+        analyzers: &Registry,
+    ) -> Result<(), PersistenceError> {
+        if u32::try_from(self.pages.num_pages() + 1).is_err() {
+            return Err(PersistenceError::TooManyPages);
+        }
+
+        self.add_keys(page.id.get(), page.keys())?;
+        self.pages.add(page, compression)?;
+
+        if let Some(search_index) = &mut self.search_index {
+            search_index.add_page(page, book_language, analyzers)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copy a data block from another book, and return its index in the
+    /// writer, to be used with [`add_page_at`](Self::add_page_at).
+    pub(crate) fn write_raw_block(&mut self, block: RawBlock) -> Result<u64, PersistenceError> {
+        Ok(self.pages.write_raw(block)?)
+    }
+
+    /// Add a page with its content in a block copied with
+    /// [`write_raw_block`](Self::write_raw_block).
+    pub(crate) fn add_page_at(
+        &mut self,
+        page: &Page,
+        block: u64,
+        offset: u64,
+        book_language: Option<&str>,
+        analyzers: &Registry,
+    ) -> Result<(), PersistenceError> {
+        if u32::try_from(self.pages.num_pages() + 1).is_err() {
+            return Err(PersistenceError::TooManyPages);
+        }
+
+        self.add_keys(page.id.get(), page.keys())?;
+        self.pages.add_at(page, block, offset)?;
+
+        if let Some(search_index) = &mut self.search_index {
+            search_index.add_page(page, book_language, analyzers)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a page already stored in the book to the page index, with its
+    /// `keys`.
+    ///
+    /// The page is not added to the search index.
+    pub(crate) fn add_existing(
+        &mut self,
+        entry: IndexEntry,
+        keys: &[String],
+    ) -> Result<(), PersistenceError> {
+        if u32::try_from(self.pages.num_pages() + 1).is_err() {
+            return Err(PersistenceError::TooManyPages);
+        }
+
+        self.add_keys(entry.page_id(), keys.iter().map(String::as_str))?;
+        self.pages.add_existing(entry);
+        Ok(())
+    }
+
+    /// Add the keys of a page to the key table.
+    ///
+    /// Fails if any key is used by another page. In that case, no key is
+    /// added.
+    fn add_keys<'a>(
+        &mut self,
+        page_id: u32,
+        keys: impl Iterator<Item = &'a str>,
+    ) -> Result<(), PersistenceError> {
+        let keys: Vec<_> = keys.collect();
+
+        for key in &keys {
+            if matches!(self.keys.get(*key), Some(id) if *id != page_id) {
+                return Err(PageError::DuplicatedKey((*key).to_owned()).into());
+            }
+        }
+
+        for key in keys {
+            self.keys.insert(key.to_owned(), page_id);
+        }
+
+        Ok(())
+    }
+
+    /// Statistics of the pages written.
+    pub(crate) fn stats(&self) -> WriteStats {
+        self.pages.stats()
+    }
+
+    /// Write the remaining sections and the final header.
+    ///
+    /// On success, returns the output stream, at the end of the book.
+    pub(crate) fn finish(self, metadata: &[MetadataEntry]) -> Result<O, PersistenceError> {
+        self.finish_with(metadata, |_| Ok(()))
+    }
+
+    /// Like [`finish`](Self::finish), but `sync` is called when all sections
+    /// are written, before updating the header, and again after the header
+    /// is written. It can be used to write the data to the storage device,
+    /// so the header never refers to sections that are not stored yet.
+    pub(crate) fn finish_with<S>(
+        self,
+        metadata: &[MetadataEntry],
+        mut sync: S,
+    ) -> Result<O, PersistenceError>
+    where
+        S: FnMut(&mut O) -> io::Result<()>,
+    {
+        let beginning = self.beginning;
+        let num_pages = self.pages.num_pages() as u32;
+
+        // The pages table.
+        let (mut output, page_pos, pages_checksum) = self.pages.finish()?;
+
+        // The key table.
+        let keys_bytes = page::persistence::write_keys(&self.keys)?;
+        let keys_pos = output.stream_position()?;
+        output.write_all(&keys_bytes)?;
+
+        // The search index.
+        let fts_pos = match self.search_index {
+            Some(search_index) => {
+                let mut db_writer = DataBlocksWriter::new(&mut output, self.compression);
+                db_writer.set_block_size(self.block_size);
+
+                #[cfg(feature = "parallel")]
+                db_writer.set_threads(self.threads);
+
+                search_index.write(db_writer)? - beginning
+            }
+
+            None => u64::MAX,
+        };
+
+        // The metadata table.
+        let mut metadata_bytes = Vec::new();
+        metadata::dump(&mut metadata_bytes, metadata)?;
+
+        let metadata_pos = output.stream_position()?;
+        output.write_all(&metadata_bytes)?;
+
+        let end = output.stream_position()?;
+
+        // All sections must be in the output before the header refers to
+        // them.
+        output.flush()?;
+        sync(&mut output)?;
+
+        // Write the final header.
+        let header = Header {
+            num_pages,
+            metadata_pos: metadata_pos - beginning,
+            metadata_len: metadata_bytes.len() as u64,
+            metadata_checksum: crc32c::crc32c(&metadata_bytes),
+            pages_pos: page_pos - beginning,
+            pages_checksum,
+            fts_pos,
+            keys_pos: keys_pos - beginning,
+            keys_len: keys_bytes.len() as u64,
+            keys_checksum: crc32c::crc32c(&keys_bytes),
+        };
+
+        output.seek(SeekFrom::Start(beginning + MAGIC.len() as u64))?;
+        header.write_with_checksum(&mut output)?;
+        output.seek(SeekFrom::Start(end))?;
+        output.flush()?;
+        sync(&mut output)?;
+
+        Ok(output)
+    }
+}
+
+#[test]
+fn dump_and_load() {
+    use crate::{Book, MetadataEntry};
+    use std::io::Cursor;
+
+    let metadata = [
+        MetadataEntry::Title("Theory Example".into()),
+        MetadataEntry::Date(1234),
+    ];
+
+    let mut builder = Book::builder();
+
+    for entry in &metadata {
+        builder.add_metadata(entry.clone());
+    }
+
+    let page1 = builder
+        .new_page("First")
+        .add_metadata(MetadataEntry::Keyword("abcdef".into()))
+        .set_content("- 1 -")
+        .clone();
+
+    let page2 = builder
+        .new_page("Second")
+        .set_parent(page1.id())
+        .add_metadata(MetadataEntry::Keyword("abc, def".into()))
+        .set_content("- 2 -")
+        .clone();
+
+    let mut buffer: Vec<u8> = Vec::new();
+    builder
+        .dump(Cursor::new(&mut buffer))
+        .expect("BookBuilder::dump");
+
+    assert_eq!(&buffer[..MAGIC.len()], MAGIC);
+
+    let mut book = Book::load(Cursor::new(buffer)).unwrap();
+
+    // Check metadata.
+    let pkg_metadata: Vec<_> = book
+        .metadata()
+        .expect("Invalid metadata")
+        .map(|entry| entry.expect("Invalid entry"))
+        .collect();
+
+    assert_eq!(pkg_metadata[..], metadata[..]);
+
+    // Load a single page.
+    let found_page = book.get_page_by_id(page2.id()).unwrap();
+    assert_eq!(found_page, page2);
+
+    // Check pages iterator.
+    let mut pages: Vec<_> = book
+        .pages()
+        .map(|page| page.expect("Invalid page"))
+        .collect();
+
+    pages.sort_by_key(|page| page.id());
+
+    assert_eq!(book.num_pages(), 2);
+    assert_eq!(pages[..], [page1, page2][..]);
+}
+
+#[test]
+fn detect_corruption() {
+    use crate::errors::PageError;
+    use crate::{Book, MetadataEntry};
+    use std::io::Cursor;
+
+    let mut builder = Book::builder();
+    builder.add_metadata(MetadataEntry::Title("Theory Example".into()));
+    let page_id = builder.new_page("First").set_content("- 1 -").id();
+
+    let mut buffer: Vec<u8> = Vec::new();
+    builder
+        .dump(Cursor::new(&mut buffer))
+        .expect("BookBuilder::dump");
+
+    let find = |needle: &[u8]| {
+        buffer
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .unwrap()
+    };
+
+    let corrupt = |position: usize| {
+        let mut buffer = buffer.clone();
+        buffer[position] ^= 0x20;
+        Book::load(Cursor::new(buffer))
+    };
+
+    // Header.
+    assert!(matches!(
+        corrupt(MAGIC.len() + 2),
+        Err(PersistenceError::SectionChecksumMismatch(Section::Header))
+    ));
+
+    // Metadata table.
+    assert!(matches!(
+        corrupt(find(b"Theory")),
+        Err(PersistenceError::SectionChecksumMismatch(Section::Metadata))
+    ));
+
+    // Page index.
+    let header = parse_header(buffer[MAGIC.len()..HEADER_END].try_into().unwrap()).unwrap();
+    assert!(matches!(
+        corrupt(header.pages_pos as usize + 1),
+        Err(PersistenceError::SectionChecksumMismatch(
+            Section::PageIndex
+        ))
+    ));
+
+    // Data block with the page content.
+    let mut book = corrupt(find(b"- 1 -")).unwrap();
+    match book.get_page_by_id(page_id) {
+        Err(PageError::DataBlock(e)) => {
+            assert!(matches!(*e, PersistenceError::ChecksumMismatch { .. }))
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn borrowed_content() {
+    use crate::errors::PageError;
+    use crate::{BlockCompression, Book};
+    use std::borrow::Cow;
+    use std::io::Cursor;
+
+    let dump = |compression| {
+        let mut builder = Book::builder();
+        builder.set_compression(compression);
+        builder.new_page("First").set_content("- 1 -");
+        builder.new_page("Second").set_content("- 2 -");
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+        buffer
+    };
+
+    let page_id = |n| crate::PageId::force_value(n);
+
+    // Uncompressed blocks are borrowed from the input.
+    let buffer = dump(BlockCompression::None);
+    let mut book = Book::load(Cursor::new(&buffer[..])).unwrap();
+
+    for (n, content) in [(1, b"- 1 -"), (2, b"- 2 -")] {
+        match book.page_content(page_id(n)).unwrap() {
+            Cow::Borrowed(bytes) => assert_eq!(bytes, content),
+            Cow::Owned(_) => panic!("Content for page {} is not borrowed", n),
+        }
+    }
+
+    assert!(matches!(
+        book.page_content(page_id(3)),
+        Err(PageError::InvalidId(3))
+    ));
+
+    // Checksums are verified before borrowing a block.
+    let mut corrupted = buffer.clone();
+    let position = buffer.windows(5).position(|w| w == b"- 2 -").unwrap();
+    corrupted[position] = b'*';
+
+    let mut book = Book::load(Cursor::new(corrupted)).unwrap();
+    match book.page_content(page_id(2)) {
+        Err(PageError::DataBlock(e)) => {
+            assert!(matches!(*e, PersistenceError::ChecksumMismatch { .. }))
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    // Blocks that can't be compressed are stored uncompressed.
+    #[cfg(feature = "lz4")]
+    {
+        let buffer = dump(BlockCompression::Lz4);
+        let mut book = Book::load(Cursor::new(&buffer[..])).unwrap();
+        match book.page_content(page_id(2)).unwrap() {
+            Cow::Borrowed(bytes) => assert_eq!(bytes, b"- 2 -"),
+            Cow::Owned(_) => panic!("Uncompressed content is not borrowed"),
+        }
+    }
+
+    // Compressed blocks are copied.
+    #[cfg(feature = "lz4")]
+    {
+        let content = "- 1 -".repeat(100);
+        let mut builder = Book::builder();
+        builder.set_compression(BlockCompression::Lz4);
+        builder.new_page("First").set_content(content.clone());
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        match book.page_content(page_id(1)).unwrap() {
+            Cow::Owned(bytes) => assert_eq!(bytes, content.as_bytes()),
+            Cow::Borrowed(_) => panic!("Compressed content is borrowed"),
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn open_mmap() {
+    use crate::Book;
+
+    let mut builder = Book::builder();
+    let page_id = builder.new_page("First").set_content("- 1 -").id();
+
+    let path = std::env::temp_dir().join(format!("theory-mmap-{}.book", std::process::id()));
+    builder.dump_to_file(&path).unwrap();
+
+    // SAFETY: the file is not modified while the book is open.
+    let mut book = unsafe { Book::open_mmap(&path).unwrap() };
+    assert_eq!(&book.page_content(page_id).unwrap()[..], b"- 1 -");
+    assert_eq!(book.get_page_by_id(page_id).unwrap().content(), b"- 1 -");
+
+    drop(book);
+    std::fs::remove_file(&path).unwrap();
+}
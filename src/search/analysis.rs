@@ -0,0 +1,445 @@
+//! Text analysis for the search index.
+//!
+//! Text is converted to a list of [tokens](Token) by an [`Analyzer`]. The same
+//! analyzer must be used to index a page and to process the queries that look
+//! for it, so its [identifier](Analyzer::id) is recorded in the book.
+//!
+//! The analyzer of every page is selected from its `Language` metadata entry,
+//! or from the one of the book if the page does not have it.
+//!
+//! # Built-in Analyzers
+//!
+//! [`TextAnalyzer`] combines a [`Tokenizer`] with a list of [`TokenFilter`]s.
+//! The analyzers provided by the crate are described in its documentation.
+//!
+//! # Custom Analyzers
+//!
+//! Any type implementing [`Analyzer`] can be registered with
+//! [`BookBuilder::add_analyzer`](crate::BookBuilder::add_analyzer). The book
+//! only stores the identifier of the analyzer, so readers have to register the
+//! same analyzer with [`Book::add_analyzer`](crate::Book::add_analyzer).
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Identifier of the analyzer used when no language is specified.
+pub(crate) const STANDARD_ID: &str = "standard";
+
+/// Identifiers of the built-in analyzers.
+const BUILTIN_IDS: &[&str] = &[STANDARD_ID, "en", "es", "ja", "de", "fr", "it", "pt"];
+
+/// A single unit of text, as produced by a [`Tokenizer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'a> {
+    /// Text of the token.
+    pub text: Cow<'a, str>,
+
+    /// Position of the token in the list produced by the tokenizer.
+    ///
+    /// Positions are kept when filters remove tokens.
+    pub position: usize,
+
+    /// Range, in bytes, of the token in the original text.
+    pub offset: Range<usize>,
+}
+
+/// Split a text in tokens.
+pub trait Tokenizer: Send + Sync {
+    /// Return the tokens found in `text`.
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<Token<'a>>;
+}
+
+/// Transform or remove the tokens produced by a [`Tokenizer`].
+pub trait TokenFilter: Send + Sync {
+    /// Return the new token, or `None` if it has to be removed.
+    fn filter<'a>(&self, token: Token<'a>) -> Option<Token<'a>>;
+}
+
+/// Convert a text into the terms stored in the search index.
+pub trait Analyzer: Send + Sync {
+    /// Identifier of the analyzer.
+    ///
+    /// It is stored in the book, so readers can use the same analyzer for
+    /// queries.
+    fn id(&self) -> &str;
+
+    /// Return the tokens found in `text`.
+    fn analyze<'a>(&self, text: &'a str) -> Vec<Token<'a>>;
+}
+
+/// Tokenizer using the word boundaries defined by
+/// [Unicode](https://www.unicode.org/reports/tr29/).
+///
+/// Only words with alphanumeric characters are returned.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UnicodeTokenizer;
+
+impl Tokenizer for UnicodeTokenizer {
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<Token<'a>> {
+        text.unicode_word_indices()
+            .enumerate()
+            .map(|(position, (start, word))| Token {
+                text: Cow::Borrowed(word),
+                position,
+                offset: start..start + word.len(),
+            })
+            .collect()
+    }
+}
+
+/// Convert tokens to lowercase.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LowercaseFilter;
+
+impl TokenFilter for LowercaseFilter {
+    fn filter<'a>(&self, mut token: Token<'a>) -> Option<Token<'a>> {
+        if token.text.chars().any(char::is_uppercase) {
+            token.text = Cow::Owned(token.text.to_lowercase());
+        }
+
+        Some(token)
+    }
+}
+
+/// Remove diacritical marks, so `canción` is converted to `cancion`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AccentFoldingFilter;
+
+impl TokenFilter for AccentFoldingFilter {
+    fn filter<'a>(&self, mut token: Token<'a>) -> Option<Token<'a>> {
+        use unicode_normalization::char::is_combining_mark;
+        use unicode_normalization::UnicodeNormalization;
+
+        if !token.text.is_ascii() {
+            let folded = token.text.nfd().filter(|c| !is_combining_mark(*c)).nfc();
+            token.text = Cow::Owned(folded.collect());
+        }
+
+        Some(token)
+    }
+}
+
+/// Remove tokens found in a list of words.
+#[derive(Debug, Default, Clone)]
+pub struct StopWordsFilter {
+    words: HashSet<Cow<'static, str>>,
+}
+
+impl StopWordsFilter {
+    /// Create a filter to remove the words in `words`.
+    pub fn new<W, I>(words: I) -> Self
+    where
+        W: Into<Cow<'static, str>>,
+        I: IntoIterator<Item = W>,
+    {
+        StopWordsFilter {
+            words: words.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl TokenFilter for StopWordsFilter {
+    fn filter<'a>(&self, token: Token<'a>) -> Option<Token<'a>> {
+        if self.words.contains(token.text.as_ref()) {
+            None
+        } else {
+            Some(token)
+        }
+    }
+}
+
+/// Reduce words to their stem, with the
+/// [Snowball](https://snowballstem.org/) algorithms.
+pub struct StemmerFilter {
+    stemmer: rust_stemmers::Stemmer,
+}
+
+impl StemmerFilter {
+    /// Create a filter with the stemmer for `algorithm`.
+    pub fn new(algorithm: rust_stemmers::Algorithm) -> Self {
+        StemmerFilter {
+            stemmer: rust_stemmers::Stemmer::create(algorithm),
+        }
+    }
+}
+
+impl TokenFilter for StemmerFilter {
+    fn filter<'a>(&self, mut token: Token<'a>) -> Option<Token<'a>> {
+        if let Cow::Owned(stem) = self.stemmer.stem(&token.text) {
+            token.text = Cow::Owned(stem);
+        }
+
+        Some(token)
+    }
+}
+
+/// An [`Analyzer`] composed by a [`Tokenizer`] and a list of [`TokenFilter`]s.
+///
+/// ```
+/// use theory::analysis::*;
+///
+/// let analyzer = TextAnalyzer::new("lower", UnicodeTokenizer)
+///     .filter(LowercaseFilter)
+///     .filter(StopWordsFilter::new(["the"]));
+///
+/// let tokens = analyzer.analyze("The Book");
+/// assert_eq!(tokens.len(), 1);
+/// assert_eq!(tokens[0].text, "book");
+/// assert_eq!(tokens[0].position, 1);
+/// ```
+///
+/// # Built-in Analyzers
+///
+/// The crate provides analyzers for the following languages:
+///
+/// | Identifier | Language   | Stop words | Stemmer |
+/// |------------|------------|------------|---------|
+/// | `standard` | (any)      | No         | No      |
+/// | `en`       | English    | Yes        | Yes     |
+/// | `es`       | Spanish    | Yes        | Yes     |
+/// | `ja`       | Japanese   | No         | No      |
+/// | `de`       | German     | No         | Yes     |
+/// | `fr`       | French     | No         | Yes     |
+/// | `it`       | Italian    | No         | Yes     |
+/// | `pt`       | Portuguese | No         | Yes     |
+///
+/// All of them split words with the Unicode segmentation rules, and apply
+/// lowercasing and accent folding. `standard` is used when no language is
+/// specified, or when there is no analyzer for it.
+///
+/// They are available with [`TextAnalyzer::builtin`].
+pub struct TextAnalyzer {
+    id: String,
+    tokenizer: Box<dyn Tokenizer>,
+    filters: Vec<Box<dyn TokenFilter>>,
+}
+
+impl TextAnalyzer {
+    /// Create a new analyzer, without filters.
+    pub fn new(id: impl Into<String>, tokenizer: impl Tokenizer + 'static) -> Self {
+        TextAnalyzer {
+            id: id.into(),
+            tokenizer: Box::new(tokenizer),
+            filters: Vec::new(),
+        }
+    }
+
+    /// Add a filter to the pipeline.
+    ///
+    /// Filters are applied in the same order they are added.
+    pub fn filter(mut self, filter: impl TokenFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Return the built-in analyzer for the identifier `id`.
+    ///
+    /// See the [type documentation](Self#built-in-analyzers) for the available
+    /// analyzers.
+    pub fn builtin(id: &str) -> Option<Self> {
+        use rust_stemmers::Algorithm;
+
+        let algorithm = match id {
+            STANDARD_ID | "ja" => None,
+            "en" => Some(Algorithm::English),
+            "es" => Some(Algorithm::Spanish),
+            "de" => Some(Algorithm::German),
+            "fr" => Some(Algorithm::French),
+            "it" => Some(Algorithm::Italian),
+            "pt" => Some(Algorithm::Portuguese),
+            _ => return None,
+        };
+
+        let stop_words: &[&'static str] = match id {
+            "en" => STOP_WORDS_EN,
+            "es" => STOP_WORDS_ES,
+            _ => &[],
+        };
+
+        let mut analyzer = TextAnalyzer::new(id, UnicodeTokenizer).filter(LowercaseFilter);
+
+        if !stop_words.is_empty() {
+            analyzer = analyzer.filter(StopWordsFilter::new(stop_words.iter().copied()));
+        }
+
+        if let Some(algorithm) = algorithm {
+            analyzer = analyzer.filter(StemmerFilter::new(algorithm));
+        }
+
+        Some(analyzer.filter(AccentFoldingFilter))
+    }
+}
+
+impl Analyzer for TextAnalyzer {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn analyze<'a>(&self, text: &'a str) -> Vec<Token<'a>> {
+        let mut tokens = self.tokenizer.tokenize(text);
+
+        for filter in &self.filters {
+            tokens = tokens
+                .into_iter()
+                .filter_map(|t| filter.filter(t))
+                .collect();
+        }
+
+        tokens
+    }
+}
+
+/// Analyzers available to build or to read a search index.
+///
+/// Custom analyzers have precedence over the built-in ones. The built-in
+/// analyzers are created with the registry, so it can be shared by multiple
+/// searches without modifying it.
+#[derive(Clone)]
+pub(crate) struct Registry {
+    by_language: HashMap<String, Arc<dyn Analyzer>>,
+    by_id: HashMap<String, Arc<dyn Analyzer>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let by_id = BUILTIN_IDS
+            .iter()
+            .map(|&id| {
+                let analyzer = TextAnalyzer::builtin(id).expect("built-in analyzer");
+                (id.to_owned(), Arc::new(analyzer) as Arc<dyn Analyzer>)
+            })
+            .collect();
+
+        Registry {
+            by_language: HashMap::new(),
+            by_id,
+        }
+    }
+}
+
+impl Registry {
+    /// Add a custom analyzer.
+    pub(crate) fn add(&mut self, language: Option<&str>, analyzer: Arc<dyn Analyzer>) {
+        if let Some(language) = language {
+            self.by_language
+                .insert(primary_language(language), Arc::clone(&analyzer));
+        }
+
+        self.by_id.insert(analyzer.id().to_owned(), analyzer);
+    }
+
+    /// Return the analyzer for a `Language` metadata entry.
+    pub(crate) fn for_language(&self, language: Option<&str>) -> Arc<dyn Analyzer> {
+        let language = language.map(primary_language);
+
+        if let Some(analyzer) = language.as_ref().and_then(|l| self.by_language.get(l)) {
+            return Arc::clone(analyzer);
+        }
+
+        match language.as_deref().and_then(|l| self.get(l)) {
+            Some(analyzer) => analyzer,
+            None => self.get(STANDARD_ID).expect("standard analyzer"),
+        }
+    }
+
+    /// Return the analyzer with the identifier `id`.
+    pub(crate) fn get(&self, id: &str) -> Option<Arc<dyn Analyzer>> {
+        self.by_id.get(id).map(Arc::clone)
+    }
+}
+
+/// Extract the primary subtag of a language tag, so `es-ES` is converted to
+/// `es`.
+fn primary_language(tag: &str) -> String {
+    tag.split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+const STOP_WORDS_EN: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+const STOP_WORDS_ES: &[&str] = &[
+    "a", "al", "algo", "como", "con", "de", "del", "el", "ella", "ellos", "en", "entre", "era",
+    "es", "esa", "ese", "eso", "esta", "este", "esto", "fue", "ha", "hay", "la", "las", "le",
+    "les", "lo", "los", "me", "mi", "muy", "más", "no", "nos", "o", "para", "pero", "por", "que",
+    "se", "si", "sin", "sobre", "su", "sus", "también", "te", "tu", "un", "una", "uno", "y", "ya",
+    "él",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(analyzer: &dyn Analyzer, text: &str) -> Vec<String> {
+        analyzer
+            .analyze(text)
+            .into_iter()
+            .map(|t| t.text.into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn builtin_analyzers() {
+        let standard = TextAnalyzer::builtin("standard").unwrap();
+        assert_eq!(
+            terms(&standard, "The HashMap::new() función"),
+            ["the", "hashmap", "new", "funcion"]
+        );
+
+        let en = TextAnalyzer::builtin("en").unwrap();
+        assert_eq!(
+            terms(&en, "The running dogs are jumping"),
+            ["run", "dog", "jump"]
+        );
+
+        let es = TextAnalyzer::builtin("es").unwrap();
+        assert_eq!(terms(&es, "Las canciones más rápidas"), ["cancion", "rap"]);
+
+        let ja = TextAnalyzer::builtin("ja").unwrap();
+        assert!(!terms(&ja, "東京タワー").is_empty());
+
+        assert!(TextAnalyzer::builtin("xx").is_none());
+    }
+
+    #[test]
+    fn token_offsets() {
+        let text = "Élan vital";
+        let tokens = TextAnalyzer::builtin("standard").unwrap().analyze(text);
+
+        assert_eq!(tokens[0].text, "elan");
+        assert_eq!(&text[tokens[0].offset.clone()], "Élan");
+        assert_eq!(tokens[1].position, 1);
+        assert_eq!(&text[tokens[1].offset.clone()], "vital");
+    }
+
+    #[test]
+    fn select_by_language() {
+        let mut registry = Registry::default();
+
+        assert_eq!(registry.for_language(Some("es-ES")).id(), "es");
+        assert_eq!(registry.for_language(Some("EN")).id(), "en");
+        assert_eq!(registry.for_language(Some("tlh")).id(), "standard");
+        assert_eq!(registry.for_language(None).id(), "standard");
+
+        // Built-in analyzers are created only once.
+        assert!(Arc::ptr_eq(
+            &registry.for_language(Some("en")),
+            &registry.get("en").unwrap()
+        ));
+
+        let custom = TextAnalyzer::new("custom-en", UnicodeTokenizer);
+        registry.add(Some("en"), Arc::new(custom));
+
+        assert_eq!(registry.for_language(Some("en-GB")).id(), "custom-en");
+        assert_eq!(registry.get("custom-en").unwrap().id(), "custom-en");
+    }
+}
@@ -0,0 +1,203 @@
+//! Approximate matching of terms.
+//!
+//! The distance between two terms is the Levenshtein distance, where
+//! transposing two adjacent characters counts as a single edit (so `hashmpa`
+//! is at distance `1` from `hashmap`).
+//!
+//! Term dictionaries are sorted, so terms sharing a prefix are adjacent. The
+//! rows of the edit distance matrix computed for a prefix are reused for all
+//! the terms starting with it, and a prefix is skipped as soon as its
+//! distance is larger than the maximum.
+
+/// Maximum edit distance allowed in a fuzzy query.
+pub(crate) const MAX_DISTANCE: u8 = 2;
+
+/// Edit distance used when a fuzzy query does not specify it, computed from
+/// the length of the word.
+pub(crate) fn auto_distance(word: &str) -> u8 {
+    match word.chars().count() {
+        0..=2 => 0,
+        3..=5 => 1,
+        _ => MAX_DISTANCE,
+    }
+}
+
+/// Find terms within a maximum edit distance of a word.
+#[derive(Debug, Clone)]
+pub(crate) struct FuzzyMatcher {
+    word: Vec<char>,
+    max_distance: u8,
+    prefix: bool,
+}
+
+impl FuzzyMatcher {
+    /// Create a matcher for `word`.
+    ///
+    /// If `prefix` is `true`, a term matches if any of its prefixes is within
+    /// `max_distance`.
+    pub(crate) fn new(word: &str, max_distance: u8, prefix: bool) -> Self {
+        FuzzyMatcher {
+            word: word.chars().collect(),
+            max_distance: max_distance.min(MAX_DISTANCE),
+            prefix,
+        }
+    }
+
+    /// Return the distance between the word and `term`, if it is not greater
+    /// than the maximum.
+    pub(crate) fn distance(&self, term: &str) -> Option<u8> {
+        self.find(&[term], |t| t).first().map(|(_, d)| *d)
+    }
+
+    /// Find the matching terms in a sorted list.
+    ///
+    /// Return the index of every matched term, with its distance.
+    pub(crate) fn find<T>(&self, terms: &[T], key: impl Fn(&T) -> &str) -> Vec<(usize, u8)> {
+        let max = usize::from(self.max_distance);
+        let width = self.word.len() + 1;
+
+        // Rows of the matrix for every character in `chars`. `best` tracks
+        // the minimum distance of any prefix of `chars`, for prefix matching.
+        let mut rows: Vec<Vec<usize>> = vec![(0..width).collect()];
+        let mut best = vec![self.word.len()];
+        let mut chars: Vec<char> = Vec::new();
+
+        let mut matches = Vec::new();
+        let mut index = 0;
+
+        while index < terms.len() {
+            let term = key(&terms[index]);
+
+            // Reuse the rows computed for the prefix shared with the
+            // previous term.
+            let common = chars
+                .iter()
+                .zip(term.chars())
+                .take_while(|(a, b)| *a == b)
+                .count();
+
+            rows.truncate(common + 1);
+            best.truncate(common + 1);
+            chars.truncate(common);
+
+            let mut dead_prefix = false;
+            for c in term.chars().skip(common) {
+                let row = self.next_row(&rows, &chars, c);
+                let min = row.iter().copied().min().unwrap_or(0);
+
+                best.push(best[best.len() - 1].min(row[width - 1]));
+                rows.push(row);
+                chars.push(c);
+
+                if min > max {
+                    dead_prefix = true;
+                    break;
+                }
+            }
+
+            if dead_prefix {
+                // No term starting with `chars` can be closer. Since terms
+                // are sorted, all of them are adjacent to the current one.
+                let prefix: String = chars.iter().collect();
+                let len = terms[index..].partition_point(|t| key(t).starts_with(&prefix));
+
+                // With prefix matching, these terms match if a prefix of
+                // `chars` was close enough.
+                let distance = best[best.len() - 1];
+                if self.prefix && distance <= max {
+                    matches.extend((index..index + len).map(|n| (n, distance as u8)));
+                }
+
+                index += len;
+                continue;
+            }
+
+            let distance = if self.prefix {
+                best[best.len() - 1]
+            } else {
+                rows[rows.len() - 1][width - 1]
+            };
+
+            if distance <= max {
+                matches.push((index, distance as u8));
+            }
+
+            index += 1;
+        }
+
+        matches
+    }
+
+    /// Compute the row of the matrix for the character `c`, appended to
+    /// `chars`.
+    fn next_row(&self, rows: &[Vec<usize>], chars: &[char], c: char) -> Vec<usize> {
+        let prev = &rows[rows.len() - 1];
+        let mut row = Vec::with_capacity(prev.len());
+        row.push(prev[0] + 1);
+
+        for (j, &w) in self.word.iter().enumerate() {
+            let cost = usize::from(w != c);
+            let mut value = (prev[j] + cost).min(prev[j + 1] + 1).min(row[j] + 1);
+
+            // Transposition of two adjacent characters.
+            if j > 0 && rows.len() > 1 {
+                if let Some(&last) = chars.last() {
+                    if w == last && self.word[j - 1] == c {
+                        value = value.min(rows[rows.len() - 2][j - 1] + 1);
+                    }
+                }
+            }
+
+            row.push(value);
+        }
+
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FuzzyMatcher;
+
+    #[test]
+    fn edit_distance() {
+        let matcher = FuzzyMatcher::new("hashmpa", 2, false);
+        assert_eq!(matcher.distance("hashmap"), Some(1));
+        assert_eq!(matcher.distance("hashmpa"), Some(0));
+        assert_eq!(matcher.distance("hashset"), None);
+        assert_eq!(matcher.distance("hash"), None);
+
+        let matcher = FuzzyMatcher::new("itre", 1, false);
+        assert_eq!(matcher.distance("iter"), Some(1));
+        assert_eq!(matcher.distance("tree"), None);
+
+        let matcher = FuzzyMatcher::new("café", 1, false);
+        assert_eq!(matcher.distance("cafe"), Some(1));
+    }
+
+    #[test]
+    fn sorted_terms() {
+        let terms = [
+            "array", "hash", "hashmap", "hashmaps", "hashset", "iter", "iterable", "iterator",
+            "map", "zip",
+        ];
+
+        let found = |word, distance, prefix| -> Vec<&str> {
+            FuzzyMatcher::new(word, distance, prefix)
+                .find(&terms, |t| t)
+                .into_iter()
+                .map(|(n, _)| terms[n])
+                .collect()
+        };
+
+        assert_eq!(found("hashmpa", 1, false), ["hashmap"]);
+        assert_eq!(found("hashmpa", 2, false), ["hashmap", "hashmaps"]);
+        assert_eq!(found("itre", 1, true), ["iter", "iterable", "iterator"]);
+        assert_eq!(
+            found("has", 0, true),
+            ["hash", "hashmap", "hashmaps", "hashset"]
+        );
+        assert_eq!(found("mop", 1, false), ["map"]);
+        assert_eq!(found("mop", 2, false), ["map", "zip"]);
+    }
+}
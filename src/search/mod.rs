@@ -0,0 +1,1148 @@
+//! Full-text search over the pages of a book.
+//!
+//! The index is built by [`BookBuilder::dump`](crate::BookBuilder::dump), and
+//! it is stored in the position indicated by the `fts_pos` field of the
+//! header. See the [`persistence`] module for details about the format.
+//!
+//! The index contains the terms found in the content of every page, and in
+//! its `Title`, `Keyword`, `Author` and `User` metadata entries. Terms are
+//! extracted with the [analyzer](analysis::Analyzer) selected for each page.
+//!
+//! Queries are described in the [`query`] module.
+
+pub(crate) mod analysis;
+pub(crate) mod fuzzy;
+pub(crate) mod persistence;
+pub(crate) mod query;
+pub(crate) mod snippets;
+
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+
+use self::analysis::Analyzer;
+use self::fuzzy::FuzzyMatcher;
+use self::query::{Field, Query, QueryError};
+use self::snippets::{Highlighter, SearchOptions, Snippet};
+use crate::page::{self, PageError, PageId};
+use crate::persistence::datablock::DataBlocksReader;
+use crate::persistence::PersistenceError;
+
+/// Errors related to search operations.
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum SearchError {
+    #[error("I/O error: {0}.")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid UTF-8 sequence.")]
+    UnicodeError(#[from] std::string::FromUtf8Error),
+
+    #[error("Failed to read a LEB128 integer: {0}.")]
+    Leb128Error(#[from] leb128::read::Error),
+
+    #[error("Invalid length: {0}.")]
+    InvalidLength(u64),
+
+    #[error("Invalid page identifier: {0}")]
+    InvalidId(u64),
+
+    #[error("Invalid field tag: {0}")]
+    InvalidField(u8),
+
+    #[error("The book does not contain a search index.")]
+    MissingIndex,
+
+    #[error("Unknown analyzer: {0}.")]
+    UnknownAnalyzer(String),
+
+    #[error("Invalid query: {0}")]
+    Query(#[from] QueryError),
+
+    #[error("Unable to read page: {0}")]
+    Page(#[from] PageError),
+
+    #[error("Invalid data block: {0}")]
+    DataBlock(PersistenceError),
+}
+
+impl From<PersistenceError> for SearchError {
+    fn from(e: PersistenceError) -> Self {
+        match e {
+            PersistenceError::Io(e) => SearchError::Io(e),
+            e => SearchError::DataBlock(e),
+        }
+    }
+}
+
+/// Location of the postings list of a term.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TermInfo {
+    /// Number of pages containing the term.
+    pub(crate) doc_freq: u64,
+
+    /// Data block with the postings list.
+    pub(crate) block_id: u64,
+
+    /// Offset in the data block for the postings list.
+    pub(crate) block_offset: u64,
+}
+
+/// BM25 parameter to control the saturation of the term frequency.
+const BM25_K1: f64 = 1.2;
+
+/// BM25 parameter to control the normalization by the field length.
+const BM25_B: f64 = 0.75;
+
+/// Factor applied to the score of a page for every edit needed to match a
+/// fuzzy query.
+const FUZZY_PENALTY: f64 = 0.5;
+
+/// Default value for the `SearchBoost` metadata entry.
+pub(crate) const DEFAULT_BOOST: u32 = 100;
+
+/// A page found by a search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    page_id: PageId,
+    score: f32,
+    snippets: Vec<Snippet>,
+}
+
+impl SearchHit {
+    /// Identifier of the page.
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    /// Relevance of the page for the query. Higher is better.
+    ///
+    /// Scores are only meaningful when compared with other hits of the same
+    /// query.
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+
+    /// Fragments of the content of the page containing the terms of the
+    /// query.
+    ///
+    /// Snippets are only computed if they are requested with
+    /// [`SearchOptions::max_snippets`].
+    pub fn snippets(&self) -> &[Snippet] {
+        &self.snippets
+    }
+}
+
+/// Search index loaded from a book.
+///
+/// The index contains a partition for every analyzer used to build it.
+pub(crate) struct Index {
+    partitions: Vec<Partition>,
+}
+
+/// Terms extracted by a single analyzer.
+struct Partition {
+    /// Identifier of the analyzer.
+    analyzer_id: String,
+
+    /// Data block with the term dictionary.
+    block_id: u64,
+
+    /// Pages in the partition, sorted by their identifiers.
+    docs: Vec<PageId>,
+
+    /// Value of the `SearchBoost` metadata entry of every page in `docs`.
+    boosts: Vec<u32>,
+
+    /// Data of every field.
+    fields: HashMap<Field, FieldIndex>,
+}
+
+/// Terms of a single field in a partition.
+struct FieldIndex {
+    /// Term dictionary, sorted by the term.
+    terms: Vec<(String, TermInfo)>,
+
+    /// Number of terms in the field of every page in `Partition::docs`.
+    lengths: Vec<u32>,
+
+    /// Sum of `lengths`.
+    total_length: u64,
+}
+
+impl FieldIndex {
+    fn new(terms: Vec<(String, TermInfo)>, lengths: Vec<u32>) -> Self {
+        let total_length = lengths.iter().map(|l| u64::from(*l)).sum();
+
+        FieldIndex {
+            terms,
+            lengths,
+            total_length,
+        }
+    }
+}
+
+/// Statistics used to compute the BM25 score: the number of pages, the
+/// length of their fields, and the number of pages containing the terms of
+/// a query.
+///
+/// They are collected from multiple indexes (like the volumes of a
+/// [`VolumeSet`](crate::VolumeSet)) with [`Index::statistics`], so the scores
+/// of the pages in all of them can be compared.
+#[derive(Default)]
+pub(crate) struct Statistics {
+    /// Statistics of every partition, by the analyzer identifier.
+    partitions: HashMap<String, PartitionStatistics>,
+
+    /// Number of pages containing every term of the query.
+    doc_freqs: HashMap<TermKey, usize>,
+}
+
+#[derive(Default)]
+struct PartitionStatistics {
+    num_docs: usize,
+
+    /// Sum of the length of every field.
+    lengths: HashMap<Field, u64>,
+}
+
+/// Key for the document frequencies in `Statistics`: the analyzer, the
+/// field, the term, and how it is found in the term dictionary.
+type TermKey = (String, Field, String, Matching);
+
+impl Statistics {
+    /// Add the statistics from another index.
+    ///
+    /// Indexes must not contain the same pages.
+    pub(crate) fn merge(&mut self, other: Statistics) {
+        for (analyzer_id, partition) in other.partitions {
+            let stats = self.partitions.entry(analyzer_id).or_default();
+            stats.num_docs += partition.num_docs;
+            for (field, length) in partition.lengths {
+                *stats.lengths.entry(field).or_default() += length;
+            }
+        }
+
+        for (key, doc_freq) in other.doc_freqs {
+            *self.doc_freqs.entry(key).or_default() += doc_freq;
+        }
+    }
+}
+
+impl Field {
+    /// Weight of the field in the relevance of a page.
+    fn weight(&self) -> f64 {
+        match self {
+            Field::Title => 3.0,
+            Field::Keyword => 2.0,
+            Field::Content | Field::Author | Field::User(_) => 1.0,
+        }
+    }
+}
+
+impl Partition {
+    /// Find the data for a single term.
+    ///
+    /// The returned slice is empty if the term is not found.
+    fn term(&self, field: &Field, term: &str) -> &[(String, TermInfo)] {
+        let terms = match self.fields.get(field) {
+            Some(f) => &f.terms,
+            None => return &[],
+        };
+
+        match terms.binary_search_by(|(t, _)| t.as_str().cmp(term)) {
+            Ok(n) => &terms[n..=n],
+            Err(_) => &[],
+        }
+    }
+
+    /// Find all terms starting with `prefix`.
+    fn terms_with_prefix(&self, field: &Field, prefix: &str) -> &[(String, TermInfo)] {
+        let terms = match self.fields.get(field) {
+            Some(f) => &f.terms,
+            None => return &[],
+        };
+
+        let start = terms.partition_point(|(t, _)| t.as_str() < prefix);
+        let len = terms[start..].partition_point(|(t, _)| t.starts_with(prefix));
+        &terms[start..start + len]
+    }
+
+    /// Find all terms matched by a [`FuzzyMatcher`], with their distances.
+    fn fuzzy_terms(&self, field: &Field, matcher: &FuzzyMatcher) -> Vec<(&TermInfo, u8)> {
+        let terms = match self.fields.get(field) {
+            Some(f) => &f.terms,
+            None => return Vec::new(),
+        };
+
+        matcher
+            .find(terms, |(t, _)| t)
+            .into_iter()
+            .map(|(n, distance)| (&terms[n].1, distance))
+            .collect()
+    }
+
+    /// Compute the BM25 score of a page for a term found `term_freq` times in
+    /// a field. `avg_length` is the average length of the field.
+    fn bm25(
+        &self,
+        field: &Field,
+        page_id: PageId,
+        term_freq: usize,
+        idf: f64,
+        avg_length: f64,
+    ) -> f64 {
+        let length_norm = match (self.fields.get(field), self.docs.binary_search(&page_id)) {
+            (Some(f), Ok(n)) if avg_length > 0.0 => {
+                let length = f.lengths.get(n).copied().unwrap_or(0) as f64;
+                1.0 - BM25_B + BM25_B * length / avg_length
+            }
+
+            _ => 1.0,
+        };
+
+        let tf = term_freq as f64;
+        field.weight() * idf * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * length_norm)
+    }
+
+    /// Factor from the `SearchBoost` metadata entry of a page.
+    fn boost(&self, page_id: PageId) -> f64 {
+        match self.docs.binary_search(&page_id) {
+            Ok(n) => self.boosts[n] as f64 / DEFAULT_BOOST as f64,
+            Err(_) => 1.0,
+        }
+    }
+}
+
+/// Pages matching a query, with their scores.
+type Scores = HashMap<PageId, f64>;
+
+/// Positions of a term in every page, and the edits needed to match it.
+type TermPostings = (HashMap<PageId, Vec<u32>>, HashMap<PageId, u8>);
+
+impl Index {
+    /// Return the pages matching `query`, sorted by their relevance.
+    ///
+    /// The query is processed with the analyzer of every partition. Each page
+    /// is scored with BM25, and then multiplied by its `SearchBoost` factor.
+    ///
+    /// If `statistics` is `None`, BM25 uses the statistics of this index.
+    pub(crate) fn search<I>(
+        &self,
+        db_reader: &mut DataBlocksReader<I>,
+        page_index: &page::Index,
+        analyzers: &analysis::Registry,
+        query: &Query,
+        options: &SearchOptions,
+        statistics: Option<&Statistics>,
+    ) -> Result<Vec<SearchHit>, SearchError>
+    where
+        I: Read + Seek,
+    {
+        // Score of every page, and the index of its partition.
+        let mut scores: HashMap<PageId, (f64, usize)> = HashMap::new();
+        let mut partition_analyzers = Vec::with_capacity(self.partitions.len());
+
+        for (n, partition) in self.partitions.iter().enumerate() {
+            let analyzer = analyzers
+                .get(&partition.analyzer_id)
+                .ok_or_else(|| SearchError::UnknownAnalyzer(partition.analyzer_id.clone()))?;
+
+            let mut evaluator = Evaluator {
+                partition,
+                analyzer: &*analyzer,
+                db_reader,
+                statistics,
+            };
+
+            if let Some(partition_scores) = evaluator.eval(query, &Field::DEFAULTS)? {
+                scores.extend(
+                    partition_scores
+                        .into_iter()
+                        .map(|(id, score)| (id, (score * partition.boost(id), n))),
+                );
+            }
+
+            partition_analyzers.push(analyzer);
+        }
+
+        let mut hits: Vec<_> = scores.into_iter().collect();
+        hits.sort_by(|(id_a, (a, _)), (id_b, (b, _))| b.total_cmp(a).then(id_a.cmp(id_b)));
+
+        if let Some(limit) = options.limit {
+            hits.truncate(limit);
+        }
+
+        // Highlighters are created only when a partition needs them.
+        let mut highlighters: Vec<Option<Highlighter>> =
+            self.partitions.iter().map(|_| None).collect();
+
+        let mut results = Vec::with_capacity(hits.len());
+        for (page_id, (score, partition)) in hits {
+            let mut snippets = Vec::new();
+
+            if options.max_snippets > 0 {
+                let content = page_index.get_content(db_reader, page_id)?;
+
+                if let Ok(content) = std::str::from_utf8(&content) {
+                    let analyzer = &*partition_analyzers[partition];
+                    let highlighter = highlighters[partition]
+                        .get_or_insert_with(|| Highlighter::new(query, analyzer));
+
+                    snippets = highlighter.snippets(content, analyzer, options);
+                }
+            }
+
+            results.push(SearchHit {
+                page_id,
+                score: score as f32,
+                snippets,
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Collect the statistics of this index to compute the score of the pages
+    /// matching `query`.
+    pub(crate) fn statistics<I>(
+        &self,
+        db_reader: &mut DataBlocksReader<I>,
+        analyzers: &analysis::Registry,
+        query: &Query,
+    ) -> Result<Statistics, SearchError>
+    where
+        I: Read + Seek,
+    {
+        let mut statistics = Statistics::default();
+
+        for partition in &self.partitions {
+            let analyzer = analyzers
+                .get(&partition.analyzer_id)
+                .ok_or_else(|| SearchError::UnknownAnalyzer(partition.analyzer_id.clone()))?;
+
+            let stats = statistics
+                .partitions
+                .entry(partition.analyzer_id.clone())
+                .or_default();
+
+            stats.num_docs += partition.docs.len();
+            for (field, index) in &partition.fields {
+                *stats.lengths.entry(field.clone()).or_default() += index.total_length;
+            }
+
+            let mut evaluator = Evaluator {
+                partition,
+                analyzer: &*analyzer,
+                db_reader,
+                statistics: None,
+            };
+
+            evaluator.doc_freqs(query, &Field::DEFAULTS, &mut statistics.doc_freqs)?;
+        }
+
+        Ok(statistics)
+    }
+
+    /// Find terms to complete `prefix`. See `Book::suggest` for details.
+    pub(crate) fn suggest(
+        &self,
+        analyzers: &analysis::Registry,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, SearchError> {
+        // Edit distance and number of pages of every term.
+        let mut candidates: HashMap<&str, (u8, u64)> = HashMap::new();
+
+        for partition in &self.partitions {
+            let analyzer = analyzers
+                .get(&partition.analyzer_id)
+                .ok_or_else(|| SearchError::UnknownAnalyzer(partition.analyzer_id.clone()))?;
+
+            let tokens = analyzer.analyze(prefix);
+            let word = match tokens.last() {
+                Some(token) => &token.text,
+                None => continue,
+            };
+
+            let matcher = FuzzyMatcher::new(word, fuzzy::auto_distance(word), true);
+
+            for field in &Field::DEFAULTS {
+                let terms = match partition.fields.get(field) {
+                    Some(f) => &f.terms,
+                    None => continue,
+                };
+
+                for (n, distance) in matcher.find(terms, |(t, _)| t) {
+                    let (term, info) = &terms[n];
+                    let candidate = candidates.entry(term).or_insert((distance, 0));
+                    candidate.0 = candidate.0.min(distance);
+                    candidate.1 = candidate.1.max(info.doc_freq);
+                }
+            }
+        }
+
+        let mut candidates: Vec<_> = candidates.into_iter().collect();
+        candidates.sort_by(|(term_a, (dist_a, freq_a)), (term_b, (dist_b, freq_b))| {
+            dist_a
+                .cmp(dist_b)
+                .then(freq_b.cmp(freq_a))
+                .then(term_a.cmp(term_b))
+        });
+
+        Ok(candidates
+            .into_iter()
+            .take(limit)
+            .map(|(term, _)| term.to_owned())
+            .collect())
+    }
+}
+
+/// How the terms of a query are found in the term dictionary.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Matching {
+    /// Terms must be equal.
+    Exact,
+
+    /// The last term of the query is a prefix.
+    Prefix,
+
+    /// Terms within a maximum edit distance.
+    Fuzzy(u8),
+}
+
+/// Compute the pages of a partition matching a query.
+struct Evaluator<'a, I> {
+    partition: &'a Partition,
+    analyzer: &'a dyn Analyzer,
+    db_reader: &'a mut DataBlocksReader<I>,
+
+    /// Statistics to use instead of the ones in `partition`.
+    statistics: Option<&'a Statistics>,
+}
+
+impl<I: Read + Seek> Evaluator<'_, I> {
+    /// Return the pages matching `query` in any of the `fields`.
+    ///
+    /// `None` is returned if the query does not contain any term after being
+    /// processed by the analyzer (for example, if it only contains stop
+    /// words). In that case, the query is ignored by its parent.
+    fn eval(&mut self, query: &Query, fields: &[Field]) -> Result<Option<Scores>, SearchError> {
+        let scores = match query {
+            Query::Term(text) | Query::Phrase(text) => {
+                self.phrase(text, fields, Matching::Exact)?
+            }
+
+            Query::Prefix(text) => self.phrase(text, fields, Matching::Prefix)?,
+
+            Query::Fuzzy(text, distance) => {
+                self.phrase(text, fields, Matching::Fuzzy(*distance))?
+            }
+
+            Query::And(items) => {
+                let mut result: Option<Scores> = None;
+                for item in items {
+                    let scores = match self.eval(item, fields)? {
+                        Some(s) => s,
+                        None => continue,
+                    };
+
+                    result = Some(match result {
+                        None => scores,
+                        Some(mut result) => {
+                            result.retain(|id, _| scores.contains_key(id));
+                            for (id, score) in result.iter_mut() {
+                                *score += scores[id];
+                            }
+                            result
+                        }
+                    });
+                }
+
+                result
+            }
+
+            Query::Or(items) => {
+                let mut result: Option<Scores> = None;
+                for item in items {
+                    if let Some(scores) = self.eval(item, fields)? {
+                        let result = result.get_or_insert_with(Scores::new);
+                        for (id, score) in scores {
+                            *result.entry(id).or_default() += score;
+                        }
+                    }
+                }
+
+                result
+            }
+
+            Query::Not(query) => self.eval(query, fields)?.map(|excluded| {
+                self.partition
+                    .docs
+                    .iter()
+                    .filter(|id| !excluded.contains_key(id))
+                    .map(|id| (*id, 0.0))
+                    .collect()
+            }),
+
+            Query::Field(field, query) => self.eval(query, std::slice::from_ref(field))?,
+        };
+
+        Ok(scores)
+    }
+
+    /// Return the pages containing the terms in `text` in consecutive
+    /// positions.
+    ///
+    /// `matching` controls how the terms are found in the dictionary.
+    fn phrase(
+        &mut self,
+        text: &str,
+        fields: &[Field],
+        matching: Matching,
+    ) -> Result<Option<Scores>, SearchError> {
+        let tokens = self.analyzer.analyze(text);
+
+        let first_position = match tokens.first() {
+            Some(token) => token.position,
+            None => return Ok(None),
+        };
+
+        let mut scores = Scores::new();
+
+        for field in fields {
+            // Positions of every term, and the sum of their IDFs.
+            let mut positions = Vec::with_capacity(tokens.len());
+            let mut idf = 0.0;
+
+            // Edits needed to match the terms in every page.
+            let mut distances: HashMap<PageId, u32> = HashMap::new();
+
+            for (n, token) in tokens.iter().enumerate() {
+                let matching = token_matching(matching, n, tokens.len());
+                let (term_positions, term_distances) =
+                    self.postings(field, &token.text, matching)?;
+
+                for (page_id, d) in term_distances {
+                    *distances.entry(page_id).or_default() += u32::from(d);
+                }
+
+                idf += self.idf(field, &token.text, matching, term_positions.len());
+
+                let offset = (token.position - first_position) as u32;
+                positions.push((offset, term_positions));
+            }
+
+            // Count how many times the terms appear in the expected positions.
+            let (first, rest) = positions.split_first().unwrap();
+            for (page_id, first_positions) in &first.1 {
+                let count = first_positions
+                    .iter()
+                    .filter(|&&start| {
+                        rest.iter().all(|(offset, term_positions)| {
+                            term_positions
+                                .get(page_id)
+                                .map(|p| p.binary_search(&(start + offset)).is_ok())
+                                .unwrap_or(false)
+                        })
+                    })
+                    .count();
+
+                if count > 0 {
+                    let edits = distances.get(page_id).copied().unwrap_or(0);
+                    let avg_length = self.avg_length(field);
+                    let score = self.partition.bm25(field, *page_id, count, idf, avg_length)
+                        * FUZZY_PENALTY.powi(edits as i32);
+                    *scores.entry(*page_id).or_default() += score;
+                }
+            }
+        }
+
+        Ok(Some(scores))
+    }
+
+    /// Return the positions of a term in every page, and the edits needed to
+    /// match it.
+    fn postings(
+        &mut self,
+        field: &Field,
+        term: &str,
+        matching: Matching,
+    ) -> Result<TermPostings, SearchError> {
+        let infos: Vec<(&TermInfo, u8)> = match matching {
+            Matching::Prefix => self
+                .partition
+                .terms_with_prefix(field, term)
+                .iter()
+                .map(|(_, info)| (info, 0))
+                .collect(),
+
+            Matching::Fuzzy(distance) => {
+                let matcher = FuzzyMatcher::new(term, distance, false);
+                self.partition.fuzzy_terms(field, &matcher)
+            }
+
+            Matching::Exact => self
+                .partition
+                .term(field, term)
+                .iter()
+                .map(|(_, info)| (info, 0))
+                .collect(),
+        };
+
+        let mut positions: HashMap<PageId, Vec<u32>> = HashMap::new();
+        let mut distances: HashMap<PageId, u8> = HashMap::new();
+        for (info, distance) in &infos {
+            for (page_id, p) in persistence::load_postings(self.db_reader, info)? {
+                positions.entry(page_id).or_default().extend(p);
+
+                if let Matching::Fuzzy(_) = matching {
+                    let d = distances.entry(page_id).or_insert(*distance);
+                    *d = (*d).min(*distance);
+                }
+            }
+        }
+
+        if infos.len() > 1 {
+            positions.values_mut().for_each(|p| p.sort_unstable());
+        }
+
+        Ok((positions, distances))
+    }
+
+    /// Inverse document frequency for a term found in `doc_freq` pages of
+    /// the partition.
+    fn idf(&self, field: &Field, term: &str, matching: Matching, doc_freq: usize) -> f64 {
+        let (n, df) = match self.statistics {
+            Some(statistics) => {
+                let analyzer_id = &self.partition.analyzer_id;
+                let key = (
+                    analyzer_id.clone(),
+                    field.clone(),
+                    term.to_owned(),
+                    matching,
+                );
+                let num_docs = statistics.partitions.get(analyzer_id).map(|p| p.num_docs);
+
+                (
+                    num_docs.unwrap_or(self.partition.docs.len()),
+                    statistics.doc_freqs.get(&key).copied().unwrap_or(doc_freq),
+                )
+            }
+
+            None => (self.partition.docs.len(), doc_freq),
+        };
+
+        let n = n as f64;
+        let df = df as f64;
+        (1.0 + (n - df + 0.5) / (df + 0.5)).ln()
+    }
+
+    /// Average length of a field in the pages.
+    fn avg_length(&self, field: &Field) -> f64 {
+        let stats = self
+            .statistics
+            .and_then(|s| s.partitions.get(&self.partition.analyzer_id));
+
+        let (total, num_docs) = match stats {
+            Some(stats) => (
+                stats.lengths.get(field).copied().unwrap_or(0),
+                stats.num_docs,
+            ),
+
+            None => (
+                self.partition
+                    .fields
+                    .get(field)
+                    .map(|f| f.total_length)
+                    .unwrap_or(0),
+                self.partition.docs.len(),
+            ),
+        };
+
+        total as f64 / num_docs.max(1) as f64
+    }
+
+    /// Count the pages containing every term of `query`, for the statistics
+    /// described in `Index::statistics`.
+    fn doc_freqs(
+        &mut self,
+        query: &Query,
+        fields: &[Field],
+        doc_freqs: &mut HashMap<TermKey, usize>,
+    ) -> Result<(), SearchError> {
+        match query {
+            Query::Term(text)
+            | Query::Phrase(text)
+            | Query::Prefix(text)
+            | Query::Fuzzy(text, _) => {
+                let matching = match query {
+                    Query::Prefix(_) => Matching::Prefix,
+                    Query::Fuzzy(_, distance) => Matching::Fuzzy(*distance),
+                    _ => Matching::Exact,
+                };
+
+                let tokens = self.analyzer.analyze(text);
+                for field in fields {
+                    for (n, token) in tokens.iter().enumerate() {
+                        let matching = token_matching(matching, n, tokens.len());
+                        let (positions, _) = self.postings(field, &token.text, matching)?;
+
+                        let key = (
+                            self.partition.analyzer_id.clone(),
+                            field.clone(),
+                            token.text.to_string(),
+                            matching,
+                        );
+
+                        *doc_freqs.entry(key).or_default() += positions.len();
+                    }
+                }
+            }
+
+            Query::And(items) | Query::Or(items) => {
+                for item in items {
+                    self.doc_freqs(item, fields, doc_freqs)?;
+                }
+            }
+
+            // Excluded pages are not scored.
+            Query::Not(_) => (),
+
+            Query::Field(field, query) => {
+                self.doc_freqs(query, std::slice::from_ref(field), doc_freqs)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Matching for the token `n` of a phrase with `len` tokens. Only the last
+/// token is used as a prefix.
+fn token_matching(matching: Matching, n: usize, len: usize) -> Matching {
+    match matching {
+        Matching::Prefix if n + 1 < len => Matching::Exact,
+        matching => matching,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::QueryError;
+    use crate::{Book, MetadataEntry, PageId, Query, SearchOptions};
+    use std::io::{Cursor, Read, Seek};
+
+    fn search<I: Read + Seek>(book: &mut Book<I>, query: &str) -> Vec<PageId> {
+        let hits = book.search(query).expect("Book::search");
+        hits.into_iter().map(|hit| hit.page_id()).collect()
+    }
+
+    #[test]
+    fn search_pages() {
+        let mut builder = Book::builder();
+
+        let p1 = builder
+            .new_page("Vectors")
+            .set_content("A contiguous growable array type.")
+            .id();
+
+        let p2 = builder
+            .new_page("HashMap")
+            .add_metadata(MetadataEntry::Keyword("collections".into()))
+            .set_content("A hash map implemented with quadratic probing.")
+            .id();
+
+        let p3 = builder
+            .new_page("Collections")
+            .set_content("Vectors, maps, and other collections. Maps are fast.")
+            .id();
+
+        builder
+            .new_page("Image")
+            .set_content(&[0xFF, 0xFE, 0x00][..]);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+
+        assert_eq!(search(&mut book, "growable"), [p1]);
+        assert_eq!(search(&mut book, "HASHMAP"), [p2]);
+        assert_eq!(search(&mut book, "collections"), [p3, p2]);
+        assert_eq!(search(&mut book, "vectors"), [p1, p3]);
+        assert_eq!(search(&mut book, "map"), [p2]);
+        assert!(search(&mut book, "missing").is_empty());
+    }
+
+    #[test]
+    fn query_language() {
+        let mut builder = Book::builder();
+
+        let p1 = builder
+            .new_page("Iterators")
+            .add_metadata(MetadataEntry::Author("Ferris".into()))
+            .set_content("Iterate over a growable array. The array grows.")
+            .id();
+
+        let p2 = builder
+            .new_page("Arrays")
+            .add_metadata(MetadataEntry::User("crate".into(), "core".into()))
+            .set_content("A fixed array. Arrays are not growable.")
+            .id();
+
+        let p3 = builder
+            .new_page("Maps")
+            .add_metadata(MetadataEntry::Keyword("hash map".into()))
+            .set_content("Iteration order is random.")
+            .id();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+
+        macro_rules! check {
+            ($query:expr, $expected:expr) => {
+                let mut found = search(&mut book, $query);
+                found.sort();
+                assert_eq!(found, $expected, "Query: {}", $query);
+            };
+        }
+
+        check!("array growable", [p1, p2]);
+        check!("\"growable array\"", [p1]);
+        check!("\"array growable\"", []);
+        check!("iter*", [p1, p3]);
+        check!("arrays OR hash", [p2, p3]);
+        check!("array NOT fixed", [p1]);
+        check!("NOT array", [p3]);
+        check!("title:arrays", [p2]);
+        check!("content:iterators", []);
+        check!("author:ferris", [p1]);
+        check!("user:crate:core", [p2]);
+        check!("user:other:core", []);
+        check!("keyword:\"hash map\"", [p3]);
+
+        assert!(matches!(
+            book.search("title:(a"),
+            Err(super::SearchError::Query(QueryError::UnmatchedParen(7)))
+        ));
+    }
+
+    #[test]
+    fn analyzer_per_language() {
+        let mut builder = Book::builder();
+        builder.add_metadata(MetadataEntry::Language("en".into()));
+
+        let p1 = builder
+            .new_page("Running")
+            .set_content("The runner runs.")
+            .id();
+
+        let p2 = builder
+            .new_page("Canciones")
+            .add_metadata(MetadataEntry::Language("es".into()))
+            .set_content("Una canción rápida.")
+            .id();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+
+        assert_eq!(search(&mut book, "run"), [p1]);
+        assert_eq!(search(&mut book, "cancion"), [p2]);
+        assert_eq!(search(&mut book, "rapidas"), [p2]);
+
+        // Stop words are not indexed.
+        assert!(search(&mut book, "the").is_empty());
+    }
+
+    #[test]
+    fn custom_analyzer() {
+        use crate::analysis::{TextAnalyzer, UnicodeTokenizer};
+
+        let analyzer = || TextAnalyzer::new("case-sensitive", UnicodeTokenizer);
+
+        let mut builder = Book::builder();
+        builder.add_analyzer("en", analyzer());
+
+        let p1 = builder
+            .new_page("Page")
+            .add_metadata(MetadataEntry::Language("en".into()))
+            .set_content("HashMap")
+            .id();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        assert!(matches!(
+            book.search("HashMap"),
+            Err(super::SearchError::UnknownAnalyzer(id)) if id == "case-sensitive"
+        ));
+
+        book.add_analyzer(analyzer());
+        assert_eq!(search(&mut book, "HashMap"), [p1]);
+        assert!(search(&mut book, "hashmap").is_empty());
+    }
+
+    #[test]
+    fn ranking() {
+        let mut builder = Book::builder();
+
+        let body = builder
+            .new_page("Reference")
+            .set_content("An iterator over the elements of a slice.")
+            .id();
+
+        let title = builder
+            .new_page("Iterator")
+            .set_content("Trait for iterating over collections.")
+            .id();
+
+        let keyword = builder
+            .new_page("Traits")
+            .add_metadata(MetadataEntry::Keyword("iterator".into()))
+            .set_content("Common traits.")
+            .id();
+
+        let short = builder.new_page("Overview").set_content("iterator").id();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer.clone())).unwrap();
+
+        let hits = book.search("iterator").unwrap();
+        assert!(hits.windows(2).all(|w| w[0].score() >= w[1].score()));
+        assert_eq!(search(&mut book, "iterator"), [title, keyword, short, body]);
+
+        // Boost the reference page.
+        let mut builder = Book::builder();
+        builder
+            .new_page("Reference")
+            .add_metadata(MetadataEntry::SearchBoost(1000))
+            .set_content("An iterator over the elements of a slice.");
+
+        builder.new_page("Overview").set_content("iterator");
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        assert_eq!(search(&mut book, "iterator")[0], body);
+    }
+
+    #[test]
+    fn limit_and_snippets() {
+        let mut builder = Book::builder();
+
+        let p1 = builder
+            .new_page("Vectors")
+            .set_content("A contiguous growable array type, written as Vec<T>.")
+            .id();
+
+        builder
+            .new_page("Arrays")
+            .set_content("A fixed-size array.");
+        builder.new_page("Image").set_content(&[0xFF, 0xFE][..]);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+
+        // Snippets are not computed by default.
+        let hits = book.search("array").unwrap();
+        assert_eq!(hits.len(), 2);
+        assert!(hits.iter().all(|hit| hit.snippets().is_empty()));
+
+        let query = Query::parse("growable array").unwrap();
+        let options = SearchOptions::default().limit(1).max_snippets(1);
+        let hits = book.search_with(&query, &options).unwrap();
+
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].page_id(), p1);
+
+        let snippets = hits[0].snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].offset(), 0);
+
+        let text = snippets[0].text();
+        let highlights: Vec<_> = snippets[0]
+            .highlights()
+            .iter()
+            .map(|range| &text[range.clone()])
+            .collect();
+        assert_eq!(highlights, ["growable", "array"]);
+    }
+
+    #[test]
+    fn fuzzy_search() {
+        let mut builder = Book::builder();
+
+        let hashmap = builder
+            .new_page("HashMap")
+            .set_content("A hash map implemented with quadratic probing.")
+            .id();
+
+        let iterators = builder
+            .new_page("Iterators")
+            .set_content("Composable external iteration. Iterate over any iterable.")
+            .id();
+
+        let hashset = builder
+            .new_page("HashSet")
+            .set_content("A hash set implemented as a HashMap where the value is ().")
+            .id();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+
+        assert!(search(&mut book, "hashmpa").is_empty());
+        assert_eq!(search(&mut book, "hashmpa~"), [hashmap, hashset]);
+        assert_eq!(search(&mut book, "hashmpa~1"), [hashmap, hashset]);
+        assert!(search(&mut book, "hashmpa~0").is_empty());
+        assert_eq!(search(&mut book, "title:hashmpa~"), [hashmap]);
+        assert_eq!(search(&mut book, "itreators~"), [iterators]);
+
+        // Snippets include fuzzy matches.
+        let query = Query::parse("probign~").unwrap();
+        let options = SearchOptions::default().max_snippets(1);
+        let hits = book.search_with(&query, &options).unwrap();
+        let snippet = &hits[0].snippets()[0];
+        assert_eq!(&snippet.text()[snippet.highlights()[0].clone()], "probing");
+
+        // Completions.
+        assert_eq!(
+            book.suggest("hash", 10).unwrap(),
+            ["hash", "hashmap", "hashset"]
+        );
+        assert_eq!(book.suggest("ITER", 2).unwrap(), ["iterable", "iterate"]);
+        assert_eq!(book.suggest("itre", 1).unwrap(), ["iterable"]);
+        assert_eq!(
+            book.suggest("a hashm", 10).unwrap(),
+            ["hashmap", "hash", "hashset"]
+        );
+        assert!(book.suggest("", 10).unwrap().is_empty());
+    }
+}
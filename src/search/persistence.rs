@@ -0,0 +1,591 @@
+//! Persistence for the search index.
+//!
+//! # Storage Format
+//!
+//! The index is composed by a *term dictionary* and a *postings list* for each
+//! term. Both are stored in data blocks.
+//!
+//! The index is split in *partitions*, one for each [analyzer] used to build
+//! it. The data stored at the `fts_pos` position in the stream is the number
+//! of partitions, followed by an entry for each one:
+//!
+//! 1. Length of the analyzer identifier, in bytes.
+//! 2. The analyzer identifier, as UTF-8.
+//! 3. Data block with the term dictionary.
+//! 4. Offset in the data block for the term dictionary.
+//!
+//! The term dictionary starts with the number of pages in the partition,
+//! followed by their identifiers. Each identifier is stored as the difference
+//! with the previous one. Then, the value of the `SearchBoost` metadata entry
+//! of every page (or `100` if the page does not have it).
+//!
+//! Then, it contains the number of [fields], and the terms of each one:
+//!
+//! 1. The tag of the field (a single byte). Metadata fields use the same tag
+//!    of the metadata table, and the content of the page uses `0`.
+//! 2. For `User` fields, the length of the key, and the key as UTF-8.
+//! 3. Number of terms in the field of every page, in the same order of the
+//!    page identifiers. These values are used to compute the BM25 score.
+//! 4. Number of terms in the field.
+//!
+//! Each term is stored as:
+//!
+//! 1. Length of the term, in bytes.
+//! 2. The term, as UTF-8.
+//! 3. Number of pages containing the term.
+//! 4. Data block with the postings list.
+//! 5. Offset in the data block for the postings list.
+//!
+//! Terms are sorted by their bytes, so they can be found with a binary search.
+//!
+//! A postings list contains an entry for each page containing the term:
+//!
+//! 1. Difference between the page identifier and the identifier of the
+//!    previous page in the list (or `0` for the first one).
+//! 2. Number of occurrences of the term in the page.
+//! 3. Positions of every occurrence, as the difference with the previous one.
+//!
+//! Except for the UTF-8 strings and the field tags, all numbers are encoded as
+//! LEB128.
+//!
+//! [analyzer]: super::analysis::Analyzer
+//! [fields]: super::query::Field
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroU32;
+
+use super::analysis::{Analyzer, Registry};
+use super::query::Field;
+use super::{FieldIndex, Index, Partition, SearchError, TermInfo, DEFAULT_BOOST};
+use crate::metadata::ByteTag;
+use crate::page::PageId;
+use crate::persistence::datablock::{DataBlocksReader, DataBlocksWriter};
+use crate::{MetadataEntry, Page};
+
+/// Gap between the positions of two metadata entries of the same field, so
+/// phrases are not found across them.
+const ENTRY_POSITION_GAP: u32 = 1;
+
+/// Terms of a partition. For every field and term, it contains the list of
+/// pages with the positions of the term in the page.
+type Postings = BTreeMap<Field, BTreeMap<String, PostingsList>>;
+
+/// Postings list of a term, encoded while the pages are added.
+///
+/// Entries use the format described in the module documentation, so the
+/// list can be copied to the output if the pages were added in order. The
+/// difference with the previous page is computed with a wrapping subtraction,
+/// so the identifiers can be restored if the pages were not in order.
+#[derive(Default)]
+struct PostingsList {
+    num_pages: u32,
+    last_id: u32,
+    unsorted: bool,
+    bytes: Vec<u8>,
+}
+
+impl PostingsList {
+    /// Add a page with the positions of the term in it.
+    fn push(&mut self, page_id: u32, positions: &[u32]) -> io::Result<()> {
+        if page_id <= self.last_id {
+            self.unsorted = true;
+        }
+
+        let delta = page_id.wrapping_sub(self.last_id);
+        leb128::write::unsigned(&mut self.bytes, u64::from(delta))?;
+        write_id_list(&mut self.bytes, positions.iter().copied())?;
+
+        self.num_pages += 1;
+        self.last_id = page_id;
+        Ok(())
+    }
+
+    /// Return the list, with the pages sorted by their identifiers.
+    fn into_sorted_bytes(self) -> io::Result<Vec<u8>> {
+        if !self.unsorted {
+            return Ok(self.bytes);
+        }
+
+        let invalid = |_| io::Error::from(io::ErrorKind::InvalidData);
+
+        // Find the location of every entry, to sort them.
+        let mut input = Cursor::new(&self.bytes[..]);
+        let mut entries = Vec::with_capacity(self.num_pages as usize);
+        let mut page_id = 0_u32;
+        for _ in 0..self.num_pages {
+            let delta = leb128::read::unsigned(&mut input).map_err(invalid)?;
+            page_id = page_id.wrapping_add(delta as u32);
+
+            let start = input.position() as usize;
+            let count = leb128::read::unsigned(&mut input).map_err(invalid)?;
+            for _ in 0..count {
+                leb128::read::unsigned(&mut input).map_err(invalid)?;
+            }
+
+            entries.push((page_id, start..input.position() as usize));
+        }
+
+        entries.sort_unstable_by_key(|(page_id, _)| *page_id);
+
+        let mut bytes = Vec::with_capacity(self.bytes.len());
+        let mut last_id = 0;
+        for (page_id, positions) in entries {
+            leb128::write::unsigned(&mut bytes, u64::from(page_id - last_id))?;
+            bytes.extend_from_slice(&self.bytes[positions]);
+            last_id = page_id;
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Data to build a partition.
+#[derive(Default)]
+struct PartitionBuilder {
+    /// Identifier and boost of every page.
+    docs: Vec<(u32, u32)>,
+
+    /// Number of terms in every field of the pages.
+    lengths: HashMap<Field, HashMap<u32, u32>>,
+
+    postings: Postings,
+}
+
+impl PartitionBuilder {
+    /// Add the terms of a page.
+    fn add_page(&mut self, page: &Page, analyzer: &dyn Analyzer) -> io::Result<()> {
+        let mut terms: HashMap<(Field, String), Vec<u32>> = HashMap::new();
+        let mut next_positions: HashMap<Field, u32> = HashMap::new();
+        let mut lengths: HashMap<Field, u32> = HashMap::new();
+        let mut boost = DEFAULT_BOOST;
+
+        let mut add_text = |field: Field, text: &str| {
+            let base = next_positions.entry(field.clone()).or_default();
+            let mut last = *base;
+
+            let tokens = analyzer.analyze(text);
+            *lengths.entry(field.clone()).or_default() += tokens.len() as u32;
+
+            for token in tokens {
+                let position = *base + token.position as u32;
+                terms
+                    .entry((field.clone(), token.text.into_owned()))
+                    .or_default()
+                    .push(position);
+
+                last = position;
+            }
+
+            *base = last + 1 + ENTRY_POSITION_GAP;
+        };
+
+        for entry in &page.metadata {
+            match entry {
+                MetadataEntry::Title(s) => add_text(Field::Title, s),
+                MetadataEntry::Keyword(s) => add_text(Field::Keyword, s),
+                MetadataEntry::Author(s) => add_text(Field::Author, s),
+                MetadataEntry::User(k, v) => add_text(Field::User(k.clone()), v),
+                MetadataEntry::SearchBoost(b) => boost = *b,
+                _ => (),
+            }
+        }
+
+        // Content with non-UTF-8 data (like images) is not indexed.
+        if let Ok(content) = std::str::from_utf8(&page.content) {
+            add_text(Field::Content, content);
+        }
+
+        let page_id = page.id.get();
+        for ((field, term), positions) in terms {
+            self.postings
+                .entry(field)
+                .or_default()
+                .entry(term)
+                .or_default()
+                .push(page_id, &positions)?;
+        }
+
+        for (field, length) in lengths {
+            self.lengths
+                .entry(field)
+                .or_default()
+                .insert(page_id, length);
+        }
+
+        self.docs.push((page_id, boost));
+        Ok(())
+    }
+
+    /// Write the postings lists in data blocks, and return the term
+    /// dictionary.
+    fn write<O>(mut self, db_writer: &mut DataBlocksWriter<O>) -> io::Result<Vec<u8>>
+    where
+        O: Write + Seek,
+    {
+        let mut dictionary = Vec::with_capacity(self.docs.len() * 16);
+
+        self.docs.sort_unstable();
+        write_id_list(&mut dictionary, self.docs.iter().map(|(id, _)| *id))?;
+
+        for (_, boost) in &self.docs {
+            leb128::write::unsigned(&mut dictionary, u64::from(*boost))?;
+        }
+
+        // Postings lists are written before the dictionary, since the
+        // identifiers of their blocks are known only when all previous
+        // blocks are written.
+        let mut fields = Vec::with_capacity(self.postings.len());
+        for (field, terms) in self.postings {
+            let mut entries = Vec::with_capacity(terms.len());
+
+            for (term, list) in terms {
+                let num_pages = list.num_pages;
+                let bytes = list.into_sorted_bytes()?;
+
+                let mut fragment = db_writer.fragment(bytes.len() as u64)?;
+                fragment.write_all(&bytes)?;
+
+                entries.push((term, num_pages, fragment.location()));
+            }
+
+            fields.push((field, entries));
+        }
+
+        leb128::write::unsigned(&mut dictionary, fields.len() as u64)?;
+
+        for (field, entries) in fields {
+            dictionary.write_all(&[field.tag()])?;
+            if let Field::User(key) = &field {
+                write_str(&mut dictionary, key)?;
+            }
+
+            let lengths = self.lengths.remove(&field).unwrap_or_default();
+            for (page_id, _) in &self.docs {
+                let length = lengths.get(page_id).copied().unwrap_or(0);
+                leb128::write::unsigned(&mut dictionary, u64::from(length))?;
+            }
+
+            leb128::write::unsigned(&mut dictionary, entries.len() as u64)?;
+
+            for (term, num_pages, loc) in entries {
+                write_str(&mut dictionary, &term)?;
+                leb128::write::unsigned(&mut dictionary, u64::from(num_pages))?;
+                leb128::write::unsigned(&mut dictionary, db_writer.block_id(loc.block)?)?;
+                leb128::write::unsigned(&mut dictionary, loc.offset)?;
+            }
+        }
+
+        Ok(dictionary)
+    }
+}
+
+/// Write a string, preceded by its length.
+fn write_str(mut output: impl Write, s: &str) -> io::Result<()> {
+    leb128::write::unsigned(&mut output, s.len() as u64)?;
+    output.write_all(s.as_bytes())
+}
+
+/// Write a sorted list of numbers, preceded by its length. Each number is
+/// stored as the difference with the previous one.
+fn write_id_list<I>(mut output: impl Write, list: I) -> io::Result<()>
+where
+    I: ExactSizeIterator<Item = u32>,
+{
+    leb128::write::unsigned(&mut output, list.len() as u64)?;
+
+    let mut last = 0;
+    for n in list {
+        leb128::write::unsigned(&mut output, u64::from(n - last))?;
+        last = n;
+    }
+
+    Ok(())
+}
+
+/// Builder for the search index.
+///
+/// The terms of every page are extracted when the page is added, so the
+/// content of the pages is not needed when the index is written. The
+/// postings lists are kept in memory, encoded with LEB128.
+#[derive(Default)]
+pub(crate) struct IndexWriter {
+    partitions: BTreeMap<String, PartitionBuilder>,
+}
+
+impl IndexWriter {
+    /// Add the terms of a page to the index.
+    ///
+    /// The analyzer for the page is selected from its `Language` metadata
+    /// entry, or `book_language` if it has none.
+    pub(crate) fn add_page(
+        &mut self,
+        page: &Page,
+        book_language: Option<&str>,
+        analyzers: &Registry,
+    ) -> io::Result<()> {
+        let language = page
+            .metadata
+            .iter()
+            .find_map(|entry| match entry {
+                MetadataEntry::Language(l) => Some(l.as_str()),
+                _ => None,
+            })
+            .or(book_language);
+
+        let analyzer = analyzers.for_language(language);
+
+        self.partitions
+            .entry(analyzer.id().to_owned())
+            .or_default()
+            .add_page(page, &*analyzer)
+    }
+
+    /// Write the search index in the output stream.
+    ///
+    /// On success, returns the offset to the table described in the module
+    /// documentation.
+    pub(crate) fn write<O>(self, mut db_writer: DataBlocksWriter<O>) -> io::Result<u64>
+    where
+        O: Write + Seek,
+    {
+        let mut table = Vec::new();
+
+        leb128::write::unsigned(&mut table, self.partitions.len() as u64)?;
+
+        for (analyzer_id, partition) in self.partitions {
+            let dictionary = partition.write(&mut db_writer)?;
+
+            let mut fragment = db_writer.fragment(dictionary.len() as u64)?;
+            fragment.write_all(&dictionary)?;
+            let dictionary_loc = fragment.location();
+
+            // Entry for the partitions table.
+            write_str(&mut table, &analyzer_id)?;
+            leb128::write::unsigned(&mut table, db_writer.block_id(dictionary_loc.block)?)?;
+            leb128::write::unsigned(&mut table, dictionary_loc.offset)?;
+        }
+
+        let mut output = db_writer.finish()?;
+
+        let position = output.stream_position()?;
+        output.write_all(&table)?;
+
+        Ok(position)
+    }
+}
+
+/// Read a string, preceded by its length.
+fn read_str(mut input: impl Read, input_len: u64) -> Result<String, SearchError> {
+    let len = leb128::read::unsigned(&mut input)?;
+    if len > input_len {
+        return Err(SearchError::InvalidLength(len));
+    }
+
+    let mut bytes = vec![0; len as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Read a list written by `write_id_list`.
+fn read_id_list(mut input: impl Read, input_len: u64) -> Result<Vec<u64>, SearchError> {
+    let len = leb128::read::unsigned(&mut input)?;
+    if len > input_len {
+        return Err(SearchError::InvalidLength(len));
+    }
+
+    let mut list = Vec::with_capacity(len as usize);
+    let mut last = 0u64;
+    for _ in 0..len {
+        let delta = leb128::read::unsigned(&mut input)?;
+        last = last
+            .checked_add(delta)
+            .ok_or(SearchError::InvalidLength(delta))?;
+        list.push(last);
+    }
+
+    Ok(list)
+}
+
+/// Convert a number read from the index to a page identifier.
+fn page_id(id: u64) -> Result<PageId, SearchError> {
+    u32::try_from(id)
+        .ok()
+        .and_then(NonZeroU32::new)
+        .map(PageId)
+        .ok_or(SearchError::InvalidId(id))
+}
+
+impl Index {
+    /// Load the term dictionaries of the index located at `position`.
+    pub(crate) fn load<I>(
+        db_reader: &mut DataBlocksReader<I>,
+        position: u64,
+    ) -> Result<Self, SearchError>
+    where
+        I: Read + Seek,
+    {
+        let input_len = db_reader.input_stream_len();
+        let input = db_reader.input_stream();
+        input.seek(SeekFrom::Start(position))?;
+
+        let num_partitions = leb128::read::unsigned(&mut *input)?;
+        if num_partitions > input_len {
+            return Err(SearchError::InvalidLength(num_partitions));
+        }
+
+        let mut locations = Vec::with_capacity(num_partitions as usize);
+        for _ in 0..num_partitions {
+            let analyzer_id = read_str(&mut *input, input_len)?;
+            let block_id = leb128::read::unsigned(&mut *input)?;
+            let block_offset = leb128::read::unsigned(&mut *input)?;
+            locations.push((analyzer_id, block_id, block_offset));
+        }
+
+        let mut partitions = Vec::with_capacity(locations.len());
+        for (analyzer_id, block_id, block_offset) in locations {
+            let partition = db_reader.with_block(block_id, block_offset, |bytes| {
+                load_partition(analyzer_id, block_id, bytes)
+            })??;
+
+            partitions.push(partition);
+        }
+
+        Ok(Index { partitions })
+    }
+
+    /// Identifiers of the data blocks used by the index.
+    pub(crate) fn block_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.partitions.iter().flat_map(|partition| {
+            let terms = partition.fields.values().flat_map(|f| f.terms.iter());
+            std::iter::once(partition.block_id).chain(terms.map(|(_, info)| info.block_id))
+        })
+    }
+
+    /// Read the postings lists of all terms, to verify that they are valid.
+    pub(crate) fn check_postings<I>(
+        &self,
+        db_reader: &mut DataBlocksReader<I>,
+    ) -> Result<(), SearchError>
+    where
+        I: Read + Seek,
+    {
+        for partition in &self.partitions {
+            for field in partition.fields.values() {
+                for (_, info) in &field.terms {
+                    load_postings(db_reader, info)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the term dictionary of a partition.
+fn load_partition(
+    analyzer_id: String,
+    block_id: u64,
+    bytes: &[u8],
+) -> Result<Partition, SearchError> {
+    let input_len = bytes.len() as u64;
+    let mut cursor = Cursor::new(bytes);
+
+    let docs: Vec<_> = read_id_list(&mut cursor, input_len)?
+        .into_iter()
+        .map(page_id)
+        .collect::<Result<_, _>>()?;
+
+    let read_u32 = |cursor: &mut Cursor<&[u8]>| -> Result<u32, SearchError> {
+        let n = leb128::read::unsigned(cursor)?;
+        u32::try_from(n).map_err(|_| SearchError::InvalidLength(n))
+    };
+
+    let boosts = (0..docs.len())
+        .map(|_| read_u32(&mut cursor))
+        .collect::<Result<_, _>>()?;
+
+    let num_fields = leb128::read::unsigned(&mut cursor)?;
+    if num_fields > input_len {
+        return Err(SearchError::InvalidLength(num_fields));
+    }
+
+    let mut fields = HashMap::with_capacity(num_fields as usize);
+    for _ in 0..num_fields {
+        let mut tag = [0];
+        cursor.read_exact(&mut tag)?;
+
+        let field = match ByteTag::try_from(tag[0]) {
+            _ if tag[0] == 0 => Field::Content,
+            Ok(ByteTag::Title) => Field::Title,
+            Ok(ByteTag::Keyword) => Field::Keyword,
+            Ok(ByteTag::Author) => Field::Author,
+            Ok(ByteTag::User) => Field::User(read_str(&mut cursor, input_len)?),
+            _ => return Err(SearchError::InvalidField(tag[0])),
+        };
+
+        let lengths = (0..docs.len())
+            .map(|_| read_u32(&mut cursor))
+            .collect::<Result<_, _>>()?;
+
+        let num_terms = leb128::read::unsigned(&mut cursor)?;
+        if num_terms > input_len {
+            return Err(SearchError::InvalidLength(num_terms));
+        }
+
+        let mut terms = Vec::with_capacity(num_terms as usize);
+        for _ in 0..num_terms {
+            let term = read_str(&mut cursor, input_len)?;
+            let info = TermInfo {
+                doc_freq: leb128::read::unsigned(&mut cursor)?,
+                block_id: leb128::read::unsigned(&mut cursor)?,
+                block_offset: leb128::read::unsigned(&mut cursor)?,
+            };
+
+            terms.push((term, info));
+        }
+
+        fields.insert(field, FieldIndex::new(terms, lengths));
+    }
+
+    Ok(Partition {
+        analyzer_id,
+        block_id,
+        docs,
+        boosts,
+        fields,
+    })
+}
+
+/// Read the postings list of a term, with the positions of the term in every
+/// page.
+pub(super) fn load_postings<I>(
+    db_reader: &mut DataBlocksReader<I>,
+    info: &TermInfo,
+) -> Result<Vec<(PageId, Vec<u32>)>, SearchError>
+where
+    I: Read + Seek,
+{
+    db_reader.with_block(info.block_id, info.block_offset, |bytes| {
+        let input_len = bytes.len() as u64;
+        let mut cursor = Cursor::new(bytes);
+        let mut postings = Vec::with_capacity(info.doc_freq.min(input_len) as usize);
+        let mut last_id = 0u64;
+
+        for _ in 0..info.doc_freq {
+            let delta = leb128::read::unsigned(&mut cursor)?;
+            last_id = last_id
+                .checked_add(delta)
+                .ok_or(SearchError::InvalidId(last_id))?;
+
+            let positions = read_id_list(&mut cursor, input_len)?
+                .into_iter()
+                .map(|p| u32::try_from(p).map_err(|_| SearchError::InvalidLength(p)))
+                .collect::<Result<_, _>>()?;
+
+            postings.push((page_id(last_id)?, positions));
+        }
+
+        Ok(postings)
+    })?
+}
@@ -0,0 +1,557 @@
+//! Queries for the search index.
+//!
+//! The syntax of the queries is described in [`Query`].
+
+use std::iter::Peekable;
+use std::str::FromStr;
+
+use super::fuzzy;
+use crate::metadata::ByteTag;
+
+/// Errors found when a query is parsed.
+///
+/// Every error includes the column (starting at `1`) where it was found.
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone)]
+#[non_exhaustive]
+pub enum QueryError {
+    #[error("Unterminated phrase at column {0}.")]
+    UnterminatedPhrase(usize),
+
+    #[error("Unmatched parenthesis at column {0}.")]
+    UnmatchedParen(usize),
+
+    #[error("Expected an expression at column {0}.")]
+    ExpectedExpression(usize),
+
+    #[error("Missing key for the `user:` field at column {0}.")]
+    MissingUserKey(usize),
+
+    #[error("Empty prefix at column {0}.")]
+    EmptyPrefix(usize),
+
+    #[error("Invalid edit distance at column {0}.")]
+    InvalidDistance(usize),
+
+    #[error("Too many nested expressions at column {0}.")]
+    TooDeep(usize),
+}
+
+impl QueryError {
+    /// Column, starting at `1`, where the error was found.
+    pub fn column(&self) -> usize {
+        match self {
+            QueryError::UnterminatedPhrase(c)
+            | QueryError::UnmatchedParen(c)
+            | QueryError::ExpectedExpression(c)
+            | QueryError::MissingUserKey(c)
+            | QueryError::EmptyPrefix(c)
+            | QueryError::InvalidDistance(c)
+            | QueryError::TooDeep(c) => *c,
+        }
+    }
+}
+
+/// Fields of a page in the search index.
+///
+/// Metadata fields map to the [`MetadataEntry`](crate::MetadataEntry)
+/// variants with the same name.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Field {
+    /// Content of the page.
+    Content,
+
+    /// `Title` metadata entries.
+    Title,
+
+    /// `Keyword` metadata entries.
+    Keyword,
+
+    /// `Author` metadata entries.
+    Author,
+
+    /// Value of the `User` metadata entries with the specified key.
+    User(String),
+}
+
+impl Field {
+    /// Fields used when a query does not specify one.
+    pub(crate) const DEFAULTS: [Field; 3] = [Field::Title, Field::Keyword, Field::Content];
+
+    /// Tag to store the field in the index.
+    ///
+    /// Metadata fields use the same tag of the metadata table.
+    pub(crate) fn tag(&self) -> u8 {
+        match self {
+            Field::Content => 0,
+            Field::Title => ByteTag::Title.into(),
+            Field::Keyword => ByteTag::Keyword.into(),
+            Field::Author => ByteTag::Author.into(),
+            Field::User(_) => ByteTag::User.into(),
+        }
+    }
+
+    /// Parse the name of a field in a query.
+    ///
+    /// `rest` is the text after the `:` character. It is used to find the key
+    /// of the `user:` field.
+    fn parse<'a>(name: &str, rest: &'a str) -> Option<(Field, Option<&'a str>)> {
+        let field = match name {
+            "title" => Field::Title,
+            "keyword" => Field::Keyword,
+            "author" => Field::Author,
+            "content" => Field::Content,
+            "user" => {
+                return Some(match rest.split_once(':') {
+                    Some((key, rest)) => (Field::User(key.to_owned()), Some(rest)),
+                    None => (Field::User(String::new()), None),
+                })
+            }
+            _ => return None,
+        };
+
+        Some((field, Some(rest)))
+    }
+}
+
+/// A search query.
+///
+/// Queries can be built programmatically, or parsed from a string with
+/// [`Query::parse`] or [`str::parse`].
+///
+/// # Syntax
+///
+/// * Words are separated by spaces. Adjacent words are combined with `AND`,
+///   so `hash map` finds pages containing both `hash` and `map`.
+///
+/// * `AND`, `OR` and `NOT` (in uppercase) combine other expressions. `NOT`
+///   has the highest precedence, and `OR` the lowest. Parenthesis can be used
+///   to group expressions.
+///
+/// * `"..."` finds pages containing a phrase.
+///
+/// * `iter*` finds pages containing any word starting with `iter`.
+///
+/// * `hashmpa~` finds pages containing words similar to `hashmpa`, like
+///   `hashmap`. The maximum number of edits (up to `2`) can be set after the
+///   `~` character, like `hashmpa~1`. Without it, the maximum depends on the
+///   length of the word.
+///
+/// * `title:`, `keyword:`, `author:` and `content:` limit an expression to a
+///   single field. `user:<key>:` looks for the value of the
+///   [`User`](crate::MetadataEntry::User) metadata entries with the key
+///   `<key>`.
+///
+/// Without a field, expressions are applied to the title, the keywords and the
+/// content of the page.
+///
+/// ```
+/// use theory::{Field, Query};
+///
+/// let query: Query = r#"title:vec* AND ("growable array" OR NOT heap)"#.parse().unwrap();
+///
+/// assert_eq!(
+///     query,
+///     Query::And(vec![
+///         Query::Field(Field::Title, Box::new(Query::Prefix("vec".into()))),
+///         Query::Or(vec![
+///             Query::Phrase("growable array".into()),
+///             Query::Not(Box::new(Query::Term("heap".into()))),
+///         ]),
+///     ])
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// Pages containing a word.
+    ///
+    /// If the analyzer splits the text in multiple words, it is handled as a
+    /// phrase.
+    Term(String),
+
+    /// Pages containing a sequence of words.
+    Phrase(String),
+
+    /// Pages containing any word starting with a prefix.
+    Prefix(String),
+
+    /// Pages containing any word within a maximum edit distance.
+    ///
+    /// The distance can not be greater than `2`.
+    Fuzzy(String, u8),
+
+    /// Pages matching all the subqueries.
+    And(Vec<Query>),
+
+    /// Pages matching any of the subqueries.
+    Or(Vec<Query>),
+
+    /// Pages not matching the subquery.
+    Not(Box<Query>),
+
+    /// Limit the subquery to a single field.
+    Field(Field, Box<Query>),
+}
+
+impl Query {
+    /// Parse a query with the syntax described in the
+    /// [type documentation](Query#syntax).
+    pub fn parse(query: &str) -> Result<Query, QueryError> {
+        let mut parser = Parser {
+            lexer: Lexer::new(query).peekable(),
+            end_column: query.chars().count() + 1,
+            depth: 0,
+        };
+
+        let query = parser.or()?;
+
+        match parser.lexer.next().transpose()? {
+            None => Ok(query),
+            Some((column, _)) => Err(QueryError::UnmatchedParen(column)),
+        }
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Query::parse(s)
+    }
+}
+
+/// Tokens found in a query string.
+#[derive(Debug, PartialEq, Eq)]
+enum Token<'a> {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Phrase(&'a str),
+    Word(&'a str),
+}
+
+/// Split a query string in tokens, with their columns.
+struct Lexer<'a> {
+    query: &'a str,
+    chars: Peekable<std::iter::Enumerate<std::str::CharIndices<'a>>>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(query: &'a str) -> Self {
+        Lexer {
+            query,
+            chars: query.char_indices().enumerate().peekable(),
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<(usize, Token<'a>), QueryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self
+            .chars
+            .next_if(|(_, (_, c))| c.is_whitespace())
+            .is_some()
+        {}
+
+        let (column, (start, c)) = self.chars.next()?;
+        let column = column + 1;
+
+        let token = match c {
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+
+            '"' => {
+                let mut end = None;
+                for (_, (n, c)) in self.chars.by_ref() {
+                    if c == '"' {
+                        end = Some(n);
+                        break;
+                    }
+                }
+
+                match end {
+                    Some(end) => Token::Phrase(&self.query[start + 1..end]),
+                    None => return Some(Err(QueryError::UnterminatedPhrase(column))),
+                }
+            }
+
+            _ => {
+                let mut end = self.query.len();
+                while let Some((_, (n, c))) = self.chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | '"') {
+                        end = *n;
+                        break;
+                    }
+
+                    self.chars.next();
+                }
+
+                match &self.query[start..end] {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    word => Token::Word(word),
+                }
+            }
+        };
+
+        Some(Ok((column, token)))
+    }
+}
+
+/// Maximum number of nested parenthesis, `NOT` operators and fields in a
+/// query, to limit the recursion of the parser.
+const MAX_DEPTH: usize = 100;
+
+/// Recursive descent parser for queries.
+struct Parser<'a> {
+    lexer: Peekable<Lexer<'a>>,
+    end_column: usize,
+
+    /// Number of nested expressions being parsed.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Return the next token, or `None` if the input is finished.
+    fn peek(&mut self) -> Result<Option<&Token<'a>>, QueryError> {
+        match self.lexer.peek() {
+            Some(Ok((_, token))) => Ok(Some(token)),
+            Some(Err(e)) => Err(e.clone()),
+            None => Ok(None),
+        }
+    }
+
+    /// Column of the next token.
+    fn next_column(&mut self) -> usize {
+        match self.lexer.peek() {
+            Some(Ok((column, _))) => *column,
+            _ => self.end_column,
+        }
+    }
+
+    /// Call `f` to parse a nested expression, which starts at `column`.
+    fn nested<T>(
+        &mut self,
+        column: usize,
+        f: impl FnOnce(&mut Self) -> Result<T, QueryError>,
+    ) -> Result<T, QueryError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(QueryError::TooDeep(column));
+        }
+
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// `or := and ("OR" and)*`
+    fn or(&mut self) -> Result<Query, QueryError> {
+        let mut items = vec![self.and()?];
+
+        while let Some(Token::Or) = self.peek()? {
+            self.lexer.next();
+            items.push(self.and()?);
+        }
+
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Query::Or(items)
+        })
+    }
+
+    /// `and := unary (["AND"] unary)*`
+    fn and(&mut self) -> Result<Query, QueryError> {
+        let mut items = vec![self.unary()?];
+
+        loop {
+            match self.peek()? {
+                Some(Token::And) => {
+                    self.lexer.next();
+                }
+
+                Some(Token::Or | Token::RParen) | None => break,
+
+                Some(_) => (),
+            }
+
+            items.push(self.unary()?);
+        }
+
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Query::And(items)
+        })
+    }
+
+    /// `unary := "NOT" unary | primary`
+    fn unary(&mut self) -> Result<Query, QueryError> {
+        if let Some(Token::Not) = self.peek()? {
+            let column = self.next_column();
+            self.lexer.next();
+            return Ok(Query::Not(Box::new(self.nested(column, Self::unary)?)));
+        }
+
+        self.primary()
+    }
+
+    /// `primary := "(" or ")" | phrase | [field ":"] word | field ":" primary`
+    fn primary(&mut self) -> Result<Query, QueryError> {
+        let column = self.next_column();
+
+        let token = match self.lexer.next().transpose()? {
+            Some((_, token)) => token,
+            None => return Err(QueryError::ExpectedExpression(column)),
+        };
+
+        match token {
+            Token::LParen => {
+                let query = self.nested(column, Self::or)?;
+                match self.lexer.next().transpose()? {
+                    Some((_, Token::RParen)) => Ok(query),
+                    _ => Err(QueryError::UnmatchedParen(column)),
+                }
+            }
+
+            Token::Phrase(phrase) => Ok(Query::Phrase(phrase.to_owned())),
+
+            Token::Word(word) => {
+                let scope = word
+                    .split_once(':')
+                    .and_then(|(name, rest)| Field::parse(name, rest));
+
+                match scope {
+                    Some((_, None)) => Err(QueryError::MissingUserKey(column)),
+
+                    Some((field, Some(""))) => Ok(Query::Field(
+                        field,
+                        Box::new(self.nested(column, Self::primary)?),
+                    )),
+
+                    Some((field, Some(rest))) => {
+                        Ok(Query::Field(field, Box::new(word_query(rest, column)?)))
+                    }
+
+                    None => word_query(word, column),
+                }
+            }
+
+            Token::RParen => Err(QueryError::UnmatchedParen(column)),
+
+            Token::And | Token::Or | Token::Not => Err(QueryError::ExpectedExpression(column)),
+        }
+    }
+}
+
+/// Build a query for a single word.
+fn word_query(word: &str, column: usize) -> Result<Query, QueryError> {
+    if let Some((term, distance)) = word.rsplit_once('~') {
+        if !term.is_empty() && distance.chars().all(|c| c.is_ascii_digit()) {
+            let distance = match distance {
+                "" => fuzzy::auto_distance(term),
+                d => match d.parse() {
+                    Ok(d) if d <= fuzzy::MAX_DISTANCE => d,
+                    _ => return Err(QueryError::InvalidDistance(column)),
+                },
+            };
+
+            return Ok(Query::Fuzzy(term.to_owned(), distance));
+        }
+    }
+
+    match word.strip_suffix('*') {
+        Some("") => Err(QueryError::EmptyPrefix(column)),
+        Some(prefix) => Ok(Query::Prefix(prefix.to_owned())),
+        None => Ok(Query::Term(word.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Field, Query, QueryError};
+
+    fn term(t: &str) -> Query {
+        Query::Term(t.into())
+    }
+
+    #[test]
+    fn parse_queries() {
+        assert_eq!(Query::parse("abc").unwrap(), term("abc"));
+
+        assert_eq!(
+            Query::parse("a b OR c").unwrap(),
+            Query::Or(vec![Query::And(vec![term("a"), term("b")]), term("c")])
+        );
+
+        assert_eq!(
+            Query::parse("a AND NOT (b OR c)").unwrap(),
+            Query::And(vec![
+                term("a"),
+                Query::Not(Box::new(Query::Or(vec![term("b"), term("c")])))
+            ])
+        );
+
+        assert_eq!(
+            Query::parse("std::vec user:crate:core title:\"a b\"").unwrap(),
+            Query::And(vec![
+                term("std::vec"),
+                Query::Field(Field::User("crate".into()), Box::new(term("core"))),
+                Query::Field(Field::Title, Box::new(Query::Phrase("a b".into()))),
+            ])
+        );
+
+        assert_eq!(
+            Query::parse("keyword:(x OR y*)").unwrap(),
+            Query::Field(
+                Field::Keyword,
+                Box::new(Query::Or(vec![term("x"), Query::Prefix("y".into())]))
+            )
+        );
+
+        assert_eq!(
+            Query::parse("hashmpa~ itre~1 ab~ ~ a~b").unwrap(),
+            Query::And(vec![
+                Query::Fuzzy("hashmpa".into(), 2),
+                Query::Fuzzy("itre".into(), 1),
+                Query::Fuzzy("ab".into(), 0),
+                term("~"),
+                term("a~b"),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        macro_rules! check {
+            ($query:expr, $err:expr) => {
+                assert_eq!(Query::parse($query), Err($err));
+            };
+        }
+
+        check!("", QueryError::ExpectedExpression(1));
+        check!("a \"b c", QueryError::UnterminatedPhrase(3));
+        check!("(a OR b", QueryError::UnmatchedParen(1));
+        check!("a b)", QueryError::UnmatchedParen(4));
+        check!("a AND", QueryError::ExpectedExpression(6));
+        check!("a OR OR", QueryError::ExpectedExpression(6));
+        check!("user:abc", QueryError::MissingUserKey(1));
+        check!("ñ *", QueryError::EmptyPrefix(3));
+        check!("title:abc~3", QueryError::InvalidDistance(1));
+
+        assert_eq!(Query::parse("x  NOT").unwrap_err().column(), 7);
+
+        // Nested expressions.
+        check!(&"(".repeat(200_000), QueryError::TooDeep(101));
+        check!(&"NOT ".repeat(200_000), QueryError::TooDeep(401));
+        check!(&"title: ".repeat(200_000), QueryError::TooDeep(701));
+
+        let query = format!("{}a{}", "(".repeat(100), ")".repeat(100));
+        assert_eq!(Query::parse(&query), Ok(Query::Term("a".into())));
+    }
+}
@@ -0,0 +1,349 @@
+//! Snippets of the content of the pages found by a search.
+//!
+//! Snippets are computed when the search is done, by reading the content of
+//! the matched pages and processing it with the same analyzer used to build
+//! the index. Thus, the index does not need to store the offsets of every
+//! term.
+
+use std::ops::Range;
+
+use super::analysis::Analyzer;
+use super::fuzzy::FuzzyMatcher;
+use super::query::{Field, Query};
+
+/// Default length, in bytes, of the snippets.
+const DEFAULT_SNIPPET_LEN: usize = 160;
+
+/// Options to control the results of a search.
+///
+/// ```
+/// use theory::SearchOptions;
+///
+/// let options = SearchOptions::default().limit(10).max_snippets(2);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub(crate) limit: Option<usize>,
+    pub(crate) max_snippets: usize,
+    pub(crate) snippet_len: usize,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            limit: None,
+            max_snippets: 0,
+            snippet_len: DEFAULT_SNIPPET_LEN,
+        }
+    }
+}
+
+impl SearchOptions {
+    /// Maximum number of hits to return.
+    ///
+    /// By default, all matched pages are returned.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Maximum number of snippets for every hit.
+    ///
+    /// By default, snippets are not computed, so the content of the pages is
+    /// not read.
+    pub fn max_snippets(mut self, max_snippets: usize) -> Self {
+        self.max_snippets = max_snippets;
+        self
+    }
+
+    /// Length, in bytes, of every snippet. Default is `160`.
+    ///
+    /// The final length can be a bit shorter, so snippets don't break words.
+    pub fn snippet_len(mut self, snippet_len: usize) -> Self {
+        self.snippet_len = snippet_len.max(1);
+        self
+    }
+}
+
+/// A fragment of the content of a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    offset: usize,
+    text: String,
+    highlights: Vec<Range<usize>>,
+}
+
+impl Snippet {
+    /// Position, in bytes, of the snippet in the content of the page.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Text of the snippet.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Ranges, in bytes, of the matched terms in [`text`](Self::text).
+    pub fn highlights(&self) -> &[Range<usize>] {
+        &self.highlights
+    }
+}
+
+/// Find the terms of a query in the content of a page.
+pub(crate) struct Highlighter {
+    /// Terms to highlight.
+    terms: Vec<Pattern>,
+}
+
+/// A term to highlight.
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+    Fuzzy(FuzzyMatcher),
+}
+
+impl Pattern {
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            Pattern::Exact(term) => text == term,
+            Pattern::Prefix(prefix) => text.starts_with(prefix.as_str()),
+            Pattern::Fuzzy(matcher) => matcher.distance(text).is_some(),
+        }
+    }
+}
+
+impl Highlighter {
+    /// Collect the terms of `query` that can be found in the content of a
+    /// page. Terms under a `NOT` expression are ignored.
+    pub(crate) fn new(query: &Query, analyzer: &dyn Analyzer) -> Self {
+        let mut terms = Vec::new();
+        collect_terms(query, analyzer, true, &mut terms);
+        Highlighter { terms }
+    }
+
+    /// Compute the snippets for `content`.
+    pub(crate) fn snippets(
+        &self,
+        content: &str,
+        analyzer: &dyn Analyzer,
+        options: &SearchOptions,
+    ) -> Vec<Snippet> {
+        let matches: Vec<_> = analyzer
+            .analyze(content)
+            .into_iter()
+            .filter(|token| self.terms.iter().any(|p| p.matches(&token.text)))
+            .map(|token| token.offset)
+            .collect();
+
+        // If the terms are not in the content, use its beginning.
+        if matches.is_empty() {
+            if options.max_snippets == 0 || content.is_empty() {
+                return Vec::new();
+            }
+
+            let end = options.snippet_len.min(content.len());
+            let end = word_end(content, 0, floor_char_boundary(content, end), 0);
+            return vec![Snippet {
+                offset: 0,
+                text: content[..end].to_owned(),
+                highlights: Vec::new(),
+            }];
+        }
+
+        let mut snippets = Vec::new();
+        let mut matches = &matches[..];
+        let mut last_end = 0;
+
+        while let Some(first) = matches.first() {
+            if snippets.len() >= options.max_snippets {
+                break;
+            }
+
+            // Skip matches cut by the end of the previous snippet.
+            if first.start < last_end {
+                matches = &matches[1..];
+                continue;
+            }
+
+            // Put some context before the first match.
+            let context = options.snippet_len / 4;
+            let mut start = floor_char_boundary(content, first.start.saturating_sub(context));
+            start = start.max(last_end);
+
+            if start > 0 {
+                if let Some((n, c)) = content[start..first.start]
+                    .char_indices()
+                    .find(|(_, c)| c.is_whitespace())
+                {
+                    start += n + c.len_utf8();
+                }
+            }
+
+            let end = (start + options.snippet_len).min(content.len());
+            let end = word_end(content, start, floor_char_boundary(content, end), first.end);
+
+            let count = matches.iter().take_while(|m| m.end <= end).count();
+            let highlights = matches[..count]
+                .iter()
+                .map(|m| m.start - start..m.end - start)
+                .collect();
+
+            snippets.push(Snippet {
+                offset: start,
+                text: content[start..end].to_owned(),
+                highlights,
+            });
+
+            matches = &matches[count..];
+            last_end = end;
+        }
+
+        snippets
+    }
+}
+
+/// Collect the terms of a query, as described in `Highlighter::new`.
+fn collect_terms(
+    query: &Query,
+    analyzer: &dyn Analyzer,
+    in_content: bool,
+    terms: &mut Vec<Pattern>,
+) {
+    match query {
+        Query::Term(text) | Query::Phrase(text) | Query::Prefix(text) | Query::Fuzzy(text, _)
+            if in_content =>
+        {
+            let tokens = analyzer.analyze(text);
+            let last = tokens.len().saturating_sub(1);
+            for (n, token) in tokens.into_iter().enumerate() {
+                terms.push(match query {
+                    Query::Prefix(_) if n == last => Pattern::Prefix(token.text.into_owned()),
+                    Query::Fuzzy(_, distance) => {
+                        Pattern::Fuzzy(FuzzyMatcher::new(&token.text, *distance, false))
+                    }
+                    _ => Pattern::Exact(token.text.into_owned()),
+                });
+            }
+        }
+
+        Query::And(items) | Query::Or(items) => {
+            for item in items {
+                collect_terms(item, analyzer, in_content, terms);
+            }
+        }
+
+        Query::Field(field, query) => {
+            collect_terms(query, analyzer, *field == Field::Content, terms);
+        }
+
+        _ => (),
+    }
+}
+
+/// Find the largest position, not greater than `n`, at a character boundary.
+fn floor_char_boundary(text: &str, mut n: usize) -> usize {
+    while !text.is_char_boundary(n) {
+        n -= 1;
+    }
+
+    n
+}
+
+/// Move `end` back to the last whitespace in `text[start..end]`, so the
+/// snippet does not break a word. `end` is not moved before `min_end`.
+fn word_end(text: &str, start: usize, end: usize, min_end: usize) -> usize {
+    if end >= text.len() || text[end..].starts_with(char::is_whitespace) {
+        return end.max(min_end);
+    }
+
+    match text[start..end].rfind(char::is_whitespace) {
+        Some(n) if start + n >= min_end => start + n,
+        _ => end.max(min_end),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::TextAnalyzer;
+
+    #[test]
+    fn compute_snippets() {
+        let analyzer = TextAnalyzer::builtin("standard").unwrap();
+        let content = "Vectors are growable arrays. Unlike slices, a vector owns its \
+                       elements. The vector can grow when elements are pushed.";
+
+        let query = Query::parse("vector* NOT slices").unwrap();
+        let highlighter = Highlighter::new(&query, &analyzer);
+
+        let options = SearchOptions::default().max_snippets(2).snippet_len(40);
+        let snippets = highlighter.snippets(content, &analyzer, &options);
+
+        assert_eq!(snippets.len(), 2);
+
+        for snippet in &snippets {
+            assert!(snippet.text().len() <= 40);
+            assert_eq!(
+                &content[snippet.offset()..snippet.offset() + snippet.text().len()],
+                snippet.text()
+            );
+
+            for range in snippet.highlights() {
+                assert!(snippet.text()[range.clone()].starts_with(&['v', 'V'][..]));
+            }
+        }
+
+        assert_eq!(snippets[0].text(), "Vectors are growable arrays. Unlike");
+        assert_eq!(snippets[0].highlights(), &[Range { start: 0, end: 7 }]);
+        assert_eq!(snippets[1].highlights().len(), 2);
+
+        // Without matches in the content.
+        let query = Query::parse("title:vectors").unwrap();
+        let snippets = Highlighter::new(&query, &analyzer).snippets(content, &analyzer, &options);
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].offset(), 0);
+        assert!(snippets[0].highlights().is_empty());
+    }
+
+    #[test]
+    fn matches_cut_by_previous_snippet() {
+        let analyzer = TextAnalyzer::builtin("standard").unwrap();
+        let content = "ab-ab-ab-ab-ab";
+
+        let query = Query::parse("ab").unwrap();
+        let highlighter = Highlighter::new(&query, &analyzer);
+
+        let options = SearchOptions::default().max_snippets(3).snippet_len(4);
+        let snippets = highlighter.snippets(content, &analyzer, &options);
+
+        assert!(!snippets.is_empty());
+
+        let mut last_end = 0;
+        for snippet in &snippets {
+            assert!(snippet.offset() >= last_end);
+            last_end = snippet.offset() + snippet.text().len();
+
+            for range in snippet.highlights() {
+                assert_eq!(&snippet.text()[range.clone()], "ab");
+            }
+        }
+    }
+
+    #[test]
+    fn non_ascii_content_without_matches() {
+        let analyzer = TextAnalyzer::builtin("standard").unwrap();
+        let content = format!("x{}", "ä".repeat(200));
+
+        let query = Query::parse("title:straße").unwrap();
+        let highlighter = Highlighter::new(&query, &analyzer);
+
+        let options = SearchOptions::default().max_snippets(3);
+        let snippets = highlighter.snippets(&content, &analyzer, &options);
+
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].offset(), 0);
+        assert_eq!(snippets[0].text().len(), 159);
+        assert!(content.starts_with(snippets[0].text()));
+    }
+}
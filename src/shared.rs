@@ -0,0 +1,350 @@
+//! Module with the `SharedBook` implementation.
+
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, PoisonError};
+
+#[cfg(any(unix, windows))]
+use std::{fs::File, path::Path};
+
+use crate::analysis::Analyzer;
+use crate::errors::MetadataError;
+use crate::page::persistence::build_page;
+use crate::persistence::datablock::cache::{CacheHandle, CacheStats};
+use crate::persistence::datablock::DataBlocksReader;
+use crate::persistence::{PositionedReader, ReadAt, Version};
+use crate::{metadata, page, persistence, search, toc};
+use crate::{Book, BookOptions, MetadataEntry, Query, SearchHit, SearchOptions};
+
+/// A book that can be read from many threads at the same time.
+///
+/// All methods to read a `SharedBook` take `&self`, so it can be stored in
+/// an [`Arc`] and used by multiple threads, without a `Mutex`. The input is
+/// read with [`ReadAt`], so every read operation has its own position, and
+/// the data blocks are kept in a cache shared by all threads.
+///
+/// ```
+/// # use theory::{Book, SharedBook};
+/// # use std::io::Cursor;
+/// let mut builder = Book::builder();
+/// let page_id = builder.new_page("First").set_content("Content").id();
+///
+/// let mut buffer = Vec::new();
+/// builder.dump(Cursor::new(&mut buffer)).unwrap();
+///
+/// let book = SharedBook::load(buffer).unwrap();
+///
+/// std::thread::scope(|s| {
+///     for _ in 0..4 {
+///         s.spawn(|| {
+///             let page = book.get_page_by_id(page_id).unwrap();
+///             assert_eq!(page.content(), b"Content");
+///         });
+///     }
+/// });
+/// ```
+pub struct SharedBook<R> {
+    /// Input with the book data.
+    input: R,
+
+    /// Size, in bytes, of the input.
+    input_len: u64,
+
+    /// Version of the file format.
+    version: Version,
+
+    /// Cache for the data blocks, shared by all threads.
+    cache: CacheHandle,
+
+    /// Number of pages in the book.
+    num_pages: usize,
+
+    /// Position, in bytes, of the metadata table in the input.
+    metadata_pos: usize,
+
+    /// Page index loaded from the input.
+    page_index: page::Index,
+
+    /// Position, in bytes, of the search index in the input, if any.
+    fts_pos: Option<u64>,
+
+    /// Search index, loaded when it is used for the first time.
+    search_index: Mutex<Option<Arc<search::Index>>>,
+
+    /// Analyzers to process search queries.
+    analyzers: search::analysis::Registry,
+}
+
+#[cfg(any(unix, windows))]
+impl SharedBook<File> {
+    /// Open a book from a file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, persistence::PersistenceError> {
+        SharedBook::load(File::open(path)?)
+    }
+}
+
+impl<R: ReadAt> SharedBook<R> {
+    /// Load book from an input, serialized with
+    /// [`BookBuilder::dump()`](crate::BookBuilder::dump).
+    pub fn load(input: R) -> Result<Self, persistence::PersistenceError> {
+        SharedBook::load_with(input, &BookOptions::default())
+    }
+
+    /// Load book from an input, with the options to control how it is read.
+    ///
+    /// Unless a [shared cache](BookOptions::shared_cache) is used, the cache
+    /// is split in multiple shards, so threads reading different blocks don't
+    /// compete for the same lock. The capacity is shared by all shards.
+    pub fn load_with(
+        input: R,
+        options: &BookOptions,
+    ) -> Result<Self, persistence::PersistenceError> {
+        let input_len = input.size()?;
+
+        let (version, num_pages, metadata_pos, page_index, fts_pos) = {
+            let options = BookOptions::default().disable_cache();
+            let book = Book::load_with(PositionedReader::new(&input, input_len), &options)?;
+            (
+                book.data_blocks.version(),
+                book.num_pages,
+                book.metadata_pos,
+                book.page_index,
+                book.fts_pos,
+            )
+        };
+
+        Ok(SharedBook {
+            input,
+            input_len,
+            version,
+            cache: options.sharded_cache_handle(),
+            num_pages,
+            metadata_pos,
+            page_index,
+            fts_pos,
+            search_index: Mutex::new(None),
+            analyzers: Default::default(),
+        })
+    }
+
+    /// Return the number of pages included in the book.
+    pub fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Return an iterator to get all metadata entries in the book.
+    pub fn metadata(
+        &self,
+    ) -> io::Result<impl Iterator<Item = Result<MetadataEntry, MetadataError>> + '_> {
+        let mut input = BufReader::new(self.stream());
+        input.seek(SeekFrom::Start(self.metadata_pos as u64))?;
+        Ok(metadata::load(input, self.input_len))
+    }
+
+    /// Return an iterator to get all pages in the book.
+    pub fn pages(&self) -> impl Iterator<Item = Result<page::Page, page::PageError>> + '_ {
+        let mut data_blocks = self.data_blocks();
+        self.page_index
+            .into_iter()
+            .map(move |(_, entry)| build_page(entry, &mut data_blocks))
+    }
+
+    /// Return a single page by its identifier.
+    pub fn get_page_by_id(&self, page_id: page::PageId) -> Result<page::Page, page::PageError> {
+        self.page_index.get_by_id(&mut self.data_blocks(), page_id)
+    }
+
+    /// Return a single page by its key.
+    ///
+    /// See [`Book::get_page_by_key`](crate::Book::get_page_by_key) for more
+    /// details.
+    pub fn get_page_by_key(&self, key: &str) -> Result<page::Page, page::PageError> {
+        let page_id = self.page_index.id_by_key(key)?;
+        self.page_index.get_by_id(&mut self.data_blocks(), page_id)
+    }
+
+    /// Table of contents of this book.
+    pub fn toc(&self) -> Result<impl Iterator<Item = crate::TocEntry>, toc::TocError> {
+        let toc = toc::BookToc::new(&mut self.data_blocks(), &self.page_index)?;
+        Ok(toc.into_iter())
+    }
+
+    /// Search pages matching `query`.
+    ///
+    /// See [`Book::search`] for more details.
+    pub fn search(&self, query: &str) -> Result<Vec<SearchHit>, search::SearchError> {
+        self.search_query(&Query::parse(query)?)
+    }
+
+    /// Search pages matching a [`Query`].
+    pub fn search_query(&self, query: &Query) -> Result<Vec<SearchHit>, search::SearchError> {
+        self.search_with(query, &SearchOptions::default())
+    }
+
+    /// Search pages matching a [`Query`], with the options to control the
+    /// results.
+    pub fn search_with(
+        &self,
+        query: &Query,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchHit>, search::SearchError> {
+        let index = self.search_index()?;
+
+        index.search(
+            &mut self.data_blocks(),
+            &self.page_index,
+            &self.analyzers,
+            query,
+            options,
+            None,
+        )
+    }
+
+    /// Return up to `limit` terms from the search index to complete `prefix`.
+    ///
+    /// See [`Book::suggest`] for more details.
+    pub fn suggest(&self, prefix: &str, limit: usize) -> Result<Vec<String>, search::SearchError> {
+        let index = self.search_index()?;
+        index.suggest(&self.analyzers, prefix, limit)
+    }
+
+    /// Return the statistics of the cache for the data blocks of this book.
+    ///
+    /// The statistics include the reads from all threads.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Register a custom analyzer to process search queries.
+    ///
+    /// See [`Book::add_analyzer`] for more details.
+    pub fn add_analyzer(&mut self, analyzer: impl Analyzer + 'static) -> &mut Self {
+        self.analyzers.add(None, Arc::new(analyzer));
+        self
+    }
+
+    /// Return a new stream to read the input.
+    fn stream(&self) -> PositionedReader<&R> {
+        PositionedReader::new(&self.input, self.input_len)
+    }
+
+    /// Return a new reader for the data blocks, using the shared cache.
+    fn data_blocks(&self) -> DataBlocksReader<PositionedReader<&R>> {
+        DataBlocksReader::with_len(
+            self.stream(),
+            self.input_len,
+            self.version,
+            self.cache.clone(),
+        )
+    }
+
+    /// Load the search index, if it is not already loaded.
+    ///
+    /// The lock is held while the index is loaded, so it is loaded only once
+    /// if multiple threads need it at the same time.
+    fn search_index(&self) -> Result<Arc<search::Index>, search::SearchError> {
+        let mut search_index = self
+            .search_index
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(index) = &*search_index {
+            return Ok(Arc::clone(index));
+        }
+
+        let fts_pos = self.fts_pos.ok_or(search::SearchError::MissingIndex)?;
+        let index = Arc::new(search::Index::load(&mut self.data_blocks(), fts_pos)?);
+        *search_index = Some(Arc::clone(&index));
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedBook;
+    use crate::{Book, BookOptions, MetadataEntry};
+    use std::io::Cursor;
+
+    #[test]
+    fn send_and_sync() {
+        fn check<T: Send + Sync>() {}
+
+        check::<SharedBook<Vec<u8>>>();
+        check::<SharedBook<std::fs::File>>();
+    }
+
+    #[test]
+    fn concurrent_reads() {
+        let mut builder = Book::builder();
+        builder.add_metadata(MetadataEntry::Title("Shared".into()));
+
+        let mut page_ids = Vec::new();
+        for n in 0..50 {
+            let page = builder
+                .new_page(format!("Page {}", n))
+                .set_content(format!("Content of the page number {}", n));
+
+            if n % 10 != 0 {
+                page.set_parent(page_ids[n - n % 10]);
+            }
+
+            page_ids.push(page.id());
+        }
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let options = BookOptions::default().cache_bytes(1 << 20);
+        let book = SharedBook::load_with(buffer, &options).unwrap();
+        assert_eq!(book.num_pages(), 50);
+
+        std::thread::scope(|s| {
+            for thread in 0..8 {
+                let book = &book;
+                let page_ids = &page_ids;
+
+                s.spawn(move || {
+                    for (n, page_id) in page_ids.iter().enumerate().skip(thread) {
+                        let page = book.get_page_by_id(*page_id).unwrap();
+                        let content = format!("Content of the page number {}", n);
+                        assert_eq!(page.content(), content.as_bytes());
+                    }
+
+                    let hits = book.search("number").unwrap();
+                    assert_eq!(hits.len(), 50);
+
+                    let toc: Vec<_> = book.toc().unwrap().collect();
+                    assert_eq!(toc.len(), 5);
+                    assert!(toc.iter().all(|e| e.children().count() == 9));
+
+                    let metadata: Vec<_> = book.metadata().unwrap().collect();
+                    assert!(
+                        matches!(&metadata[..], [Ok(MetadataEntry::Title(t))] if t == "Shared")
+                    );
+
+                    assert_eq!(book.pages().count(), 50);
+                });
+            }
+        });
+
+        let stats = book.cache_stats();
+        assert!(stats.hits() > 0);
+        assert!(stats.misses() > 0);
+    }
+
+    #[test]
+    fn open_file() {
+        let mut builder = Book::builder();
+        let page_id = builder.new_page("First").set_content("- 1 -").id();
+
+        let path = std::env::temp_dir().join(format!("theory-shared-{}.book", std::process::id()));
+        builder.dump_to_file(&path).unwrap();
+
+        let book = SharedBook::open(&path).unwrap();
+        assert_eq!(book.get_page_by_id(page_id).unwrap().content(), b"- 1 -");
+
+        drop(book);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -3,7 +3,8 @@
 use std::collections::{BTreeMap, HashMap};
 use std::io::{Read, Seek};
 
-use crate::page::{Index, PageId};
+use crate::page::persistence::IndexEntry;
+use crate::page::{Index, PageError, PageId};
 use crate::persistence::datablock::DataBlocksReader;
 
 use tinyvec::{ArrayVec, TinyVec};
@@ -30,6 +31,9 @@ pub enum TocError {
 
     #[error("Too many nested levels.")]
     ParentLoop,
+
+    #[error("Failed to open a volume: {0}.")]
+    Volume(crate::persistence::PersistenceError),
 }
 
 /// Entry in the TOC tree.
@@ -93,18 +97,26 @@ impl BookToc {
     ) -> Result<Self, TocError>
     where
         I: Read + Seek,
+    {
+        BookToc::with_titles(index, |entry| entry.get_page_title(data_blocks))
+    }
+
+    /// Build the TOC from the entries of a page index, sorted by their
+    /// identifiers, using `get_title` to read the title of every page.
+    pub(crate) fn with_titles<'a, E, F>(entries: E, mut get_title: F) -> Result<Self, TocError>
+    where
+        E: IntoIterator<Item = (&'a PageId, &'a IndexEntry)>,
+        F: FnMut(&IndexEntry) -> Result<String, PageError>,
     {
         let mut parents = HashMap::new();
         let mut tree = BTreeMap::new();
 
-        for (id, index_entry) in index {
+        for (id, index_entry) in entries {
             let parent_id = index_entry.parent_id();
 
             parents.insert(*id, parent_id);
 
-            let title = index_entry
-                .get_page_title(data_blocks)
-                .map_err(TocError::TitleError)?;
+            let title = get_title(index_entry).map_err(TocError::TitleError)?;
 
             match parent_id {
                 None => {
@@ -117,20 +129,7 @@ impl BookToc {
 
                 Some(parent_id) => {
                     // Compute the path using the cache in `parents`.
-                    let mut path = ArrayVec::<[_; MAX_SUB_LEVEL]>::new();
-                    let mut last_id = parent_id;
-
-                    loop {
-                        if path.try_push(Some(last_id)).is_some() {
-                            return Err(TocError::ParentLoop);
-                        }
-
-                        match parents.get(&last_id) {
-                            Some(None) => break,
-                            Some(Some(next_id)) => last_id = *next_id,
-                            None => return Err(TocError::InvalidParent(parent_id)),
-                        }
-                    }
+                    let path = ancestors(&parents, parent_id)?;
 
                     let target = path.into_iter().flatten().rev().try_fold(
                         (None, &mut tree),
@@ -157,6 +156,33 @@ impl BookToc {
     }
 }
 
+/// Path of pages from `parent_id` to the root of the tree, using the parent of
+/// every page in `parents`.
+///
+/// [`TocError::ParentLoop`] is returned if the path is too long, which is
+/// usually caused by a page being its own ancestor.
+pub(crate) fn ancestors(
+    parents: &HashMap<PageId, Option<PageId>>,
+    parent_id: PageId,
+) -> Result<ArrayVec<[Option<PageId>; MAX_SUB_LEVEL]>, TocError> {
+    let mut path = ArrayVec::new();
+    let mut last_id = parent_id;
+
+    loop {
+        if path.try_push(Some(last_id)).is_some() {
+            return Err(TocError::ParentLoop);
+        }
+
+        match parents.get(&last_id) {
+            Some(None) => break,
+            Some(Some(next_id)) => last_id = *next_id,
+            None => return Err(TocError::InvalidParent(parent_id)),
+        }
+    }
+
+    Ok(path)
+}
+
 impl IntoIterator for BookToc {
     type Item = TocEntry;
     type IntoIter = std::collections::btree_map::IntoValues<PageId, TocEntry>;
@@ -0,0 +1,348 @@
+//! Consistency checks for a book file.
+//!
+//! [`Book::verify`] reads every structure in the book, instead of waiting for
+//! a reader to find a problem when a page is accessed.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::{Read, Seek};
+
+use crate::errors::{MetadataError, PageError, PersistenceError, SearchError, TocError};
+use crate::page::{persistence::build_page, PageId};
+use crate::{search, toc, Book};
+
+/// Result of [`Book::verify`].
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    issues: Vec<VerifyIssue>,
+
+    num_pages: usize,
+
+    num_blocks: usize,
+}
+
+impl VerifyReport {
+    /// Return `true` if no issues were found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Issues found in the book.
+    pub fn issues(&self) -> &[VerifyIssue] {
+        &self.issues
+    }
+
+    /// Number of pages checked.
+    pub fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Number of data blocks checked.
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+}
+
+/// An issue found by [`Book::verify`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum VerifyIssue {
+    /// An entry in the metadata table of the book can't be parsed.
+    BookMetadata(MetadataError),
+
+    /// A data block can't be read, or its data can't be decompressed.
+    InvalidBlock {
+        block_id: u64,
+        error: PersistenceError,
+    },
+
+    /// Two data blocks use the same bytes in the file.
+    OverlappingBlocks { block_id: u64, next_block_id: u64 },
+
+    /// A page can't be read from its data blocks, or its metadata can't be
+    /// parsed.
+    InvalidPage { page_id: PageId, error: PageError },
+
+    /// The parent of a page does not exist.
+    MissingParent { page_id: PageId, parent_id: PageId },
+
+    /// A page is its own ancestor, or it is too deep in the tree.
+    ParentLoop { page_id: PageId },
+
+    /// An entry in the key table refers to a page that does not exist.
+    MissingKeyPage { key: String, page_id: PageId },
+
+    /// The search index can't be loaded.
+    SearchIndex(SearchError),
+}
+
+impl fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyIssue::BookMetadata(e) => write!(f, "Invalid book metadata: {}", e),
+
+            VerifyIssue::InvalidBlock { block_id, error } => {
+                write!(f, "Invalid data block {}: {}", block_id, error)
+            }
+
+            VerifyIssue::OverlappingBlocks {
+                block_id,
+                next_block_id,
+            } => write!(
+                f,
+                "Data block {} overlaps with block {}",
+                block_id, next_block_id
+            ),
+
+            VerifyIssue::InvalidPage { page_id, error } => {
+                write!(f, "Invalid page {}: {}", u32::from(*page_id), error)
+            }
+
+            VerifyIssue::MissingParent { page_id, parent_id } => write!(
+                f,
+                "Parent {} of page {} does not exist",
+                u32::from(*parent_id),
+                u32::from(*page_id)
+            ),
+
+            VerifyIssue::ParentLoop { page_id } => {
+                write!(f, "Loop in the parents of page {}", u32::from(*page_id))
+            }
+
+            VerifyIssue::MissingKeyPage { key, page_id } => write!(
+                f,
+                "Page {} for key {:?} does not exist",
+                u32::from(*page_id),
+                key
+            ),
+
+            VerifyIssue::SearchIndex(e) => write!(f, "Invalid search index: {}", e),
+        }
+    }
+}
+
+/// Check all the structures of a book.
+pub(crate) fn verify<I>(book: &mut Book<I>) -> VerifyReport
+where
+    I: Read + Seek,
+{
+    let mut report = VerifyReport {
+        num_pages: book.num_pages,
+        ..VerifyReport::default()
+    };
+
+    // Book metadata.
+    match book.metadata() {
+        Ok(entries) => report.issues.extend(
+            entries
+                .filter_map(Result::err)
+                .map(VerifyIssue::BookMetadata),
+        ),
+
+        Err(e) => report
+            .issues
+            .push(VerifyIssue::BookMetadata(MetadataError::IoError(e))),
+    }
+
+    // Blocks referenced by the page index and by the search index.
+    let mut block_ids = BTreeSet::new();
+
+    for (_, entry) in &book.page_index {
+        block_ids.extend(entry.block_ids());
+    }
+
+    if let Some(fts_pos) = book.fts_pos {
+        match search::Index::load(&mut book.data_blocks, fts_pos) {
+            Ok(index) => {
+                block_ids.extend(index.block_ids());
+
+                if let Err(e) = index.check_postings(&mut book.data_blocks) {
+                    report.issues.push(VerifyIssue::SearchIndex(e));
+                }
+            }
+
+            Err(e) => report.issues.push(VerifyIssue::SearchIndex(e)),
+        }
+    }
+
+    // Every block must be readable, and they must not overlap.
+    report.num_blocks = block_ids.len();
+
+    let mut block_ends = BTreeMap::new();
+    for block_id in block_ids {
+        let end = book.data_blocks.block_end(block_id).and_then(|end| {
+            book.data_blocks.with_block(block_id, 0, |_| ())?;
+            Ok(end)
+        });
+
+        match end {
+            Ok(end) => {
+                block_ends.insert(block_id, end);
+            }
+
+            Err(error) => report
+                .issues
+                .push(VerifyIssue::InvalidBlock { block_id, error }),
+        }
+    }
+
+    let mut last_block: Option<(u64, u64)> = None;
+    for (block_id, end) in block_ends {
+        if let Some((last_id, last_end)) = last_block {
+            if last_end > block_id {
+                report.issues.push(VerifyIssue::OverlappingBlocks {
+                    block_id: last_id,
+                    next_block_id: block_id,
+                });
+            }
+        }
+
+        last_block = Some((block_id, end));
+    }
+
+    // Pages.
+    let mut parents = HashMap::new();
+
+    for (page_id, entry) in &book.page_index {
+        parents.insert(*page_id, entry.parent_id());
+
+        if let Err(error) = build_page(entry, &mut book.data_blocks) {
+            report.issues.push(VerifyIssue::InvalidPage {
+                page_id: *page_id,
+                error,
+            });
+        }
+    }
+
+    // Tree of pages.
+    for (page_id, entry) in &book.page_index {
+        let parent_id = match entry.parent_id() {
+            Some(id) => id,
+            None => continue,
+        };
+
+        let page_id = *page_id;
+        match toc::ancestors(&parents, parent_id) {
+            Ok(_) => (),
+
+            Err(TocError::ParentLoop) => report.issues.push(VerifyIssue::ParentLoop { page_id }),
+
+            Err(_) => {
+                if !parents.contains_key(&parent_id) {
+                    report
+                        .issues
+                        .push(VerifyIssue::MissingParent { page_id, parent_id });
+                }
+            }
+        }
+    }
+
+    // Key table.
+    for (key, page_id) in book.page_index.keys() {
+        if !parents.contains_key(&page_id) {
+            report.issues.push(VerifyIssue::MissingKeyPage {
+                key: key.to_owned(),
+                page_id,
+            });
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VerifyIssue;
+    use crate::errors::PersistenceError;
+    use crate::{Book, PageId};
+    use std::io::Cursor;
+
+    #[test]
+    fn verify_books() {
+        let mut builder = Book::builder();
+
+        let p1 = builder.new_page("A").set_content("- A -").id();
+        let p2 = builder
+            .new_page("B")
+            .set_parent(p1)
+            .set_content("- B -")
+            .id();
+        builder.new_page("C").set_parent(p2);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer.clone())).unwrap();
+        let report = book.verify();
+
+        assert!(report.is_ok(), "{:?}", report.issues());
+        assert_eq!(report.num_pages(), 3);
+        assert!(report.num_blocks() > 1);
+
+        // Corrupt the block with the content.
+        let position = buffer.windows(5).position(|w| w == b"- B -").unwrap();
+        buffer[position] = b'*';
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        let report = book.verify();
+
+        let issues = report.issues();
+        assert_eq!(issues.len(), 4, "{:?}", issues);
+
+        assert!(matches!(
+            issues[0],
+            VerifyIssue::InvalidBlock {
+                error: PersistenceError::ChecksumMismatch { .. },
+                ..
+            }
+        ));
+
+        let pages: Vec<_> = issues[1..]
+            .iter()
+            .map(|issue| match issue {
+                VerifyIssue::InvalidPage { page_id, .. } => *page_id,
+                _ => panic!("Unexpected issue: {}", issue),
+            })
+            .collect();
+
+        assert_eq!(pages.len(), 3);
+    }
+
+    #[test]
+    fn verify_tree() {
+        let mut builder = Book::builder();
+
+        let missing = PageId::force_value(100);
+
+        let p1 = builder
+            .new_page("A")
+            .set_parent(PageId::force_value(2))
+            .id();
+        let p2 = builder.new_page("B").set_parent(p1).id();
+        let p3 = builder.new_page("C").set_parent(missing).id();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        builder
+            .dump(Cursor::new(&mut buffer))
+            .expect("BookBuilder::dump");
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        let report = book.verify();
+
+        let issues: Vec<_> = report.issues().iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            issues,
+            [
+                VerifyIssue::ParentLoop { page_id: p1 }.to_string(),
+                VerifyIssue::ParentLoop { page_id: p2 }.to_string(),
+                VerifyIssue::MissingParent {
+                    page_id: p3,
+                    parent_id: missing
+                }
+                .to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,781 @@
+//! Module with the `VolumeSet` implementation, and the function to split a
+//! book into volumes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::analysis::Analyzer;
+use crate::builder::toc_tree;
+use crate::page::persistence::{build_page, read_content_len};
+use crate::page::{Page, PageError};
+use crate::persistence::PersistenceError;
+use crate::search::{SearchError, Statistics};
+use crate::toc::{BookToc, TocError};
+use crate::{
+    BlockCompression, Book, BookWriter, CompressionPolicy, MetadataEntry, PageId, Query, SearchHit,
+    SearchOptions, TocEntry,
+};
+
+/// Options to split a book with [`Book::split_volumes`].
+///
+/// By default, every top-level entry of the table of contents, with all its
+/// descendants, is stored in its own volume.
+///
+/// ```
+/// use theory::SplitOptions;
+///
+/// // Put multiple subtrees in the same volume, up to 10 MiB of content.
+/// let options = SplitOptions::default().max_size(10 << 20);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SplitOptions {
+    max_size: Option<u64>,
+    compression: CompressionPolicy,
+}
+
+impl SplitOptions {
+    /// Store consecutive top-level subtrees in the same volume, while the
+    /// size of their content, before compression, is not larger than
+    /// `bytes`.
+    ///
+    /// Subtrees are never split, so a subtree larger than `bytes` is stored
+    /// in its own volume.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Set the compression method to store data in the volumes.
+    ///
+    /// It is the default method of the [`CompressionPolicy`].
+    pub fn compression(mut self, compression: BlockCompression) -> Self {
+        self.compression.set_default(compression);
+        self
+    }
+
+    /// Set the policy to select the compression method of every page.
+    pub fn compression_policy(mut self, policy: CompressionPolicy) -> Self {
+        self.compression = policy;
+        self
+    }
+}
+
+impl<I: Read + Seek> Book<I> {
+    /// Split this book into multiple files, along the subtrees of the table
+    /// of contents.
+    ///
+    /// The volumes are written in the same directory of `path`, adding the
+    /// volume number to its name: `docs.theory` is split into
+    /// `docs.1.theory`, `docs.2.theory`, etc. Pages keep their identifiers.
+    ///
+    /// Every volume contains the metadata of this book, a
+    /// [`Volume`](MetadataEntry::Volume) entry with its number, and a
+    /// [`VolumeFile`](MetadataEntry::VolumeFile) entry for each volume in the
+    /// set. The volumes can be read as a single book with [`VolumeSet`].
+    ///
+    /// The search index of every volume is built with the built-in
+    /// [analyzers](crate::analysis).
+    ///
+    /// Volumes are written one after the other, so only one file is open at
+    /// a time. If any volume can't be written, all files created by this
+    /// function are removed.
+    ///
+    /// Returns the paths of the volumes.
+    pub fn split_volumes(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: &SplitOptions,
+    ) -> Result<Vec<PathBuf>, PersistenceError> {
+        let path = path.as_ref();
+
+        let metadata: Vec<_> = read_metadata(self)?
+            .into_iter()
+            .filter(|entry| !is_volume_marker(entry))
+            .collect();
+
+        let volumes = self.plan_volumes(options)?;
+
+        // The names of the volumes only depend on their numbers, so the
+        // `VolumeFile` entries are known before writing them.
+        let paths = (1..=volumes.len())
+            .map(|number| volume_path(path, number))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut files = Vec::with_capacity(paths.len());
+        for (index, path) in paths.iter().enumerate() {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap();
+            files.push(MetadataEntry::VolumeFile(index as u32 + 1, name.to_owned()));
+        }
+
+        let entries: Vec<_> = (&self.page_index).into_iter().collect();
+
+        let mut created = 0;
+        let mut write_volumes = || {
+            for (index, pages) in volumes.iter().enumerate() {
+                let mut writer = BookWriter::create_file(&paths[index])?;
+                created += 1;
+
+                writer.set_compression_policy(options.compression.clone());
+
+                // Metadata is added before the pages, so the `Language`
+                // entry is used for the search index.
+                for entry in &metadata {
+                    writer.add_metadata(entry.clone());
+                }
+
+                for page in pages {
+                    writer.add_page(&build_page(entries[*page].1, &mut self.data_blocks)?)?;
+                }
+
+                writer.add_metadata(MetadataEntry::Volume(index as u32 + 1));
+                for entry in &files {
+                    writer.add_metadata(entry.clone());
+                }
+
+                writer.finish()?;
+            }
+
+            Ok(())
+        };
+
+        match write_volumes() {
+            Ok(()) => Ok(paths),
+
+            Err(e) => {
+                for path in &paths[..created] {
+                    let _ = std::fs::remove_file(path);
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Assign the pages to the volumes.
+    ///
+    /// Returns the indices of the pages in the page index for every volume,
+    /// in the order of the table of contents. There is always at least one
+    /// volume.
+    fn plan_volumes(&mut self, options: &SplitOptions) -> Result<Vec<Vec<usize>>, PageError> {
+        let entries: Vec<_> = (&self.page_index).into_iter().collect();
+        let tree: Vec<_> = entries
+            .iter()
+            .map(|(id, entry)| (id.0, entry.parent_id().map(|p| p.0)))
+            .collect();
+
+        let mut volumes: Vec<Vec<usize>> = Vec::new();
+        let mut volume_size = 0;
+
+        let mut order = toc_tree(&tree).into_iter().peekable();
+        while let Some((root, _)) = order.next() {
+            let mut subtree = vec![root];
+            while let Some((index, _)) = order.next_if(|(_, parent)| parent.is_some()) {
+                subtree.push(index);
+            }
+
+            // The size of the content is needed only to group the subtrees.
+            let mut size = 0;
+            if options.max_size.is_some() {
+                for index in &subtree {
+                    size += read_content_len(entries[*index].1, &mut self.data_blocks)?;
+                }
+            }
+
+            match (volumes.last_mut(), options.max_size) {
+                (Some(volume), Some(max_size)) if volume_size + size <= max_size => {
+                    volume.extend(subtree);
+                    volume_size += size;
+                }
+
+                _ => {
+                    volumes.push(subtree);
+                    volume_size = size;
+                }
+            }
+        }
+
+        // A book without pages is stored in a single volume.
+        if volumes.is_empty() {
+            volumes.push(Vec::new());
+        }
+
+        Ok(volumes)
+    }
+}
+
+/// Path of the file for a volume.
+fn volume_path(path: &Path, number: usize) -> Result<PathBuf, PersistenceError> {
+    let invalid_path = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid volume path.");
+
+    let mut name = path.file_stem().ok_or_else(invalid_path)?.to_owned();
+    name.push(format!(".{}", number));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+
+    // The name is stored in the metadata table.
+    if name.to_str().is_none() {
+        return Err(invalid_path().into());
+    }
+
+    Ok(path.with_file_name(name))
+}
+
+/// A set of volumes, created by [`Book::split_volumes`], read as a single
+/// book.
+///
+/// Volumes are opened when they are needed. For example,
+/// [`get_page_by_id`](Self::get_page_by_id) opens volumes only until the page
+/// is found, but [`toc`](Self::toc) and [`search`](Self::search) open all of
+/// them.
+///
+/// A book without the [`Volume`](MetadataEntry::Volume) entry is read as a
+/// set with a single volume.
+///
+/// ```no_run
+/// use theory::VolumeSet;
+///
+/// let mut volumes = VolumeSet::open("docs.1.theory").unwrap();
+/// for entry in volumes.toc().unwrap() {
+///     println!("{}", entry.title());
+/// }
+/// ```
+pub struct VolumeSet<I> {
+    /// File names of the volumes, with the book if it is already opened.
+    volumes: Vec<(String, Option<Book<I>>)>,
+
+    /// Metadata of the book, without the entries for the volumes.
+    metadata: Vec<MetadataEntry>,
+
+    /// Function to open the input of a volume from its file name.
+    open: OpenFn<I>,
+
+    /// Custom analyzers to process search queries.
+    analyzers: Vec<Arc<dyn Analyzer>>,
+}
+
+/// Boxed function used by `VolumeSet` to open its volumes.
+type OpenFn<I> = Box<dyn FnMut(&str) -> io::Result<I>>;
+
+impl VolumeSet<File> {
+    /// Open the volume in `path`. The other volumes in the set are opened
+    /// from the same directory.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PersistenceError> {
+        let path = path.as_ref();
+        let directory = path.parent().map(Path::to_owned).unwrap_or_default();
+
+        let book = Book::load(File::open(path)?)?;
+        VolumeSet::new(book, move |name| File::open(directory.join(name)))
+    }
+}
+
+impl<I: Read + Seek> VolumeSet<I> {
+    /// Create a set from one of its volumes.
+    ///
+    /// The other volumes are opened with `open`, which receives the file name
+    /// of the volume, as it is stored in the
+    /// [`VolumeFile`](MetadataEntry::VolumeFile) entries.
+    pub fn new<F>(mut book: Book<I>, open: F) -> Result<Self, PersistenceError>
+    where
+        F: FnMut(&str) -> io::Result<I> + 'static,
+    {
+        let metadata = read_metadata(&mut book)?;
+
+        let mut files = BTreeMap::new();
+        for entry in &metadata {
+            if let MetadataEntry::VolumeFile(number, name) = entry {
+                files.insert(*number, name.clone());
+            }
+        }
+
+        let mut volumes: Vec<_> = files.into_values().map(|name| (name, None)).collect();
+
+        match volume_number(&metadata) {
+            None => volumes = vec![(String::new(), Some(book))],
+
+            Some(number) => match volumes.get_mut((number as usize).wrapping_sub(1)) {
+                Some((_, volume)) if volumes_are_sequential(&metadata) => *volume = Some(book),
+                _ => {
+                    return Err(PersistenceError::InvalidVolume(format!(
+                        "missing file for the volume {}",
+                        number
+                    )))
+                }
+            },
+        }
+
+        let metadata = metadata
+            .into_iter()
+            .filter(|entry| !is_volume_marker(entry))
+            .collect();
+
+        Ok(VolumeSet {
+            volumes,
+            metadata,
+            open: Box::new(open),
+            analyzers: Vec::new(),
+        })
+    }
+
+    /// Return the number of volumes in the set.
+    pub fn num_volumes(&self) -> usize {
+        self.volumes.len()
+    }
+
+    /// Return `true` if the volume is already opened.
+    ///
+    /// Volumes are numbered from `1`.
+    pub fn is_open(&self, number: usize) -> bool {
+        matches!(self.volumes.get(number.wrapping_sub(1)), Some((_, Some(_))))
+    }
+
+    /// Return a volume of the set, opening it if necessary.
+    ///
+    /// Volumes are numbered from `1`.
+    pub fn volume(&mut self, number: usize) -> Result<&mut Book<I>, PersistenceError> {
+        let (name, volume) = self
+            .volumes
+            .get_mut(number.wrapping_sub(1))
+            .ok_or_else(|| PersistenceError::InvalidVolume(format!("no volume {}", number)))?;
+
+        if volume.is_none() {
+            let mut book = Book::load((self.open)(name)?)?;
+
+            // The file must be a volume of the same set.
+            if volume_number(&read_metadata(&mut book)?) != Some(number as u32) {
+                return Err(PersistenceError::InvalidVolume(format!(
+                    "{} is not the volume {}",
+                    name, number
+                )));
+            }
+
+            for analyzer in &self.analyzers {
+                book.analyzers.add(None, Arc::clone(analyzer));
+            }
+
+            *volume = Some(book);
+        }
+
+        Ok(volume.as_mut().unwrap())
+    }
+
+    /// Return the metadata entries of the book.
+    ///
+    /// The entries to describe the volumes are not included.
+    pub fn metadata(&self) -> &[MetadataEntry] {
+        &self.metadata
+    }
+
+    /// Return the number of pages in all volumes.
+    pub fn num_pages(&mut self) -> Result<usize, PersistenceError> {
+        Ok(self.open_all()?.map(|book| book.num_pages()).sum())
+    }
+
+    /// Return an iterator to get all pages in the book, in the order of the
+    /// volumes.
+    pub fn pages(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<Page, PageError>> + '_, PersistenceError> {
+        Ok(self.open_all()?.flat_map(|book| book.pages()))
+    }
+
+    /// Return a single page by its identifier.
+    ///
+    /// The volumes already opened are checked first.
+    pub fn get_page_by_id(&mut self, page_id: PageId) -> Result<Page, PageError> {
+        for number in self.lookup_order() {
+            let book = self.volume(number)?;
+            if book.page_index.entry(page_id).is_ok() {
+                return book.get_page_by_id(page_id);
+            }
+        }
+
+        Err(PageError::InvalidId(page_id.into()))
+    }
+
+    /// Return a single page by its key.
+    ///
+    /// Like in [`get_page_by_id`](Self::get_page_by_id), the volumes already
+    /// opened are checked first.
+    pub fn get_page_by_key(&mut self, key: &str) -> Result<Page, PageError> {
+        for number in self.lookup_order() {
+            let book = self.volume(number)?;
+            if let Ok(page_id) = book.page_index.id_by_key(key) {
+                return book.get_page_by_id(page_id);
+            }
+        }
+
+        Err(PageError::InvalidKey(key.to_owned()))
+    }
+
+    /// Table of contents of the book, with the pages of all volumes.
+    pub fn toc(&mut self) -> Result<impl Iterator<Item = TocEntry>, TocError> {
+        let mut entries = BTreeMap::new();
+        let mut titles = HashMap::new();
+
+        for book in self.open_all().map_err(TocError::Volume)? {
+            for (id, entry) in &book.page_index {
+                let title = entry
+                    .get_page_title(&mut book.data_blocks)
+                    .map_err(TocError::TitleError)?;
+
+                entries.insert(*id, entry.clone());
+                titles.insert(entry.page_id(), title);
+            }
+        }
+
+        let toc = BookToc::with_titles(&entries, |entry| {
+            Ok(titles.remove(&entry.page_id()).unwrap_or_default())
+        })?;
+
+        Ok(toc.into_iter())
+    }
+
+    /// Search pages matching `query` in all volumes.
+    ///
+    /// See [`Book::search`] for more details.
+    pub fn search(&mut self, query: &str) -> Result<Vec<SearchHit>, SearchError> {
+        self.search_with(&Query::parse(query)?, &SearchOptions::default())
+    }
+
+    /// Search pages matching a [`Query`] in all volumes, with the options to
+    /// control the results.
+    ///
+    /// Hits from all volumes are sorted by their relevance. The scores are
+    /// computed with the statistics of all volumes (like the number of pages
+    /// containing every term), so they are the same as if the pages were in
+    /// a single book.
+    pub fn search_with(
+        &mut self,
+        query: &Query,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchHit>, SearchError> {
+        let mut statistics = Statistics::default();
+        for book in self.open_all()? {
+            statistics.merge(book.search_statistics(query)?);
+        }
+
+        let mut hits = Vec::new();
+        for book in self.open_all()? {
+            hits.extend(book.search_with_statistics(query, options, Some(&statistics))?);
+        }
+
+        hits.sort_by(|a, b| {
+            b.score()
+                .total_cmp(&a.score())
+                .then(a.page_id().cmp(&b.page_id()))
+        });
+
+        if let Some(limit) = options.limit {
+            hits.truncate(limit);
+        }
+
+        Ok(hits)
+    }
+
+    /// Register a custom analyzer to process search queries.
+    ///
+    /// See [`Book::add_analyzer`] for more details.
+    pub fn add_analyzer(&mut self, analyzer: impl Analyzer + 'static) -> &mut Self {
+        let analyzer: Arc<dyn Analyzer> = Arc::new(analyzer);
+
+        for (_, book) in &mut self.volumes {
+            if let Some(book) = book {
+                book.analyzers.add(None, Arc::clone(&analyzer));
+            }
+        }
+
+        self.analyzers.push(analyzer);
+        self
+    }
+
+    /// Numbers of the volumes to find a page: first the opened volumes, and
+    /// then the others.
+    fn lookup_order(&self) -> Vec<usize> {
+        let opened = self.volumes.iter().map(|(_, book)| book.is_some());
+        let closed = self.volumes.iter().map(|(_, book)| book.is_none());
+
+        let opened = opened.enumerate().filter(|(_, open)| *open);
+        let closed = closed.enumerate().filter(|(_, closed)| *closed);
+
+        opened.chain(closed).map(|(index, _)| index + 1).collect()
+    }
+
+    /// Open all volumes, and return an iterator over them.
+    fn open_all(&mut self) -> Result<impl Iterator<Item = &mut Book<I>>, PersistenceError> {
+        for number in 1..=self.volumes.len() {
+            self.volume(number)?;
+        }
+
+        Ok(self
+            .volumes
+            .iter_mut()
+            .filter_map(|(_, book)| book.as_mut()))
+    }
+}
+
+/// Read all metadata entries of a book.
+fn read_metadata<I: Read + Seek>(
+    book: &mut Book<I>,
+) -> Result<Vec<MetadataEntry>, PersistenceError> {
+    let metadata = book
+        .metadata()?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| PageError::InvalidMetadata(e.to_string()))?;
+
+    Ok(metadata)
+}
+
+/// Return the number in the `Volume` entry.
+fn volume_number(metadata: &[MetadataEntry]) -> Option<u32> {
+    metadata.iter().find_map(|entry| match entry {
+        MetadataEntry::Volume(n) => Some(*n),
+        _ => None,
+    })
+}
+
+/// Return `true` if the `VolumeFile` entries are numbered from `1`, without
+/// gaps or duplicates.
+fn volumes_are_sequential(metadata: &[MetadataEntry]) -> bool {
+    metadata
+        .iter()
+        .filter_map(|entry| match entry {
+            MetadataEntry::VolumeFile(n, _) => Some(*n),
+            _ => None,
+        })
+        .enumerate()
+        .all(|(index, n)| n as usize == index + 1)
+}
+
+/// Return `true` if the entry describes the volumes of a set.
+fn is_volume_marker(entry: &MetadataEntry) -> bool {
+    matches!(
+        entry,
+        MetadataEntry::Volume(_) | MetadataEntry::VolumeFile(..)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SplitOptions, VolumeSet};
+    use crate::errors::PersistenceError;
+    use crate::{Book, MetadataEntry, PageId};
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    /// Build a book with three top-level subtrees.
+    fn build_book() -> (Vec<u8>, Vec<PageId>) {
+        let mut builder = Book::builder();
+        builder.add_metadata(MetadataEntry::Title("Volumes".into()));
+
+        let mut ids = Vec::new();
+        for (name, size) in [("A", 100), ("B", 100), ("C", 1000)] {
+            let root = builder
+                .new_page(name)
+                .set_content(format!("{} root", name))
+                .id();
+
+            let child = builder
+                .new_page(format!("{}.1", name))
+                .set_content(format!("{} child ", name).repeat(size / 8))
+                .set_parent(root)
+                .id();
+
+            ids.extend([root, child]);
+        }
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+        (buffer, ids)
+    }
+
+    fn split(name: &str, options: &SplitOptions) -> Vec<PathBuf> {
+        let (buffer, _) = build_book();
+        let path =
+            std::env::temp_dir().join(format!("theory-{}-{}.book", name, std::process::id()));
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        book.split_volumes(path, options).unwrap()
+    }
+
+    fn toc_titles(entries: impl Iterator<Item = crate::TocEntry>) -> Vec<(String, Vec<u32>)> {
+        fn visit(entry: &crate::TocEntry, titles: &mut Vec<(String, Vec<u32>)>) {
+            titles.push((entry.title().to_owned(), entry.section_numbers().to_vec()));
+            for child in entry.children() {
+                visit(child, titles);
+            }
+        }
+
+        let mut titles = Vec::new();
+        for entry in entries {
+            visit(&entry, &mut titles);
+        }
+
+        titles
+    }
+
+    #[test]
+    fn split_by_subtree() {
+        let (buffer, ids) = build_book();
+        let paths = split("volumes", &SplitOptions::default());
+        assert_eq!(paths.len(), 3);
+
+        // Every volume has the book metadata and the markers.
+        let mut volume = Book::load(std::fs::File::open(&paths[1]).unwrap()).unwrap();
+        assert!(volume.verify().is_ok());
+        assert_eq!(volume.num_pages(), 2);
+
+        let metadata: Vec<_> = volume.metadata().unwrap().map(Result::unwrap).collect();
+        assert!(metadata.contains(&MetadataEntry::Title("Volumes".into())));
+        assert!(metadata.contains(&MetadataEntry::Volume(2)));
+
+        for (index, path) in paths.iter().enumerate() {
+            let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+            assert!(metadata.contains(&MetadataEntry::VolumeFile(index as u32 + 1, name)));
+        }
+
+        // Volumes are opened only when they are needed.
+        let mut volumes = VolumeSet::open(&paths[1]).unwrap();
+        assert_eq!(volumes.num_volumes(), 3);
+        assert_eq!(volumes.metadata(), [MetadataEntry::Title("Volumes".into())]);
+
+        let page = volumes.get_page_by_id(ids[3]).unwrap();
+        assert_eq!(page.parent(), Some(ids[2]));
+        assert!(!volumes.is_open(1) && volumes.is_open(2) && !volumes.is_open(3));
+
+        let page = volumes.get_page_by_id(ids[4]).unwrap();
+        assert_eq!(page.content(), b"C root");
+        assert!(volumes.is_open(3));
+
+        // The set is read like the original book.
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        assert_eq!(volumes.num_pages().unwrap(), book.num_pages());
+        assert_eq!(
+            toc_titles(volumes.toc().unwrap()),
+            toc_titles(book.toc().unwrap())
+        );
+
+        let hits: Vec<_> = volumes.search("child").unwrap();
+        let ids_found: Vec<_> = hits.iter().map(|hit| hit.page_id()).collect();
+        assert_eq!(ids_found.len(), 3);
+        assert!([ids[1], ids[3], ids[5]]
+            .iter()
+            .all(|id| ids_found.contains(id)));
+
+        let pages = volumes
+            .pages()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let original = book.pages().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(pages, original);
+
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn search_scores() {
+        let (buffer, _) = build_book();
+        let paths = split("volumes-scores", &SplitOptions::default().max_size(400));
+
+        let mut volumes = VolumeSet::open(&paths[0]).unwrap();
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+
+        // Scores are the same of the original book.
+        for query in ["child", "root OR a*", "title:c OR \"b child\"", "chlid~"] {
+            let scores = |hits: Vec<crate::SearchHit>| {
+                let mut scores: Vec<_> = hits.iter().map(|h| (h.page_id(), h.score())).collect();
+                scores.sort_by_key(|(id, _)| *id);
+                scores
+            };
+
+            let expected = scores(book.search(query).unwrap());
+            let found = scores(volumes.search(query).unwrap());
+
+            assert!(!expected.is_empty());
+            assert_eq!(expected.len(), found.len(), "{}", query);
+            for ((id_a, a), (id_b, b)) in expected.iter().zip(&found) {
+                assert_eq!(id_a, id_b);
+                assert!((a - b).abs() < 1e-4, "{}: {} != {}", query, a, b);
+            }
+        }
+
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn split_by_size() {
+        let paths = split("volumes-size", &SplitOptions::default().max_size(400));
+
+        // `A` and `B` fit in the first volume, but `C` is too large.
+        assert_eq!(paths.len(), 2);
+
+        let mut volumes = VolumeSet::open(&paths[0]).unwrap();
+        assert_eq!(volumes.volume(1).unwrap().num_pages(), 4);
+        assert_eq!(volumes.volume(2).unwrap().num_pages(), 2);
+        assert!(volumes.volume(3).is_err());
+
+        // A file from a different volume is rejected.
+        std::fs::copy(&paths[0], &paths[1]).unwrap();
+
+        let mut volumes = VolumeSet::open(&paths[0]).unwrap();
+        assert!(matches!(
+            volumes.num_pages(),
+            Err(PersistenceError::InvalidVolume(_))
+        ));
+
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn remove_volumes_on_error() {
+        let mut builder = Book::builder();
+        builder.set_block_size(16);
+
+        for name in ["A", "B", "C"] {
+            builder
+                .new_page(name)
+                .set_content(format!("Content of the page {}", name));
+        }
+
+        let mut buffer = Vec::new();
+        builder.dump(Cursor::new(&mut buffer)).unwrap();
+
+        // Corrupt the data block of the last page.
+        let needle = b"the page C";
+        let position = buffer.windows(needle.len()).position(|w| w == needle);
+        buffer[position.unwrap()] = b'T';
+
+        let path = std::env::temp_dir().join(format!("theory-broken-{}.book", std::process::id()));
+
+        let mut book = Book::load(Cursor::new(buffer)).unwrap();
+        assert!(book.split_volumes(&path, &SplitOptions::default()).is_err());
+
+        for number in 1..=3 {
+            let name = format!("theory-broken-{}.{}.book", std::process::id(), number);
+            assert!(!path.with_file_name(name).exists());
+        }
+    }
+
+    #[test]
+    fn single_volume() {
+        let (buffer, ids) = build_book();
+
+        let book = Book::load(Cursor::new(buffer)).unwrap();
+        let mut volumes = VolumeSet::new(book, |_| unreachable!()).unwrap();
+
+        assert_eq!(volumes.num_volumes(), 1);
+        assert_eq!(volumes.num_pages().unwrap(), 6);
+        assert!(volumes.get_page_by_id(ids[5]).is_ok());
+    }
+}